@@ -0,0 +1,272 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::resource::{AutoScaler, AutoScalerSpec};
+use futures::channel::mpsc::UnboundedSender;
+use futures::SinkExt;
+use serde::Deserialize;
+use serde_json::json;
+use slog::{error, info, warn, Logger};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Pangolin's configuration file, AutoScalers declared outside of the kubernetes api, eg. in a
+/// ConfigMap mounted into the pod.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PangolinConfig {
+    /// Statically configured AutoScalers.
+    #[serde(default)]
+    pub autoscalers: Vec<ConfiguredAutoScaler>,
+}
+
+/// An AutoScaler declared in the configuration file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfiguredAutoScaler {
+    /// Name of the AutoScaler, used in logs and to identify it, must be unique per namespace.
+    pub name: String,
+    /// Namespace of the AutoScaler, matching objects are looked for here.
+    pub namespace: String,
+    /// The same specification as an AutoScaler resource.
+    pub spec: AutoScalerSpec,
+}
+
+impl PangolinConfig {
+    /// Read the contents of the configuration file at the path.
+    pub fn read(path: &str) -> Result<String, Error> {
+        std::fs::read_to_string(path).context(ConfigFile { path })
+    }
+
+    /// Parse and validate a configuration file, invalid fields are reported with their location
+    /// in the file, eg. `autoscalers[0].spec.limits.replicas`.
+    pub fn parse(path: &str, contents: &str) -> Result<Self, Error> {
+        let config: Self = serde_yaml::from_str(contents).context(ConfigParse { path })?;
+        let mut declared = HashMap::new();
+        for (index, autoscaler) in config.autoscalers.iter().enumerate() {
+            if let Err(Error::InvalidSpec { field, reason }) = autoscaler.spec.validate() {
+                return InvalidSpec {
+                    field: format!("autoscalers[{}].spec.{}", index, field),
+                    reason,
+                }
+                .fail();
+            }
+            // Only one of several AutoScalers sharing a name could be reconciled.
+            let key = (autoscaler.namespace.as_str(), autoscaler.name.as_str());
+            if let Some(declared_index) = declared.insert(key, index) {
+                return InvalidSpec {
+                    field: format!("autoscalers[{}].name", index),
+                    reason: format!(
+                        "{} in namespace {} is already declared by autoscalers[{}]",
+                        autoscaler.name, autoscaler.namespace, declared_index
+                    ),
+                }
+                .fail();
+            }
+        }
+        Ok(config)
+    }
+
+    /// The configured AutoScalers, as if they had been retrieved from the kubernetes api.
+    pub fn autoscalers(&self) -> Result<Vec<AutoScaler>, Error> {
+        self.autoscalers
+            .iter()
+            .map(|autoscaler| {
+                serde_json::from_value(json!({
+                    "apiVersion": "pangolinscaler.com/v1alpha1",
+                    "kind": "AutoScaler",
+                    "metadata": {
+                        "name": autoscaler.name,
+                        "namespace": autoscaler.namespace,
+                    },
+                    "spec": autoscaler.spec,
+                }))
+                .context(JsonSerialization {})
+            })
+            .collect()
+    }
+}
+
+/// Poll the configuration file for changes, sending the autoscalers of every valid new version.
+/// Kubernetes updates a mounted ConfigMap by swapping a symlink to a new directory, which an
+/// inotify watch on the file itself never sees, so the file is re-read (following the symlink) and
+/// compared with the contents last seen. Invalid versions are logged and the previous
+/// configuration is kept.
+pub async fn watch_config(
+    logger: Logger,
+    path: String,
+    mut contents: String,
+    poll_interval: Duration,
+    mut updates: UnboundedSender<Vec<AutoScaler>>,
+) {
+    loop {
+        delay_for(poll_interval).await;
+
+        let updated_contents = match PangolinConfig::read(&path) {
+            Ok(updated_contents) => updated_contents,
+            Err(err) => {
+                warn!(logger, "Failed to read configuration file, keeping the previous configuration";
+                    "error" => format!("{}", err));
+                continue;
+            }
+        };
+        if updated_contents == contents {
+            continue;
+        }
+        contents = updated_contents;
+
+        match PangolinConfig::parse(&path, &contents).and_then(|config| config.autoscalers()) {
+            Ok(autoscalers) => {
+                info!(logger, "Reloading configuration file"; "autoscalers" => autoscalers.len());
+                if updates.send(autoscalers).await.is_err() {
+                    // Nothing is listening for updates any more.
+                    break;
+                }
+            }
+            Err(err) => {
+                error!(logger, "Invalid configuration file, keeping the previous configuration";
+                    "error" => format!("{}", err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::unbounded;
+    use futures::StreamExt;
+    use slog::{o, Drain};
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use tokio::time::timeout;
+
+    const CONFIG: &str = r#"
+autoscalers:
+  - name: web
+    namespace: default
+    spec:
+      strategy: BangBang
+      kind: Deployment
+      selector:
+        matchLabels:
+          app: web
+      metric:
+        name: connections
+        interval: 10
+      interval: 60
+      cooldown: 300
+      limits:
+        replicas:
+          min: 2
+          max: 10
+      bangBang:
+        lower: 10.0
+        upper: 50.0
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = PangolinConfig::parse("config.yaml", CONFIG).unwrap();
+        let autoscalers = config.autoscalers().unwrap();
+        assert_eq!(autoscalers.len(), 1);
+        assert_eq!(autoscalers[0].metadata.name, "web");
+        assert_eq!(
+            autoscalers[0].metadata.namespace.as_deref(),
+            Some("default")
+        );
+        assert_eq!(autoscalers[0].spec.cooldown_for(true), 300);
+
+        // Inconsistent bounds are reported with the offending field.
+        let invalid = CONFIG.replace("min: 2", "min: 20");
+        let err = PangolinConfig::parse("config.yaml", &invalid).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "invalid autoscalers[0].spec.limits.replicas: min (20) is greater than max (10)"
+        );
+    }
+
+    #[test]
+    fn test_parse_duplicate_autoscalers() {
+        let entries = CONFIG.replace("\nautoscalers:\n", "\n");
+        let duplicated = format!("{}{}", CONFIG, entries);
+        let err = PangolinConfig::parse("config.yaml", &duplicated).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "invalid autoscalers[1].name: web in namespace default is already declared by autoscalers[0]"
+        );
+
+        // The same name may be used in another namespace.
+        let other_namespace = format!("{}{}", CONFIG, entries.replace("default", "other"));
+        let config = PangolinConfig::parse("config.yaml", &other_namespace).unwrap();
+        assert_eq!(config.autoscalers().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_watch_config() {
+        // Mounted ConfigMaps are a symlink into a data directory, which is swapped on updates.
+        let dir = std::env::temp_dir().join(format!("pangolin-config-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let swap = |version: &str, contents: &str| {
+            std::fs::create_dir(dir.join(version)).unwrap();
+            std::fs::write(dir.join(version).join("config.yaml"), contents).unwrap();
+            symlink(version, dir.join("..data_tmp")).unwrap();
+            std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+        };
+        swap("v1", CONFIG);
+        symlink(
+            Path::new("..data").join("config.yaml"),
+            dir.join("config.yaml"),
+        )
+        .unwrap();
+
+        let (update_sender, mut updates) = unbounded();
+        tokio::spawn(watch_config(
+            get_logger(),
+            dir.join("config.yaml").to_str().unwrap().into(),
+            CONFIG.into(),
+            Duration::from_millis(10),
+            update_sender,
+        ));
+        let update_timeout = Duration::from_secs(5);
+
+        swap("v2", &CONFIG.replace("cooldown: 300", "cooldown: 600"));
+        let autoscalers = timeout(update_timeout, updates.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(autoscalers[0].spec.cooldown_for(true), 600);
+
+        // An invalid version is skipped, keeping the previous configuration.
+        swap("v3", &CONFIG.replace("min: 2", "min: 20"));
+        delay_for(Duration::from_millis(100)).await;
+        swap("v4", &CONFIG.replace("cooldown: 300", "cooldown: 900"));
+        let autoscalers = timeout(update_timeout, updates.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(autoscalers[0].spec.cooldown_for(true), 900);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn get_logger() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+}
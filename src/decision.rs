@@ -0,0 +1,217 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long the decision of an object that is no longer reconciled, eg. a deleted object, is kept.
+const DECISION_RETENTION: Duration = Duration::from_secs(3600);
+
+/// How often the decisions of objects that are no longer reconciled are expired.
+const DECISION_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The value of a metric, and the number of replicas its strategy wants.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecisionMetric {
+    pub name: String,
+    pub value: f64,
+    pub desired_replicas: u32,
+}
+
+/// A limit that moved the desired number of replicas, eg. the object's own bounds.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DecisionClamp {
+    pub clamp: &'static str,
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Why an object was, or wasn't, scaled in a reconciliation.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Decision {
+    pub kind: &'static str,
+    pub namespace: String,
+    pub name: String,
+    /// When the reconciliation started, in RFC 3339 format.
+    pub timestamp: String,
+    pub current_replicas: Option<u32>,
+    pub metrics: Vec<DecisionMetric>,
+    /// The number of replicas the metrics and schedules call for, before any limits are applied.
+    pub target_replicas: Option<u32>,
+    pub clamps: Vec<DecisionClamp>,
+    /// The number of replicas the object is scaled to, once every limit has been applied.
+    pub desired_replicas: Option<u32>,
+    /// What was done, the same as the decision field of pangolin's log lines, eg. scaled or cooldown.
+    pub action: &'static str,
+    pub error: Option<String>,
+    #[serde(skip)]
+    decided_at: DateTime<Utc>,
+}
+
+impl Decision {
+    pub fn new(kind: &'static str, namespace: &str, name: &str) -> Self {
+        Self::new_at(kind, namespace, name, Utc::now())
+    }
+
+    fn new_at(kind: &'static str, namespace: &str, name: &str, now: DateTime<Utc>) -> Self {
+        Self {
+            kind,
+            namespace: namespace.into(),
+            name: name.into(),
+            timestamp: now.to_rfc3339(),
+            current_replicas: None,
+            metrics: Vec::new(),
+            target_replicas: None,
+            clamps: Vec::new(),
+            desired_replicas: None,
+            action: "unknown",
+            error: None,
+            decided_at: now,
+        }
+    }
+
+    pub fn metric(&mut self, name: &str, value: f64, desired_replicas: u32) {
+        self.metrics.push(DecisionMetric {
+            name: name.into(),
+            value,
+            desired_replicas,
+        });
+    }
+
+    pub fn clamp(&mut self, clamp: &'static str, from: u32, to: u32) {
+        self.clamps.push(DecisionClamp { clamp, from, to });
+    }
+
+    pub fn conclude(&mut self, action: &'static str) {
+        self.action = action;
+    }
+
+    fn key(&self) -> String {
+        format!("{}/{}/{}", self.kind, self.namespace, self.name)
+    }
+}
+
+/// The latest decision for each reconciled object, kept in memory for the decisions endpoint.
+#[derive(Default)]
+pub struct DecisionLog {
+    decisions: Mutex<Decisions>,
+}
+
+#[derive(Default)]
+struct Decisions {
+    by_object: HashMap<String, Decision>,
+    /// When decisions were last expired, so that recording stays cheap with many objects.
+    expired_at: Option<DateTime<Utc>>,
+}
+
+impl DecisionLog {
+    /// Replace the object's previous decision.
+    pub fn record(&self, decision: Decision) {
+        let mut decisions = self.decisions.lock().unwrap();
+        // Periodically forget objects that haven't been reconciled in a long time.
+        let expiry_interval = chrono::Duration::from_std(DECISION_EXPIRY_INTERVAL).unwrap();
+        let expire = decisions.expired_at.map_or(true, |expired_at| {
+            decision.decided_at - expired_at >= expiry_interval
+        });
+        if expire {
+            let retention = chrono::Duration::from_std(DECISION_RETENTION).unwrap();
+            decisions
+                .by_object
+                .retain(|_, previous| decision.decided_at - previous.decided_at <= retention);
+            decisions.expired_at = Some(decision.decided_at);
+        }
+        decisions.by_object.insert(decision.key(), decision);
+    }
+
+    /// The latest decisions, ordered by object.
+    pub fn latest(&self) -> Vec<Decision> {
+        let mut decisions: Vec<Decision> = self
+            .decisions
+            .lock()
+            .unwrap()
+            .by_object
+            .values()
+            .cloned()
+            .collect();
+        decisions.sort_by_key(Decision::key);
+        decisions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_decision_log() {
+        let log = DecisionLog::default();
+        let started = Utc.ymd(2020, 3, 29).and_hms(1, 30, 0);
+
+        let mut decision = Decision::new_at("Deployment", "default", "web", started);
+        decision.current_replicas = Some(2);
+        decision.metric("connections", 500.0, 5);
+        decision.target_replicas = Some(5);
+        decision.clamp("max_scale_step", 5, 3);
+        decision.desired_replicas = Some(3);
+        decision.conclude("scaled");
+        log.record(decision);
+        log.record(Decision::new_at("Deployment", "default", "api", started));
+        assert_eq!(
+            serde_json::to_value(&log.latest()[1]).unwrap(),
+            serde_json::json!({
+                "kind": "Deployment",
+                "namespace": "default",
+                "name": "web",
+                "timestamp": "2020-03-29T01:30:00+00:00",
+                "currentReplicas": 2,
+                "metrics": [{"name": "connections", "value": 500.0, "desiredReplicas": 5}],
+                "targetReplicas": 5,
+                "clamps": [{"clamp": "max_scale_step", "from": 5, "to": 3}],
+                "desiredReplicas": 3,
+                "action": "scaled",
+                "error": null,
+            })
+        );
+
+        // A later decision replaces the last, and objects that are no longer reconciled expire.
+        let later = started + chrono::Duration::hours(2);
+        let mut decision = Decision::new_at("Deployment", "default", "web", later);
+        decision.conclude("cooldown");
+        log.record(decision);
+        let latest = log.latest();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].action, "cooldown");
+
+        // Expiry only runs once per interval, not on every decision recorded.
+        let much_later = later + chrono::Duration::hours(2);
+        log.record(Decision::new_at("Deployment", "default", "api", much_later));
+        log.record(Decision::new_at(
+            "Deployment",
+            "default",
+            "worker",
+            much_later + chrono::Duration::seconds(1),
+        ));
+        assert_eq!(log.latest().len(), 2);
+        let decisions = log.decisions.lock().unwrap();
+        assert_eq!(decisions.expired_at, Some(much_later));
+    }
+}
@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use hyper::StatusCode;
 use snafu::Snafu;
 
 /// Pangolin error types.
@@ -28,11 +29,410 @@ pub enum Error {
     #[snafu(display("json serialization error: {}", source))]
     JsonSerialization { source: serde_json::Error },
 
+    /// Prometheus queries that could not be evaluated.
+    #[snafu(display("prometheus query {} failed: {}", query, message))]
+    PrometheusQuery { query: String, message: String },
+
+    /// Metric queries that didn't return any series.
+    #[snafu(display("no series returned for metric query: {}", query))]
+    NoMetricSeries { query: String },
+
+    /// None of an object's pods reported the metric.
+    #[snafu(display("no pods reported metric: {}", metric))]
+    NoPodMetrics { metric: String },
+
+    /// StatsD gauges that have never been pushed to the listener.
+    #[snafu(display("no statsd gauge received for key: {}", key))]
+    NoStatsdGauge { key: String },
+
+    /// External metric endpoints that could not be read.
+    #[snafu(display("external metric {} failed: {}", endpoint, message))]
+    ExternalMetric { endpoint: String, message: String },
+
+    /// External metric endpoints that didn't respond within the request timeout.
+    #[snafu(display(
+        "external metric {} timed out after {} milliseconds",
+        endpoint,
+        timeout_ms
+    ))]
+    ExternalMetricTimeout { endpoint: String, timeout_ms: u32 },
+
+    /// External metric endpoints that responded with an unsuccessful http status.
+    #[snafu(display("external metric {} returned unexpected status: {}", endpoint, status))]
+    ExternalMetricStatus { endpoint: String, status: u16 },
+
     /// Kubernetes API related errors.
-    #[snafu(display("kubernetes error: {}", source))]
+    #[snafu(display("kubernetes error: {}{}", source, api_status(source)))]
     Kube { source: kube::Error },
 
-    /// Kubernetes specification errors.
-    #[snafu(display("kubernetes spec is missing fields"))]
-    KubeSpec {},
+    /// Kubernetes API errors for a specific object, eg. while scaling it.
+    #[snafu(display(
+        "kubernetes error for {} {}/{}: {}{}",
+        kind,
+        namespace,
+        name,
+        source,
+        api_status(source)
+    ))]
+    KubeObject {
+        kind: String,
+        namespace: String,
+        name: String,
+        source: kube::Error,
+    },
+
+    /// Kubernetes API errors listing objects of a resource kind.
+    #[snafu(display(
+        "kubernetes error listing {} in {}: {}{}",
+        kind,
+        namespace,
+        source,
+        api_status(source)
+    ))]
+    KubeList {
+        kind: String,
+        namespace: String,
+        source: kube::Error,
+    },
+
+    /// Timestamp parsing errors, eg. from a malformed annotation.
+    #[snafu(display("timestamp parse error: {}", source))]
+    TimestampParse { source: chrono::ParseError },
+
+    /// Annotation values that could not be parsed.
+    #[snafu(display("invalid value for annotation {}: {}: {}", name, value, source))]
+    InvalidAnnotation {
+        name: String,
+        value: String,
+        source: std::num::ParseIntError,
+    },
+
+    /// Minimum and maximum replica bounds that are inverted.
+    #[snafu(display("invalid replica bounds: min {} is greater than max {}", min, max))]
+    InvalidReplicaBounds { min: u32, max: u32 },
+
+    /// Scaling operations whose target falls outside the object's replica bounds.
+    #[snafu(display(
+        "refusing to scale {}/{} to {} replicas, outside of its bounds [{}, {}]",
+        namespace,
+        name,
+        replicas,
+        min,
+        max
+    ))]
+    ReplicasOutOfBounds {
+        namespace: String,
+        name: String,
+        replicas: u32,
+        min: u32,
+        max: u32,
+    },
+
+    /// Label selectors that can't be rendered, eg. with an unknown operator.
+    #[snafu(display("invalid label selector: {}", reason))]
+    InvalidLabelSelector { reason: String },
+
+    /// Named container ports that a pod doesn't expose.
+    #[snafu(display("pod {} has no container port named {}", pod, port))]
+    PortNotFound { pod: String, port: String },
+
+    /// Port numbers outside of the valid range.
+    #[snafu(display("invalid port number: {}", port))]
+    InvalidPort { port: i32 },
+
+    /// Server-side apply patches that conflict with fields owned by another field manager, eg. a
+    /// `kubectl apply` that set the replicas.
+    #[snafu(display(
+        "scaling {}/{} conflicts with another field manager, use --force-apply true to take ownership: {}",
+        namespace,
+        name,
+        message
+    ))]
+    FieldManagerConflict {
+        namespace: String,
+        name: String,
+        message: String,
+    },
+
+    /// Objects that were deleted before they could be scaled.
+    #[snafu(display("object {}/{} no longer exists", namespace, name))]
+    ObjectGone { namespace: String, name: String },
+
+    /// Cron expressions that could not be parsed.
+    #[snafu(display("invalid schedule {}: {}", expression, reason))]
+    InvalidSchedule { expression: String, reason: String },
+
+    /// Timezones that aren't in the IANA timezone database.
+    #[snafu(display("invalid timezone: {}", timezone))]
+    InvalidTimezone { timezone: String },
+
+    /// Kubernetes API calls that didn't complete in time.
+    #[snafu(display("{} timed out after {} seconds", operation, seconds))]
+    Timeout { operation: String, seconds: u64 },
+
+    /// Unknown kubernetes configuration sources.
+    #[snafu(display("invalid kubernetes configuration source: {}", source_name))]
+    InvalidConfigSource { source_name: String },
+
+    /// Kubernetes specification errors for a specific object.
+    #[snafu(display("{} {}/{} spec is missing fields", kind, namespace, name))]
+    KubeObjectSpec {
+        kind: String,
+        namespace: String,
+        name: String,
+    },
+
+    /// JSON serialization errors for a specific object, eg. while building a patch.
+    #[snafu(display(
+        "json serialization error for {} {}/{}: {}",
+        kind,
+        namespace,
+        name,
+        source
+    ))]
+    ObjectSerialization {
+        kind: String,
+        namespace: String,
+        name: String,
+        source: serde_json::Error,
+    },
+
+    /// AutoScaler specifications with invalid or inconsistent fields.
+    #[snafu(display("invalid {}: {}", field, reason))]
+    InvalidSpec { field: String, reason: String },
+
+    /// Configuration files that could not be read.
+    #[snafu(display("failed to read configuration file {}: {}", path, source))]
+    ConfigFile {
+        path: String,
+        source: std::io::Error,
+    },
+
+    /// Configuration files that are not valid YAML, or don't match the expected structure.
+    #[snafu(display("failed to parse configuration file {}: {}", path, source))]
+    ConfigParse {
+        path: String,
+        source: serde_yaml::Error,
+    },
+
+    /// Webhooks that didn't accept a notification.
+    #[snafu(display("webhook notification to {} failed: {}", endpoint, message))]
+    WebhookNotification { endpoint: String, message: String },
+
+    /// Resources that discovery found can't be autoscaled, eg. without a scale subresource.
+    #[snafu(display("resource {} can't be autoscaled: {}", resource, reason))]
+    UnsupportedResource { resource: String, reason: String },
+
+    /// Custom resources whose CustomResourceDefinition isn't installed in the cluster.
+    #[snafu(display(
+        "the {} custom resource ({}/{}) is not installed in the cluster",
+        kind,
+        group,
+        version
+    ))]
+    MissingCustomResource {
+        kind: String,
+        group: String,
+        version: String,
+    },
+
+    /// Reconciliation tasks that panicked, or were cancelled, before they completed.
+    #[snafu(display("reconciliation task failed: {}", message))]
+    ReconcileTask { message: String },
+
+    /// The metrics.k8s.io api isn't registered, or its backing metrics server isn't responding.
+    #[snafu(display(
+        "the metrics.k8s.io api is unavailable ({}), is metrics-server installed and running?",
+        code
+    ))]
+    MetricsApiUnavailable { code: u16 },
+
+    /// Operations that aren't supported by a resource kind.
+    #[snafu(display("unsupported operation: {}", operation))]
+    Unsupported { operation: String },
+}
+
+impl Error {
+    /// The kubernetes API error response, if the error came from the API server.
+    pub fn kube_api_response(&self) -> Option<&kube::ErrorResponse> {
+        match self {
+            Error::Kube {
+                source: kube::Error::Api(response),
+            }
+            | Error::KubeObject {
+                source: kube::Error::Api(response),
+                ..
+            }
+            | Error::KubeList {
+                source: kube::Error::Api(response),
+                ..
+            } => Some(response),
+            _ => None,
+        }
+    }
+
+    /// Is the operation likely to succeed if it is retried? Rate limiting, timeouts and an
+    /// unavailable API server are transient, whereas eg. being forbidden is permanent.
+    pub fn is_transient(&self) -> bool {
+        if let Some(response) = self.kube_api_response() {
+            return is_transient_status(response.code);
+        }
+        match self {
+            Error::Timeout { .. } | Error::ExternalMetricTimeout { .. } => true,
+            Error::ExternalMetricStatus { status, .. } => is_transient_status(*status),
+            Error::MetricsApiUnavailable { code } => is_transient_status(*code),
+            Error::HttpClient { source }
+            | Error::Kube {
+                source: kube::Error::ReqwestError(source),
+            }
+            | Error::KubeObject {
+                source: kube::Error::ReqwestError(source),
+                ..
+            }
+            | Error::KubeList {
+                source: kube::Error::ReqwestError(source),
+                ..
+            } => source.is_timeout() || is_connect_error(source),
+            _ => false,
+        }
+    }
+}
+
+/// The http status of an error response from the API server, eg. ` (403 Forbidden)`, so it shows
+/// up in logs without verbose logging. Empty for errors that never reached the API server.
+fn api_status(source: &kube::Error) -> String {
+    match source {
+        kube::Error::Api(response) => {
+            let reason = StatusCode::from_u16(response.code)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or(&response.reason);
+            format!(" ({} {})", response.code, reason)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Did the request fail to establish a connection? This release of reqwest doesn't classify
+/// connection errors itself, so look for the underlying hyper error.
+fn is_connect_error(err: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(hyper_err) = err.downcast_ref::<hyper::Error>() {
+            if hyper_err.is_connect() {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Is the http status returned by the API server, or an external metric endpoint, worth retrying?
+fn is_transient_status(code: u16) -> bool {
+    match code {
+        // Timeouts and rate limiting. Conflicts aren't, repeating a request that conflicted with a
+        // concurrent modification conflicts again, the object has to be fetched again first.
+        408 | 429 => true,
+        // The API server, or a proxy in front of it, is struggling.
+        500 | 502 | 503 | 504 => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_is_transient() {
+        let api_error = |code: u16| Error::KubeObject {
+            kind: "StatefulSet".into(),
+            namespace: "default".into(),
+            name: "web".into(),
+            source: kube::Error::Api(kube::ErrorResponse {
+                status: "Failure".into(),
+                message: "error".into(),
+                reason: "error".into(),
+                code,
+            }),
+        };
+        assert!(api_error(429).is_transient());
+        assert!(api_error(503).is_transient());
+        assert!(!api_error(403).is_transient());
+        assert!(!api_error(404).is_transient());
+        assert!(!api_error(409).is_transient());
+
+        // A server that accepts connections but never responds times out.
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let timed_out = client
+            .get(&format!("http://{}", address))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(Error::HttpClient { source: timed_out }.is_transient());
+
+        // Once the server has gone away, connecting to it is refused.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let connection_refused = client
+            .get(&format!("http://{}", address))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(Error::HttpClient {
+            source: connection_refused
+        }
+        .is_transient());
+
+        assert!(Error::Timeout {
+            operation: "scaling object".into(),
+            seconds: 10,
+        }
+        .is_transient());
+        assert!(Error::ExternalMetricStatus {
+            endpoint: "http://adapter".into(),
+            status: 503,
+        }
+        .is_transient());
+        assert!(!Error::ExternalMetricStatus {
+            endpoint: "http://adapter".into(),
+            status: 404,
+        }
+        .is_transient());
+        assert!(Error::MetricsApiUnavailable { code: 503 }.is_transient());
+        assert!(!Error::MetricsApiUnavailable { code: 404 }.is_transient());
+        assert!(!Error::InvalidPort { port: 0 }.is_transient());
+    }
+
+    #[test]
+    fn test_kube_error_display() {
+        let api_error = |code: u16, reason: &str| Error::KubeList {
+            kind: "StatefulSet".into(),
+            namespace: "default".into(),
+            source: kube::Error::Api(kube::ErrorResponse {
+                status: "Failure".into(),
+                message: "statefulsets is forbidden".into(),
+                reason: reason.into(),
+                code,
+            }),
+        };
+        assert!(format!("{}", api_error(403, "Forbidden")).ends_with(" (403 Forbidden)"));
+        // Statuses without a canonical reason fall back to the API server's.
+        assert!(format!("{}", api_error(599, "Unknown")).ends_with(" (599 Unknown)"));
+    }
 }
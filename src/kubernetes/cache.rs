@@ -0,0 +1,247 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::common::build_label_selector;
+use crate::kubernetes::{KubernetesObject, KubernetesObjectEvent, KubernetesResourceTrait};
+use crate::telemetry::observe_kube_api_latency;
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// A cached list result.
+struct KubernetesListCacheEntry {
+    namespace: Option<String>,
+    listed_at: Instant,
+    objects: Vec<KubernetesObject>,
+}
+
+/// Shared store of recently listed objects, keyed by resource kind, namespace and label selectors.
+pub struct KubernetesListCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, KubernetesListCacheEntry>>,
+}
+
+impl KubernetesListCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Force every following list to go to the k8s api, eg. after the client has been rebuilt.
+    pub async fn clear(&self) {
+        self.entries.lock().await.clear();
+    }
+
+    /// Force the next list of objects in a namespace to go to the k8s api.
+    /// Cluster wide lists include the namespace, so are always invalidated.
+    pub async fn invalidate(&self, namespace: &str) {
+        self.entries
+            .lock()
+            .await
+            .retain(|_, entry| match &entry.namespace {
+                Some(entry_namespace) => entry_namespace != namespace,
+                None => false,
+            });
+    }
+}
+
+/// A kubernetes resource whose list results are served from a shared cache, until they expire.
+pub struct CachedKubernetesResource<R> {
+    resource: R,
+    cache: Arc<KubernetesListCache>,
+    namespace: Option<String>,
+    kind: String,
+    selectors: Vec<LabelSelector>,
+    field_selector: Option<String>,
+}
+
+impl<R> CachedKubernetesResource<R> {
+    pub fn new(
+        resource: R,
+        cache: Arc<KubernetesListCache>,
+        kind: &str,
+        namespace: Option<&str>,
+        selectors: &[LabelSelector],
+    ) -> Self {
+        Self {
+            resource,
+            cache,
+            namespace: namespace.map(String::from),
+            kind: kind.into(),
+            selectors: selectors.to_vec(),
+            field_selector: None,
+        }
+    }
+
+    /// Key the cache on the field selector the underlying resource was narrowed with, if any.
+    pub fn with_field_selector(mut self, field_selector: Option<&str>) -> Self {
+        self.field_selector = field_selector.map(String::from);
+        self
+    }
+
+    /// Cache entries are keyed by resource kind, namespace, label selectors and field selector.
+    fn key(&self) -> Result<String, Error> {
+        let label_selectors = self
+            .selectors
+            .iter()
+            .map(build_label_selector)
+            .collect::<Result<Vec<String>, Error>>()?;
+        Ok(format!(
+            "{}/{}/{}/{}",
+            self.kind,
+            self.namespace.as_deref().unwrap_or("*"),
+            label_selectors.join("|"),
+            self.field_selector.as_deref().unwrap_or_default()
+        ))
+    }
+}
+
+#[async_trait]
+impl<R: KubernetesResourceTrait + Send + Sync> KubernetesResourceTrait
+    for CachedKubernetesResource<R>
+{
+    async fn list(&self) -> Result<Vec<KubernetesObject>, Error> {
+        // Serve the cached objects, if they are fresh enough.
+        let key = self.key()?;
+        if let Some(entry) = self.cache.entries.lock().await.get(&key) {
+            if entry.listed_at.elapsed() < self.cache.ttl {
+                return Ok(entry.objects.clone());
+            }
+        }
+
+        let started = Instant::now();
+        let objects = self.resource.list().await?;
+        observe_kube_api_latency("list", started);
+        self.cache.entries.lock().await.insert(
+            key,
+            KubernetesListCacheEntry {
+                namespace: self.namespace.clone(),
+                listed_at: Instant::now(),
+                objects: objects.clone(),
+            },
+        );
+        Ok(objects)
+    }
+
+    async fn watch(
+        &self,
+    ) -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error> {
+        self.resource.watch().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::unbounded;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A resource that counts how many times it has been listed.
+    struct CountingResource {
+        lists: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl KubernetesResourceTrait for CountingResource {
+        async fn list(&self) -> Result<Vec<KubernetesObject>, Error> {
+            self.lists.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+
+        async fn watch(
+            &self,
+        ) -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error> {
+            Ok(unbounded().1)
+        }
+    }
+
+    fn cached_resource(
+        cache: Arc<KubernetesListCache>,
+        lists: Arc<AtomicUsize>,
+    ) -> CachedKubernetesResource<CountingResource> {
+        let mut match_labels = BTreeMap::new();
+        match_labels.insert(String::from("app"), String::from("test"));
+        let selector = LabelSelector {
+            match_labels: Some(match_labels),
+            ..Default::default()
+        };
+        CachedKubernetesResource::new(
+            CountingResource { lists },
+            cache,
+            "StatefulSet",
+            Some("default"),
+            &[selector],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_cached_list() {
+        let cache = Arc::new(KubernetesListCache::new(Duration::from_secs(60)));
+        let lists = Arc::new(AtomicUsize::new(0));
+
+        let resource = cached_resource(cache.clone(), lists.clone());
+        resource.list().await.unwrap();
+        resource.list().await.unwrap();
+        assert_eq!(lists.load(Ordering::SeqCst), 1);
+
+        // The cache is shared between resources with the same key.
+        cached_resource(cache.clone(), lists.clone())
+            .list()
+            .await
+            .unwrap();
+        assert_eq!(lists.load(Ordering::SeqCst), 1);
+
+        cache.invalidate("default").await;
+        resource.list().await.unwrap();
+        assert_eq!(lists.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_list_field_selector() {
+        let cache = Arc::new(KubernetesListCache::new(Duration::from_secs(60)));
+        let lists = Arc::new(AtomicUsize::new(0));
+
+        // Narrower lists aren't served from, or to, the cache entry of the wider list.
+        cached_resource(cache.clone(), lists.clone())
+            .list()
+            .await
+            .unwrap();
+        let resource = cached_resource(cache.clone(), lists.clone())
+            .with_field_selector(Some("metadata.name=web"));
+        resource.list().await.unwrap();
+        resource.list().await.unwrap();
+        assert_eq!(lists.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_list() {
+        let cache = Arc::new(KubernetesListCache::new(Duration::from_secs(0)));
+        let lists = Arc::new(AtomicUsize::new(0));
+
+        let resource = cached_resource(cache, lists.clone());
+        resource.list().await.unwrap();
+        resource.list().await.unwrap();
+        assert_eq!(lists.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::Error;
+use crate::telemetry::KUBE_API_CIRCUIT_OPEN;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stops pangolin hammering an unhealthy kubernetes api server. After enough consecutive
+/// failures the breaker opens and calls are refused for a cooldown, then a single probe is let
+/// through (half open), closing the breaker again if it succeeds.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+/// The state of a circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    /// Calls are allowed, counting the consecutive failures so far.
+    Closed { consecutive_failures: u32 },
+    /// Calls are refused until the cooldown is over.
+    Open { until: Instant },
+    /// A single probe call is in flight, since the instant.
+    HalfOpen { probe_started: Instant },
+}
+
+impl CircuitBreaker {
+    /// A failure threshold of zero disables the breaker.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(CircuitState::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Should a call be made at the instant? Once the cooldown is over the first caller becomes
+    /// the probe, a probe that never reports back is replaced after another cooldown.
+    pub fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } if now < until => false,
+            CircuitState::HalfOpen { probe_started } if now < probe_started + self.cooldown => {
+                false
+            }
+            _ => {
+                *state = CircuitState::HalfOpen { probe_started: now };
+                true
+            }
+        }
+    }
+
+    /// Record the outcome of a call, returns the new state if the outcome changed it between
+    /// open and closed. Only errors suggesting the api server is unhealthy count as failures.
+    pub fn record<T>(&self, result: &Result<T, Error>, now: Instant) -> Option<CircuitState> {
+        match result {
+            Err(err) if is_unhealthy(err) => self.record_failure(now),
+            _ => self.record_success(),
+        }
+    }
+
+    fn record_success(&self) -> Option<CircuitState> {
+        let mut state = self.state.lock().unwrap();
+        let was_closed = match *state {
+            CircuitState::Closed { .. } => true,
+            _ => false,
+        };
+        *state = CircuitState::Closed {
+            consecutive_failures: 0,
+        };
+        KUBE_API_CIRCUIT_OPEN.set(0);
+        if was_closed {
+            None
+        } else {
+            Some(*state)
+        }
+    }
+
+    fn record_failure(&self, now: Instant) -> Option<CircuitState> {
+        if self.failure_threshold == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            CircuitState::Closed {
+                consecutive_failures,
+            } if consecutive_failures + 1 < self.failure_threshold => {
+                *state = CircuitState::Closed {
+                    consecutive_failures: consecutive_failures + 1,
+                };
+                None
+            }
+            // Calls already in flight when the breaker opened don't extend the cooldown.
+            CircuitState::Open { .. } => None,
+            _ => {
+                *state = CircuitState::Open {
+                    until: now + self.cooldown,
+                };
+                KUBE_API_CIRCUIT_OPEN.set(1);
+                Some(*state)
+            }
+        }
+    }
+}
+
+/// Does the error suggest the api server is unhealthy? Conflicts are a normal part of optimistic
+/// concurrency, and permanent errors (eg. forbidden) show the api server is answering.
+fn is_unhealthy(err: &Error) -> bool {
+    err.is_transient()
+        && err
+            .kube_api_response()
+            .map_or(true, |response| response.code != 409)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let failure: Result<(), Error> = Err(Error::Timeout {
+            operation: "listing objects".into(),
+            seconds: 10,
+        });
+        let now = Instant::now();
+
+        // Trips open after consecutive failures, and refuses calls during the cooldown.
+        assert_eq!(breaker.record(&failure, now), None);
+        assert_eq!(breaker.record(&failure, now), None);
+        assert_eq!(
+            breaker.record(&failure, now),
+            Some(CircuitState::Open {
+                until: now + Duration::from_secs(30)
+            })
+        );
+        assert!(!breaker.allow(now + Duration::from_secs(10)));
+
+        // After the cooldown a single probe is allowed, a failed probe opens the breaker again.
+        let probe = now + Duration::from_secs(30);
+        assert!(breaker.allow(probe));
+        assert!(!breaker.allow(probe));
+        assert_eq!(
+            *breaker.state.lock().unwrap(),
+            CircuitState::HalfOpen {
+                probe_started: probe
+            }
+        );
+        assert!(breaker.record(&failure, probe).is_some());
+        assert!(!breaker.allow(probe + Duration::from_secs(1)));
+
+        // A successful probe closes the breaker.
+        let probe = probe + Duration::from_secs(30);
+        assert!(breaker.allow(probe));
+        assert_eq!(
+            breaker.record(&Ok(()), probe),
+            Some(CircuitState::Closed {
+                consecutive_failures: 0
+            })
+        );
+        assert!(breaker.allow(probe));
+    }
+}
@@ -0,0 +1,301 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use kube::client::APIClient;
+use kube::config::{self, Configuration};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Client};
+use slog::{error, info, Logger};
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+/// Environment variable overriding where the kubernetes configuration is loaded from, either
+/// `incluster` or `kubeconfig`, eg. to run against a remote cluster from a laptop.
+pub const CONFIG_SOURCE_ENV: &str = "PANGOLIN_KUBE_CONFIG_SOURCE";
+
+/// Where kubernetes mounts the service account's token, CA certificate and namespace.
+pub const DEFAULT_SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Where the kubernetes configuration is loaded from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigSource {
+    /// The pod's service account token and CA certificate.
+    InCluster,
+    /// A local kubeconfig file, eg. `~/.kube/config`.
+    KubeConfig,
+}
+
+/// Kubernetes client that is rebuilt whenever the service account token is rotated. Bound service
+/// account tokens expire, and the kubelet periodically replaces the projected token file, so a
+/// configuration loaded once would eventually be rejected by the API server. In between rotations
+/// the same client is handed out, so its connections are reused.
+pub struct RefreshingConfig {
+    logger: Logger,
+    source: ConfigSource,
+    service_account_dir: PathBuf,
+    state: Mutex<RefreshingConfigState>,
+}
+
+struct RefreshingConfigState {
+    kube_client: APIClient,
+    token_modified: Option<SystemTime>,
+    generation: u64,
+}
+
+impl RefreshingConfig {
+    /// Load the kubernetes configuration, detecting whether pangolin is running in a cluster. In a
+    /// cluster the service account is read from the directory it is mounted in.
+    pub async fn load(logger: Logger, service_account_dir: &str) -> Result<Self, Error> {
+        let source = detect_config_source(
+            std::env::var(CONFIG_SOURCE_ENV).ok().as_deref(),
+            std::env::var("KUBERNETES_SERVICE_HOST").ok().as_deref(),
+        )?;
+        Self::load_from(logger, source, service_account_dir.into()).await
+    }
+
+    async fn load_from(
+        logger: Logger,
+        source: ConfigSource,
+        service_account_dir: PathBuf,
+    ) -> Result<Self, Error> {
+        let token_modified = token_modified(source, &service_account_dir);
+        let kube_client = APIClient::new(load_config(source, &service_account_dir).await?);
+        Ok(Self {
+            logger,
+            source,
+            service_account_dir,
+            state: Mutex::new(RefreshingConfigState {
+                kube_client,
+                token_modified,
+                generation: 0,
+            }),
+        })
+    }
+
+    /// Where the configuration was loaded from.
+    pub fn source(&self) -> ConfigSource {
+        self.source
+    }
+
+    /// The shared client, rebuilding it first if the token has been rotated. Should rebuilding
+    /// fail, eg. while the kubelet is still writing the new token, the error is logged and the
+    /// previous client handed out, the reload is retried on the next call.
+    pub async fn client(&self) -> APIClient {
+        if let Err(err) = self.refresh().await {
+            error!(self.logger, "Failed to reload kubernetes configuration, keeping the previous configuration";
+                "error" => format!("{}", err));
+        }
+        self.state.lock().await.kube_client.clone()
+    }
+
+    /// Incremented every time the configuration is reloaded, so long lived clients such as watches
+    /// can tell when they need to be rebuilt.
+    pub async fn generation(&self) -> u64 {
+        self.state.lock().await.generation
+    }
+
+    /// Reload the configuration if the token has been rotated since it was last loaded.
+    /// Returns true if the configuration was reloaded.
+    pub async fn refresh(&self) -> Result<bool, Error> {
+        let mut state = self.state.lock().await;
+        let token_modified = token_modified(self.source, &self.service_account_dir);
+        if token_modified == state.token_modified {
+            return Ok(false);
+        }
+        state.kube_client =
+            APIClient::new(load_config(self.source, &self.service_account_dir).await?);
+        state.token_modified = token_modified;
+        state.generation += 1;
+        info!(self.logger, "Reloaded kubernetes configuration after token rotation";
+            "generation" => state.generation);
+        Ok(true)
+    }
+}
+
+/// Load the configuration from a source.
+async fn load_config(
+    source: ConfigSource,
+    service_account_dir: &Path,
+) -> Result<Configuration, Error> {
+    match source {
+        ConfigSource::InCluster => in_cluster_config(service_account_dir).context(Kube {}),
+        ConfigSource::KubeConfig => config::load_kube_config().await.context(Kube {}),
+    }
+}
+
+/// The in cluster configuration, from the service account mounted in the directory and the api
+/// server address kubernetes sets in every pod's environment.
+fn in_cluster_config(service_account_dir: &Path) -> Result<Configuration, kube::Error> {
+    let read = |name: &str| {
+        let path = service_account_dir.join(name);
+        std::fs::read(&path).map_err(|err| {
+            kube::Error::KubeConfig(format!("unable to read {}: {}", path.display(), err))
+        })
+    };
+    let server = match (
+        std::env::var("KUBERNETES_SERVICE_HOST"),
+        std::env::var("KUBERNETES_SERVICE_PORT"),
+    ) {
+        (Ok(host), Ok(port)) => format!("https://{}:{}", host, port),
+        _ => {
+            return Err(kube::Error::KubeConfig(
+                "KUBERNETES_SERVICE_HOST and KUBERNETES_SERVICE_PORT must be set".into(),
+            ))
+        }
+    };
+    let certificate = Certificate::from_pem(&read("ca.crt")?)
+        .map_err(|err| kube::Error::KubeConfig(format!("invalid CA certificate: {}", err)))?;
+    let token = String::from_utf8_lossy(&read("token")?).trim().to_string();
+    let namespace = String::from_utf8_lossy(&read("namespace")?)
+        .trim()
+        .to_string();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|err| kube::Error::KubeConfig(format!("invalid bearer token: {}", err)))?,
+    );
+    let client = Client::builder()
+        .add_root_certificate(certificate)
+        .default_headers(headers)
+        .build()
+        .map_err(|err| kube::Error::KubeConfig(format!("unable to build client: {}", err)))?;
+    Ok(Configuration::with_default_ns(server, client, namespace))
+}
+
+/// When the service account token was last written, for in cluster configurations.
+fn token_modified(source: ConfigSource, service_account_dir: &Path) -> Option<SystemTime> {
+    match source {
+        ConfigSource::InCluster => std::fs::metadata(service_account_dir.join("token"))
+            .and_then(|metadata| metadata.modified())
+            .ok(),
+        ConfigSource::KubeConfig => None,
+    }
+}
+
+/// Kubernetes sets the service host variable in every pod, so its presence means we are running in
+/// a cluster. Failures to load the detected configuration are reported rather than silently
+/// falling back to the other source.
+fn detect_config_source(
+    source_override: Option<&str>,
+    service_host: Option<&str>,
+) -> Result<ConfigSource, Error> {
+    match source_override {
+        Some(source) if source.eq_ignore_ascii_case("incluster") => Ok(ConfigSource::InCluster),
+        Some(source) if source.eq_ignore_ascii_case("kubeconfig") => Ok(ConfigSource::KubeConfig),
+        Some(source) => InvalidConfigSource {
+            source_name: source,
+        }
+        .fail(),
+        None if service_host.is_some() => Ok(ConfigSource::InCluster),
+        None => Ok(ConfigSource::KubeConfig),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, Drain};
+    use std::time::Duration;
+    use tokio::time::delay_for;
+
+    /// A self-signed CA certificate, only ever parsed.
+    const CA_CERTIFICATE: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBjTCCATOgAwIBAgIUB8uLc+tftHOIih6XLjEHXyo7SlEwCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQcGFuZ29saW4tdGVzdC1jYTAgFw0yNjEwMTQxNzQ3MTNaGA8y
+MTI2MDkyMDE3NDcxM1owGzEZMBcGA1UEAwwQcGFuZ29saW4tdGVzdC1jYTBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABDPfAP+q/z96h8+d6O1ukWIexqewJ7Kzyj0H
+qVZoIv7yOU+kCoyykCIIbujLYZk1on1/3FFf9uFhnUk1kWjASTmjUzBRMB0GA1Ud
+DgQWBBSMwWshPb3B64aYCd9oQ3OMhHMbbTAfBgNVHSMEGDAWgBSMwWshPb3B64aY
+Cd9oQ3OMhHMbbTAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQCp
+2EwDr3QO5eiYMRVMb0JAIVPwPhijThih0K8ExBbYwgIgBnDbt20Vk+BUvCINfqF3
+VpUPtZ58Uiv/D450xiLuXaw=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_detect_config_source() {
+        assert_eq!(
+            detect_config_source(None, Some("10.0.0.1")).unwrap(),
+            ConfigSource::InCluster
+        );
+        assert_eq!(
+            detect_config_source(None, None).unwrap(),
+            ConfigSource::KubeConfig
+        );
+        assert_eq!(
+            detect_config_source(Some("kubeconfig"), Some("10.0.0.1")).unwrap(),
+            ConfigSource::KubeConfig
+        );
+        assert_eq!(
+            detect_config_source(Some("InCluster"), None).unwrap(),
+            ConfigSource::InCluster
+        );
+        assert!(detect_config_source(Some("remote"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        std::env::set_var("KUBERNETES_SERVICE_HOST", "127.0.0.1");
+        std::env::set_var("KUBERNETES_SERVICE_PORT", "6443");
+        let dir =
+            std::env::temp_dir().join(format!("pangolin-service-account-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ca.crt"), CA_CERTIFICATE).unwrap();
+        std::fs::write(dir.join("namespace"), "pangolin").unwrap();
+        std::fs::write(dir.join("token"), "first").unwrap();
+        // Leave the token's modification time room to change.
+        let rotate_token = |token: &'static str| {
+            let path = dir.join("token");
+            async move {
+                delay_for(Duration::from_millis(20)).await;
+                std::fs::write(path, token).unwrap();
+            }
+        };
+
+        let config =
+            RefreshingConfig::load_from(get_logger(), ConfigSource::InCluster, dir.clone())
+                .await
+                .unwrap();
+        assert!(!config.refresh().await.unwrap());
+        assert_eq!(config.generation().await, 0);
+
+        rotate_token("second").await;
+        assert!(config.refresh().await.unwrap());
+        assert_eq!(config.generation().await, 1);
+
+        // A token that can't be used keeps the previous client, and the reload is retried.
+        rotate_token("not\u{1}valid").await;
+        assert!(config.refresh().await.is_err());
+        config.client().await;
+        assert_eq!(config.generation().await, 1);
+        rotate_token("third").await;
+        assert!(config.refresh().await.unwrap());
+        assert_eq!(config.generation().await, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn get_logger() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+}
@@ -15,20 +15,556 @@
  */
 
 use crate::error::*;
-use kube::api::{Api, ListParams};
+use crate::kubernetes::rate_limit::throttle;
+use crate::kubernetes::{
+    FieldManager, KubernetesObject, KubernetesObjectEvent, PodEndpoint, PodEndpoints, PodMetrics,
+    PodPort, ScaleOutcome, ScalePatchType,
+};
+use crate::resource::{format_annotation_timestamp, parse_annotation_timestamp, IpFamily};
+use crate::telemetry::{observe_kube_api_latency, SCALE_OPERATIONS};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::stream::select_all;
+use futures::{Future, SinkExt, StreamExt};
+use k8s_openapi::api::core::v1::{PodSpec, PodStatus, PodTemplateSpec};
+use k8s_openapi::api::discovery::v1beta1::EndpointSlice;
+use k8s_openapi::api::policy::v1beta1::{PodDisruptionBudgetSpec, PodDisruptionBudgetStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, Time};
+use kube::api::{
+    Api, Informer, ListParams, Object, ObjectList, ObjectMeta, OwnerReference, PatchParams,
+    PatchStrategy, PostParams, RawApi, Scale, ScaleSpec, WatchEvent,
+};
 use kube::client::APIClient;
-use snafu::ResultExt;
-use std::collections::BTreeMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
 
-/// Retrieve all the pod ips associated with a deployment.
+/// Default maximum number of objects to retrieve per list request.
+pub(crate) const DEFAULT_LIST_PAGE_LIMIT: u32 = 500;
+
+/// Default phases of the pods whose ips are retrieved, pods that are still starting aren't included.
+pub(crate) const DEFAULT_POD_PHASES: &[&str] = &["Running"];
+
+/// Group and version of the resource metrics api served by metrics-server.
+const METRICS_API_GROUP: &str = "metrics.k8s.io";
+const METRICS_API_VERSION: &str = "v1beta1";
+
+/// Label linking an EndpointSlice to the service it belongs to.
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// Maximum number of attempts at scaling an object that is being concurrently modified.
+const SCALE_CONFLICT_ATTEMPTS: u32 = 3;
+
+/// Maximum number of entries kept in an object's scale history annotation.
+const SCALE_HISTORY_LENGTH: usize = 10;
+
+/// Maximum length of the reason recorded in an object's last scale reason annotation.
+const SCALE_REASON_LENGTH: usize = 256;
+
+/// Total size of an object's annotations (keys and values) that pangolin's annotations are kept
+/// within, safely under the api server's limit of 256KiB to leave room for other tooling.
+const ANNOTATIONS_SIZE_BUDGET: usize = 240 * 1024;
+
+/// The position of a single page within a paginated list request. The ListParams of this kube
+/// release have no notion of pagination, so the page is carried alongside them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ListPage {
+    pub(crate) limit: u32,
+    pub(crate) continue_token: Option<String>,
+}
+
+/// A page of a list response. The ListMeta of this kube release never picks up the continue
+/// token, as it expects the field to be named `continue_`, so the list metadata is read here.
+#[derive(Clone, Deserialize)]
+pub(crate) struct PagedList<K> {
+    #[serde(default)]
+    metadata: PagedListMeta,
+    items: Vec<K>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+struct PagedListMeta {
+    #[serde(rename = "continue")]
+    continue_token: Option<String>,
+}
+
+/// List every object across all pages, following continue tokens, so large lists aren't truncated
+/// at the first page. Errors record the kind and namespace being listed.
+pub(crate) async fn list_all_pages<K, F, Fut>(
+    kind: &str,
+    namespace: &Option<String>,
+    list_params: ListParams,
+    page_limit: u32,
+    mut list_page: F,
+) -> Result<Vec<K>, Error>
+where
+    K: Clone,
+    F: FnMut(ListParams, ListPage) -> Fut,
+    Fut: Future<Output = Result<PagedList<K>, kube::Error>>,
+{
+    let mut page = ListPage {
+        limit: page_limit,
+        continue_token: None,
+    };
+    let mut objects: Vec<K> = Vec::new();
+    loop {
+        throttle().await;
+        let result = list_page(list_params.clone(), page.clone()).await;
+        let result = result.context(KubeList {
+            kind,
+            namespace: namespace.as_deref().unwrap_or("all namespaces"),
+        })?;
+        objects.extend(result.items);
+        match result.metadata.continue_token {
+            Some(continue_token) if !continue_token.is_empty() => {
+                page.continue_token = Some(continue_token)
+            }
+            _ => break,
+        }
+    }
+    Ok(objects)
+}
+
+/// Request a single page of a list of objects, appending the page limit and continue token
+/// to the list request.
+pub(crate) async fn request_list_page<K>(
+    kube_client: APIClient,
+    api: RawApi,
+    list_params: ListParams,
+    page: ListPage,
+) -> Result<PagedList<K>, kube::Error>
+where
+    K: Clone + DeserializeOwned,
+{
+    let mut request = api.list(&list_params)?;
+    let mut uri = format!("{}&limit={}", request.uri(), page.limit);
+    if let Some(continue_token) = &page.continue_token {
+        uri.push_str("&continue=");
+        uri.push_str(&encode_query_value(continue_token));
+    }
+    *request.uri_mut() = uri
+        .parse::<hyper::Uri>()
+        .map_err(|err| kube::Error::HttpError(err.into()))?;
+    kube_client.request::<PagedList<K>>(request).await
+}
+
+/// Send a request as a server-side dry run, if asked to. kube's `PatchParams` asks for one with
+/// `dryRun=true`, which the api server rejects, as it only accepts `dryRun=All`.
+fn server_dry_run(
+    mut request: hyper::Request<Vec<u8>>,
+    dry_run: bool,
+) -> Result<hyper::Request<Vec<u8>>, kube::Error> {
+    if dry_run {
+        let uri = format!("{}&dryRun=All", request.uri());
+        *request.uri_mut() = uri
+            .parse::<hyper::Uri>()
+            .map_err(|err| kube::Error::HttpError(err.into()))?;
+    }
+    Ok(request)
+}
+
+/// Percent encode a query parameter value, continue tokens are opaque and may contain any byte.
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// List every object matching any of the label selectors, each selector is listed in turn and
+/// objects matched by more than one selector are only returned once.
+pub(crate) async fn list_selected_pages<K, F, Fut>(
+    kind: &str,
+    namespace: &Option<String>,
+    selectors: &[LabelSelector],
+    field_selector: &Option<String>,
+    page_limit: u32,
+    mut list_page: F,
+) -> Result<Vec<K>, Error>
+where
+    K: Clone + kube::api::KubeObject,
+    F: FnMut(ListParams, ListPage) -> Fut,
+    Fut: Future<Output = Result<PagedList<K>, kube::Error>>,
+{
+    let mut listed: HashSet<(Option<String>, String)> = HashSet::new();
+    let mut objects: Vec<K> = Vec::new();
+    for selector in selectors {
+        let list_params = ListParams {
+            label_selector: Some(build_label_selector(selector)?),
+            field_selector: field_selector.clone(),
+            ..Default::default()
+        };
+        for object in
+            list_all_pages(kind, namespace, list_params, page_limit, &mut list_page).await?
+        {
+            let metadata = object.meta();
+            if listed.insert((metadata.namespace.clone(), metadata.name.clone())) {
+                objects.push(object);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// Retrieve all the pod ips associated with a deployment, of pods in any of the given phases, eg.
+/// `Pending` as well as `Running` to count pods that are still starting.
 pub(crate) async fn get_running_pod_ips(
     kube_client: APIClient,
     namespace: &str,
     match_labels: &BTreeMap<String, String>,
+    phases: &[&str],
+) -> Result<Vec<String>, Error> {
+    get_pod_ips(kube_client, namespace, match_labels, phases, false).await
+}
+
+/// Retrieve the names of the pods in any of the given phases matching a set of labels.
+pub(crate) async fn get_pod_names(
+    kube_client: APIClient,
+    namespace: &str,
+    match_labels: &BTreeMap<String, String>,
+    phases: &[&str],
 ) -> Result<Vec<String>, Error> {
-    let label_selector = Some(build_label_selector(match_labels));
+    let pods = list_pods(kube_client, namespace, match_labels, phases, false).await?;
+    Ok(pods.into_iter().map(|pod| pod.metadata.name).collect())
+}
+
+/// Set a label on a pod, or remove it when no value is given.
+pub(crate) async fn patch_pod_label(
+    kube_client: APIClient,
+    namespace: &str,
+    name: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    let patch = json!({ "metadata": { "labels": { key: value } } });
+    throttle().await;
+    Api::v1Pod(kube_client)
+        .within(namespace)
+        .patch(
+            name,
+            &PatchParams {
+                patch_strategy: PatchStrategy::Merge,
+                ..Default::default()
+            },
+            serde_json::to_vec(&patch).context(JsonSerialization {})?,
+        )
+        .await
+        .context(KubeObject {
+            kind: "Pod",
+            namespace,
+            name,
+        })?;
+    Ok(())
+}
+
+/// A pod's entry in the metrics.k8s.io api, with the resource usage of each of its containers.
+#[derive(Clone, Deserialize)]
+struct PodMetricsEntry {
+    metadata: ObjectMeta,
+    timestamp: Option<Time>,
+    #[serde(default)]
+    containers: Vec<ContainerUsage>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ContainerUsage {
+    #[serde(default)]
+    usage: BTreeMap<String, String>,
+}
+
+/// Retrieve the CPU and memory usage of the pods in any of the given phases matching a set of
+/// labels, from the metrics.k8s.io api.
+pub(crate) async fn get_pod_utilization(
+    kube_client: APIClient,
+    namespace: &str,
+    match_labels: &BTreeMap<String, String>,
+    phases: &[&str],
+) -> Result<Vec<PodMetrics>, Error> {
+    let pod_names: HashSet<String> =
+        get_pod_names(kube_client.clone(), namespace, match_labels, phases)
+            .await?
+            .into_iter()
+            .collect();
+
+    let pod_metrics = RawApi::customResource("pods")
+        .group(METRICS_API_GROUP)
+        .version(METRICS_API_VERSION)
+        .within(namespace)
+        .list(&ListParams {
+            label_selector: Some(build_label_selector(&LabelSelector {
+                match_labels: Some(match_labels.clone()),
+                ..Default::default()
+            })?),
+            ..Default::default()
+        })
+        .context(Kube {})?;
+    throttle().await;
+    let pod_metrics: ObjectList<PodMetricsEntry> = kube_client
+        .request(pod_metrics)
+        .await
+        .context(Kube {})
+        .map_err(metrics_api_unavailable)?;
+
+    Ok(pod_usage(&pod_metrics.items, &pod_names))
+}
+
+/// The api server responds with a 404 when the metrics api isn't registered, and a 503 when the
+/// metrics server behind it is down, so explain those rather than surfacing a bare status.
+fn metrics_api_unavailable(err: Error) -> Error {
+    match err.kube_api_response() {
+        Some(response) if response.code == 404 || response.code == 503 => {
+            Error::MetricsApiUnavailable {
+                code: response.code,
+            }
+        }
+        _ => err,
+    }
+}
+
+/// Total the usage of each pod's containers, for the pods that are included. Metrics linger for
+/// pods that have just terminated, and are missing for pods that haven't been sampled yet.
+fn pod_usage(pod_metrics: &[PodMetricsEntry], pod_names: &HashSet<String>) -> Vec<PodMetrics> {
+    pod_metrics
+        .iter()
+        .filter(|entry| pod_names.contains(&entry.metadata.name))
+        .map(|entry| {
+            let total = |resource: &str| {
+                entry
+                    .containers
+                    .iter()
+                    .filter_map(|container| parse_quantity(container.usage.get(resource)?))
+                    .sum()
+            };
+            PodMetrics {
+                pod_name: entry.metadata.name.clone(),
+                cpu: total("cpu"),
+                memory: total("memory"),
+                timestamp: entry.timestamp.as_ref().map(|timestamp| timestamp.0),
+            }
+        })
+        .collect()
+}
+
+/// Retrieve the pod ips of running pods that are also ready, ie. passing their readiness probes.
+/// Pods without a readiness probe are marked ready by the kubelet once their containers start.
+pub(crate) async fn get_ready_pod_ips(
+    kube_client: APIClient,
+    namespace: &str,
+    match_labels: &BTreeMap<String, String>,
+) -> Result<Vec<String>, Error> {
+    get_pod_ips(
+        kube_client,
+        namespace,
+        match_labels,
+        DEFAULT_POD_PHASES,
+        true,
+    )
+    .await
+}
+
+async fn get_pod_ips(
+    kube_client: APIClient,
+    namespace: &str,
+    match_labels: &BTreeMap<String, String>,
+    phases: &[&str],
+    require_ready: bool,
+) -> Result<Vec<String>, Error> {
+    let pods = list_pods(kube_client, namespace, match_labels, phases, require_ready).await?;
+    Ok(pods
+        .iter()
+        .filter_map(|pod| {
+            pod.status
+                .as_ref()
+                .and_then(|status| select_pod_ip(status, None))
+        })
+        .collect())
+}
+
+/// Retrieve the ip and port of every running pod associated with an object.
+/// A named port is resolved against each pod's container ports, as they can differ between pods
+/// during a rollout. Dual-stack pods use an address from the preferred family, if they have one.
+pub(crate) async fn get_running_pod_endpoints(
+    kube_client: APIClient,
+    namespace: &str,
+    match_labels: &BTreeMap<String, String>,
+    port: &PodPort,
+    ip_family: Option<IpFamily>,
+    require_ready: bool,
+) -> Result<PodEndpoints, Error> {
+    let pods = list_pods(
+        kube_client,
+        namespace,
+        match_labels,
+        DEFAULT_POD_PHASES,
+        require_ready,
+    )
+    .await?;
+    Ok(running_pod_endpoints(&pods, port, ip_family))
+}
+
+/// Extract the endpoints of pods with an ip, skipping those that don't expose the named port (eg.
+/// pods from an older revision during a rollout) rather than failing the whole object.
+fn running_pod_endpoints(
+    pods: &[Object<PodSpec, PodStatus>],
+    port: &PodPort,
+    ip_family: Option<IpFamily>,
+) -> PodEndpoints {
+    let mut pod_endpoints = PodEndpoints::default();
+    for pod in pods.iter() {
+        if let Some(pod_ip) = pod
+            .status
+            .as_ref()
+            .and_then(|status| select_pod_ip(status, ip_family))
+        {
+            match resolve_container_port(pod, port) {
+                Ok(port) => pod_endpoints.endpoints.push(PodEndpoint {
+                    pod_name: Some(pod.metadata.name.clone()),
+                    ip: pod_ip,
+                    port,
+                }),
+                Err(_) => pod_endpoints.skipped.push(pod.metadata.name.clone()),
+            }
+        }
+    }
+    pod_endpoints
+}
+
+/// Retrieve the ready endpoints of a service from its EndpointSlices. This is cheaper than listing
+/// pods, and the endpoints controller has already taken readiness into account.
+pub(crate) async fn get_endpoint_slice_endpoints(
+    kube_client: APIClient,
+    namespace: &str,
+    service_name: &str,
+    port: &PodPort,
+    ip_family: Option<IpFamily>,
+) -> Result<PodEndpoints, Error> {
+    let endpoint_slices = RawApi::customResource("endpointslices")
+        .group("discovery.k8s.io")
+        .version("v1beta1")
+        .within(namespace)
+        .list(&ListParams {
+            label_selector: Some(format!("{}={}", SERVICE_NAME_LABEL, service_name)),
+            ..Default::default()
+        })
+        .context(Kube {})?;
+    throttle().await;
+    let endpoint_slices: ObjectList<EndpointSlice> = kube_client
+        .request(endpoint_slices)
+        .await
+        .context(Kube {})?;
+
+    Ok(ready_slice_endpoints(
+        &endpoint_slices.items,
+        port,
+        ip_family,
+    ))
+}
+
+/// Extract the ready endpoints from a service's EndpointSlices. Dual-stack services have a set of
+/// slices per address family, so only the slices of a single family are used to avoid counting
+/// each pod twice. Slices that don't expose the named port are skipped.
+fn ready_slice_endpoints(
+    endpoint_slices: &[EndpointSlice],
+    port: &PodPort,
+    ip_family: Option<IpFamily>,
+) -> PodEndpoints {
+    let address_type = |ip_family: IpFamily| match ip_family {
+        IpFamily::IPv4 => "IPv4",
+        IpFamily::IPv6 => "IPv6",
+    };
+    let preferred_address_type = ip_family
+        .map(address_type)
+        .filter(|preferred| {
+            endpoint_slices
+                .iter()
+                .any(|endpoint_slice| endpoint_slice.address_type == *preferred)
+        })
+        .or_else(|| {
+            endpoint_slices
+                .iter()
+                .map(|endpoint_slice| endpoint_slice.address_type.as_str())
+                .find(|address_type| *address_type != "FQDN")
+        });
+
+    let mut pod_endpoints = PodEndpoints::default();
+    for endpoint_slice in endpoint_slices.iter().filter(|endpoint_slice| {
+        Some(endpoint_slice.address_type.as_str()) == preferred_address_type
+    }) {
+        let endpoint_port = match port {
+            PodPort::Number(number) => Some(*number),
+            PodPort::Name(name) => endpoint_slice
+                .ports
+                .iter()
+                .flatten()
+                .find(|endpoint_port| endpoint_port.name.as_deref() == Some(name.as_str()))
+                .and_then(|endpoint_port| endpoint_port.port)
+                .map(|port| port as u16),
+        };
+        let endpoint_port = match endpoint_port {
+            Some(endpoint_port) => endpoint_port,
+            None => {
+                pod_endpoints.skipped.push(
+                    endpoint_slice
+                        .metadata
+                        .as_ref()
+                        .and_then(|metadata| metadata.name.clone())
+                        .unwrap_or_default(),
+                );
+                continue;
+            }
+        };
+
+        for endpoint in endpoint_slice.endpoints.iter() {
+            // An unknown readiness should be interpreted as ready.
+            let ready = endpoint
+                .conditions
+                .as_ref()
+                .and_then(|conditions| conditions.ready)
+                .unwrap_or(true);
+            if let (true, Some(address)) = (ready, endpoint.addresses.first()) {
+                pod_endpoints.endpoints.push(PodEndpoint {
+                    pod_name: endpoint
+                        .target_ref
+                        .as_ref()
+                        .filter(|target_ref| target_ref.kind.as_deref() == Some("Pod"))
+                        .and_then(|target_ref| target_ref.name.clone()),
+                    ip: address.clone(),
+                    port: endpoint_port,
+                });
+            }
+        }
+    }
+
+    pod_endpoints
+}
+
+/// List the pods in any of the given phases matching a set of labels, optionally only those that
+/// are ready.
+async fn list_pods(
+    kube_client: APIClient,
+    namespace: &str,
+    match_labels: &BTreeMap<String, String>,
+    phases: &[&str],
+    require_ready: bool,
+) -> Result<Vec<Object<PodSpec, PodStatus>>, Error> {
+    let label_selector = Some(build_label_selector(&LabelSelector {
+        match_labels: Some(match_labels.clone()),
+        ..Default::default()
+    })?);
 
     // Retrieve the list of pods matching the label selector.
+    throttle().await;
     let pods = Api::v1Pod(kube_client)
         .within(namespace)
         .list(&ListParams {
@@ -38,33 +574,2589 @@ pub(crate) async fn get_running_pod_ips(
         .await
         .context(Kube {})?;
 
-    // Extract the address from each pod's metadata.
-    let mut pod_ips: Vec<String> = Vec::new();
-    for pod in pods {
-        if let Some(status) = &pod.status {
-            if let Some(phase) = &status.phase {
-                if !phase.eq_ignore_ascii_case("Running") {
+    Ok(pods
+        .into_iter()
+        .filter(|pod| match &pod.status {
+            Some(status) => in_phases(status, phases) && (!require_ready || is_pod_ready(status)),
+            None => false,
+        })
+        .collect())
+}
+
+/// Is the pod in one of the phases? Pods that don't report a phase yet are included.
+fn in_phases(status: &PodStatus, phases: &[&str]) -> bool {
+    match &status.phase {
+        Some(phase) => phases
+            .iter()
+            .any(|included| phase.eq_ignore_ascii_case(included)),
+        None => true,
+    }
+}
+
+/// Pick a pod address, preferring the requested family and otherwise falling back to the primary
+/// address. `podIPs` lists every address of a dual-stack pod, whereas `podIP` only holds the first.
+fn select_pod_ip(status: &PodStatus, ip_family: Option<IpFamily>) -> Option<String> {
+    let mut pod_ips: Vec<String> = status
+        .pod_ips
+        .iter()
+        .flatten()
+        .filter_map(|pod_ip| pod_ip.ip.clone())
+        .collect();
+    if pod_ips.is_empty() {
+        pod_ips.extend(status.pod_ip.clone());
+    }
+
+    if let Some(ip_family) = ip_family {
+        let preferred = pod_ips
+            .iter()
+            .find(|pod_ip| match pod_ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => ip_family == IpFamily::IPv4,
+                Ok(IpAddr::V6(_)) => ip_family == IpFamily::IPv6,
+                Err(_) => false,
+            });
+        if let Some(preferred) = preferred {
+            return Some(preferred.clone());
+        }
+    }
+
+    pod_ips.into_iter().next()
+}
+
+/// Resolve a port selector to a port number using the pod's container ports.
+fn resolve_container_port(pod: &Object<PodSpec, PodStatus>, port: &PodPort) -> Result<u16, Error> {
+    match port {
+        PodPort::Number(number) => Ok(*number),
+        PodPort::Name(name) => pod
+            .spec
+            .containers
+            .iter()
+            .flat_map(|container| container.ports.iter().flatten())
+            .find(|container_port| container_port.name.as_deref() == Some(name.as_str()))
+            .map(|container_port| container_port.container_port as u16)
+            .context(PortNotFound {
+                pod: pod.metadata.name.clone(),
+                port: name.clone(),
+            }),
+    }
+}
+
+/// Does the pod have a `Ready=True` condition?
+fn is_pod_ready(status: &PodStatus) -> bool {
+    status
+        .conditions
+        .iter()
+        .flatten()
+        .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+}
+
+/// Retrieve the number of pods that can be disrupted without violating any pod disruption budget
+/// covering pods with the supplied labels. Returns None if no budget covers the pods.
+pub(crate) async fn get_disruptions_allowed(
+    kube_client: APIClient,
+    namespace: &str,
+    pod_labels: &BTreeMap<String, String>,
+) -> Result<Option<u32>, Error> {
+    throttle().await;
+    let pod_disruption_budgets =
+        Api::<Object<PodDisruptionBudgetSpec, PodDisruptionBudgetStatus>>::customResource(
+            kube_client,
+            "poddisruptionbudgets",
+        )
+        .group("policy")
+        .version("v1beta1")
+        .within(namespace)
+        .list(&ListParams::default())
+        .await
+        .context(Kube {})?;
+
+    // The most restrictive matching budget wins.
+    Ok(pod_disruption_budgets
+        .into_iter()
+        .filter(|pod_disruption_budget| {
+            pod_disruption_budget
+                .spec
+                .selector
+                .as_ref()
+                .map(|selector| selector_matches(selector, pod_labels))
+                .unwrap_or(false)
+        })
+        .map(|pod_disruption_budget| {
+            // A budget the disruption controller hasn't processed yet allows no disruptions.
+            pod_disruption_budget
+                .status
+                .map(|status| status.disruptions_allowed.max(0) as u32)
+                .unwrap_or(0)
+        })
+        .min())
+}
+
+/// Does a label selector match the supplied labels? An empty selector matches nothing.
+fn selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let match_labels = selector.match_labels.as_ref();
+    let match_expressions = selector.match_expressions.as_ref();
+    if match_labels.map(|m| m.is_empty()).unwrap_or(true)
+        && match_expressions.map(|m| m.is_empty()).unwrap_or(true)
+    {
+        return false;
+    }
+
+    let labels_match = match_labels
+        .map(|match_labels| {
+            match_labels
+                .iter()
+                .all(|(name, value)| labels.get(name) == Some(value))
+        })
+        .unwrap_or(true);
+    let expressions_match = match_expressions
+        .map(|match_expressions| {
+            match_expressions.iter().all(|requirement| {
+                let value = labels.get(&requirement.key);
+                let in_values = value
+                    .map(|value| {
+                        requirement
+                            .values
+                            .as_ref()
+                            .map(|values| values.contains(value))
+                            .unwrap_or(false)
+                    })
+                    .unwrap_or(false);
+                match requirement.operator.as_str() {
+                    "In" => in_values,
+                    "NotIn" => !in_values,
+                    "Exists" => value.is_some(),
+                    "DoesNotExist" => value.is_none(),
+                    _ => false,
+                }
+            })
+        })
+        .unwrap_or(true);
+    labels_match && expressions_match
+}
+
+/// Watch for changes to objects, forwarding each converted change to the returned channel.
+/// If the watch expires (410 Gone) the informer is reset and every matching object is re-listed,
+/// and emitted as a single resync event. Watching stops once the receiver is dropped.
+pub(crate) fn watch_objects<K, F>(
+    informer: Informer<K>,
+    api: Api<K>,
+    list_params: ListParams,
+    convert: F,
+) -> UnboundedReceiver<Result<KubernetesObjectEvent, Error>>
+where
+    K: Clone + DeserializeOwned + kube::api::KubeObject + Send + Sync + 'static,
+    F: Fn(K) -> Option<KubernetesObject> + Send + Sync + 'static,
+{
+    let (mut event_sender, event_receiver) = unbounded::<Result<KubernetesObjectEvent, Error>>();
+    tokio::spawn(async move {
+        loop {
+            throttle().await;
+            let mut events = match informer.poll().await.context(Kube {}) {
+                Ok(events) => events.boxed(),
+                Err(err) => {
+                    if event_sender.send(Err(err)).await.is_err() {
+                        return;
+                    }
+                    // Avoid hammering the api server while it is unavailable.
+                    delay_for(Duration::from_secs(1)).await;
                     continue;
                 }
+            };
+
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(WatchEvent::Added(object)) => {
+                        convert(object).map(KubernetesObjectEvent::Added).map(Ok)
+                    }
+                    Ok(WatchEvent::Modified(object)) => {
+                        convert(object).map(KubernetesObjectEvent::Modified).map(Ok)
+                    }
+                    Ok(WatchEvent::Deleted(object)) => {
+                        convert(object).map(KubernetesObjectEvent::Deleted).map(Ok)
+                    }
+                    Ok(WatchEvent::Error(ref err)) if err.code == 410 => {
+                        // Our resource version has expired, re-list to resynchronize.
+                        let objects = async {
+                            throttle().await;
+                            informer.reset().await?;
+                            throttle().await;
+                            api.list(&list_params).await
+                        }
+                        .await
+                        .context(Kube {});
+                        Some(objects.map(|objects| {
+                            KubernetesObjectEvent::Resync(
+                                objects.into_iter().filter_map(&convert).collect(),
+                            )
+                        }))
+                    }
+                    Ok(WatchEvent::Error(err)) => Some(Err(Error::Kube {
+                        source: kube::Error::Api(err),
+                    })),
+                    Err(err) => Some(Err(Error::Kube { source: err })),
+                };
+                if let Some(event) = event {
+                    if event_sender.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    event_receiver
+}
+
+/// Merge the watches of each selector into a single channel. Resync events only cover the objects
+/// matching the selector whose watch expired.
+pub(crate) fn merge_watches<T: Send + 'static>(
+    mut watches: Vec<UnboundedReceiver<T>>,
+) -> UnboundedReceiver<T> {
+    if watches.len() == 1 {
+        return watches.remove(0);
+    }
+    let (event_sender, event_receiver) = unbounded::<T>();
+    // Forwarding stops once the receiver is dropped, which in turn stops each watch.
+    tokio::spawn(select_all(watches).map(Ok).forward(event_sender));
+    event_receiver
+}
+
+/// A patch that makes up part of a scaling operation.
+#[derive(Debug)]
+struct ScalePatch {
+    strategy: ScalePatchType,
+    body: Value,
+}
+
+/// The kube patch strategy that sends a patch of the given type.
+fn patch_strategy(patch_type: ScalePatchType) -> PatchStrategy {
+    match patch_type {
+        ScalePatchType::Apply => PatchStrategy::Apply,
+        ScalePatchType::Merge => PatchStrategy::Merge,
+        ScalePatchType::Json => PatchStrategy::JSON,
+        ScalePatchType::Strategic => PatchStrategy::Strategic,
+    }
+}
+
+/// The patches that scale an object to a number of replicas (if it isn't already scaled) and
+/// record pangolin's annotations, in the order they are sent. Server-side apply only applies the
+/// replicas, so that pangolin's ownership of the field is tracked in the object's managedFields,
+/// and the annotations follow in a merge patch. The other patch types change both at once.
+#[allow(clippy::too_many_arguments)]
+fn scale_patches(
+    patch_type: ScalePatchType,
+    api_group: &str,
+    api_version: &str,
+    kind: &str,
+    namespace: &str,
+    name: &str,
+    replicas: Option<u32>,
+    annotations: serde_json::Map<String, Value>,
+    has_annotations: bool,
+) -> Vec<ScalePatch> {
+    let mut spec = serde_json::Map::new();
+    if let Some(replicas) = replicas {
+        spec.insert(String::from("replicas"), json!(replicas));
+    }
+    let merge_body = json!({
+        "metadata": {
+            "annotations": annotations
+        },
+        "spec": spec
+    });
+    match patch_type {
+        ScalePatchType::Apply => {
+            let mut patches = Vec::new();
+            if let Some(replicas) = replicas {
+                // Only the replicas are applied, every other field is left to its existing managers.
+                patches.push(ScalePatch {
+                    strategy: ScalePatchType::Apply,
+                    body: json!({
+                        "apiVersion": group_version(api_group, api_version),
+                        "kind": kind,
+                        "metadata": {
+                            "name": name,
+                            "namespace": namespace
+                        },
+                        "spec": {
+                            "replicas": replicas
+                        }
+                    }),
+                });
+            }
+            patches.push(ScalePatch {
+                strategy: ScalePatchType::Merge,
+                body: json!({
+                    "metadata": {
+                        "annotations": annotations
+                    }
+                }),
+            });
+            patches
+        }
+        ScalePatchType::Merge => vec![ScalePatch {
+            strategy: ScalePatchType::Merge,
+            body: merge_body,
+        }],
+        ScalePatchType::Strategic => vec![ScalePatch {
+            strategy: ScalePatchType::Strategic,
+            body: merge_body,
+        }],
+        ScalePatchType::Json => {
+            let mut operations = Vec::new();
+            if let Some(replicas) = replicas {
+                operations.push(json!({
+                    "op": "replace",
+                    "path": "/spec/replicas",
+                    "value": replicas
+                }));
+            }
+            if has_annotations {
+                // Adding an existing member replaces it.
+                for (key, value) in annotations {
+                    operations.push(json!({
+                        "op": "add",
+                        "path": format!("/metadata/annotations/{}", escape_json_pointer(&key)),
+                        "value": value
+                    }));
+                }
+            } else {
+                // Members can't be added to an annotations map that doesn't exist yet.
+                operations.push(json!({
+                    "op": "add",
+                    "path": "/metadata/annotations",
+                    "value": annotations
+                }));
+            }
+            vec![ScalePatch {
+                strategy: ScalePatchType::Json,
+                body: Value::Array(operations),
+            }]
+        }
+    }
+}
+
+/// The apiVersion of objects in an api group, core objects have no group prefix.
+fn group_version(api_group: &str, api_version: &str) -> String {
+    if api_group.is_empty() {
+        api_version.into()
+    } else {
+        format!("{}/{}", api_group, api_version)
+    }
+}
+
+/// Escape a key for use in a JSON pointer, eg. `pangolinscaler.com/last_modified`.
+fn escape_json_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// An object as it was listed, which the first attempt at scaling it starts from. Retries after a
+/// conflict fetch the object again.
+pub(crate) struct ListedObject<'a> {
+    pub(crate) metadata: &'a ObjectMeta,
+    /// The listed `spec.replicas`, or the replicas of a custom resource's scale subresource.
+    pub(crate) replicas: Option<i32>,
+}
+
+/// An attempt's patch of an object's replicas.
+#[derive(Clone, Copy)]
+struct PatchedReplicas {
+    previous_replicas: Option<u64>,
+}
+
+/// The progress of a scaling operation across its attempts.
+struct ScaleProgress<'a> {
+    listed: ListedObject<'a>,
+    /// When the scaling operation started, as recorded in the object's annotations.
+    started: DateTime<Utc>,
+    /// Set once an attempt has patched the object's replicas, so that a retry after its
+    /// annotations failed to patch still records the scaling operation.
+    patched_replicas: Mutex<Option<PatchedReplicas>>,
+}
+
+impl<'a> ScaleProgress<'a> {
+    fn new(listed: ListedObject<'a>) -> Self {
+        ScaleProgress {
+            listed,
+            started: Utc::now(),
+            patched_replicas: Mutex::new(None),
+        }
+    }
+
+    /// The replicas the scaling operation is from, given the object's current replicas, and
+    /// whether its replicas still need to be patched.
+    fn previous_replicas(
+        &self,
+        current_replicas: Option<u64>,
+        replicas: u32,
+    ) -> (Option<u64>, bool) {
+        match *self.patched_replicas.lock().unwrap() {
+            // An earlier attempt scaled the object, but failed to record it.
+            Some(patched) if current_replicas == Some(replicas as u64) => {
+                (patched.previous_replicas, false)
+            }
+            _ => (current_replicas, current_replicas != Some(replicas as u64)),
+        }
+    }
+
+    fn replicas_patched(&self, previous_replicas: Option<u64>) {
+        *self.patched_replicas.lock().unwrap() = Some(PatchedReplicas { previous_replicas });
+    }
+}
+
+/// Scale an object, by patching its replicas with the field manager's patch type, and record the
+/// time of the scaling operation in its annotations. Patches that conflict with a concurrent
+/// modification of the object are retried with exponential backoff, any other errors are returned immediately.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn scale_object(
+    kube_client: APIClient,
+    api_group: &str,
+    api_version: &str,
+    resource: &str,
+    kind: &str,
+    namespace: &str,
+    listed: ListedObject<'_>,
+    replicas: u32,
+    reason: &str,
+    annotation_prefix: &str,
+    field_manager: &FieldManager,
+) -> Result<ScaleOutcome, Error> {
+    let name = listed.metadata.name.as_str();
+    let progress = ScaleProgress::new(listed);
+    let mut attempts = 0;
+    with_scale_retries(resource, namespace, name, field_manager.dry_run, || {
+        attempts += 1;
+        try_scale_object(
+            kube_client.clone(),
+            api_group,
+            api_version,
+            resource,
+            kind,
+            namespace,
+            replicas,
+            reason,
+            annotation_prefix,
+            field_manager,
+            &progress,
+            attempts > 1,
+        )
+    })
+    .await
+}
+
+/// Scale an object through its scale subresource, for custom resources whose replicas aren't
+/// necessarily at `spec.replicas`, and record the time of the scaling operation in its
+/// annotations. Conflicts are retried in the same way as `scale_object`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn scale_object_subresource(
+    kube_client: APIClient,
+    api_group: &str,
+    api_version: &str,
+    resource: &str,
+    kind: &str,
+    namespace: &str,
+    listed: ListedObject<'_>,
+    replicas: u32,
+    reason: &str,
+    annotation_prefix: &str,
+    field_manager: &FieldManager,
+) -> Result<ScaleOutcome, Error> {
+    let name = listed.metadata.name.as_str();
+    let progress = ScaleProgress::new(listed);
+    let mut attempts = 0;
+    with_scale_retries(resource, namespace, name, field_manager.dry_run, || {
+        attempts += 1;
+        try_scale_object_subresource(
+            kube_client.clone(),
+            api_group,
+            api_version,
+            resource,
+            kind,
+            namespace,
+            replicas,
+            reason,
+            annotation_prefix,
+            field_manager,
+            &progress,
+            attempts > 1,
+        )
+    })
+    .await
+}
+
+/// Attempt a scaling operation, retrying conflicts with exponential backoff, and record its
+/// latency and result. Server-side dry runs that succeed didn't scale anything, so aren't counted
+/// as successful scaling operations.
+async fn with_scale_retries<T, F, Fut>(
+    resource: &str,
+    namespace: &str,
+    name: &str,
+    dry_run: bool,
+    mut try_scale: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let started = Instant::now();
+    let mut attempt = 1;
+    let result = loop {
+        match try_scale().await {
+            // Another field manager owns the fields, retrying won't change that.
+            Err(ref err) if is_field_manager_conflict(err) => {
+                break FieldManagerConflict {
+                    namespace: namespace.to_string(),
+                    name: name.to_string(),
+                    message: err
+                        .kube_api_response()
+                        .map(|response| response.message.clone())
+                        .unwrap_or_default(),
+                }
+                .fail()
             }
-            if let Some(pod_ip) = &status.pod_ip {
-                pod_ips.push(pod_ip.clone());
+            Err(ref err) if is_conflict(err) && attempt < SCALE_CONFLICT_ATTEMPTS => {
+                delay_for(Duration::from_millis(100 * 2u64.pow(attempt))).await;
+                attempt += 1;
             }
+            // The object was deleted since it was listed, there is nothing left to scale.
+            Err(ref err) if is_not_found(err) => {
+                break ObjectGone {
+                    namespace: namespace.to_string(),
+                    name: name.to_string(),
+                }
+                .fail()
+            }
+            result => break result,
         }
+    };
+
+    observe_kube_api_latency("scale", started);
+    SCALE_OPERATIONS
+        .with_label_values(&[
+            resource,
+            match result {
+                Ok(_) if dry_run => "dry_run",
+                Ok(_) => "success",
+                Err(Error::ObjectGone { .. }) => "gone",
+                Err(_) => "error",
+            },
+        ])
+        .inc();
+    result
+}
+
+/// Is the error a kubernetes API NotFound response?
+fn is_not_found(err: &Error) -> bool {
+    err.kube_api_response()
+        .map(|response| response.code == 404)
+        .unwrap_or(false)
+}
+
+/// Is the error a kubernetes API Conflict response, from a concurrent modification?
+fn is_conflict(err: &Error) -> bool {
+    err.kube_api_response()
+        .map(|response| response.code == 409)
+        .unwrap_or(false)
+}
+
+/// Is the error a server-side apply conflict, over fields owned by another field manager? These
+/// share the Conflict status of concurrent modifications, but are told apart by their message.
+fn is_field_manager_conflict(err: &Error) -> bool {
+    err.kube_api_response()
+        .map(|response| response.code == 409 && response.message.starts_with("Apply failed with"))
+        .unwrap_or(false)
+}
+
+/// Record a kubernetes event against an object that has been scaled, so that pangolin's actions
+/// show up in `kubectl describe`. Objects that were already at the replicas aren't recorded. When
+/// the object was scaled as a server-side dry run, so is the event.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn emit_scale_event(
+    kube_client: APIClient,
+    api_version: &str,
+    kind: &str,
+    namespace: &str,
+    metadata: &ObjectMeta,
+    previous_replicas: Option<u32>,
+    replicas: u32,
+    reason: &str,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let from_replicas = match previous_replicas {
+        Some(previous_replicas) if previous_replicas == replicas => return Ok(()),
+        previous_replicas => previous_replicas.unwrap_or(0),
+    };
+    let event = scale_event(
+        api_version,
+        kind,
+        namespace,
+        metadata,
+        from_replicas,
+        replicas,
+        reason,
+        Utc::now(),
+    );
+    // The cluster's api level predates events.k8s.io/v1, so use the beta.
+    let request = RawApi::customResource("events")
+        .group("events.k8s.io")
+        .version("v1beta1")
+        .within(namespace)
+        .create(
+            &PostParams { dry_run },
+            serde_json::to_vec(&event).context(JsonSerialization {})?,
+        )
+        .context(Kube {})?;
+    throttle().await;
+    let _: Value = kube_client.request(request).await.context(KubeObject {
+        kind: "Event",
+        namespace,
+        name: metadata.name.as_str(),
+    })?;
+    Ok(())
+}
+
+/// An events.k8s.io event regarding an object scaled from one number of replicas to another.
+#[allow(clippy::too_many_arguments)]
+fn scale_event(
+    api_version: &str,
+    kind: &str,
+    namespace: &str,
+    metadata: &ObjectMeta,
+    from_replicas: u32,
+    to_replicas: u32,
+    reason: &str,
+    now: DateTime<Utc>,
+) -> Value {
+    let (event_reason, direction) = if to_replicas > from_replicas {
+        ("ScaledUp", "up")
+    } else {
+        ("ScaledDown", "down")
+    };
+    json!({
+        "apiVersion": "events.k8s.io/v1beta1",
+        "kind": "Event",
+        "metadata": {
+            "generateName": format!("{}.", metadata.name),
+            "namespace": namespace,
+        },
+        // The api server only accepts event times with microseconds.
+        "eventTime": now.to_rfc3339_opts(SecondsFormat::Micros, true),
+        "reportingController": "pangolinscaler.com/pangolin",
+        "reportingInstance": std::env::var("HOSTNAME").unwrap_or_else(|_| String::from("pangolin")),
+        "action": "Scale",
+        "reason": event_reason,
+        "regarding": {
+            "apiVersion": api_version,
+            "kind": kind,
+            "name": metadata.name,
+            "namespace": namespace,
+            "uid": metadata.uid,
+        },
+        "note": format!(
+            "Scaled {} from {} to {} replicas: {}",
+            direction,
+            from_replicas,
+            to_replicas,
+            truncate_scale_reason(reason)
+        ),
+        "type": "Normal",
+    })
+}
+
+/// The annotations recording a scaling operation from the previous to the new number of replicas,
+/// and the reason for it, trimmed to keep the object's annotations within the size budget.
+fn scale_annotations(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+    previous_replicas: Option<u64>,
+    replicas: u32,
+    reason: &str,
+    utc_now: DateTime<Utc>,
+) -> Result<(serde_json::Map<String, Value>, ScaleOutcome), Error> {
+    // Record when the object was last scaled, so other autoscalers can back off.
+    let mut annotations = serde_json::Map::new();
+    annotations.insert(
+        format!("{}/last_modified", annotation_prefix),
+        Value::String(format_annotation_timestamp(utc_now)),
+    );
+    // And keep a short audit trail of the scaling operations we've performed.
+    if previous_replicas != Some(replicas as u64) {
+        let scale_history_annotation = format!("{}/scale-history", annotation_prefix);
+        let scale_history = append_scale_history(
+            metadata
+                .annotations
+                .get(&scale_history_annotation)
+                .map(String::as_str),
+            ScaleHistoryEntry {
+                timestamp: format_annotation_timestamp(utc_now),
+                from: previous_replicas.unwrap_or(0) as u32,
+                to: replicas,
+            },
+        )?;
+        annotations.insert(scale_history_annotation, Value::String(scale_history));
+        annotations.insert(
+            format!("{}/last-scale-reason", annotation_prefix),
+            Value::String(truncate_scale_reason(reason)),
+        );
     }
+    let mut outcome = fit_annotations(metadata, annotation_prefix, &mut annotations)?;
+    outcome.previous_replicas = previous_replicas.map(|previous_replicas| previous_replicas as u32);
+    Ok((annotations, outcome))
+}
 
-    Ok(pod_ips)
+/// Trim pangolin's annotations until the object's annotations fit within the size budget, other
+/// tooling's annotations count toward the same limit. The oldest scale history entries are
+/// dropped first, then the scale reason, and as a last resort every annotation, rather than
+/// failing the scaling operation.
+fn fit_annotations(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+    annotations: &mut serde_json::Map<String, Value>,
+) -> Result<ScaleOutcome, Error> {
+    let scale_history_annotation = format!("{}/scale-history", annotation_prefix);
+    let scale_reason_annotation = format!("{}/last-scale-reason", annotation_prefix);
+    let mut outcome = ScaleOutcome::default();
+    while annotations_size(metadata, annotations) > ANNOTATIONS_SIZE_BUDGET {
+        let entries: Option<Vec<ScaleHistoryEntry>> = annotations
+            .get(&scale_history_annotation)
+            .and_then(Value::as_str)
+            .and_then(|history| serde_json::from_str(history).ok());
+        match entries {
+            Some(mut entries) if entries.len() > 1 => {
+                entries.remove(0);
+                annotations.insert(
+                    scale_history_annotation.clone(),
+                    Value::String(serde_json::to_string(&entries).context(JsonSerialization {})?),
+                );
+                outcome.trimmed_history_entries += 1;
+            }
+            _ if annotations.remove(&scale_reason_annotation).is_some() => {
+                outcome.dropped_reason = true;
+            }
+            _ => {
+                annotations.clear();
+                outcome.dropped_annotations = true;
+                break;
+            }
+        }
+    }
+    Ok(outcome)
 }
 
-/// Convert a matchLabels map into a list of labels for the kubernetes api.
-pub(crate) fn build_label_selector(match_labels: &BTreeMap<String, String>) -> String {
-    match_labels
+/// The total size of an object's annotations, keys and values, once new annotations are applied.
+fn annotations_size(metadata: &ObjectMeta, annotations: &serde_json::Map<String, Value>) -> usize {
+    let unchanged: usize = metadata
+        .annotations
         .iter()
-        .fold(String::new(), |mut labels, (name, value)| {
-            if !labels.is_empty() {
-                labels.push(',');
+        .filter(|(key, _)| !annotations.contains_key(*key))
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+    let changed: usize = annotations
+        .iter()
+        .map(|(key, value)| key.len() + value.as_str().map_or(0, str::len))
+        .sum();
+    unchanged + changed
+}
+
+/// Truncate a scale reason to fit in an annotation, without splitting a character.
+fn truncate_scale_reason(reason: &str) -> String {
+    if reason.len() <= SCALE_REASON_LENGTH {
+        return reason.into();
+    }
+    let mut end = SCALE_REASON_LENGTH - 3;
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &reason[..end])
+}
+
+/// A single attempt at scaling an object.
+#[allow(clippy::too_many_arguments)]
+async fn try_scale_object(
+    kube_client: APIClient,
+    api_group: &str,
+    api_version: &str,
+    resource: &str,
+    kind: &str,
+    namespace: &str,
+    replicas: u32,
+    reason: &str,
+    annotation_prefix: &str,
+    field_manager: &FieldManager,
+    progress: &ScaleProgress<'_>,
+    refetch: bool,
+) -> Result<ScaleOutcome, Error> {
+    let name = progress.listed.metadata.name.as_str();
+    let mut api = RawApi::customResource(resource)
+        .group(api_group)
+        .version(api_version)
+        .within(namespace);
+    if api_group.is_empty() {
+        // Objects in the core group, eg. ReplicationControllers, are served under /api.
+        api.prefix = "api".into();
+    }
+
+    // After a conflict fetch the latest object, the concurrent modification may have already
+    // scaled it.
+    let (metadata, current_replicas) = if refetch {
+        throttle().await;
+        let object: Object<Value, Value> = kube_client
+            .request(api.get(name).context(Kube {})?)
+            .await
+            .context(KubeObject {
+                kind,
+                namespace,
+                name,
+            })?;
+        let current_replicas = object.spec.get("replicas").and_then(Value::as_u64);
+        (object.metadata, current_replicas)
+    } else {
+        (
+            progress.listed.metadata.clone(),
+            progress
+                .listed
+                .replicas
+                .map(|replicas| replicas.max(0) as u64),
+        )
+    };
+    ensure_within_replica_bounds(
+        &metadata,
+        annotation_prefix,
+        namespace,
+        name,
+        current_replicas,
+        replicas,
+    )?;
+    let (previous_replicas, patch_replicas) =
+        progress.previous_replicas(current_replicas, replicas);
+    let (annotations, outcome) = scale_annotations(
+        &metadata,
+        annotation_prefix,
+        previous_replicas,
+        replicas,
+        reason,
+        progress.started,
+    )?;
+
+    for patch in scale_patches(
+        field_manager.patch_type,
+        api_group,
+        api_version,
+        kind,
+        namespace,
+        name,
+        if patch_replicas { Some(replicas) } else { None },
+        annotations,
+        !metadata.annotations.is_empty(),
+    ) {
+        // Forcing ownership of fields only applies to server-side apply.
+        let patch_params = match patch.strategy {
+            ScalePatchType::Apply => PatchParams {
+                patch_strategy: PatchStrategy::Apply,
+                field_manager: Some(field_manager.name.clone()),
+                force: field_manager.force,
+                ..Default::default()
+            },
+            strategy => PatchParams {
+                patch_strategy: patch_strategy(strategy),
+                field_manager: Some(field_manager.name.clone()),
+                ..Default::default()
+            },
+        };
+        let request = api
+            .patch(
+                name,
+                &patch_params,
+                serde_json::to_vec(&patch.body).context(ObjectSerialization {
+                    kind,
+                    namespace,
+                    name,
+                })?,
+            )
+            .context(Kube {})?;
+        let request = server_dry_run(request, field_manager.dry_run).context(Kube {})?;
+        throttle().await;
+        let _: Object<Value, Value> = kube_client.request(request).await.context(KubeObject {
+            kind,
+            namespace,
+            name,
+        })?;
+        // The replicas are in the first patch.
+        if patch_replicas {
+            progress.replicas_patched(previous_replicas);
+        }
+    }
+    Ok(outcome)
+}
+
+/// A single attempt at scaling an object through its scale subresource.
+#[allow(clippy::too_many_arguments)]
+async fn try_scale_object_subresource(
+    kube_client: APIClient,
+    api_group: &str,
+    api_version: &str,
+    resource: &str,
+    kind: &str,
+    namespace: &str,
+    replicas: u32,
+    reason: &str,
+    annotation_prefix: &str,
+    field_manager: &FieldManager,
+    progress: &ScaleProgress<'_>,
+    refetch: bool,
+) -> Result<ScaleOutcome, Error> {
+    let name = progress.listed.metadata.name.as_str();
+    let api = RawApi::customResource(resource)
+        .group(api_group)
+        .version(api_version)
+        .within(namespace);
+
+    // After a conflict fetch the latest object, the scale subresource doesn't carry its
+    // annotations.
+    let (metadata, current_replicas) = if refetch {
+        throttle().await;
+        let object = custom_object_api(
+            kube_client.clone(),
+            api_group,
+            api_version,
+            resource,
+            namespace,
+        )
+        .get(name)
+        .await
+        .context(KubeObject {
+            kind,
+            namespace,
+            name,
+        })?;
+        let scale = get_scale(
+            kube_client.clone(),
+            api_group,
+            api_version,
+            resource,
+            kind,
+            namespace,
+            name,
+        )
+        .await?;
+        let current_replicas = scale.spec.replicas.map(|replicas| replicas.max(0) as u64);
+        (object.metadata, current_replicas)
+    } else {
+        (
+            progress.listed.metadata.clone(),
+            progress
+                .listed
+                .replicas
+                .map(|replicas| replicas.max(0) as u64),
+        )
+    };
+    ensure_within_replica_bounds(
+        &metadata,
+        annotation_prefix,
+        namespace,
+        name,
+        current_replicas,
+        replicas,
+    )?;
+    let (previous_replicas, patch_replicas) =
+        progress.previous_replicas(current_replicas, replicas);
+
+    // The scale subresource doesn't support server-side apply, so replicas are merge patched.
+    let patch_params = PatchParams {
+        field_manager: Some(field_manager.name.clone()),
+        ..Default::default()
+    };
+    if patch_replicas {
+        let patch = json!({
+            "spec": ScaleSpec {
+                replicas: Some(replicas as i32)
+            }
+        });
+        let request = api
+            .patch_scale(
+                name,
+                &patch_params,
+                serde_json::to_vec(&patch).context(ObjectSerialization {
+                    kind,
+                    namespace,
+                    name,
+                })?,
+            )
+            .context(Kube {})?;
+        let request = server_dry_run(request, field_manager.dry_run).context(Kube {})?;
+        throttle().await;
+        let _: Scale = kube_client.request(request).await.context(KubeObject {
+            kind,
+            namespace,
+            name,
+        })?;
+        progress.replicas_patched(previous_replicas);
+    }
+
+    let (annotations, outcome) = scale_annotations(
+        &metadata,
+        annotation_prefix,
+        previous_replicas,
+        replicas,
+        reason,
+        progress.started,
+    )?;
+    let patch = json!({
+        "metadata": {
+            "annotations": annotations
+        }
+    });
+    let request = api
+        .patch(
+            name,
+            &patch_params,
+            serde_json::to_vec(&patch).context(ObjectSerialization {
+                kind,
+                namespace,
+                name,
+            })?,
+        )
+        .context(Kube {})?;
+    let request = server_dry_run(request, field_manager.dry_run).context(Kube {})?;
+    throttle().await;
+    let _: Object<Value, Value> = kube_client.request(request).await.context(KubeObject {
+        kind,
+        namespace,
+        name,
+    })?;
+    Ok(outcome)
+}
+
+/// The typed api of a custom resource, for requests that don't need to be sent as server-side dry
+/// runs, or paginated.
+fn custom_object_api(
+    kube_client: APIClient,
+    api_group: &str,
+    api_version: &str,
+    resource: &str,
+    namespace: &str,
+) -> Api<Object<Value, Value>> {
+    Api::customResource(kube_client, resource)
+        .group(api_group)
+        .version(api_version)
+        .within(namespace)
+}
+
+/// Retrieve an object's scale subresource, with its desired and observed replicas and the
+/// selector of its pods.
+pub(crate) async fn get_scale(
+    kube_client: APIClient,
+    api_group: &str,
+    api_version: &str,
+    resource: &str,
+    kind: &str,
+    namespace: &str,
+    name: &str,
+) -> Result<Scale, Error> {
+    throttle().await;
+    custom_object_api(kube_client, api_group, api_version, resource, namespace)
+        .get_scale(name)
+        .await
+        .context(KubeObject {
+            kind,
+            namespace,
+            name,
+        })
+}
+
+/// Parse the serialized label selector of a scale subresource, eg. `app=web,tier=frontend`, into
+/// the labels of the object's pods. Only equality based selectors can be used to find pods.
+pub(crate) fn parse_label_selector(selector: &str) -> Result<BTreeMap<String, String>, Error> {
+    let mut match_labels = BTreeMap::new();
+    for requirement in selector
+        .split(',')
+        .map(str::trim)
+        .filter(|requirement| !requirement.is_empty())
+    {
+        // Both `key=value` and `key==value` are equality requirements.
+        match requirement.find('=') {
+            Some(index) if index > 0 && !requirement[..index].ends_with('!') => {
+                match_labels.insert(
+                    requirement[..index].trim().into(),
+                    requirement[index + 1..]
+                        .trim_start_matches('=')
+                        .trim()
+                        .into(),
+                );
+            }
+            _ => {
+                return InvalidLabelSelector {
+                    reason: format!(
+                        "only equality based requirements are supported: {}",
+                        requirement
+                    ),
+                }
+                .fail()
+            }
+        }
+    }
+    ensure!(
+        !match_labels.is_empty(),
+        InvalidLabelSelector {
+            reason: "empty selector would match every pod"
+        }
+    );
+    Ok(match_labels)
+}
+
+/// A single scaling operation, as recorded in an object's scale history annotation.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct ScaleHistoryEntry {
+    timestamp: String,
+    from: u32,
+    to: u32,
+}
+
+/// Append an entry to a serialized scale history, dropping the oldest entries once full.
+/// A missing or corrupt history is started afresh. An entry that's already the latest, recorded
+/// by an earlier attempt at the same scaling operation, isn't appended again.
+fn append_scale_history(history: Option<&str>, entry: ScaleHistoryEntry) -> Result<String, Error> {
+    let mut entries: Vec<ScaleHistoryEntry> = history
+        .and_then(|history| serde_json::from_str(history).ok())
+        .unwrap_or_default();
+    if entries.last() != Some(&entry) {
+        entries.push(entry);
+    }
+    let excess_entries = entries.len().saturating_sub(SCALE_HISTORY_LENGTH);
+    entries.drain(..excess_entries);
+    serde_json::to_string(&entries).context(JsonSerialization {})
+}
+
+/// The number of pods added by the most recent scaling operation in an object's scale history,
+/// zero if it wasn't a scale up.
+pub(crate) fn last_scale_up_pods(metadata: &ObjectMeta, annotation_prefix: &str) -> u32 {
+    let entries: Vec<ScaleHistoryEntry> = metadata
+        .annotations
+        .get(&format!("{}/scale-history", annotation_prefix))
+        .and_then(|history| serde_json::from_str(history).ok())
+        .unwrap_or_default();
+    entries
+        .last()
+        .map_or(0, |entry| entry.to.saturating_sub(entry.from))
+}
+
+/// How long a new pod is expected to take to become available (seconds): until the slowest of its
+/// containers' readiness probes has passed, plus the time it must then stay ready. None if nothing
+/// delays the pod's readiness.
+pub(crate) fn readiness_seconds(
+    template: Option<&PodTemplateSpec>,
+    min_ready_seconds: Option<i32>,
+) -> Option<u32> {
+    let probe_seconds = template
+        .and_then(|template| template.spec.as_ref())
+        .and_then(|spec| {
+            spec.containers
+                .iter()
+                .filter_map(|container| container.readiness_probe.as_ref())
+                .map(|probe| {
+                    // Probes run every 10 seconds by default, and pass after a single success.
+                    let initial_delay = probe.initial_delay_seconds.unwrap_or(0).max(0) as u32;
+                    let period = probe.period_seconds.unwrap_or(10).max(1) as u32;
+                    let success_threshold = probe.success_threshold.unwrap_or(1).max(1) as u32;
+                    initial_delay.saturating_add(period.saturating_mul(success_threshold))
+                })
+                .max()
+        })
+        .unwrap_or(0);
+    Some(probe_seconds.saturating_add(min_ready_seconds.unwrap_or(0).max(0) as u32))
+        .filter(|seconds| *seconds > 0)
+}
+
+/// Scope an api to a namespace, or to every namespace if none is supplied.
+pub(crate) fn within_namespace<K>(api: Api<K>, namespace: &Option<String>) -> Api<K> {
+    match namespace {
+        Some(namespace) => api.within(namespace),
+        None => api,
+    }
+}
+
+/// Restrict a raw api to a namespace, if one is given.
+pub(crate) fn within_namespace_raw(api: RawApi, namespace: &Option<String>) -> RawApi {
+    match namespace {
+        Some(namespace) => api.within(namespace),
+        None => api,
+    }
+}
+
+/// Render a label selector, both matchLabels and matchExpressions, for the kubernetes api.
+/// The selector syntax has no escaping, so invalid label keys and values are rejected.
+pub(crate) fn build_label_selector(selector: &LabelSelector) -> Result<String, Error> {
+    let mut requirements: Vec<String> = Vec::new();
+    if let Some(match_labels) = &selector.match_labels {
+        for (name, value) in match_labels {
+            validate_label_key(name)?;
+            validate_label_value(value)?;
+            requirements.push(format!("{}={}", name, value));
+        }
+    }
+    if let Some(match_expressions) = &selector.match_expressions {
+        for requirement in match_expressions {
+            validate_label_key(&requirement.key)?;
+            for value in requirement.values.iter().flatten() {
+                validate_label_value(value)?;
+            }
+            let values = requirement
+                .values
+                .as_ref()
+                .map(|values| values.join(","))
+                .unwrap_or_default();
+            // Set based operators need values to compare against, existence operators take none.
+            let takes_values = match requirement.operator.as_str() {
+                "In" | "NotIn" => true,
+                _ => false,
+            };
+            let has_values = requirement
+                .values
+                .as_ref()
+                .map_or(false, |values| !values.is_empty());
+            ensure!(
+                takes_values == has_values,
+                InvalidLabelSelector {
+                    reason: format!(
+                        "operator {} for key {} {}",
+                        requirement.operator,
+                        requirement.key,
+                        if takes_values {
+                            "requires values"
+                        } else {
+                            "takes no values"
+                        }
+                    ),
+                }
+            );
+            requirements.push(match requirement.operator.as_str() {
+                "In" => format!("{} in ({})", requirement.key, values),
+                "NotIn" => format!("{} notin ({})", requirement.key, values),
+                "Exists" => requirement.key.clone(),
+                "DoesNotExist" => format!("!{}", requirement.key),
+                operator => {
+                    return InvalidLabelSelector {
+                        reason: format!(
+                            "unknown operator {} for key {}",
+                            operator, requirement.key
+                        ),
+                    }
+                    .fail()
+                }
+            });
+        }
+    }
+    Ok(requirements.join(","))
+}
+
+/// Label keys are an optional DNS subdomain prefix and a name, eg. `app.kubernetes.io/name`.
+pub(crate) fn validate_label_key(key: &str) -> Result<(), Error> {
+    let (prefix, name) = match key.rfind('/') {
+        Some(index) => (Some(&key[..index]), &key[index + 1..]),
+        None => (None, key),
+    };
+    let valid_prefix = prefix
+        .map(|prefix| {
+            !prefix.is_empty()
+                && prefix.len() <= 253
+                && prefix.split('.').all(|label| {
+                    !label.is_empty()
+                        && label
+                            .chars()
+                            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                        && !label.starts_with('-')
+                        && !label.ends_with('-')
+                })
+        })
+        .unwrap_or(true);
+    ensure!(
+        valid_prefix && !name.is_empty() && is_label_name(name),
+        InvalidLabelSelector {
+            reason: format!("invalid label key {:?}", key),
+        }
+    );
+    Ok(())
+}
+
+/// Label values are empty, or a name of up to 63 characters.
+pub(crate) fn validate_label_value(value: &str) -> Result<(), Error> {
+    ensure!(
+        value.is_empty() || is_label_name(value),
+        InvalidLabelSelector {
+            reason: format!("invalid label value {:?}", value),
+        }
+    );
+    Ok(())
+}
+
+/// Up to 63 alphanumerics, `-`, `_` or `.`, beginning and ending with an alphanumeric.
+fn is_label_name(name: &str) -> bool {
+    name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+/// Retrieve the last modified timestamp from an object's annotations.
+pub(crate) fn last_modified(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    Ok(
+        if let Some(last_modified_timestamp) = metadata
+            .annotations
+            .get(&format!("{}/last_modified", annotation_prefix))
+        {
+            Some(parse_annotation_timestamp(last_modified_timestamp).context(TimestampParse {})?)
+        } else {
+            None
+        },
+    )
+}
+
+/// Is a boolean pangolin annotation, eg. `pangolinscaler.com/allow-scale-to-zero`, set to true on an object?
+pub(crate) fn has_enabled_annotation(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+    name: &str,
+) -> bool {
+    metadata
+        .annotations
+        .get(&format!("{}/{}", annotation_prefix, name))
+        .map(|value| value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Retrieve the minimum and maximum number of replicas from an object's annotations.
+/// Missing annotations default to the widest possible bounds.
+pub(crate) fn replica_bounds(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+) -> Result<(u32, u32), Error> {
+    let min_replicas =
+        replicas_annotation(metadata, annotation_prefix, "min-replicas")?.unwrap_or(0);
+    let max_replicas =
+        replicas_annotation(metadata, annotation_prefix, "max-replicas")?.unwrap_or(std::u32::MAX);
+    ensure!(
+        min_replicas <= max_replicas,
+        InvalidReplicaBounds {
+            min: min_replicas,
+            max: max_replicas
+        }
+    );
+    Ok((min_replicas, max_replicas))
+}
+
+/// Refuse scaling operations whose target is outside of the replica bounds of the same object the
+/// current replicas were read from, so bounds changed since the object was listed are respected.
+/// Objects that are already outside of their bounds may still be scaled toward them.
+fn ensure_within_replica_bounds(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+    namespace: &str,
+    name: &str,
+    previous_replicas: Option<u64>,
+    replicas: u32,
+) -> Result<(), Error> {
+    let (min_replicas, max_replicas) = replica_bounds(metadata, annotation_prefix)?;
+    let toward_bounds = match previous_replicas {
+        Some(previous_replicas) if previous_replicas > max_replicas as u64 => {
+            (replicas as u64) < previous_replicas
+        }
+        Some(previous_replicas) if previous_replicas < min_replicas as u64 => {
+            (replicas as u64) > previous_replicas
+        }
+        _ => false,
+    };
+    ensure!(
+        (min_replicas <= replicas && replicas <= max_replicas) || toward_bounds,
+        ReplicasOutOfBounds {
+            namespace,
+            name,
+            replicas,
+            min: min_replicas,
+            max: max_replicas,
+        }
+    );
+    Ok(())
+}
+
+/// Parse a replica count annotation, eg. `pangolinscaler.com/min-replicas`.
+fn replicas_annotation(
+    metadata: &ObjectMeta,
+    annotation_prefix: &str,
+    name: &str,
+) -> Result<Option<u32>, Error> {
+    let annotation_name = format!("{}/{}", annotation_prefix, name);
+    if let Some(value) = metadata.annotations.get(&annotation_name) {
+        Ok(Some(value.trim().parse().context(InvalidAnnotation {
+            name: annotation_name.clone(),
+            value: value.clone(),
+        })?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Retrieve the storage that can still be requested by PersistentVolumeClaims in a namespace
+/// (bytes), the smallest headroom under the `requests.storage` limit of any ResourceQuota.
+/// Returns None if no quota limits storage.
+pub(crate) async fn get_remaining_storage_quota(
+    kube_client: APIClient,
+    namespace: &str,
+) -> Result<Option<f64>, Error> {
+    throttle().await;
+    let resource_quotas = Api::v1ResourceQuota(kube_client)
+        .within(namespace)
+        .list(&ListParams::default())
+        .await
+        .context(KubeList {
+            kind: "ResourceQuota",
+            namespace,
+        })?;
+
+    Ok(resource_quotas
+        .into_iter()
+        .filter_map(|resource_quota| {
+            let status = resource_quota.status?;
+            let hard = parse_quantity(&status.hard?.get("requests.storage")?.0)?;
+            let used = status
+                .used
+                .as_ref()
+                .and_then(|used| used.get("requests.storage"))
+                .and_then(|used| parse_quantity(&used.0))
+                .unwrap_or(0.0);
+            Some((hard - used).max(0.0))
+        })
+        .fold(None, |remaining: Option<f64>, headroom| {
+            Some(remaining.map_or(headroom, |remaining| remaining.min(headroom)))
+        }))
+}
+
+/// Retrieve the names of every PersistentVolumeClaim in a namespace.
+pub(crate) async fn get_persistent_volume_claim_names(
+    kube_client: APIClient,
+    namespace: &str,
+) -> Result<HashSet<String>, Error> {
+    throttle().await;
+    let persistent_volume_claims = Api::v1PersistentVolumeClaim(kube_client)
+        .within(namespace)
+        .list(&ListParams::default())
+        .await
+        .context(KubeList {
+            kind: "PersistentVolumeClaim",
+            namespace,
+        })?;
+    Ok(persistent_volume_claims
+        .into_iter()
+        .map(|persistent_volume_claim| persistent_volume_claim.metadata.name)
+        .collect())
+}
+
+/// Parse a kubernetes resource quantity, eg. `10Gi` or `500M`, into a plain number.
+pub(crate) fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let suffixes: &[(&str, f64)] = &[
+        ("Ki", 1024f64),
+        ("Mi", 1024f64.powi(2)),
+        ("Gi", 1024f64.powi(3)),
+        ("Ti", 1024f64.powi(4)),
+        ("Pi", 1024f64.powi(5)),
+        ("Ei", 1024f64.powi(6)),
+        ("n", 1e-9),
+        ("u", 1e-6),
+        ("m", 1e-3),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+    ];
+    let (number, multiplier) = suffixes
+        .iter()
+        .find(|(suffix, _)| quantity.ends_with(suffix))
+        .map(|(suffix, multiplier)| (&quantity[..quantity.len() - suffix.len()], *multiplier))
+        .unwrap_or((quantity, 1.0));
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+        .map(|number| number * multiplier)
+}
+
+/// The object's controlling owner, if it is of one of the supplied kinds.
+pub(crate) fn controlling_owner<'a, S: AsRef<str>>(
+    metadata: &'a ObjectMeta,
+    owner_kinds: &[S],
+) -> Option<&'a OwnerReference> {
+    metadata.ownerReferences.iter().find(|owner_reference| {
+        owner_reference.controller
+            && owner_kinds
+                .iter()
+                .any(|owner_kind| owner_reference.kind == owner_kind.as_ref())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::stub::{status_response, StubApiServer};
+    use crate::resource::ANNOTATION_BASE;
+    use chrono::TimeZone;
+    use futures::future;
+    use k8s_openapi::api::core::v1::{Container, PodCondition, Probe};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_with_scale_retries_conflicts() {
+        let conflict = |message: &str| Error::KubeObject {
+            kind: "StatefulSet".into(),
+            namespace: "default".into(),
+            name: "web".into(),
+            source: kube::Error::Api(kube::ErrorResponse {
+                status: "Failure".into(),
+                message: message.into(),
+                reason: "Conflict".into(),
+                code: 409,
+            }),
+        };
+
+        // Concurrent modifications are retried.
+        let mut attempts = 0;
+        let result: Result<(), Error> =
+            with_scale_retries("statefulsets", "default", "web", false, || {
+                attempts += 1;
+                future::ready(Err(conflict(
+                    "the object has been modified; please apply your changes to the latest version",
+                )))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, SCALE_CONFLICT_ATTEMPTS);
+
+        // Fields owned by another field manager fail straight away.
+        let mut attempts = 0;
+        let result: Result<(), Error> =
+            with_scale_retries("statefulsets", "default", "web", false, || {
+                attempts += 1;
+                future::ready(Err(conflict(
+                    "Apply failed with 1 conflict: conflict with \"kubectl\": .spec.replicas",
+                )))
+            })
+            .await;
+        match result {
+            Err(Error::FieldManagerConflict { message, .. }) => {
+                assert!(message.ends_with(".spec.replicas"))
+            }
+            _ => panic!("expected a field manager conflict"),
+        }
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_scale_event() {
+        let metadata: ObjectMeta = serde_json::from_value(json!({
+            "name": "web",
+            "namespace": "default",
+            "uid": "4bd3c5a2-1b8e-4f3c-9d0a-7e6f5a4b3c2d"
+        }))
+        .unwrap();
+        let event = scale_event(
+            "apps/v1",
+            "StatefulSet",
+            "default",
+            &metadata,
+            5,
+            3,
+            "connections 40.0 wants 3 replicas",
+            Utc.ymd(2020, 3, 29).and_hms(1, 30, 0),
+        );
+        assert_eq!(event["apiVersion"], json!("events.k8s.io/v1beta1"));
+        assert_eq!(event["eventTime"], json!("2020-03-29T01:30:00.000000Z"));
+        assert_eq!(event["reason"], json!("ScaledDown"));
+        assert_eq!(
+            event["regarding"],
+            json!({
+                "apiVersion": "apps/v1",
+                "kind": "StatefulSet",
+                "name": "web",
+                "namespace": "default",
+                "uid": "4bd3c5a2-1b8e-4f3c-9d0a-7e6f5a4b3c2d"
+            })
+        );
+        assert_eq!(
+            event["note"],
+            json!("Scaled down from 5 to 3 replicas: connections 40.0 wants 3 replicas")
+        );
+    }
+
+    #[test]
+    fn test_select_pod_ip() {
+        let status: PodStatus = serde_json::from_value(json!({
+            "podIP": "10.0.0.1",
+            "podIPs": [{ "ip": "10.0.0.1" }, { "ip": "fd00::1" }]
+        }))
+        .unwrap();
+        assert_eq!(select_pod_ip(&status, None).unwrap(), "10.0.0.1");
+        assert_eq!(
+            select_pod_ip(&status, Some(IpFamily::IPv4)).unwrap(),
+            "10.0.0.1"
+        );
+        assert_eq!(
+            select_pod_ip(&status, Some(IpFamily::IPv6)).unwrap(),
+            "fd00::1"
+        );
+
+        // Single stack pods fall back to their only address.
+        let status: PodStatus = serde_json::from_value(json!({ "podIP": "10.0.0.1" })).unwrap();
+        assert_eq!(
+            select_pod_ip(&status, Some(IpFamily::IPv6)).unwrap(),
+            "10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_ready_slice_endpoints() {
+        let endpoint_slices: Vec<EndpointSlice> = serde_json::from_value(json!([
+            {
+                "metadata": { "name": "web-ipv4" },
+                "addressType": "IPv4",
+                "ports": [{ "name": "metrics", "port": 9100 }],
+                "endpoints": [
+                    { "addresses": ["10.0.0.1"], "conditions": { "ready": true } },
+                    { "addresses": ["10.0.0.2"], "conditions": { "ready": false } },
+                    { "addresses": ["10.0.0.3"] },
+                ]
+            },
+            {
+                "metadata": { "name": "web-ipv6" },
+                "addressType": "IPv6",
+                "ports": [{ "name": "metrics", "port": 9100 }],
+                "endpoints": [{ "addresses": ["fd00::1"], "conditions": { "ready": true } }]
+            }
+        ]))
+        .unwrap();
+
+        let ips = |pod_endpoints: PodEndpoints| -> Vec<String> {
+            pod_endpoints
+                .endpoints
+                .into_iter()
+                .map(|pod_endpoint| pod_endpoint.ip)
+                .collect()
+        };
+        let metrics = PodPort::Name("metrics".into());
+        assert_eq!(
+            ips(ready_slice_endpoints(&endpoint_slices, &metrics, None)),
+            vec!["10.0.0.1", "10.0.0.3"]
+        );
+        assert_eq!(
+            ips(ready_slice_endpoints(
+                &endpoint_slices,
+                &metrics,
+                Some(IpFamily::IPv6)
+            )),
+            vec!["fd00::1"]
+        );
+        assert_eq!(
+            ready_slice_endpoints(&endpoint_slices, &PodPort::Name("admin".into()), None),
+            PodEndpoints {
+                endpoints: vec![],
+                skipped: vec![String::from("web-ipv4")],
             }
-            labels.push_str(&format!("{}={}", name, value));
-            labels
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_all_pages() {
+        let mut requests: Vec<ListPage> = Vec::new();
+        let objects = list_all_pages("Pod", &None, ListParams::default(), 2, |_, page| {
+            let result = match page.continue_token.as_deref() {
+                Some("page-2") => json!({
+                    "metadata": {},
+                    "items": [{ "name": "web-b" }, { "name": "web-c" }]
+                }),
+                _ => json!({
+                    "metadata": { "continue": "page-2" },
+                    "items": [{ "name": "web-a" }]
+                }),
+            };
+            requests.push(page);
+            async move { Ok(serde_json::from_value::<PagedList<Value>>(result).unwrap()) }
         })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            objects,
+            vec![
+                json!({ "name": "web-a" }),
+                json!({ "name": "web-b" }),
+                json!({ "name": "web-c" })
+            ]
+        );
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|page| page.limit == 2));
+    }
+
+    #[test]
+    fn test_encode_query_value() {
+        assert_eq!(encode_query_value("eyJ2Ijoi-_.~"), "eyJ2Ijoi-_.~");
+        assert_eq!(encode_query_value("a+b/c="), "a%2Bb%2Fc%3D");
+    }
+
+    #[tokio::test]
+    async fn test_list_selected_pages() {
+        let selector = |app: &str| {
+            let mut match_labels = BTreeMap::new();
+            match_labels.insert(String::from("app"), String::from(app));
+            LabelSelector {
+                match_labels: Some(match_labels),
+                ..Default::default()
+            }
+        };
+        let object = |name: &str| {
+            json!({
+                "metadata": { "namespace": "default", "name": name },
+                "spec": {}
+            })
+        };
+        let objects: Vec<Object<Value, Value>> = list_selected_pages(
+            "Pod",
+            &None,
+            &[selector("web"), selector("api")],
+            &None,
+            2,
+            |list_params, _| {
+                // The selectors overlap on web-b.
+                let items = match list_params.label_selector.as_deref() {
+                    Some("app=web") => vec![object("web-a"), object("web-b")],
+                    _ => vec![object("web-b"), object("api-a")],
+                };
+                let page = json!({ "metadata": {}, "items": items });
+                async move { Ok(serde_json::from_value(page).unwrap()) }
+            },
+        )
+        .await
+        .unwrap();
+
+        let names: Vec<&str> = objects
+            .iter()
+            .map(|object| object.metadata.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["web-a", "web-b", "api-a"]);
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        let api_error = |code: u16| Error::KubeObject {
+            kind: "StatefulSet".into(),
+            namespace: "default".into(),
+            name: "missing".into(),
+            source: kube::Error::Api(kube::ErrorResponse {
+                status: "Failure".into(),
+                message: "statefulsets.apps \"missing\" not found".into(),
+                reason: "NotFound".into(),
+                code,
+            }),
+        };
+        assert!(is_not_found(&api_error(404)));
+        assert!(!is_not_found(&api_error(409)));
+        assert!(!is_not_found(&Error::KubeObjectSpec {
+            kind: "StatefulSet".into(),
+            namespace: "default".into(),
+            name: "missing".into(),
+        }));
+    }
+
+    #[test]
+    fn test_resolve_container_port() {
+        let pod: Object<PodSpec, PodStatus> = serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web-0" },
+            "spec": {
+                "containers": [
+                    { "name": "web", "ports": [{ "name": "http", "containerPort": 8080 }] },
+                    { "name": "exporter", "ports": [{ "name": "metrics", "containerPort": 9100 }] },
+                ]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            resolve_container_port(&pod, &PodPort::Name("metrics".into())).unwrap(),
+            9100
+        );
+        assert_eq!(
+            resolve_container_port(&pod, &PodPort::Number(9090)).unwrap(),
+            9090
+        );
+        assert!(resolve_container_port(&pod, &PodPort::Name("admin".into())).is_err());
+    }
+
+    #[test]
+    fn test_running_pod_endpoints() {
+        let pods: Vec<Object<PodSpec, PodStatus>> = serde_json::from_value(json!([
+            {
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "web-0" },
+                "spec": {
+                    "containers": [
+                        { "name": "web", "ports": [{ "name": "metrics", "containerPort": 9100 }] },
+                    ]
+                },
+                "status": { "podIP": "10.0.0.1" }
+            },
+            {
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "web-1" },
+                "spec": {
+                    "containers": [
+                        { "name": "web", "ports": [{ "name": "http", "containerPort": 8080 }] },
+                    ]
+                },
+                "status": { "podIP": "10.0.0.2" }
+            },
+            {
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "web-2" },
+                "spec": { "containers": [{ "name": "web" }] },
+                "status": {}
+            }
+        ]))
+        .unwrap();
+
+        // Pods without the named port are skipped, pods without an ip are ignored.
+        assert_eq!(
+            running_pod_endpoints(&pods, &PodPort::Name("metrics".into()), None),
+            PodEndpoints {
+                endpoints: vec![PodEndpoint {
+                    pod_name: Some(String::from("web-0")),
+                    ip: String::from("10.0.0.1"),
+                    port: 9100,
+                }],
+                skipped: vec![String::from("web-1")],
+            }
+        );
+        assert_eq!(
+            running_pod_endpoints(&pods, &PodPort::Number(9090), None)
+                .endpoints
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let mut metadata = ObjectMeta::default();
+        assert!(last_modified(&metadata, ANNOTATION_BASE).unwrap().is_none());
+
+        metadata.annotations.insert(
+            format!("{}/last_modified", ANNOTATION_BASE),
+            "2020-02-18T10:00:00+00:00".into(),
+        );
+        assert_eq!(
+            last_modified(&metadata, ANNOTATION_BASE).unwrap().unwrap(),
+            Utc.ymd(2020, 2, 18).and_hms(10, 0, 0)
+        );
+
+        metadata.annotations.insert(
+            format!("{}/last_modified", ANNOTATION_BASE),
+            "not-a-date".into(),
+        );
+        assert!(last_modified(&metadata, ANNOTATION_BASE).is_err());
+
+        // Annotations belonging to another pangolin instance are ignored.
+        assert!(last_modified(&metadata, "other.example.com")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_build_label_selector() {
+        let requirement = |operator: &str, values: Option<Vec<&str>>| LabelSelectorRequirement {
+            key: String::from("tier"),
+            operator: String::from(operator),
+            values: values.map(|values| values.into_iter().map(String::from).collect()),
+        };
+        let selector = |match_expressions| LabelSelector {
+            match_labels: None,
+            match_expressions: Some(match_expressions),
+        };
+
+        assert_eq!(build_label_selector(&LabelSelector::default()).unwrap(), "");
+        assert_eq!(build_label_selector(&selector(vec![])).unwrap(), "");
+
+        let mut match_labels = BTreeMap::new();
+        match_labels.insert(String::from("app"), String::from("zookeeper"));
+        match_labels.insert(String::from("env"), String::from("prod"));
+        assert_eq!(
+            build_label_selector(&LabelSelector {
+                match_labels: Some(match_labels),
+                match_expressions: Some(vec![requirement("Exists", None)]),
+            })
+            .unwrap(),
+            "app=zookeeper,env=prod,tier"
+        );
+
+        assert_eq!(
+            build_label_selector(&selector(vec![requirement(
+                "In",
+                Some(vec!["backend", "cache"])
+            )]))
+            .unwrap(),
+            "tier in (backend,cache)"
+        );
+        assert_eq!(
+            build_label_selector(&selector(vec![requirement(
+                "NotIn",
+                Some(vec!["frontend"])
+            )]))
+            .unwrap(),
+            "tier notin (frontend)"
+        );
+        assert_eq!(
+            build_label_selector(&selector(vec![requirement("DoesNotExist", None)])).unwrap(),
+            "!tier"
+        );
+        assert!(build_label_selector(&selector(vec![requirement("Near", None)])).is_err());
+
+        // Set based operators without values, and existence operators with them, are rejected
+        // rather than rendered into selectors the API server can't parse.
+        assert!(build_label_selector(&selector(vec![requirement("In", None)])).is_err());
+        assert!(build_label_selector(&selector(vec![requirement("In", Some(vec![]))])).is_err());
+        assert!(build_label_selector(&selector(vec![requirement("NotIn", Some(vec![]))])).is_err());
+        assert!(build_label_selector(&selector(vec![requirement(
+            "Exists",
+            Some(vec!["backend"])
+        )]))
+        .is_err());
+        assert!(build_label_selector(&selector(vec![requirement(
+            "DoesNotExist",
+            Some(vec!["backend"])
+        )]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_label_selector_validation() {
+        let labels_selector = |name: &str, value: &str| {
+            let mut match_labels = BTreeMap::new();
+            match_labels.insert(String::from(name), String::from(value));
+            LabelSelector {
+                match_labels: Some(match_labels),
+                ..Default::default()
+            }
+        };
+
+        assert_eq!(
+            build_label_selector(&labels_selector("app.kubernetes.io/name", "my-app.v1")).unwrap(),
+            "app.kubernetes.io/name=my-app.v1"
+        );
+        assert_eq!(
+            build_label_selector(&labels_selector("app", "")).unwrap(),
+            "app="
+        );
+
+        // Values that would otherwise break the selector syntax are rejected.
+        assert!(build_label_selector(&labels_selector("app", "a,b")).is_err());
+        assert!(build_label_selector(&labels_selector("app", "a=b")).is_err());
+        assert!(build_label_selector(&labels_selector("app", "-a")).is_err());
+        assert!(build_label_selector(&labels_selector("a,b", "app")).is_err());
+        assert!(build_label_selector(&labels_selector("/app", "app")).is_err());
+        assert!(build_label_selector(&labels_selector("app", &"a".repeat(64))).is_err());
+        assert!(build_label_selector(&LabelSelector {
+            match_expressions: Some(vec![LabelSelectorRequirement {
+                key: String::from("tier"),
+                operator: String::from("In"),
+                values: Some(vec![String::from("a,b")]),
+            }]),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_is_pod_ready() {
+        let condition = |type_: &str, status: &str| PodCondition {
+            type_: String::from(type_),
+            status: String::from(status),
+            ..Default::default()
+        };
+
+        assert!(!is_pod_ready(&PodStatus::default()));
+        assert!(is_pod_ready(&PodStatus {
+            conditions: Some(vec![
+                condition("PodScheduled", "True"),
+                condition("Ready", "True")
+            ]),
+            ..Default::default()
+        }));
+        assert!(!is_pod_ready(&PodStatus {
+            conditions: Some(vec![
+                condition("PodScheduled", "True"),
+                condition("Ready", "False")
+            ]),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_in_phases() {
+        let status = |phase: &str| PodStatus {
+            phase: Some(String::from(phase)),
+            ..Default::default()
+        };
+
+        assert!(in_phases(&status("Running"), DEFAULT_POD_PHASES));
+        assert!(!in_phases(&status("Pending"), DEFAULT_POD_PHASES));
+        assert!(in_phases(&status("Running"), &["Running", "Pending"]));
+        assert!(in_phases(&status("Pending"), &["Running", "Pending"]));
+        assert!(!in_phases(&status("Succeeded"), &["Running", "Pending"]));
+        assert!(!in_phases(&status("Running"), &["Pending"]));
+        assert!(in_phases(&status("pending"), &["Pending"]));
+        assert!(!in_phases(&status("Running"), &[]));
+        assert!(in_phases(&PodStatus::default(), DEFAULT_POD_PHASES));
+    }
+
+    #[test]
+    fn test_selector_matches() {
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("app"), String::from("zookeeper"));
+        labels.insert(String::from("tier"), String::from("backend"));
+
+        assert!(!selector_matches(&LabelSelector::default(), &labels));
+
+        let mut match_labels = BTreeMap::new();
+        match_labels.insert(String::from("app"), String::from("zookeeper"));
+        let mut selector = LabelSelector {
+            match_labels: Some(match_labels),
+            ..Default::default()
+        };
+        assert!(selector_matches(&selector, &labels));
+
+        selector.match_expressions = Some(vec![LabelSelectorRequirement {
+            key: String::from("tier"),
+            operator: String::from("NotIn"),
+            values: Some(vec![String::from("backend")]),
+        }]);
+        assert!(!selector_matches(&selector, &labels));
+    }
+
+    #[test]
+    fn test_append_scale_history() {
+        let entry = |to| ScaleHistoryEntry {
+            timestamp: "2020-03-29T01:30:00Z".into(),
+            from: 1,
+            to,
+        };
+
+        // Missing and corrupt histories are started afresh.
+        let history = append_scale_history(None, entry(2)).unwrap();
+        assert_eq!(
+            history,
+            r#"[{"timestamp":"2020-03-29T01:30:00Z","from":1,"to":2}]"#
+        );
+        assert_eq!(
+            append_scale_history(Some("not-json"), entry(2)).unwrap(),
+            history
+        );
+
+        // Only the most recent entries are kept.
+        let mut history = history;
+        for to in 3..20 {
+            history = append_scale_history(Some(&history), entry(to)).unwrap();
+        }
+        let entries: Vec<ScaleHistoryEntry> = serde_json::from_str(&history).unwrap();
+        assert_eq!(entries.len(), SCALE_HISTORY_LENGTH);
+        assert_eq!(entries.first().unwrap().to, 10);
+        assert_eq!(entries.last().unwrap().to, 19);
+
+        // Retrying a scaling operation doesn't record it twice.
+        assert_eq!(
+            append_scale_history(Some(&history), entry(19)).unwrap(),
+            history
+        );
+    }
+
+    #[test]
+    fn test_truncate_scale_reason() {
+        let reason = "metric=connections value=1200 target-per-replica=100";
+        assert_eq!(truncate_scale_reason(reason), reason);
+
+        // Long reasons are cut short, on a character boundary.
+        let reason = "é".repeat(SCALE_REASON_LENGTH);
+        let truncated = truncate_scale_reason(&reason);
+        assert!(truncated.len() <= SCALE_REASON_LENGTH);
+        assert!(truncated.ends_with("..."));
+    }
+
+    #[test]
+    fn test_fit_annotations() {
+        let scale = |metadata: &ObjectMeta| {
+            scale_annotations(
+                metadata,
+                ANNOTATION_BASE,
+                Some(2),
+                3,
+                "metric=connections value=300 target-per-replica=100",
+                Utc::now(),
+            )
+            .unwrap()
+        };
+        let history_annotation = format!("{}/scale-history", ANNOTATION_BASE);
+        let reason_annotation = format!("{}/last-scale-reason", ANNOTATION_BASE);
+        let mut metadata = ObjectMeta::default();
+        let history = (0..SCALE_HISTORY_LENGTH).fold(None, |history: Option<String>, to| {
+            Some(
+                append_scale_history(
+                    history.as_deref(),
+                    ScaleHistoryEntry {
+                        timestamp: "2020-03-29T01:30:00Z".into(),
+                        from: 1,
+                        to: to as u32,
+                    },
+                )
+                .unwrap(),
+            )
+        });
+        metadata
+            .annotations
+            .insert(history_annotation.clone(), history.unwrap());
+
+        // Small objects are annotated in full.
+        let (annotations, outcome) = scale(&metadata);
+        assert!(!outcome.trimmed());
+        assert_eq!(annotations.len(), 3);
+
+        // Large annotations from other tooling trim the history, keeping the latest entry.
+        metadata.annotations.insert(
+            "example.com/large".into(),
+            "x".repeat(ANNOTATIONS_SIZE_BUDGET - 300),
+        );
+        let (annotations, outcome) = scale(&metadata);
+        assert!(outcome.trimmed_history_entries > 0);
+        assert!(!outcome.dropped_annotations);
+        assert!(annotations_size(&metadata, &annotations) <= ANNOTATIONS_SIZE_BUDGET);
+        let entries: Vec<ScaleHistoryEntry> =
+            serde_json::from_str(annotations[&history_annotation].as_str().unwrap()).unwrap();
+        assert_eq!(entries.last().unwrap().to, 3);
+
+        // Then the reason, and finally every annotation.
+        metadata.annotations.insert(
+            "example.com/large".into(),
+            "x".repeat(ANNOTATIONS_SIZE_BUDGET - 200),
+        );
+        let (annotations, outcome) = scale(&metadata);
+        assert!(outcome.dropped_reason);
+        assert!(!outcome.dropped_annotations);
+        assert!(!annotations.contains_key(&reason_annotation));
+        metadata.annotations.insert(
+            "example.com/large".into(),
+            "x".repeat(ANNOTATIONS_SIZE_BUDGET),
+        );
+        let (annotations, outcome) = scale(&metadata);
+        assert!(outcome.dropped_annotations);
+        assert!(annotations.is_empty());
+    }
+
+    #[test]
+    fn test_last_scale_up_pods() {
+        let mut metadata = ObjectMeta::default();
+        assert_eq!(last_scale_up_pods(&metadata, ANNOTATION_BASE), 0);
+
+        let scale_history_annotation = format!("{}/scale-history", ANNOTATION_BASE);
+        metadata.annotations.insert(
+            scale_history_annotation.clone(),
+            r#"[{"timestamp":"2020-02-18T10:00:00+00:00","from":4,"to":2},{"timestamp":"2020-02-18T11:00:00+00:00","from":2,"to":3}]"#.into(),
+        );
+        assert_eq!(last_scale_up_pods(&metadata, ANNOTATION_BASE), 1);
+
+        metadata.annotations.insert(
+            scale_history_annotation,
+            r#"[{"timestamp":"2020-02-18T10:00:00+00:00","from":3,"to":2}]"#.into(),
+        );
+        assert_eq!(last_scale_up_pods(&metadata, ANNOTATION_BASE), 0);
+    }
+
+    #[test]
+    fn test_readiness_seconds() {
+        let template = |probes: Vec<Option<Probe>>| PodTemplateSpec {
+            spec: Some(PodSpec {
+                containers: probes
+                    .into_iter()
+                    .map(|readiness_probe| Container {
+                        readiness_probe,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(readiness_seconds(None, None), None);
+        assert_eq!(
+            readiness_seconds(Some(&template(vec![None])), Some(0)),
+            None
+        );
+        assert_eq!(readiness_seconds(None, Some(30)), Some(30));
+
+        // The slowest container's probe, plus the time pods must stay ready.
+        let template = template(vec![
+            Some(Probe::default()),
+            Some(Probe {
+                initial_delay_seconds: Some(20),
+                period_seconds: Some(5),
+                success_threshold: Some(2),
+                ..Default::default()
+            }),
+            None,
+        ]);
+        assert_eq!(readiness_seconds(Some(&template), None), Some(30));
+        assert_eq!(readiness_seconds(Some(&template), Some(15)), Some(45));
+    }
+
+    #[test]
+    fn test_replica_bounds() {
+        let mut metadata = ObjectMeta::default();
+        assert_eq!(
+            replica_bounds(&metadata, ANNOTATION_BASE).unwrap(),
+            (0, std::u32::MAX)
+        );
+
+        metadata
+            .annotations
+            .insert(format!("{}/min-replicas", ANNOTATION_BASE), "2".into());
+        metadata
+            .annotations
+            .insert(format!("{}/max-replicas", ANNOTATION_BASE), "8".into());
+        assert_eq!(replica_bounds(&metadata, ANNOTATION_BASE).unwrap(), (2, 8));
+
+        metadata
+            .annotations
+            .insert(format!("{}/min-replicas", ANNOTATION_BASE), "10".into());
+        assert!(replica_bounds(&metadata, ANNOTATION_BASE).is_err());
+
+        metadata
+            .annotations
+            .insert(format!("{}/min-replicas", ANNOTATION_BASE), "lots".into());
+        assert!(replica_bounds(&metadata, ANNOTATION_BASE).is_err());
+    }
+
+    #[test]
+    fn test_ensure_within_replica_bounds() {
+        let mut metadata = ObjectMeta::default();
+        metadata
+            .annotations
+            .insert(format!("{}/min-replicas", ANNOTATION_BASE), "2".into());
+        metadata
+            .annotations
+            .insert(format!("{}/max-replicas", ANNOTATION_BASE), "8".into());
+        let ensure = |metadata: &ObjectMeta, previous_replicas, replicas| {
+            ensure_within_replica_bounds(
+                metadata,
+                ANNOTATION_BASE,
+                "default",
+                "web",
+                previous_replicas,
+                replicas,
+            )
+        };
+        assert!(ensure(&metadata, Some(4), 8).is_ok());
+        assert!(ensure(&metadata, Some(4), 2).is_ok());
+        match ensure(&metadata, Some(4), 9) {
+            Err(Error::ReplicasOutOfBounds { min, max, .. }) => assert_eq!((min, max), (2, 8)),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(ensure(&metadata, Some(4), 1).is_err());
+
+        // Objects outside of their bounds may only move toward them.
+        assert!(ensure(&metadata, Some(12), 10).is_ok());
+        assert!(ensure(&metadata, Some(12), 13).is_err());
+        assert!(ensure(&metadata, Some(1), 1).is_err());
+
+        // Inverted bounds are refused outright.
+        metadata
+            .annotations
+            .insert(format!("{}/min-replicas", ANNOTATION_BASE), "10".into());
+        match ensure(&metadata, Some(4), 5) {
+            Err(Error::InvalidReplicaBounds { min, max }) => assert_eq!((min, max), (10, 8)),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_quantity() {
+        assert_eq!(
+            parse_quantity("10Gi"),
+            Some(10.0 * 1024.0 * 1024.0 * 1024.0)
+        );
+        assert_eq!(parse_quantity("500M"), Some(500e6));
+        assert_eq!(parse_quantity("1.5Ki"), Some(1536.0));
+        assert_eq!(parse_quantity("250m"), Some(0.25));
+        assert_eq!(parse_quantity("5000000n"), Some(5_000_000.0 * 1e-9));
+        assert_eq!(parse_quantity("1e9"), Some(1e9));
+        assert_eq!(parse_quantity("1024"), Some(1024.0));
+        assert_eq!(parse_quantity("lots"), None);
+    }
+
+    #[allow(clippy::float_cmp)]
+    #[test]
+    fn test_pod_usage() {
+        let pod_metrics: Vec<PodMetricsEntry> = serde_json::from_value(json!([
+            {
+                "metadata": {"name": "web-0"},
+                "timestamp": "2020-03-29T12:00:00Z",
+                "containers": [
+                    {"name": "web", "usage": {"cpu": "250m", "memory": "64Mi"}},
+                    {"name": "proxy", "usage": {"cpu": "50000000n", "memory": "16Mi"}},
+                ],
+            },
+            {
+                "metadata": {"name": "web-1"},
+                "containers": [{"name": "web", "usage": {"cpu": "1"}}],
+            },
+            {
+                "metadata": {"name": "web-terminated"},
+                "containers": [{"name": "web", "usage": {"cpu": "1", "memory": "1Gi"}}],
+            },
+        ]))
+        .unwrap();
+        let pod_names: HashSet<String> = vec![
+            String::from("web-0"),
+            String::from("web-1"),
+            String::from("web-2"),
+        ]
+        .into_iter()
+        .collect();
+
+        let usage = pod_usage(&pod_metrics, &pod_names);
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0].pod_name, "web-0");
+        assert!((usage[0].cpu - 0.3).abs() < 1e-9);
+        assert_eq!(usage[0].memory, 80.0 * 1024.0 * 1024.0);
+        assert_eq!(
+            usage[0].timestamp,
+            Some(Utc.ymd(2020, 3, 29).and_hms(12, 0, 0))
+        );
+        assert_eq!(usage[1].cpu, 1.0);
+        assert_eq!(usage[1].memory, 0.0);
+        assert_eq!(usage[1].timestamp, None);
+    }
+
+    #[test]
+    fn test_controlling_owner() {
+        let metadata: ObjectMeta = serde_json::from_value(json!({
+            "name": "web-5d8f7c",
+            "ownerReferences": [
+                {
+                    "apiVersion": "apps/v1",
+                    "kind": "Deployment",
+                    "name": "web",
+                    "uid": "1234",
+                    "controller": true,
+                    "blockOwnerDeletion": true
+                }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            controlling_owner(&metadata, &["Deployment"]).unwrap().name,
+            "web"
+        );
+        assert!(controlling_owner(&metadata, &["Rollout"]).is_none());
+        // An empty skip list opts in to scaling owned objects.
+        assert!(controlling_owner::<&str>(&metadata, &[]).is_none());
+    }
+
+    #[test]
+    fn test_parse_label_selector() {
+        let labels = parse_label_selector("app=web,tier==frontend").unwrap();
+        assert_eq!(labels.get("app").unwrap(), "web");
+        assert_eq!(labels.get("tier").unwrap(), "frontend");
+        assert!(parse_label_selector("app!=web").is_err());
+        assert!(parse_label_selector("tier in (frontend)").is_err());
+        assert!(parse_label_selector("").is_err());
+    }
+
+    #[test]
+    fn test_scale_patches() {
+        let mut annotations = serde_json::Map::new();
+        annotations.insert(
+            format!("{}/last_modified", ANNOTATION_BASE),
+            json!("2020-01-01T00:00:00Z"),
+        );
+        let patches = |patch_type, replicas, has_annotations| {
+            scale_patches(
+                patch_type,
+                "apps",
+                "v1",
+                "Deployment",
+                "default",
+                "web",
+                replicas,
+                annotations.clone(),
+                has_annotations,
+            )
+        };
+
+        let apply = patches(ScalePatchType::Apply, Some(3), true);
+        assert_eq!(apply.len(), 2);
+        assert_eq!(apply[0].strategy, ScalePatchType::Apply);
+        assert_eq!(apply[0].body["spec"], json!({ "replicas": 3 }));
+        assert_eq!(apply[1].strategy, ScalePatchType::Merge);
+        // Already scaled objects only have their annotations patched.
+        assert_eq!(patches(ScalePatchType::Apply, None, true).len(), 1);
+
+        let merge = patches(ScalePatchType::Merge, Some(3), true);
+        assert_eq!(merge.len(), 1);
+        assert_eq!(merge[0].body["spec"], json!({ "replicas": 3 }));
+        assert_eq!(
+            merge[0].body["metadata"]["annotations"],
+            Value::Object(annotations.clone())
+        );
+
+        let strategic = patches(ScalePatchType::Strategic, Some(3), true);
+        assert_eq!(strategic.len(), 1);
+        assert_eq!(strategic[0].strategy, ScalePatchType::Strategic);
+        assert_eq!(
+            strategic[0].body,
+            json!({
+                "metadata": { "annotations": annotations.clone() },
+                "spec": { "replicas": 3 }
+            })
+        );
+        // Already scaled objects only have their annotations patched.
+        assert_eq!(
+            patches(ScalePatchType::Strategic, None, true)[0].body["spec"],
+            json!({})
+        );
+
+        let json_patch = patches(ScalePatchType::Json, Some(3), true);
+        assert_eq!(json_patch[0].strategy, ScalePatchType::Json);
+        assert_eq!(
+            json_patch[0].body,
+            json!([
+                { "op": "replace", "path": "/spec/replicas", "value": 3 },
+                {
+                    "op": "add",
+                    "path": "/metadata/annotations/pangolinscaler.com~1last_modified",
+                    "value": "2020-01-01T00:00:00Z"
+                }
+            ])
+        );
+        let json_patch = patches(ScalePatchType::Json, None, false);
+        assert_eq!(
+            json_patch[0].body,
+            json!([{
+                "op": "add",
+                "path": "/metadata/annotations",
+                "value": annotations.clone()
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scale_object_subresource_retry() {
+        // The scale is patched, but the annotations conflict with a concurrent modification once.
+        let conflicts = Arc::new(Mutex::new(1));
+        let server = StubApiServer::spawn(move |request| {
+            let widget = json!({
+                "apiVersion": "example.com/v1",
+                "kind": "Widget",
+                "metadata": { "name": "web", "namespace": "default" },
+                "spec": { "replicas": 5 }
+            });
+            let scale = json!({
+                "apiVersion": "autoscaling/v1",
+                "kind": "Scale",
+                "metadata": { "name": "web", "namespace": "default" },
+                "spec": { "replicas": 5 },
+                "status": { "replicas": 5 }
+            });
+            let path = request
+                .path
+                .trim_start_matches("/apis/example.com/v1/namespaces/default/widgets");
+            match (request.method.as_str(), path) {
+                ("GET", "/web") => (200, widget),
+                ("GET", "/web/scale") | ("PATCH", "/web/scale") => (200, scale),
+                ("PATCH", "/web") if *conflicts.lock().unwrap() > 0 => {
+                    *conflicts.lock().unwrap() -= 1;
+                    status_response(409, "Conflict", "the object has been modified")
+                }
+                ("PATCH", "/web") => (200, widget),
+                _ => status_response(404, "NotFound", "not found"),
+            }
+        });
+        let metadata = ObjectMeta {
+            name: "web".into(),
+            namespace: Some("default".into()),
+            ..Default::default()
+        };
+        let outcome = scale_object_subresource(
+            server.kube_client.clone(),
+            "example.com",
+            "v1",
+            "widgets",
+            "Widget",
+            "default",
+            ListedObject {
+                metadata: &metadata,
+                replicas: Some(3),
+            },
+            5,
+            "connections",
+            ANNOTATION_BASE,
+            &FieldManager {
+                name: "pangolin".into(),
+                force: false,
+                patch_type: ScalePatchType::Merge,
+                dry_run: false,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.previous_replicas, Some(3));
+
+        // Only the retry fetches the object and its scale, and it doesn't patch the scale again.
+        let requests: Vec<(String, String)> = server
+            .requests()
+            .into_iter()
+            .map(|request| {
+                (
+                    request.method,
+                    request
+                        .path
+                        .trim_start_matches("/apis/example.com/v1/namespaces/default/widgets")
+                        .to_string(),
+                )
+            })
+            .collect();
+        let expected: Vec<(String, String)> = vec![
+            ("PATCH", "/web/scale"),
+            ("PATCH", "/web"),
+            ("GET", "/web"),
+            ("GET", "/web/scale"),
+            ("PATCH", "/web"),
+        ]
+        .into_iter()
+        .map(|(method, path)| (method.to_string(), path.to_string()))
+        .collect();
+        assert_eq!(requests, expected);
+        let patches = server.patches();
+        assert_eq!(patches[0].body, json!({ "spec": { "replicas": 5 } }));
+        let history: Vec<ScaleHistoryEntry> = serde_json::from_str(
+            patches[2].body["metadata"]["annotations"]
+                [&format!("{}/scale-history", ANNOTATION_BASE)]
+                .as_str()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!((history[0].from, history[0].to), (3, 5));
+    }
 }
@@ -0,0 +1,432 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::common::{
+    build_label_selector, emit_scale_event, get_disruptions_allowed, get_endpoint_slice_endpoints,
+    get_pod_utilization, get_ready_pod_ips, get_running_pod_endpoints, get_running_pod_ips,
+    get_scale, last_modified, list_selected_pages, merge_watches, parse_label_selector,
+    request_list_page, scale_object_subresource, watch_objects, within_namespace,
+    within_namespace_raw, ListedObject, DEFAULT_LIST_PAGE_LIMIT, DEFAULT_POD_PHASES,
+};
+use crate::kubernetes::discovery::{discover_resource, DiscoveredResource};
+use crate::kubernetes::rate_limit::throttle;
+use crate::kubernetes::{
+    EndpointDiscovery, FieldManager, KubernetesObject, KubernetesObjectEvent,
+    KubernetesObjectTrait, KubernetesResourceTrait, PodEndpoints, PodMetrics, PodPort,
+    ScaleOutcome, ScalingStatus,
+};
+use crate::resource::{AutoScalerCustomResource, IpFamily};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::channel::mpsc::UnboundedReceiver;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::{Api, Informer, Object, ObjectMeta, Scale};
+use kube::api::{ListParams, RawApi};
+use kube::client::APIClient;
+use serde_json::Value;
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+
+/// Custom resource kind related functions, for any resource with a scale subresource.
+pub struct KubernetesCustomResource {
+    kube_client: APIClient,
+    annotation_prefix: String,
+    namespace: Option<String>,
+    selectors: Vec<LabelSelector>,
+    custom_resource: AutoScalerCustomResource,
+    field_selector: Option<String>,
+    page_limit: u32,
+}
+
+impl KubernetesCustomResource {
+    pub fn new(
+        kube_client: APIClient,
+        annotation_prefix: &str,
+        namespace: Option<&str>,
+        selectors: &[LabelSelector],
+        custom_resource: &AutoScalerCustomResource,
+    ) -> Self {
+        Self {
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
+            namespace: namespace.map(String::from),
+            selectors: selectors.to_vec(),
+            custom_resource: custom_resource.clone(),
+            field_selector: None,
+            page_limit: DEFAULT_LIST_PAGE_LIMIT,
+        }
+    }
+
+    /// Further narrow the listed objects with a field selector, eg. `metadata.name=web`.
+    pub fn with_field_selector(mut self, field_selector: Option<&str>) -> Self {
+        self.field_selector = field_selector.map(String::from);
+        self
+    }
+
+    /// The maximum number of objects to retrieve per list request.
+    pub fn with_page_limit(mut self, page_limit: u32) -> Self {
+        self.page_limit = page_limit;
+        self
+    }
+
+    /// Discover the resource, verifying that it is served and can be scaled.
+    async fn discover(&self) -> Result<DiscoveredResource, Error> {
+        discover_resource(
+            self.kube_client.clone(),
+            &self.custom_resource.group,
+            &self.custom_resource.version,
+            &self.custom_resource.resource,
+        )
+        .await
+    }
+}
+
+/// The dynamic api for a discovered resource.
+fn custom_api(kube_client: APIClient, resource: &DiscoveredResource) -> Api<Object<Value, Value>> {
+    Api::customResource(kube_client, &resource.resource)
+        .group(&resource.group)
+        .version(&resource.version)
+}
+
+/// The raw api for the discovered custom resource.
+fn custom_raw_api(resource: &DiscoveredResource) -> RawApi {
+    RawApi::customResource(&resource.resource)
+        .group(&resource.group)
+        .version(&resource.version)
+}
+
+#[async_trait]
+impl KubernetesResourceTrait for KubernetesCustomResource {
+    async fn list(&self) -> Result<Vec<KubernetesObject>, Error> {
+        let resource = self.discover().await?;
+        // Retrieve the list of objects matching the label selector, one page at a time.
+        let api = within_namespace_raw(custom_raw_api(&resource), &self.namespace);
+        let custom_objects: Vec<Object<Value, Value>> = list_selected_pages(
+            resource.kind,
+            &self.namespace,
+            &self.selectors,
+            &self.field_selector,
+            self.page_limit,
+            |list_params, page| {
+                request_list_page(self.kube_client.clone(), api.clone(), list_params, page)
+            },
+        )
+        .await?;
+        let mut objects: Vec<KubernetesObject> = Vec::new();
+        for custom_object in custom_objects {
+            objects.push(KubernetesObject::Custom(KubernetesCustomObject::new(
+                self.kube_client.clone(),
+                &self.annotation_prefix,
+                custom_object
+                    .metadata
+                    .namespace
+                    .as_deref()
+                    .unwrap_or_default(),
+                &custom_object.metadata,
+                &resource,
+            )))
+        }
+        Ok(objects)
+    }
+
+    async fn watch(
+        &self,
+    ) -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error> {
+        let resource = self.discover().await?;
+        let kube_client = self.kube_client.clone();
+        let mut watches = Vec::new();
+        for selector in &self.selectors {
+            let label_selector = build_label_selector(selector)?;
+            let list_params = ListParams {
+                label_selector: Some(label_selector.clone()),
+                field_selector: self.field_selector.clone(),
+                ..Default::default()
+            };
+            // Watch for changes to objects matching the label selector.
+            let mut informer = Informer::new(within_namespace(
+                custom_api(kube_client.clone(), &resource),
+                &self.namespace,
+            ))
+            .labels(&label_selector);
+            if let Some(field_selector) = &self.field_selector {
+                informer = informer.fields(field_selector);
+            }
+            throttle().await;
+            let informer = informer.init().await.context(Kube {})?;
+            let object_client = self.kube_client.clone();
+            let annotation_prefix = self.annotation_prefix.clone();
+            let object_resource = resource.clone();
+            watches.push(watch_objects(
+                informer,
+                within_namespace(custom_api(kube_client.clone(), &resource), &self.namespace),
+                list_params,
+                move |custom_object| {
+                    Some(KubernetesObject::Custom(KubernetesCustomObject::new(
+                        object_client.clone(),
+                        &annotation_prefix,
+                        custom_object
+                            .metadata
+                            .namespace
+                            .as_deref()
+                            .unwrap_or_default(),
+                        &custom_object.metadata,
+                        &object_resource,
+                    )))
+                },
+            ));
+        }
+        // Objects matching more than one selector may report each change more than once.
+        Ok(merge_watches(watches))
+    }
+}
+
+/// Custom resource related functions, replicas are read and changed through the object's scale
+/// subresource, so they work regardless of where the resource keeps its replicas.
+#[derive(Clone)]
+pub struct KubernetesCustomObject {
+    kube_client: APIClient,
+    annotation_prefix: String,
+    namespace: String,
+    metadata: ObjectMeta,
+    resource: DiscoveredResource,
+}
+
+impl KubernetesCustomObject {
+    pub fn new(
+        kube_client: APIClient,
+        annotation_prefix: &str,
+        namespace: &str,
+        metadata: &ObjectMeta,
+        resource: &DiscoveredResource,
+    ) -> Self {
+        Self {
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
+            namespace: namespace.into(),
+            metadata: metadata.clone(),
+            resource: resource.clone(),
+        }
+    }
+
+    /// Context for errors about missing fields in the object's scale subresource.
+    fn spec_context(&self) -> KubeObjectSpec<&'static str, &str, &str> {
+        KubeObjectSpec {
+            kind: self.kind(),
+            namespace: self.namespace.as_str(),
+            name: self.metadata.name.as_str(),
+        }
+    }
+
+    /// The object's scale subresource.
+    async fn scale_subresource(&self) -> Result<Scale, Error> {
+        get_scale(
+            self.kube_client.clone(),
+            &self.resource.group,
+            &self.resource.version,
+            &self.resource.resource,
+            self.kind(),
+            &self.namespace,
+            &self.metadata.name,
+        )
+        .await
+    }
+
+    /// The labels of the object's pods, from the selector reported by its scale subresource.
+    async fn pod_labels(&self) -> Result<BTreeMap<String, String>, Error> {
+        let scale = self.scale_subresource().await?;
+        let selector = scale
+            .status
+            .and_then(|status| status.selector)
+            .context(self.spec_context())?;
+        parse_label_selector(&selector)
+    }
+}
+
+#[async_trait]
+impl KubernetesObjectTrait for KubernetesCustomObject {
+    fn kind(&self) -> &'static str {
+        self.resource.kind
+    }
+
+    fn namespace_and_name(&self) -> (String, String) {
+        (self.namespace.clone(), self.metadata.name.clone())
+    }
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn annotation_prefix(&self) -> &str {
+        &self.annotation_prefix
+    }
+
+    async fn last_modified(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        last_modified(&self.metadata, &self.annotation_prefix)
+    }
+
+    async fn desired_replicas(&self) -> Result<u32, Error> {
+        self.scale_subresource()
+            .await?
+            .spec
+            .replicas
+            .context(self.spec_context())
+            .map(|replicas| replicas.max(0) as u32)
+    }
+
+    async fn current_replicas(&self) -> Result<u32, Error> {
+        Ok(self
+            .scale_subresource()
+            .await?
+            .status
+            .map(|status| status.replicas)
+            .unwrap_or(0)
+            .max(0) as u32)
+    }
+
+    async fn ready_replicas(&self) -> Result<u32, Error> {
+        // The scale subresource doesn't report readiness, so count the ready pods instead.
+        Ok(self.ready_pod_ips().await?.len() as u32)
+    }
+
+    async fn scaling_status(&self) -> Result<ScalingStatus, Error> {
+        // Read the desired and observed replicas from a single request.
+        let scale = self.scale_subresource().await?;
+        let desired = scale.spec.replicas.context(self.spec_context())?.max(0) as u32;
+        let current = scale
+            .status
+            .map(|status| status.replicas)
+            .unwrap_or(0)
+            .max(0) as u32;
+        Ok(ScalingStatus::new(
+            desired,
+            current,
+            self.ready_replicas().await?,
+        ))
+    }
+
+    async fn pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels().await?;
+        get_running_pod_ips(
+            self.kube_client.clone(),
+            &self.namespace,
+            &labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn pod_utilization(&self) -> Result<Vec<PodMetrics>, Error> {
+        let labels = self.pod_labels().await?;
+        get_pod_utilization(
+            self.kube_client.clone(),
+            &self.namespace,
+            &labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn ready_pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels().await?;
+        get_ready_pod_ips(self.kube_client.clone(), &self.namespace, &labels).await
+    }
+
+    async fn ready_pod_endpoints(
+        &self,
+        discovery: &EndpointDiscovery,
+        port: &PodPort,
+        ip_family: Option<IpFamily>,
+    ) -> Result<PodEndpoints, Error> {
+        let kube_client = self.kube_client.clone();
+        match discovery {
+            EndpointDiscovery::Pods => {
+                let labels = self.pod_labels().await?;
+                get_running_pod_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    &labels,
+                    port,
+                    ip_family,
+                    true,
+                )
+                .await
+            }
+            EndpointDiscovery::EndpointSlices(service_name) => {
+                get_endpoint_slice_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    service_name,
+                    port,
+                    ip_family,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn disruptions_allowed(&self) -> Result<Option<u32>, Error> {
+        let labels = self.pod_labels().await?;
+        get_disruptions_allowed(self.kube_client.clone(), &self.namespace, &labels).await
+    }
+
+    async fn scale(
+        &self,
+        replicas: u32,
+        reason: &str,
+        field_manager: &FieldManager,
+    ) -> Result<ScaleOutcome, Error> {
+        // Skip no-op patches, so we don't rewrite last_modified and reset the cooldown.
+        let previous_replicas = self.desired_replicas().await?;
+        if previous_replicas == replicas {
+            return Ok(ScaleOutcome::default());
+        }
+        let kube_client = self.kube_client.clone();
+        let mut outcome = scale_object_subresource(
+            kube_client.clone(),
+            &self.resource.group,
+            &self.resource.version,
+            &self.resource.resource,
+            self.kind(),
+            &self.namespace,
+            ListedObject {
+                metadata: &self.metadata,
+                replicas: Some(previous_replicas as i32),
+            },
+            replicas,
+            reason,
+            &self.annotation_prefix,
+            field_manager,
+        )
+        .await?;
+        // Events are best effort, the object has already been scaled.
+        outcome.event_error = emit_scale_event(
+            kube_client,
+            &format!("{}/{}", self.resource.group, self.resource.version),
+            self.kind(),
+            &self.namespace,
+            &self.metadata,
+            outcome.previous_replicas,
+            replicas,
+            reason,
+            field_manager.dry_run,
+        )
+        .await
+        .err()
+        .map(|err| err.to_string());
+        Ok(outcome)
+    }
+}
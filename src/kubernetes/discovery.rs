@@ -0,0 +1,192 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::rate_limit::throttle;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{APIGroupList, APIResourceList};
+use kube::client::APIClient;
+use lazy_static::lazy_static;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Resources that have already been discovered, by group, version and resource.
+    static ref DISCOVERED_RESOURCES: Mutex<HashMap<(String, String, String), DiscoveredResource>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A scalable resource served by the api server, as found through discovery.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredResource {
+    /// The api group of the resource, eg. `example.com`.
+    pub group: String,
+    /// The api version of the resource, eg. `v1`.
+    pub version: String,
+    /// The plural resource name, eg. `widgets`.
+    pub resource: String,
+    /// The kind of the resource's objects, eg. `Widget`.
+    pub kind: &'static str,
+}
+
+/// Discover a resource by group, version and resource name, verifying the api server serves it
+/// and that it can be scaled through a scale subresource. Discovered resources are cached, as
+/// the resources served by the api server rarely change.
+pub async fn discover_resource(
+    kube_client: APIClient,
+    group: &str,
+    version: &str,
+    resource: &str,
+) -> Result<DiscoveredResource, Error> {
+    let key = (group.to_string(), version.to_string(), resource.to_string());
+    if let Some(discovered) = DISCOVERED_RESOURCES.lock().unwrap().get(&key) {
+        return Ok(discovered.clone());
+    }
+
+    // Check the group and version are served first, for a clearer error than a 404.
+    let unsupported = |reason: String| Error::UnsupportedResource {
+        resource: format!("{}/{}/{}", group, version, resource),
+        reason,
+    };
+    throttle().await;
+    let api_groups: APIGroupList = kube_client
+        .request(
+            hyper::Request::get("/apis")
+                .body(Vec::new())
+                .map_err(|err| unsupported(err.to_string()))?,
+        )
+        .await
+        .context(Kube {})?;
+    let api_group = api_groups
+        .groups
+        .iter()
+        .find(|api_group| api_group.name == group)
+        .with_context(|| UnsupportedResource {
+            resource: format!("{}/{}/{}", group, version, resource),
+            reason: format!("api group {} is not served", group),
+        })?;
+    ensure!(
+        api_group
+            .versions
+            .iter()
+            .any(|group_version| group_version.version == version),
+        UnsupportedResource {
+            resource: format!("{}/{}/{}", group, version, resource),
+            reason: format!("api group {} does not serve version {}", group, version),
+        }
+    );
+
+    throttle().await;
+    let api_resources: APIResourceList = kube_client
+        .request(
+            hyper::Request::get(format!("/apis/{}/{}", group, version))
+                .body(Vec::new())
+                .map_err(|err| unsupported(err.to_string()))?,
+        )
+        .await
+        .context(Kube {})?;
+    let discovered = scalable_resource(&api_resources, group, version, resource)?;
+    DISCOVERED_RESOURCES
+        .lock()
+        .unwrap()
+        .insert(key, discovered.clone());
+    Ok(discovered)
+}
+
+/// Find a namespaced resource with a scale subresource in an api group version's resources.
+fn scalable_resource(
+    api_resources: &APIResourceList,
+    group: &str,
+    version: &str,
+    resource: &str,
+) -> Result<DiscoveredResource, Error> {
+    let unsupported = |reason: &str| {
+        UnsupportedResource {
+            resource: format!("{}/{}/{}", group, version, resource),
+            reason,
+        }
+        .fail()
+    };
+    let api_resource = match api_resources
+        .resources
+        .iter()
+        .find(|api_resource| api_resource.name == resource)
+    {
+        Some(api_resource) => api_resource,
+        None => return unsupported("the resource is not served"),
+    };
+    if !api_resource.namespaced {
+        return unsupported("only namespaced resources can be autoscaled");
+    }
+    let scale_subresource = format!("{}/scale", resource);
+    if !api_resources
+        .resources
+        .iter()
+        .any(|api_resource| api_resource.name == scale_subresource)
+    {
+        return unsupported("the resource has no scale subresource");
+    }
+    Ok(DiscoveredResource {
+        group: group.into(),
+        version: version.into(),
+        resource: resource.into(),
+        // Objects report their kind as a static string, each resource is only discovered once.
+        kind: Box::leak(api_resource.kind.clone().into_boxed_str()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_scalable_resource() {
+        let api_resources: APIResourceList = serde_json::from_value(json!({
+            "groupVersion": "example.com/v1",
+            "resources": [
+                {
+                    "name": "widgets",
+                    "singularName": "widget",
+                    "namespaced": true,
+                    "kind": "Widget",
+                    "verbs": ["get", "list", "watch", "patch"]
+                },
+                {
+                    "name": "widgets/scale",
+                    "singularName": "",
+                    "namespaced": true,
+                    "kind": "Scale",
+                    "verbs": ["get", "patch", "update"]
+                },
+                {
+                    "name": "gadgets",
+                    "singularName": "gadget",
+                    "namespaced": true,
+                    "kind": "Gadget",
+                    "verbs": ["get", "list"]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let discovered = scalable_resource(&api_resources, "example.com", "v1", "widgets").unwrap();
+        assert_eq!(discovered.kind, "Widget");
+        // Resources without a scale subresource, or that aren't served at all, can't be scaled.
+        assert!(scalable_resource(&api_resources, "example.com", "v1", "gadgets").is_err());
+        assert!(scalable_resource(&api_resources, "example.com", "v1", "sprockets").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::common::within_namespace;
+use crate::kubernetes::rate_limit::throttle;
+use k8s_openapi::api::autoscaling::v1::{
+    HorizontalPodAutoscalerSpec, HorizontalPodAutoscalerStatus,
+};
+use kube::api::{Api, ListParams, Object};
+use kube::client::APIClient;
+use snafu::ResultExt;
+use std::collections::HashSet;
+
+/// An object targeted by a HorizontalPodAutoscaler, as namespace, kind and name.
+pub type HorizontalPodAutoscalerTarget = (String, String, String);
+
+/// Retrieve every object targeted by a HorizontalPodAutoscaler, in the namespace if supplied,
+/// otherwise in every namespace. Pangolin leaves these objects alone to avoid fighting the HPA.
+pub async fn horizontal_pod_autoscaler_targets(
+    kube_client: APIClient,
+    namespace: Option<&str>,
+) -> Result<HashSet<HorizontalPodAutoscalerTarget>, Error> {
+    throttle().await;
+    let horizontal_pod_autoscalers = within_namespace(
+        Api::<Object<HorizontalPodAutoscalerSpec, HorizontalPodAutoscalerStatus>>::customResource(
+            kube_client,
+            "horizontalpodautoscalers",
+        )
+        .group("autoscaling")
+        .version("v1"),
+        &namespace.map(String::from),
+    )
+    .list(&ListParams::default())
+    .await
+    .context(Kube {})?;
+
+    Ok(horizontal_pod_autoscalers
+        .into_iter()
+        .map(|horizontal_pod_autoscaler| {
+            (
+                horizontal_pod_autoscaler
+                    .metadata
+                    .namespace
+                    .unwrap_or_default(),
+                horizontal_pod_autoscaler.spec.scale_target_ref.kind,
+                horizontal_pod_autoscaler.spec.scale_target_ref.name,
+            )
+        })
+        .collect())
+}
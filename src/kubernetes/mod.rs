@@ -15,62 +15,561 @@
  */
 
 use crate::error::*;
+use crate::kubernetes::common::{
+    controlling_owner, has_enabled_annotation, last_scale_up_pods, replica_bounds,
+    validate_label_key, validate_label_value,
+};
+use crate::kubernetes::custom::{KubernetesCustomObject, KubernetesCustomResource};
+use crate::kubernetes::daemonset::{KubernetesDaemonSetObject, KubernetesDaemonSetResource};
 use crate::kubernetes::deployment::{KubernetesDeploymentObject, KubernetesDeploymentResource};
 use crate::kubernetes::replicaset::{KubernetesReplicaSetObject, KubernetesReplicaSetResource};
+use crate::kubernetes::replicationcontroller::{
+    KubernetesReplicationControllerObject, KubernetesReplicationControllerResource,
+};
+use crate::kubernetes::rollout::{KubernetesRolloutObject, KubernetesRolloutResource};
 use crate::kubernetes::statefulset::{KubernetesStatefulSetObject, KubernetesStatefulSetResource};
+use crate::resource::IpFamily;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use enum_dispatch::enum_dispatch;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::Future;
+use kube::api::{ObjectMeta, OwnerReference};
+use snafu::{ensure, OptionExt};
+use std::time::Duration;
+use tokio::time::timeout;
 
+/// Caching of list results.
+pub mod cache;
+/// Pausing kubernetes api calls while the api server is unhealthy.
+pub mod circuit_breaker;
+/// Kubernetes client configuration.
+pub mod client;
 /// Private shared functionality
 mod common;
+/// Custom resource trait implementations, through the scale subresource.
+pub mod custom;
+/// Kubernetes DaemonSet trait implementations.
+pub mod daemonset;
 /// Kubernetes Deployment trait implementations.
 pub mod deployment;
+/// Discovery of the resources served by the api server.
+pub mod discovery;
+/// HorizontalPodAutoscaler ownership detection.
+pub mod hpa;
+/// Client side rate limiting of kubernetes api requests.
+pub mod rate_limit;
 /// Kubernetes ReplicaSet trait implementations.
 pub mod replicaset;
+/// Kubernetes ReplicationController trait implementations.
+pub mod replicationcontroller;
+/// Argo Rollout trait implementations.
+pub mod rollout;
 /// Kubernetes StatefulSet trait implementations.
 pub mod statefulset;
+/// An in-process kubernetes api server for tests.
+#[cfg(test)]
+pub(crate) mod stub;
+
+/// A container port, selected either by name or by number.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PodPort {
+    /// A named container port, eg. `metrics`.
+    Name(String),
+    /// A port number, used as is.
+    Number(u16),
+}
+
+/// Bound a kubernetes API call by a timeout, so that a slow API server can't stall reconciliation.
+pub async fn with_timeout<T>(
+    operation: &str,
+    duration: Duration,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout(duration, future).await {
+        Ok(result) => result,
+        Err(_) => Timeout {
+            operation,
+            seconds: duration.as_secs(),
+        }
+        .fail(),
+    }
+}
+
+/// The field manager pangolin applies changes as, recorded in each object's managedFields.
+#[derive(Clone, Debug)]
+pub struct FieldManager {
+    /// Name of the field manager, eg. `pangolin`.
+    pub name: String,
+    /// Take ownership of fields managed by others, rather than failing with a conflict.
+    pub force: bool,
+    /// The type of patch replica changes are sent as.
+    pub patch_type: ScalePatchType,
+    /// Send changes as a server-side dry run, validated by the api server and admission webhooks
+    /// without being persisted.
+    pub dry_run: bool,
+}
+
+/// The type of patch that scales objects, as admission webhooks may treat them differently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalePatchType {
+    /// Server-side apply, tracking pangolin's ownership of the replicas field.
+    Apply,
+    /// A JSON merge patch (RFC 7386).
+    Merge,
+    /// A JSON patch (RFC 6902).
+    Json,
+    /// A strategic merge patch.
+    Strategic,
+}
+
+/// A reference to an object, for reporting on it after the object itself is gone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectRef {
+    pub kind: &'static str,
+    pub namespace: String,
+    pub name: String,
+}
+
+impl ObjectRef {
+    pub fn new(kubernetes_object: &KubernetesObject) -> ObjectRef {
+        let (namespace, name) = kubernetes_object.namespace_and_name();
+        ObjectRef {
+            kind: kubernetes_object.kind(),
+            namespace,
+            name,
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}/{}", self.kind, self.namespace, self.name)
+    }
+}
+
+/// The replicas of an object, and whether it has converged on its desired number of replicas.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScalingStatus {
+    /// The desired number of replicas, from the object's spec.
+    pub desired: u32,
+    /// The observed number of replicas, from the object's status.
+    pub current: u32,
+    /// The number of replicas that are ready.
+    pub ready: u32,
+    /// Whether every desired replica has been observed and is ready.
+    pub converged: bool,
+}
+
+impl ScalingStatus {
+    pub fn new(desired: u32, current: u32, ready: u32) -> ScalingStatus {
+        ScalingStatus {
+            desired,
+            current,
+            ready,
+            converged: current == desired && ready >= desired,
+        }
+    }
+}
+
+/// The outcome of a scaling operation, pangolin's own annotations are trimmed rather than fail the
+/// operation when the object's annotations would grow past the api server's size limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScaleOutcome {
+    /// The number of replicas the object had when it was scaled, as read from the api server.
+    pub previous_replicas: Option<u32>,
+    /// Why the scaling operation couldn't be recorded as an event, if it couldn't.
+    pub event_error: Option<String>,
+    /// The number of the oldest scale history entries that were dropped.
+    pub trimmed_history_entries: usize,
+    /// Whether the reason for the scaling operation was left out.
+    pub dropped_reason: bool,
+    /// Whether every annotation was left out, the replicas are still changed.
+    pub dropped_annotations: bool,
+}
+
+impl ScaleOutcome {
+    /// Were any of pangolin's annotations trimmed?
+    pub fn trimmed(&self) -> bool {
+        self.trimmed_history_entries > 0 || self.dropped_reason || self.dropped_annotations
+    }
+}
+
+/// How an object's controller brings up new pods, as in a StatefulSet's `podManagementPolicy`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PodManagementPolicy {
+    /// One pod at a time, each waiting for the previous pod to be ready.
+    OrderedReady,
+    /// Every pod at once.
+    Parallel,
+}
+
+impl PodManagementPolicy {
+    /// The time after a scale up that added the given number of pods, during which its pods are
+    /// still warming up (seconds). Ordered pods start one after another, so each warms up in turn.
+    pub fn warmup_for(self, warmup: u32, pods_added: u32) -> u32 {
+        match self {
+            PodManagementPolicy::OrderedReady => warmup.saturating_mul(pods_added.max(1)),
+            PodManagementPolicy::Parallel => warmup,
+        }
+    }
+}
+
+/// The number of replicas to scale to that keeps at least one of the updated pods of an in-flight
+/// partitioned update, those at or above the partition, as the highest ordinal pods are removed
+/// first.
+pub fn keep_updated_pods(partition: u32, current_replicas: u32, desired_replicas: u32) -> u32 {
+    if desired_replicas < current_replicas && current_replicas > partition {
+        desired_replicas.max(partition.saturating_add(1))
+    } else {
+        desired_replicas
+    }
+}
+
+/// Parse a label in the form `key=value`, eg. `pangolin.io/autoscale=true`. Both the key and the
+/// value must be non-empty, as objects are selected by the value with an `In` requirement.
+pub fn parse_label(label: &str) -> Result<(String, String), Error> {
+    let mut parts = label.splitn(2, '=');
+    let key = parts.next().unwrap_or_default();
+    let value = parts.next().context(InvalidLabelSelector {
+        reason: format!("expected a label in the form key=value, got {:?}", label),
+    })?;
+    ensure!(
+        !key.is_empty() && !value.is_empty(),
+        InvalidLabelSelector {
+            reason: format!("label {:?} needs both a key and a value", label),
+        }
+    );
+    validate_label_key(key)?;
+    validate_label_value(value)?;
+    Ok((key.into(), value.into()))
+}
+
+/// The resource usage of a pod, as reported by the metrics.k8s.io api.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PodMetrics {
+    /// The name of the pod.
+    pub pod_name: String,
+    /// Combined CPU usage of the pod's containers (cores).
+    pub cpu: f64,
+    /// Combined memory usage of the pod's containers (bytes).
+    pub memory: f64,
+    /// When the usage was sampled by the metrics server, if known.
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// How to discover the endpoints of an object's pods.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EndpointDiscovery {
+    /// List the object's pods directly.
+    Pods,
+    /// Read the EndpointSlices of the named service fronting the object.
+    EndpointSlices(String),
+}
+
+/// The address of a port on a running pod.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PodEndpoint {
+    /// The name of the pod, if known.
+    pub pod_name: Option<String>,
+    /// The pod ip.
+    pub ip: String,
+    /// The resolved port number.
+    pub port: u16,
+}
+
+impl std::fmt::Display for PodEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // IPv6 addresses must be bracketed to be used alongside a port.
+        if self.ip.contains(':') {
+            write!(f, "[{}]:{}", self.ip, self.port)
+        } else {
+            write!(f, "{}:{}", self.ip, self.port)
+        }
+    }
+}
+
+/// The endpoints of an object's ready pods.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PodEndpoints {
+    /// The endpoints of pods exposing the port.
+    pub endpoints: Vec<PodEndpoint>,
+    /// The pods, or EndpointSlices, skipped because they don't expose the named port.
+    pub skipped: Vec<String>,
+}
 
 /// Kubernetes resource families.
 #[enum_dispatch]
 pub enum KubernetesResource {
+    /// A list of apps/v1 DaemonSet resources.
+    DaemonSet(KubernetesDaemonSetResource),
     /// A list of apps/v1 Deployment resources.
     Deployment(KubernetesDeploymentResource),
     /// A list of apps/v1 ReplicaSet resources.
     ReplicaSet(KubernetesReplicaSetResource),
+    /// A list of core v1 ReplicationController resources.
+    ReplicationController(KubernetesReplicationControllerResource),
+    /// A list of argoproj.io/v1alpha1 Rollout resources.
+    Rollout(KubernetesRolloutResource),
+    /// A list of discovered custom resources.
+    Custom(KubernetesCustomResource),
     /// A list of apps/v1 StatefulSet resources.
     StatefulSet(KubernetesStatefulSetResource),
 }
 
 /// Kubernetes objects, eg deployments etc.
+// Rollouts embed a full pod template, boxing it would complicate every other kind's dispatch.
+#[allow(clippy::large_enum_variant)]
 #[enum_dispatch]
+#[derive(Clone)]
 pub enum KubernetesObject {
+    /// An apps/v1 DaemonSet object (read-only).
+    DaemonSet(KubernetesDaemonSetObject),
     /// An apps/v1 Deployment object.
     Deployment(KubernetesDeploymentObject),
     /// An apps/v1 ReplicaSet object.
     ReplicaSet(KubernetesReplicaSetObject),
+    /// A core v1 ReplicationController object.
+    ReplicationController(KubernetesReplicationControllerObject),
+    /// An argoproj.io/v1alpha1 Rollout object.
+    Rollout(KubernetesRolloutObject),
+    /// A discovered custom resource object.
+    Custom(KubernetesCustomObject),
     /// An apps/v1 StatefulSet object.
     StatefulSet(KubernetesStatefulSetObject),
 }
 
+/// A change to a watched kubernetes object.
+// Events are short lived, boxing the object would only add an allocation per event.
+#[allow(clippy::large_enum_variant)]
+pub enum KubernetesObjectEvent {
+    /// A new matching object.
+    Added(KubernetesObject),
+    /// A matching object that has been modified.
+    Modified(KubernetesObject),
+    /// A matching object that has been deleted.
+    Deleted(KubernetesObject),
+    /// The watch expired and has been re-established, the full set of matching objects.
+    Resync(Vec<KubernetesObject>),
+}
+
 #[async_trait]
 #[enum_dispatch(KubernetesResource)]
 pub trait KubernetesResourceTrait {
     /// Retrieve a list of matching objects from the k8s api.
     async fn list(&self) -> Result<Vec<KubernetesObject>, Error>;
+    /// Watch the k8s api for changes to matching objects.
+    async fn watch(&self)
+        -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error>;
 }
 
 #[async_trait]
 #[enum_dispatch(KubernetesObject)]
 pub trait KubernetesObjectTrait {
+    /// The kubernetes kind of the object, eg. StatefulSet.
+    fn kind(&self) -> &'static str;
     /// The namespace and name of the object.
     fn namespace_and_name(&self) -> (String, String);
+    /// The object's metadata, eg. labels and annotations.
+    fn metadata(&self) -> &ObjectMeta;
+    /// The prefix of pangolin's annotations on the object, eg. `pangolinscaler.com`.
+    fn annotation_prefix(&self) -> &str;
+    /// Has autoscaling been paused for the object, eg. during maintenance?
+    fn is_paused(&self) -> bool {
+        has_enabled_annotation(self.metadata(), self.annotation_prefix(), "paused")
+    }
+    /// Is the object part way through rolling out an update, eg. a new image?
+    fn is_rolling_out(&self) -> bool {
+        false
+    }
+    /// Was the object's most recent scaling operation a scale up?
+    fn last_scaled_up(&self) -> bool {
+        self.last_scale_up_pods() > 0
+    }
+    /// The number of pods added by the object's most recent scaling operation, zero if it wasn't
+    /// a scale up.
+    fn last_scale_up_pods(&self) -> u32 {
+        last_scale_up_pods(self.metadata(), self.annotation_prefix())
+    }
+    /// The ordinal at or above which pods are updated by an in-flight partitioned rolling update,
+    /// eg. of a StatefulSet canary. None if the object's updates aren't partitioned, or none is
+    /// in flight.
+    fn update_partition(&self) -> Option<u32> {
+        None
+    }
+    /// How long the object's new pods are expected to take to become available (seconds), from
+    /// their readiness probes and minimum ready time. None if nothing delays their readiness.
+    fn readiness_seconds(&self) -> Option<u32> {
+        None
+    }
+    /// How the object's controller brings up new pods.
+    fn pod_management_policy(&self) -> PodManagementPolicy {
+        PodManagementPolicy::Parallel
+    }
+    /// Has the object opted in to being scaled down to zero replicas?
+    fn allows_scale_to_zero(&self) -> bool {
+        has_enabled_annotation(
+            self.metadata(),
+            self.annotation_prefix(),
+            "allow-scale-to-zero",
+        )
+    }
+    /// Has the object opted in to notifications when it is scaled?
+    fn wants_scale_notifications(&self) -> bool {
+        has_enabled_annotation(self.metadata(), self.annotation_prefix(), "notify")
+    }
+    /// The object's controlling owner, if it is one of the given kinds of higher level controller
+    /// that manages the object's replicas itself, eg. the Deployment of a ReplicaSet.
+    fn controlling_owner(&self, owner_kinds: &[String]) -> Option<&OwnerReference> {
+        controlling_owner(self.metadata(), owner_kinds)
+    }
+    /// The minimum and maximum number of replicas allowed by the object's annotations.
+    fn replica_bounds(&self) -> Result<(u32, u32), Error> {
+        replica_bounds(self.metadata(), self.annotation_prefix())
+    }
+    /// The names of the pods that scaling down to the given number of replicas will remove.
+    /// None if the object's controller doesn't remove pods in a predictable order.
+    fn removed_pods(&self, _replicas: u32) -> Result<Option<Vec<String>>, Error> {
+        Ok(None)
+    }
     /// The last time the object was modified by the autoscaler.
     async fn last_modified(&self) -> Result<Option<DateTime<Utc>>, Error>;
-    /// The current number of replicas.
-    async fn replicas(&self) -> Result<u32, Error>;
+    /// The number of seconds since the object was last modified by the autoscaler.
+    async fn seconds_since_last_modified(&self) -> Result<Option<i64>, Error> {
+        let utc_now: DateTime<Utc> = Utc::now();
+        Ok(self
+            .last_modified()
+            .await?
+            .map(|last_modified| utc_now.signed_duration_since(last_modified).num_seconds()))
+    }
+    /// The desired number of replicas, from the object's spec.
+    async fn desired_replicas(&self) -> Result<u32, Error>;
+    /// The observed number of replicas, from the object's status.
+    /// This may lag behind the desired number of replicas while the object converges.
+    async fn current_replicas(&self) -> Result<u32, Error>;
+    /// The number of replicas that are currently ready.
+    async fn ready_replicas(&self) -> Result<u32, Error>;
+    /// The desired, observed and ready replicas of the object, and whether it has converged.
+    async fn scaling_status(&self) -> Result<ScalingStatus, Error> {
+        Ok(ScalingStatus::new(
+            self.desired_replicas().await?,
+            self.current_replicas().await?,
+            self.ready_replicas().await?,
+        ))
+    }
     /// The pod ips of every running pod belonging to this object.
     async fn pod_ips(&self) -> Result<Vec<String>, Error>;
-    /// Update the number of replicas associated with this object.
-    async fn scale(&self, replicas: u32) -> Result<(), Error>;
+    /// The stable DNS names of every running pod belonging to this object, eg. those of a
+    /// StatefulSet's pods through its governing headless service. Unlike pod ips they survive the
+    /// pods being restarted.
+    async fn pod_dns_names(&self) -> Result<Vec<String>, Error> {
+        Unsupported {
+            operation: format!("stable pod dns names for a {}", self.kind()),
+        }
+        .fail()
+    }
+    /// The CPU and memory usage of every running pod belonging to this object, from the
+    /// metrics.k8s.io api.
+    async fn pod_utilization(&self) -> Result<Vec<PodMetrics>, Error>;
+    /// The pod ips of every running pod belonging to this object that is passing its readiness checks.
+    async fn ready_pod_ips(&self) -> Result<Vec<String>, Error>;
+    /// The ip and resolved port of every ready pod belonging to this object. Pods that don't expose
+    /// a named port are skipped, rather than failing the whole object.
+    async fn ready_pod_endpoints(
+        &self,
+        discovery: &EndpointDiscovery,
+        port: &PodPort,
+        ip_family: Option<IpFamily>,
+    ) -> Result<PodEndpoints, Error>;
+    /// Take some of this object's pods out of the services fronting them, by removing a label the
+    /// services select on but the object doesn't, or return them by restoring the label from the
+    /// pod template.
+    async fn set_pods_serving(
+        &self,
+        _pod_names: &[String],
+        _label: &str,
+        _serving: bool,
+    ) -> Result<(), Error> {
+        Unsupported {
+            operation: format!("taking pods out of service for a {}", self.kind()),
+        }
+        .fail()
+    }
+    /// The number of pods that can be disrupted without violating a pod disruption budget.
+    /// None if the object's pods aren't covered by any budget.
+    async fn disruptions_allowed(&self) -> Result<Option<u32>, Error>;
+    /// The largest number of replicas, up to the supplied number, whose new PersistentVolumeClaims
+    /// fit within the namespace's storage quota. None if storage doesn't limit the object's replicas.
+    async fn max_replicas_within_storage_quota(
+        &self,
+        _replicas: u32,
+    ) -> Result<Option<u32>, Error> {
+        Ok(None)
+    }
+    /// Update the number of replicas associated with this object, recording the reason for the
+    /// scaling operation in its annotations.
+    async fn scale(
+        &self,
+        replicas: u32,
+        reason: &str,
+        field_manager: &FieldManager,
+    ) -> Result<ScaleOutcome, Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let result: Result<(), Error> =
+            with_timeout("list", Duration::from_millis(10), future::pending()).await;
+        match result {
+            Err(Error::Timeout { operation, .. }) => assert_eq!(operation, "list"),
+            _ => panic!("expected a timeout"),
+        }
+
+        let result = with_timeout("list", Duration::from_secs(1), future::ok(42)).await;
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn test_keep_updated_pods() {
+        // Pods 3 and 4 of 5 are updated, scaling down may remove one of them but not both.
+        assert_eq!(keep_updated_pods(3, 5, 4), 4);
+        assert_eq!(keep_updated_pods(3, 5, 2), 4);
+        // Scaling up, or without any updated pods, is left alone.
+        assert_eq!(keep_updated_pods(3, 5, 8), 8);
+        assert_eq!(keep_updated_pods(3, 3, 1), 1);
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("pangolin.io/autoscale=true").unwrap(),
+            (String::from("pangolin.io/autoscale"), String::from("true"))
+        );
+        assert!(parse_label("pangolin.io/autoscale").is_err());
+        assert!(parse_label("=true").is_err());
+        assert!(parse_label("pangolin.io/autoscale=").is_err());
+        assert!(parse_label("pangolin.io/autoscale=a=b").is_err());
+        assert!(parse_label("pangolin io=true").is_err());
+    }
+
+    #[test]
+    fn test_pod_management_policy_warmup() {
+        assert_eq!(PodManagementPolicy::Parallel.warmup_for(60, 3), 60);
+        assert_eq!(PodManagementPolicy::OrderedReady.warmup_for(60, 3), 180);
+        assert_eq!(PodManagementPolicy::OrderedReady.warmup_for(60, 0), 60);
+    }
+
+    #[test]
+    fn test_scaling_status() {
+        assert!(ScalingStatus::new(3, 3, 3).converged);
+        // Still rolling out the replicas of a scale up.
+        assert!(!ScalingStatus::new(5, 3, 3).converged);
+        // Observed, but not yet ready.
+        assert!(!ScalingStatus::new(3, 3, 2).converged);
+    }
 }
@@ -0,0 +1,334 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::telemetry::KUBE_API_RATE_LIMIT_DELAY;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time::delay_for;
+
+lazy_static! {
+    /// Rate limiter shared by every kubernetes api request, unlimited unless configured.
+    static ref KUBE_API_RATE_LIMITER: RwLock<Option<Arc<TokenBucket>>> = RwLock::new(None);
+}
+
+/// Limit kubernetes api requests to a sustained rate (requests per second), allowing bursts of
+/// up to `burst` requests, like kubectl's `--qps` and `--burst`.
+pub fn set_kube_api_rate_limit(qps: f64, burst: u32) {
+    *KUBE_API_RATE_LIMITER.write().unwrap() = Some(Arc::new(TokenBucket::new(qps, burst)));
+}
+
+/// Wait until the next kubernetes api request is allowed, if requests are rate limited.
+pub async fn throttle() {
+    let rate_limiter = KUBE_API_RATE_LIMITER.read().unwrap().clone();
+    if let Some(rate_limiter) = rate_limiter {
+        let started = Instant::now();
+        rate_limiter.acquire().await;
+        KUBE_API_RATE_LIMIT_DELAY.observe(started.elapsed().as_secs_f64());
+    }
+}
+
+/// Token bucket rate limiter. The bucket holds up to `burst` tokens and is refilled at `qps`
+/// tokens per second, every request takes a token.
+pub struct TokenBucket {
+    qps: f64,
+    burst: f64,
+    state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(qps: f64, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            qps,
+            burst,
+            state: Mutex::new(TokenBucketState {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait for, and take, a token.
+    pub async fn acquire(&self) {
+        while let Err(wait) = self.try_acquire(Instant::now()) {
+            delay_for(wait).await;
+        }
+    }
+
+    /// Take a token if one is available at the instant, otherwise return how long until one is.
+    fn try_acquire(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.qps).min(self.burst);
+        state.updated = state.updated.max(now);
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - state.tokens) / self.qps))
+        }
+    }
+}
+
+/// Concurrency and rate limits for the scaling operations in a namespace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamespaceLimit {
+    /// Maximum number of scaling operations in flight at once.
+    pub max_concurrent_scales: usize,
+    /// Sustained rate of scaling operations (per second) and the burst allowed above it, if the
+    /// rate is limited.
+    pub rate: Option<(f64, u32)>,
+}
+
+/// Parse a namespace limit, `NAMESPACE=CONCURRENCY[:QPS[:BURST]]`, eg. `team-a=2:0.5:2`. The
+/// burst defaults to one second's worth of operations.
+pub fn parse_namespace_limit(limit: &str) -> Result<(String, NamespaceLimit), String> {
+    let invalid = || {
+        format!(
+            "expected NAMESPACE=CONCURRENCY[:QPS[:BURST]], got {}",
+            limit
+        )
+    };
+    let mut parts = limit.splitn(2, '=');
+    let namespace = parts.next().unwrap_or_default();
+    let mut values = parts.next().ok_or_else(invalid)?.split(':');
+    let max_concurrent_scales: usize = values
+        .next()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .ok_or_else(invalid)?;
+    let qps = match values.next() {
+        Some(qps) => Some(
+            qps.parse::<f64>()
+                .ok()
+                .filter(|qps| *qps > 0.0)
+                .ok_or_else(invalid)?,
+        ),
+        None => None,
+    };
+    let burst = match values.next() {
+        Some(burst) => Some(burst.parse::<u32>().map_err(|_| invalid())?),
+        None => None,
+    };
+    if namespace.is_empty() || values.next().is_some() {
+        return Err(invalid());
+    }
+    Ok((
+        namespace.into(),
+        NamespaceLimit {
+            max_concurrent_scales,
+            rate: qps.map(|qps| (qps, burst.unwrap_or_else(|| qps.ceil() as u32))),
+        },
+    ))
+}
+
+/// Limits on the scaling operations in each namespace, on top of the global limits, so that a
+/// busy namespace can't use up the scaling budget of every other. Namespaces without a limit of
+/// their own take the `*` limit, if there is one, otherwise only the global limits apply.
+pub struct NamespaceLimiter {
+    limits: HashMap<String, NamespaceLimit>,
+    throttles: Mutex<HashMap<String, Arc<NamespaceThrottle>>>,
+}
+
+/// The scaling slots and rate limit of a namespace.
+struct NamespaceThrottle {
+    semaphore: Semaphore,
+    bucket: Option<TokenBucket>,
+}
+
+/// A slot for a scaling operation in a namespace, released when dropped.
+pub struct NamespacePermit {
+    throttle: Arc<NamespaceThrottle>,
+}
+
+impl Drop for NamespacePermit {
+    fn drop(&mut self) {
+        self.throttle.semaphore.add_permits(1);
+    }
+}
+
+impl NamespaceLimiter {
+    pub fn new(limits: Vec<(String, NamespaceLimit)>) -> Self {
+        Self {
+            limits: limits.into_iter().collect(),
+            throttles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for a free slot to scale an object in the namespace, then for the namespace's rate
+    /// limit. None if the namespace isn't limited.
+    pub async fn acquire(&self, namespace: &str) -> Option<NamespacePermit> {
+        let throttle = self.throttle(namespace)?;
+        throttle.semaphore.acquire().await.forget();
+        // The slot is released even if waiting on the rate limit is abandoned.
+        let permit = NamespacePermit { throttle };
+        if let Some(bucket) = &permit.throttle.bucket {
+            bucket.acquire().await;
+        }
+        Some(permit)
+    }
+
+    /// The namespace's throttle, created on first use.
+    fn throttle(&self, namespace: &str) -> Option<Arc<NamespaceThrottle>> {
+        let limit = self
+            .limits
+            .get(namespace)
+            .or_else(|| self.limits.get("*"))?;
+        let mut throttles = self.throttles.lock().unwrap();
+        let throttle = throttles.entry(namespace.into()).or_insert_with(|| {
+            Arc::new(NamespaceThrottle {
+                semaphore: Semaphore::new(limit.max_concurrent_scales),
+                bucket: limit.rate.map(|(qps, burst)| TokenBucket::new(qps, burst)),
+            })
+        });
+        Some(throttle.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, FutureExt};
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(2.0, 2);
+        let now = Instant::now();
+
+        // A full bucket allows a burst, then requests wait for the bucket to refill.
+        assert!(bucket.try_acquire(now).is_ok());
+        assert!(bucket.try_acquire(now).is_ok());
+        let wait = bucket.try_acquire(now).unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        assert!(bucket.try_acquire(now + Duration::from_millis(500)).is_ok());
+        assert!(bucket
+            .try_acquire(now + Duration::from_millis(500))
+            .is_err());
+
+        // The bucket never holds more than the burst.
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_err());
+    }
+
+    #[test]
+    fn test_parse_namespace_limit() {
+        assert_eq!(
+            parse_namespace_limit("team-a=2:0.5:4").unwrap(),
+            (
+                String::from("team-a"),
+                NamespaceLimit {
+                    max_concurrent_scales: 2,
+                    rate: Some((0.5, 4)),
+                }
+            )
+        );
+        assert_eq!(
+            parse_namespace_limit("*=1:2.5").unwrap().1.rate,
+            Some((2.5, 3))
+        );
+        assert_eq!(parse_namespace_limit("team-a=3").unwrap().1.rate, None);
+        assert!(parse_namespace_limit("team-a").is_err());
+        assert!(parse_namespace_limit("team-a=0").is_err());
+        assert!(parse_namespace_limit("team-a=1:0").is_err());
+        assert!(parse_namespace_limit("=1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_namespace_limiter() {
+        let limiter = NamespaceLimiter::new(vec![
+            parse_namespace_limit("team-a=1").unwrap(),
+            parse_namespace_limit("team-b=4:1:1").unwrap(),
+            parse_namespace_limit("*=2").unwrap(),
+        ]);
+
+        // Each namespace is capped to its own number of concurrent operations.
+        let permit = limiter.acquire("team-a").now_or_never().unwrap();
+        assert!(permit.is_some());
+        assert!(limiter.acquire("team-a").now_or_never().is_none());
+        drop(permit);
+        assert!(limiter.acquire("team-a").now_or_never().is_some());
+
+        // And to its own rate.
+        assert!(limiter.acquire("team-b").now_or_never().is_some());
+        assert!(limiter.acquire("team-b").now_or_never().is_none());
+
+        // Other namespaces fall back to the default limit.
+        let _first = limiter.acquire("team-c").now_or_never().unwrap();
+        let _second = limiter.acquire("team-c").now_or_never().unwrap();
+        assert!(limiter.acquire("team-c").now_or_never().is_none());
+        assert!(limiter.acquire("team-d").now_or_never().is_some());
+
+        // Without a default only the global limits apply.
+        let limiter = NamespaceLimiter::new(Vec::new());
+        assert!(limiter.acquire("team-a").now_or_never().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_scales_within_limits() {
+        // Scaling 50 objects at once never has more operations in flight than the global limit,
+        // or than a namespace's own limit. Each count is of those in flight and the most seen.
+        let global = Arc::new(Semaphore::new(5));
+        let limiter = Arc::new(NamespaceLimiter::new(vec![parse_namespace_limit(
+            "team-a=2",
+        )
+        .unwrap()]));
+        let in_flight = Arc::new(Mutex::new((0, 0)));
+        let team_a_in_flight = Arc::new(Mutex::new((0, 0)));
+        let enter = |count: &Mutex<(usize, usize)>| {
+            let mut count = count.lock().unwrap();
+            count.0 += 1;
+            count.1 = count.1.max(count.0);
+        };
+        let leave = |count: &Mutex<(usize, usize)>| count.lock().unwrap().0 -= 1;
+
+        let scales = (0..50).map(|object| {
+            let namespace = if object % 2 == 0 { "team-a" } else { "team-b" };
+            let global = global.clone();
+            let limiter = limiter.clone();
+            let in_flight = in_flight.clone();
+            let team_a_in_flight = team_a_in_flight.clone();
+            tokio::spawn(async move {
+                let _namespace_permit = limiter.acquire(namespace).await;
+                let _permit = global.acquire().await;
+                enter(&in_flight);
+                if namespace == "team-a" {
+                    enter(&team_a_in_flight);
+                }
+                delay_for(Duration::from_millis(5)).await;
+                if namespace == "team-a" {
+                    leave(&team_a_in_flight);
+                }
+                leave(&in_flight);
+            })
+        });
+        for scale in future::join_all(scales).await {
+            scale.unwrap();
+        }
+
+        assert_eq!(*in_flight.lock().unwrap(), (0, 5));
+        assert_eq!(*team_a_in_flight.lock().unwrap(), (0, 2));
+    }
+}
@@ -15,165 +15,363 @@
  */
 
 use crate::error::*;
-use crate::kubernetes::common::{build_label_selector, get_running_pod_ips};
-use crate::kubernetes::{KubernetesObject, KubernetesObjectTrait, KubernetesResourceTrait};
-use crate::resource::ANNOTATION_BASE;
+use crate::kubernetes::common::{
+    build_label_selector, emit_scale_event, get_disruptions_allowed, get_endpoint_slice_endpoints,
+    get_pod_utilization, get_ready_pod_ips, get_running_pod_endpoints, get_running_pod_ips,
+    last_modified, list_selected_pages, merge_watches, readiness_seconds, request_list_page,
+    scale_object, watch_objects, within_namespace, within_namespace_raw, ListedObject,
+    DEFAULT_LIST_PAGE_LIMIT, DEFAULT_POD_PHASES,
+};
+use crate::kubernetes::rate_limit::throttle;
+use crate::kubernetes::{
+    EndpointDiscovery, FieldManager, KubernetesObject, KubernetesObjectEvent,
+    KubernetesObjectTrait, KubernetesResourceTrait, PodEndpoints, PodMetrics, PodPort,
+    ScaleOutcome,
+};
+use crate::resource::IpFamily;
 use async_trait::async_trait;
 use chrono::prelude::*;
-use k8s_openapi::api::apps::v1::ReplicaSetSpec;
-use kube::api::{Api, ObjectMeta};
-use kube::api::{ListParams, PatchParams};
+use futures::channel::mpsc::UnboundedReceiver;
+use k8s_openapi::api::apps::v1::{ReplicaSetSpec, ReplicaSetStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::{Api, Informer, ObjectMeta};
+use kube::api::{ListParams, Object, RawApi};
 use kube::client::APIClient;
-use serde_json::json;
 use snafu::{OptionExt, ResultExt};
 use std::collections::BTreeMap;
 
 /// Kubernetes ReplicaSet resource kind related functions.
 pub struct KubernetesReplicaSetResource {
-    kube_config: kube::config::Configuration,
-    namespace: String,
-    label_selector: String,
+    kube_client: APIClient,
+    annotation_prefix: String,
+    namespace: Option<String>,
+    selectors: Vec<LabelSelector>,
+    field_selector: Option<String>,
+    page_limit: u32,
 }
 
 impl KubernetesReplicaSetResource {
     pub fn new(
-        kube_config: kube::config::Configuration,
-        namespace: &str,
-        match_labels: &BTreeMap<String, String>,
+        kube_client: APIClient,
+        annotation_prefix: &str,
+        namespace: Option<&str>,
+        selectors: &[LabelSelector],
     ) -> Self {
         Self {
-            kube_config,
-            namespace: namespace.into(),
-            label_selector: build_label_selector(match_labels),
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
+            namespace: namespace.map(String::from),
+            selectors: selectors.to_vec(),
+            field_selector: None,
+            page_limit: DEFAULT_LIST_PAGE_LIMIT,
         }
     }
+
+    /// Further narrow the listed ReplicaSets with a field selector, eg. `metadata.name=web`.
+    pub fn with_field_selector(mut self, field_selector: Option<&str>) -> Self {
+        self.field_selector = field_selector.map(String::from);
+        self
+    }
+
+    /// The maximum number of ReplicaSets to retrieve per list request.
+    pub fn with_page_limit(mut self, page_limit: u32) -> Self {
+        self.page_limit = page_limit;
+        self
+    }
 }
 
 #[async_trait]
 impl KubernetesResourceTrait for KubernetesReplicaSetResource {
     async fn list(&self) -> Result<Vec<KubernetesObject>, Error> {
-        let kube_client = APIClient::new(self.kube_config.clone());
-        // Retrieve the list of ReplicaSet objects matching the label selector.
-        let replicasets = Api::v1ReplicaSet(kube_client)
-            .within(&self.namespace)
-            .list(&ListParams {
-                label_selector: Some(self.label_selector.clone()),
-                ..Default::default()
-            })
-            .await
-            .context(Kube {})?;
+        // Retrieve the list of ReplicaSet objects matching the label selector, one page at a time.
+        let api = within_namespace_raw(RawApi::v1ReplicaSet(), &self.namespace);
+        let replicasets: Vec<Object<ReplicaSetSpec, ReplicaSetStatus>> = list_selected_pages(
+            "ReplicaSet",
+            &self.namespace,
+            &self.selectors,
+            &self.field_selector,
+            self.page_limit,
+            |list_params, page| {
+                request_list_page(self.kube_client.clone(), api.clone(), list_params, page)
+            },
+        )
+        .await?;
         let mut objects: Vec<KubernetesObject> = Vec::new();
         for replicaset in replicasets {
             objects.push(KubernetesObject::ReplicaSet(
                 KubernetesReplicaSetObject::new(
-                    self.kube_config.clone(),
-                    &self.namespace,
+                    self.kube_client.clone(),
+                    &self.annotation_prefix,
+                    replicaset.metadata.namespace.as_deref().unwrap_or_default(),
                     &replicaset.metadata,
                     &replicaset.spec,
+                    &replicaset.status,
                 ),
             ))
         }
         Ok(objects)
     }
+
+    async fn watch(
+        &self,
+    ) -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error> {
+        let kube_client = self.kube_client.clone();
+        let mut watches = Vec::new();
+        for selector in &self.selectors {
+            let label_selector = build_label_selector(selector)?;
+            let list_params = ListParams {
+                label_selector: Some(label_selector.clone()),
+                field_selector: self.field_selector.clone(),
+                ..Default::default()
+            };
+            // Watch for changes to ReplicaSet objects matching the label selector.
+            let mut informer = Informer::new(within_namespace(
+                Api::v1ReplicaSet(kube_client.clone()),
+                &self.namespace,
+            ))
+            .labels(&label_selector);
+            if let Some(field_selector) = &self.field_selector {
+                informer = informer.fields(field_selector);
+            }
+            throttle().await;
+            let informer = informer.init().await.context(Kube {})?;
+            let object_client = self.kube_client.clone();
+            let annotation_prefix = self.annotation_prefix.clone();
+            watches.push(watch_objects(
+                informer,
+                within_namespace(Api::v1ReplicaSet(kube_client.clone()), &self.namespace),
+                list_params,
+                move |replicaset| {
+                    Some(KubernetesObject::ReplicaSet(
+                        KubernetesReplicaSetObject::new(
+                            object_client.clone(),
+                            &annotation_prefix,
+                            replicaset.metadata.namespace.as_deref().unwrap_or_default(),
+                            &replicaset.metadata,
+                            &replicaset.spec,
+                            &replicaset.status,
+                        ),
+                    ))
+                },
+            ));
+        }
+        // Objects matching more than one selector may report each change more than once.
+        Ok(merge_watches(watches))
+    }
 }
 
 /// Kubernetes ReplicaSet related functions.
+#[derive(Clone)]
 pub struct KubernetesReplicaSetObject {
-    kube_config: kube::config::Configuration,
+    kube_client: APIClient,
+    annotation_prefix: String,
     namespace: String,
     metadata: ObjectMeta,
     spec: ReplicaSetSpec,
+    status: Option<ReplicaSetStatus>,
 }
 
 impl KubernetesReplicaSetObject {
     pub fn new(
-        kube_config: kube::config::Configuration,
+        kube_client: APIClient,
+        annotation_prefix: &str,
         namespace: &str,
         metadata: &ObjectMeta,
         spec: &ReplicaSetSpec,
+        status: &Option<ReplicaSetStatus>,
     ) -> Self {
         Self {
-            kube_config,
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
             namespace: namespace.into(),
             metadata: metadata.clone(),
             spec: spec.clone(),
+            status: status.clone(),
         }
     }
+
+    /// Context for errors about missing fields in the ReplicaSet's specification.
+    fn spec_context(&self) -> KubeObjectSpec<&'static str, &str, &str> {
+        KubeObjectSpec {
+            kind: self.kind(),
+            namespace: self.namespace.as_str(),
+            name: self.metadata.name.as_str(),
+        }
+    }
+
+    /// The labels of the ReplicaSet's pods, from its pod template.
+    fn pod_labels(&self) -> Result<&BTreeMap<String, String>, Error> {
+        self.spec
+            .template
+            .as_ref()
+            .context(self.spec_context())?
+            .metadata
+            .as_ref()
+            .context(self.spec_context())?
+            .labels
+            .as_ref()
+            .context(self.spec_context())
+    }
 }
 
 #[async_trait]
 impl KubernetesObjectTrait for KubernetesReplicaSetObject {
+    fn kind(&self) -> &'static str {
+        "ReplicaSet"
+    }
+
+    fn readiness_seconds(&self) -> Option<u32> {
+        readiness_seconds(self.spec.template.as_ref(), self.spec.min_ready_seconds)
+    }
+
     fn namespace_and_name(&self) -> (String, String) {
         (self.namespace.clone(), self.metadata.name.clone())
     }
 
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn annotation_prefix(&self) -> &str {
+        &self.annotation_prefix
+    }
+
     async fn last_modified(&self) -> Result<Option<DateTime<Utc>>, Error> {
-        Ok(
-            // Retrieve the last modified timestamp from the ReplicaSet's annotations.
-            if let Some(last_modified_timestamp) = self
-                .metadata
-                .annotations
-                .get(&format!("{}/last_modified", ANNOTATION_BASE))
-            {
-                Some(DateTime::from_utc(
-                    DateTime::<FixedOffset>::parse_from_rfc3339(last_modified_timestamp)
-                        .unwrap()
-                        .naive_utc(),
-                    Utc,
-                ))
-            } else {
-                None
-            },
-        )
+        last_modified(&self.metadata, &self.annotation_prefix)
     }
 
-    async fn replicas(&self) -> Result<u32, Error> {
+    async fn desired_replicas(&self) -> Result<u32, Error> {
         self.spec
             .replicas
-            .context(KubeSpec {})
+            .context(self.spec_context())
             .map(|replicas| replicas as u32)
     }
 
-    async fn pod_ips(&self) -> Result<Vec<String>, Error> {
-        let labels = self
-            .spec
-            .template
-            .as_ref()
-            .context(KubeSpec {})?
-            .metadata
+    async fn current_replicas(&self) -> Result<u32, Error> {
+        Ok(self
+            .status
             .as_ref()
-            .context(KubeSpec {})?
-            .labels
+            .map(|status| status.replicas)
+            .unwrap_or(0) as u32)
+    }
+
+    async fn ready_replicas(&self) -> Result<u32, Error> {
+        // A freshly created ReplicaSet may not have a status yet.
+        Ok(self
+            .status
             .as_ref()
-            .context(KubeSpec {})?;
-        let kube_client = APIClient::new(self.kube_config.clone());
-        get_running_pod_ips(kube_client, &self.namespace, labels).await
-    }
-
-    async fn scale(&self, replicas: u32) -> Result<(), Error> {
-        let utc_now: DateTime<Utc> = Utc::now();
-        let patch = json!({
-            "metadata": {
-                "annotations": {
-                    format!("{}/last_modified", ANNOTATION_BASE): utc_now.to_rfc3339()
-                }
-            },
-            "spec": {
-                "replicas": replicas
+            .and_then(|status| status.ready_replicas)
+            .unwrap_or(0) as u32)
+    }
+
+    async fn pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels()?;
+        get_running_pod_ips(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn pod_utilization(&self) -> Result<Vec<PodMetrics>, Error> {
+        let labels = self.pod_labels()?;
+        get_pod_utilization(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn ready_pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels()?;
+        get_ready_pod_ips(self.kube_client.clone(), &self.namespace, labels).await
+    }
+
+    async fn ready_pod_endpoints(
+        &self,
+        discovery: &EndpointDiscovery,
+        port: &PodPort,
+        ip_family: Option<IpFamily>,
+    ) -> Result<PodEndpoints, Error> {
+        let kube_client = self.kube_client.clone();
+        match discovery {
+            EndpointDiscovery::Pods => {
+                let labels = self.pod_labels()?;
+                get_running_pod_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    labels,
+                    port,
+                    ip_family,
+                    true,
+                )
+                .await
+            }
+            EndpointDiscovery::EndpointSlices(service_name) => {
+                get_endpoint_slice_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    service_name,
+                    port,
+                    ip_family,
+                )
+                .await
             }
-        });
-        // Patch (update) the ReplicaSet object.
-        let patch_params = PatchParams::default();
-        let kube_client = APIClient::new(self.kube_config.clone());
-        Api::v1ReplicaSet(kube_client)
-            .within(&self.namespace)
-            .patch(
-                &self.metadata.name,
-                &patch_params,
-                serde_json::to_vec(&patch).context(JsonSerialization {})?,
-            )
-            .await
-            .context(Kube {})?;
-        Ok(())
+        }
+    }
+
+    async fn disruptions_allowed(&self) -> Result<Option<u32>, Error> {
+        let labels = self.pod_labels()?;
+        get_disruptions_allowed(self.kube_client.clone(), &self.namespace, labels).await
+    }
+
+    async fn scale(
+        &self,
+        replicas: u32,
+        reason: &str,
+        field_manager: &FieldManager,
+    ) -> Result<ScaleOutcome, Error> {
+        // Skip no-op patches, so we don't rewrite last_modified and reset the cooldown.
+        if self.spec.replicas == Some(replicas as i32) {
+            return Ok(ScaleOutcome::default());
+        }
+        let kube_client = self.kube_client.clone();
+        let mut outcome = scale_object(
+            kube_client.clone(),
+            "apps",
+            "v1",
+            "replicasets",
+            "ReplicaSet",
+            &self.namespace,
+            ListedObject {
+                metadata: &self.metadata,
+                replicas: self.spec.replicas,
+            },
+            replicas,
+            reason,
+            &self.annotation_prefix,
+            field_manager,
+        )
+        .await?;
+        // Events are best effort, the object has already been scaled.
+        outcome.event_error = emit_scale_event(
+            kube_client,
+            "apps/v1",
+            "ReplicaSet",
+            &self.namespace,
+            &self.metadata,
+            outcome.previous_replicas,
+            replicas,
+            reason,
+            field_manager.dry_run,
+        )
+        .await
+        .err()
+        .map(|err| err.to_string());
+        Ok(outcome)
     }
 }
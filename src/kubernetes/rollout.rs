@@ -0,0 +1,450 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::common::{
+    build_label_selector, emit_scale_event, get_disruptions_allowed, get_endpoint_slice_endpoints,
+    get_pod_utilization, get_ready_pod_ips, get_running_pod_endpoints, get_running_pod_ips,
+    last_modified, list_selected_pages, merge_watches, request_list_page, scale_object,
+    watch_objects, within_namespace, within_namespace_raw, ListedObject, DEFAULT_LIST_PAGE_LIMIT,
+    DEFAULT_POD_PHASES,
+};
+use crate::kubernetes::rate_limit::throttle;
+use crate::kubernetes::{
+    EndpointDiscovery, FieldManager, KubernetesObject, KubernetesObjectEvent,
+    KubernetesObjectTrait, KubernetesResourceTrait, PodEndpoints, PodMetrics, PodPort,
+    ScaleOutcome,
+};
+use crate::resource::IpFamily;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::channel::mpsc::UnboundedReceiver;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::{Api, Informer, Object, ObjectMeta};
+use kube::api::{ListParams, RawApi};
+use kube::client::APIClient;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+
+/// The api group of Argo Rollouts.
+const ROLLOUT_GROUP: &str = "argoproj.io";
+
+/// The api version of Argo Rollouts.
+const ROLLOUT_VERSION: &str = "v1alpha1";
+
+/// The subset of an Argo Rollout's specification pangolin relies on.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RolloutSpec {
+    /// The desired number of replicas, Argo defaults this to 1 when it is unset.
+    pub replicas: Option<i32>,
+    /// Selector for the Rollout's pods, across every revision.
+    pub selector: LabelSelector,
+}
+
+/// The subset of an Argo Rollout's status pangolin relies on.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RolloutStatus {
+    pub replicas: Option<i32>,
+    pub ready_replicas: Option<i32>,
+}
+
+/// An argoproj.io/v1alpha1 Rollout.
+type Rollout = Object<RolloutSpec, RolloutStatus>;
+
+/// The api for Rollout custom resources.
+fn rollout_api(kube_client: APIClient) -> Api<Rollout> {
+    Api::customResource(kube_client, "rollouts")
+        .group(ROLLOUT_GROUP)
+        .version(ROLLOUT_VERSION)
+}
+
+/// The raw api for Rollout custom resources.
+fn rollout_raw_api() -> RawApi {
+    RawApi::customResource("rollouts")
+        .group(ROLLOUT_GROUP)
+        .version(ROLLOUT_VERSION)
+}
+
+/// The api server doesn't serve Rollouts unless Argo Rollouts' CRD is installed, so explain
+/// missing resource errors rather than surfacing a bare 404.
+fn crd_not_installed(err: Error) -> Error {
+    match err.kube_api_response() {
+        Some(response) if response.code == 404 => Error::MissingCustomResource {
+            kind: "Rollout".into(),
+            group: ROLLOUT_GROUP.into(),
+            version: ROLLOUT_VERSION.into(),
+        },
+        _ => err,
+    }
+}
+
+/// Kubernetes Rollout resource kind related functions.
+pub struct KubernetesRolloutResource {
+    kube_client: APIClient,
+    annotation_prefix: String,
+    namespace: Option<String>,
+    selectors: Vec<LabelSelector>,
+    field_selector: Option<String>,
+    page_limit: u32,
+}
+
+impl KubernetesRolloutResource {
+    pub fn new(
+        kube_client: APIClient,
+        annotation_prefix: &str,
+        namespace: Option<&str>,
+        selectors: &[LabelSelector],
+    ) -> Self {
+        Self {
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
+            namespace: namespace.map(String::from),
+            selectors: selectors.to_vec(),
+            field_selector: None,
+            page_limit: DEFAULT_LIST_PAGE_LIMIT,
+        }
+    }
+
+    /// Further narrow the listed Rollouts with a field selector, eg. `metadata.name=web`.
+    pub fn with_field_selector(mut self, field_selector: Option<&str>) -> Self {
+        self.field_selector = field_selector.map(String::from);
+        self
+    }
+
+    /// The maximum number of Rollouts to retrieve per list request.
+    pub fn with_page_limit(mut self, page_limit: u32) -> Self {
+        self.page_limit = page_limit;
+        self
+    }
+}
+
+#[async_trait]
+impl KubernetesResourceTrait for KubernetesRolloutResource {
+    async fn list(&self) -> Result<Vec<KubernetesObject>, Error> {
+        // Retrieve the list of Rollout objects matching the label selector, one page at a time.
+        let api = within_namespace_raw(rollout_raw_api(), &self.namespace);
+        let rollouts: Vec<Rollout> = list_selected_pages(
+            "Rollout",
+            &self.namespace,
+            &self.selectors,
+            &self.field_selector,
+            self.page_limit,
+            |list_params, page| {
+                request_list_page(self.kube_client.clone(), api.clone(), list_params, page)
+            },
+        )
+        .await
+        .map_err(crd_not_installed)?;
+        let mut objects: Vec<KubernetesObject> = Vec::new();
+        for rollout in rollouts {
+            objects.push(KubernetesObject::Rollout(KubernetesRolloutObject::new(
+                self.kube_client.clone(),
+                &self.annotation_prefix,
+                rollout.metadata.namespace.as_deref().unwrap_or_default(),
+                &rollout.metadata,
+                &rollout.spec,
+                &rollout.status,
+            )))
+        }
+        Ok(objects)
+    }
+
+    async fn watch(
+        &self,
+    ) -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error> {
+        let kube_client = self.kube_client.clone();
+        let mut watches = Vec::new();
+        for selector in &self.selectors {
+            let label_selector = build_label_selector(selector)?;
+            let list_params = ListParams {
+                label_selector: Some(label_selector.clone()),
+                field_selector: self.field_selector.clone(),
+                ..Default::default()
+            };
+            // Watch for changes to Rollout objects matching the label selector.
+            let mut informer = Informer::new(within_namespace(
+                rollout_api(kube_client.clone()),
+                &self.namespace,
+            ))
+            .labels(&label_selector);
+            if let Some(field_selector) = &self.field_selector {
+                informer = informer.fields(field_selector);
+            }
+            throttle().await;
+            let informer = informer
+                .init()
+                .await
+                .context(Kube {})
+                .map_err(crd_not_installed)?;
+            let object_client = self.kube_client.clone();
+            let annotation_prefix = self.annotation_prefix.clone();
+            watches.push(watch_objects(
+                informer,
+                within_namespace(rollout_api(kube_client.clone()), &self.namespace),
+                list_params,
+                move |rollout| {
+                    Some(KubernetesObject::Rollout(KubernetesRolloutObject::new(
+                        object_client.clone(),
+                        &annotation_prefix,
+                        rollout.metadata.namespace.as_deref().unwrap_or_default(),
+                        &rollout.metadata,
+                        &rollout.spec,
+                        &rollout.status,
+                    )))
+                },
+            ));
+        }
+        // Objects matching more than one selector may report each change more than once.
+        Ok(merge_watches(watches))
+    }
+}
+
+/// Kubernetes Rollout related functions.
+#[derive(Clone)]
+pub struct KubernetesRolloutObject {
+    kube_client: APIClient,
+    annotation_prefix: String,
+    namespace: String,
+    metadata: ObjectMeta,
+    spec: RolloutSpec,
+    status: Option<RolloutStatus>,
+}
+
+impl KubernetesRolloutObject {
+    pub fn new(
+        kube_client: APIClient,
+        annotation_prefix: &str,
+        namespace: &str,
+        metadata: &ObjectMeta,
+        spec: &RolloutSpec,
+        status: &Option<RolloutStatus>,
+    ) -> Self {
+        Self {
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
+            namespace: namespace.into(),
+            metadata: metadata.clone(),
+            spec: spec.clone(),
+            status: status.clone(),
+        }
+    }
+
+    /// Context for errors about missing fields in the Rollout's specification.
+    fn spec_context(&self) -> KubeObjectSpec<&'static str, &str, &str> {
+        KubeObjectSpec {
+            kind: self.kind(),
+            namespace: self.namespace.as_str(),
+            name: self.metadata.name.as_str(),
+        }
+    }
+
+    /// The labels of the Rollout's pods, from its selector. Argo adds a pod template hash label
+    /// to each revision's pods, so the template's labels would only match a single revision.
+    fn pod_labels(&self) -> Result<&BTreeMap<String, String>, Error> {
+        self.spec
+            .selector
+            .match_labels
+            .as_ref()
+            .context(self.spec_context())
+    }
+}
+
+#[async_trait]
+impl KubernetesObjectTrait for KubernetesRolloutObject {
+    fn kind(&self) -> &'static str {
+        "Rollout"
+    }
+
+    fn namespace_and_name(&self) -> (String, String) {
+        (self.namespace.clone(), self.metadata.name.clone())
+    }
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn annotation_prefix(&self) -> &str {
+        &self.annotation_prefix
+    }
+
+    async fn last_modified(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        last_modified(&self.metadata, &self.annotation_prefix)
+    }
+
+    async fn desired_replicas(&self) -> Result<u32, Error> {
+        Ok(self.spec.replicas.unwrap_or(1).max(0) as u32)
+    }
+
+    async fn current_replicas(&self) -> Result<u32, Error> {
+        Ok(self
+            .status
+            .as_ref()
+            .and_then(|status| status.replicas)
+            .unwrap_or(0) as u32)
+    }
+
+    async fn ready_replicas(&self) -> Result<u32, Error> {
+        // A freshly created Rollout may not have a status yet.
+        Ok(self
+            .status
+            .as_ref()
+            .and_then(|status| status.ready_replicas)
+            .unwrap_or(0) as u32)
+    }
+
+    async fn pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels()?;
+        get_running_pod_ips(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn pod_utilization(&self) -> Result<Vec<PodMetrics>, Error> {
+        let labels = self.pod_labels()?;
+        get_pod_utilization(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn ready_pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels()?;
+        get_ready_pod_ips(self.kube_client.clone(), &self.namespace, labels).await
+    }
+
+    async fn ready_pod_endpoints(
+        &self,
+        discovery: &EndpointDiscovery,
+        port: &PodPort,
+        ip_family: Option<IpFamily>,
+    ) -> Result<PodEndpoints, Error> {
+        let kube_client = self.kube_client.clone();
+        match discovery {
+            EndpointDiscovery::Pods => {
+                let labels = self.pod_labels()?;
+                get_running_pod_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    labels,
+                    port,
+                    ip_family,
+                    true,
+                )
+                .await
+            }
+            EndpointDiscovery::EndpointSlices(service_name) => {
+                get_endpoint_slice_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    service_name,
+                    port,
+                    ip_family,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn disruptions_allowed(&self) -> Result<Option<u32>, Error> {
+        let labels = self.pod_labels()?;
+        get_disruptions_allowed(self.kube_client.clone(), &self.namespace, labels).await
+    }
+
+    async fn scale(
+        &self,
+        replicas: u32,
+        reason: &str,
+        field_manager: &FieldManager,
+    ) -> Result<ScaleOutcome, Error> {
+        // Skip no-op patches, so we don't rewrite last_modified and reset the cooldown.
+        if self.spec.replicas == Some(replicas as i32) {
+            return Ok(ScaleOutcome::default());
+        }
+        let kube_client = self.kube_client.clone();
+        let mut outcome = scale_object(
+            kube_client.clone(),
+            ROLLOUT_GROUP,
+            ROLLOUT_VERSION,
+            "rollouts",
+            "Rollout",
+            &self.namespace,
+            ListedObject {
+                metadata: &self.metadata,
+                replicas: self.spec.replicas,
+            },
+            replicas,
+            reason,
+            &self.annotation_prefix,
+            field_manager,
+        )
+        .await?;
+        // Events are best effort, the object has already been scaled.
+        outcome.event_error = emit_scale_event(
+            kube_client,
+            &format!("{}/{}", ROLLOUT_GROUP, ROLLOUT_VERSION),
+            "Rollout",
+            &self.namespace,
+            &self.metadata,
+            outcome.previous_replicas,
+            replicas,
+            reason,
+            field_manager.dry_run,
+        )
+        .await
+        .err()
+        .map(|err| err.to_string());
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crd_not_installed() {
+        let api_error = |code: u16| Error::KubeList {
+            kind: "Rollout".into(),
+            namespace: "default".into(),
+            source: kube::Error::Api(kube::ErrorResponse {
+                status: "Failure".into(),
+                message: "the server could not find the requested resource".into(),
+                reason: "NotFound".into(),
+                code,
+            }),
+        };
+        match crd_not_installed(api_error(404)) {
+            Error::MissingCustomResource { kind, group, .. } => {
+                assert_eq!(kind, "Rollout");
+                assert_eq!(group, "argoproj.io");
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+        match crd_not_installed(api_error(403)) {
+            Error::KubeList { .. } => {}
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+}
@@ -15,163 +15,973 @@
  */
 
 use crate::error::*;
-use crate::kubernetes::common::{build_label_selector, get_running_pod_ips};
-use crate::kubernetes::{KubernetesObject, KubernetesObjectTrait, KubernetesResourceTrait};
-use crate::resource::ANNOTATION_BASE;
+use crate::kubernetes::common::{
+    build_label_selector, emit_scale_event, get_disruptions_allowed, get_endpoint_slice_endpoints,
+    get_persistent_volume_claim_names, get_pod_names, get_pod_utilization, get_ready_pod_ips,
+    get_remaining_storage_quota, get_running_pod_endpoints, get_running_pod_ips, last_modified,
+    list_selected_pages, merge_watches, parse_quantity, patch_pod_label, readiness_seconds,
+    request_list_page, scale_object, watch_objects, within_namespace, within_namespace_raw,
+    ListedObject, DEFAULT_LIST_PAGE_LIMIT, DEFAULT_POD_PHASES,
+};
+use crate::kubernetes::rate_limit::throttle;
+use crate::kubernetes::{
+    EndpointDiscovery, FieldManager, KubernetesObject, KubernetesObjectEvent,
+    KubernetesObjectTrait, KubernetesResourceTrait, PodEndpoints, PodManagementPolicy, PodMetrics,
+    PodPort, ScaleOutcome,
+};
+use crate::resource::IpFamily;
 use async_trait::async_trait;
 use chrono::prelude::*;
-use k8s_openapi::api::apps::v1::StatefulSetSpec;
-use kube::api::Api;
-use kube::api::{ListParams, ObjectMeta, PatchParams};
+use futures::channel::mpsc::UnboundedReceiver;
+use k8s_openapi::api::apps::v1::{StatefulSetSpec, StatefulSetStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::{Api, Informer};
+use kube::api::{ListParams, Object, ObjectMeta, RawApi};
 use kube::client::APIClient;
-use serde_json::json;
-use snafu::{OptionExt, ResultExt};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::BTreeMap;
 
 /// Kubernetes StatefulSet resource kind related functions.
 pub struct KubernetesStatefulSetResource {
-    kube_config: kube::config::Configuration,
-    namespace: String,
-    label_selector: String,
+    kube_client: APIClient,
+    annotation_prefix: String,
+    namespace: Option<String>,
+    selectors: Vec<LabelSelector>,
+    field_selector: Option<String>,
+    page_limit: u32,
 }
 
 impl KubernetesStatefulSetResource {
     pub fn new(
-        kube_config: kube::config::Configuration,
-        namespace: &str,
-        match_labels: &BTreeMap<String, String>,
+        kube_client: APIClient,
+        annotation_prefix: &str,
+        namespace: Option<&str>,
+        selectors: &[LabelSelector],
     ) -> Self {
         Self {
-            kube_config,
-            namespace: namespace.into(),
-            label_selector: build_label_selector(match_labels),
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
+            namespace: namespace.map(String::from),
+            selectors: selectors.to_vec(),
+            field_selector: None,
+            page_limit: DEFAULT_LIST_PAGE_LIMIT,
         }
     }
+
+    /// Further narrow the listed StatefulSets with a field selector, eg. `metadata.name=web`.
+    pub fn with_field_selector(mut self, field_selector: Option<&str>) -> Self {
+        self.field_selector = field_selector.map(String::from);
+        self
+    }
+
+    /// The maximum number of StatefulSets to retrieve per list request.
+    pub fn with_page_limit(mut self, page_limit: u32) -> Self {
+        self.page_limit = page_limit;
+        self
+    }
 }
 
 #[async_trait]
 impl KubernetesResourceTrait for KubernetesStatefulSetResource {
     async fn list(&self) -> Result<Vec<KubernetesObject>, Error> {
-        let kube_client = APIClient::new(self.kube_config.clone());
-        // Retrieve the list of StatefulSet objects matching the label selector.
-        let statefulsets = Api::v1StatefulSet(kube_client)
-            .within(&self.namespace)
-            .list(&ListParams {
-                label_selector: Some(self.label_selector.clone()),
-                ..Default::default()
-            })
-            .await
-            .context(Kube {})?;
+        // Retrieve the list of StatefulSet objects matching the label selector, one page at a time.
+        let api = within_namespace_raw(RawApi::v1Statefulset(), &self.namespace);
+        let statefulsets: Vec<Object<StatefulSetSpec, StatefulSetStatus>> = list_selected_pages(
+            "StatefulSet",
+            &self.namespace,
+            &self.selectors,
+            &self.field_selector,
+            self.page_limit,
+            |list_params, page| {
+                request_list_page(self.kube_client.clone(), api.clone(), list_params, page)
+            },
+        )
+        .await?;
         let mut objects: Vec<KubernetesObject> = Vec::new();
         for statefulset in statefulsets {
             objects.push(KubernetesObject::StatefulSet(
                 KubernetesStatefulSetObject::new(
-                    self.kube_config.clone(),
-                    &self.namespace,
+                    self.kube_client.clone(),
+                    &self.annotation_prefix,
+                    statefulset
+                        .metadata
+                        .namespace
+                        .as_deref()
+                        .unwrap_or_default(),
                     &statefulset.metadata,
                     &statefulset.spec,
+                    &statefulset.status,
                 ),
             ))
         }
         Ok(objects)
     }
+
+    async fn watch(
+        &self,
+    ) -> Result<UnboundedReceiver<Result<KubernetesObjectEvent, Error>>, Error> {
+        let kube_client = self.kube_client.clone();
+        let mut watches = Vec::new();
+        for selector in &self.selectors {
+            let label_selector = build_label_selector(selector)?;
+            let list_params = ListParams {
+                label_selector: Some(label_selector.clone()),
+                field_selector: self.field_selector.clone(),
+                ..Default::default()
+            };
+            // Watch for changes to StatefulSet objects matching the label selector.
+            let mut informer = Informer::new(within_namespace(
+                Api::v1StatefulSet(kube_client.clone()),
+                &self.namespace,
+            ))
+            .labels(&label_selector);
+            if let Some(field_selector) = &self.field_selector {
+                informer = informer.fields(field_selector);
+            }
+            throttle().await;
+            let informer = informer.init().await.context(Kube {})?;
+            let object_client = self.kube_client.clone();
+            let annotation_prefix = self.annotation_prefix.clone();
+            watches.push(watch_objects(
+                informer,
+                within_namespace(Api::v1StatefulSet(kube_client.clone()), &self.namespace),
+                list_params,
+                move |statefulset| {
+                    Some(KubernetesObject::StatefulSet(
+                        KubernetesStatefulSetObject::new(
+                            object_client.clone(),
+                            &annotation_prefix,
+                            statefulset
+                                .metadata
+                                .namespace
+                                .as_deref()
+                                .unwrap_or_default(),
+                            &statefulset.metadata,
+                            &statefulset.spec,
+                            &statefulset.status,
+                        ),
+                    ))
+                },
+            ));
+        }
+        // Objects matching more than one selector may report each change more than once.
+        Ok(merge_watches(watches))
+    }
 }
 
 /// Kubernetes StatefulSet related functions.
+#[derive(Clone)]
 pub struct KubernetesStatefulSetObject {
-    kube_config: kube::config::Configuration,
+    kube_client: APIClient,
+    annotation_prefix: String,
     namespace: String,
     metadata: ObjectMeta,
     spec: StatefulSetSpec,
+    status: Option<StatefulSetStatus>,
 }
 
 impl KubernetesStatefulSetObject {
     pub fn new(
-        kube_config: kube::config::Configuration,
+        kube_client: APIClient,
+        annotation_prefix: &str,
         namespace: &str,
         metadata: &ObjectMeta,
         spec: &StatefulSetSpec,
+        status: &Option<StatefulSetStatus>,
     ) -> Self {
         Self {
-            kube_config,
+            kube_client,
+            annotation_prefix: annotation_prefix.into(),
             namespace: namespace.into(),
             metadata: metadata.clone(),
             spec: spec.clone(),
+            status: status.clone(),
         }
     }
+
+    /// The names of the pods that will be terminated when scaling down to the target number of
+    /// replicas, highest ordinal first. Empty when the target isn't a scale down.
+    pub fn pods_to_remove(&self, target: u32) -> Result<Vec<String>, Error> {
+        let current_replicas = self.spec.replicas.context(self.spec_context())?.max(0) as u32;
+        Ok(highest_ordinal_pods(
+            &self.metadata.name,
+            current_replicas,
+            target,
+        ))
+    }
+
+    /// Context for errors about missing fields in the StatefulSet's specification.
+    fn spec_context(&self) -> KubeObjectSpec<&'static str, &str, &str> {
+        KubeObjectSpec {
+            kind: self.kind(),
+            namespace: self.namespace.as_str(),
+            name: self.metadata.name.as_str(),
+        }
+    }
+
+    /// The labels of the StatefulSet's pods, from its pod template.
+    fn pod_labels(&self) -> Result<&BTreeMap<String, String>, Error> {
+        self.spec
+            .template
+            .metadata
+            .as_ref()
+            .context(self.spec_context())?
+            .labels
+            .as_ref()
+            .context(self.spec_context())
+    }
 }
 
 #[async_trait]
 impl KubernetesObjectTrait for KubernetesStatefulSetObject {
+    fn kind(&self) -> &'static str {
+        "StatefulSet"
+    }
+
+    fn removed_pods(&self, replicas: u32) -> Result<Option<Vec<String>>, Error> {
+        Ok(Some(self.pods_to_remove(replicas)?))
+    }
+
+    fn is_rolling_out(&self) -> bool {
+        rolling_out(&self.spec, &self.status)
+    }
+
+    fn update_partition(&self) -> Option<u32> {
+        in_flight_update_partition(&self.spec, &self.status)
+    }
+
+    fn pod_management_policy(&self) -> PodManagementPolicy {
+        // OrderedReady is the kubernetes default.
+        match self.spec.pod_management_policy.as_deref() {
+            Some("Parallel") => PodManagementPolicy::Parallel,
+            _ => PodManagementPolicy::OrderedReady,
+        }
+    }
+
+    fn readiness_seconds(&self) -> Option<u32> {
+        // StatefulSets have no minimum ready time at this api level.
+        readiness_seconds(Some(&self.spec.template), None)
+    }
+
     fn namespace_and_name(&self) -> (String, String) {
         (self.namespace.clone(), self.metadata.name.clone())
     }
 
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn annotation_prefix(&self) -> &str {
+        &self.annotation_prefix
+    }
+
     async fn last_modified(&self) -> Result<Option<DateTime<Utc>>, Error> {
-        Ok(
-            // Retrieve the last modified timestamp from the StatefulSet's annotations.
-            if let Some(last_modified_timestamp) = self
-                .metadata
-                .annotations
-                .get(&format!("{}/last_modified", ANNOTATION_BASE))
-            {
-                Some(DateTime::from_utc(
-                    DateTime::<FixedOffset>::parse_from_rfc3339(last_modified_timestamp)
-                        .unwrap()
-                        .naive_utc(),
-                    Utc,
-                ))
-            } else {
-                None
-            },
-        )
+        last_modified(&self.metadata, &self.annotation_prefix)
     }
 
-    async fn replicas(&self) -> Result<u32, Error> {
+    async fn desired_replicas(&self) -> Result<u32, Error> {
         self.spec
             .replicas
-            .context(KubeSpec {})
+            .context(self.spec_context())
             .map(|replicas| replicas as u32)
     }
 
-    async fn pod_ips(&self) -> Result<Vec<String>, Error> {
-        let labels = self
-            .spec
-            .template
-            .metadata
+    async fn current_replicas(&self) -> Result<u32, Error> {
+        Ok(self
+            .status
             .as_ref()
-            .context(KubeSpec {})?
-            .labels
+            .map(|status| status.replicas)
+            .unwrap_or(0) as u32)
+    }
+
+    async fn ready_replicas(&self) -> Result<u32, Error> {
+        // A freshly created StatefulSet may not have a status yet.
+        Ok(self
+            .status
             .as_ref()
-            .context(KubeSpec {})?;
-        let kube_client = APIClient::new(self.kube_config.clone());
-        get_running_pod_ips(kube_client, &self.namespace, labels).await
+            .and_then(|status| status.ready_replicas)
+            .unwrap_or(0) as u32)
+    }
+
+    async fn pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels()?;
+        get_running_pod_ips(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn pod_utilization(&self) -> Result<Vec<PodMetrics>, Error> {
+        let labels = self.pod_labels()?;
+        get_pod_utilization(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await
+    }
+
+    async fn pod_dns_names(&self) -> Result<Vec<String>, Error> {
+        // Without a governing service the pods have no stable names.
+        ensure!(!self.spec.service_name.is_empty(), self.spec_context());
+        let labels = self.pod_labels()?;
+        let pod_names = get_pod_names(
+            self.kube_client.clone(),
+            &self.namespace,
+            labels,
+            DEFAULT_POD_PHASES,
+        )
+        .await?;
+        Ok(stable_pod_dns_names(
+            &self.metadata.name,
+            &self.spec.service_name,
+            &self.namespace,
+            &pod_names,
+        ))
+    }
+
+    async fn ready_pod_ips(&self) -> Result<Vec<String>, Error> {
+        let labels = self.pod_labels()?;
+        get_ready_pod_ips(self.kube_client.clone(), &self.namespace, labels).await
+    }
+
+    async fn ready_pod_endpoints(
+        &self,
+        discovery: &EndpointDiscovery,
+        port: &PodPort,
+        ip_family: Option<IpFamily>,
+    ) -> Result<PodEndpoints, Error> {
+        let kube_client = self.kube_client.clone();
+        match discovery {
+            EndpointDiscovery::Pods => {
+                let labels = self.pod_labels()?;
+                get_running_pod_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    labels,
+                    port,
+                    ip_family,
+                    true,
+                )
+                .await
+            }
+            EndpointDiscovery::EndpointSlices(service_name) => {
+                get_endpoint_slice_endpoints(
+                    kube_client,
+                    &self.namespace,
+                    service_name,
+                    port,
+                    ip_family,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn set_pods_serving(
+        &self,
+        pod_names: &[String],
+        label: &str,
+        serving: bool,
+    ) -> Result<(), Error> {
+        // Pods that no longer match the StatefulSet's selector would be orphaned and replaced.
+        let selector = &self.spec.selector;
+        let selected = selector
+            .match_labels
+            .iter()
+            .flatten()
+            .any(|(key, _)| key == label)
+            || selector
+                .match_expressions
+                .iter()
+                .flatten()
+                .any(|requirement| requirement.key == label);
+        let value = self.pod_labels()?.get(label);
+        ensure!(
+            !selected && value.is_some(),
+            InvalidSpec {
+                field: "connections.drain.serviceLabel",
+                reason: format!(
+                    "{} must be a pod template label outside of the StatefulSet's selector",
+                    label
+                ),
+            }
+        );
+        let value = if serving {
+            value.map(String::as_str)
+        } else {
+            None
+        };
+        for pod_name in pod_names {
+            patch_pod_label(
+                self.kube_client.clone(),
+                &self.namespace,
+                pod_name,
+                label,
+                value,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn disruptions_allowed(&self) -> Result<Option<u32>, Error> {
+        let labels = self.pod_labels()?;
+        get_disruptions_allowed(self.kube_client.clone(), &self.namespace, labels).await
     }
 
-    async fn scale(&self, replicas: u32) -> Result<(), Error> {
-        let utc_now: DateTime<Utc> = Utc::now();
-        let patch = json!({
+    async fn max_replicas_within_storage_quota(&self, replicas: u32) -> Result<Option<u32>, Error> {
+        // Each new pod gets a PersistentVolumeClaim per template, named `<template>-<name>-<ordinal>`.
+        let claim_templates: Vec<(String, f64)> = self
+            .spec
+            .volume_claim_templates
+            .iter()
+            .flatten()
+            .map(|claim_template| {
+                let template_name = claim_template
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.name.clone())
+                    .unwrap_or_default();
+                let storage = claim_template
+                    .spec
+                    .as_ref()
+                    .and_then(|spec| spec.resources.as_ref())
+                    .and_then(|resources| resources.requests.as_ref())
+                    .and_then(|requests| requests.get("storage"))
+                    .and_then(|storage| parse_quantity(&storage.0))
+                    .unwrap_or(0.0);
+                (template_name, storage)
+            })
+            .collect();
+        let current_replicas = self.spec.replicas.unwrap_or(0) as u32;
+        if claim_templates.is_empty() || replicas <= current_replicas {
+            return Ok(None);
+        }
+        let remaining_storage =
+            match get_remaining_storage_quota(self.kube_client.clone(), &self.namespace).await? {
+                Some(remaining_storage) => remaining_storage,
+                None => return Ok(None),
+            };
+
+        // Claims left behind by an earlier scale down are reused, rather than provisioned again.
+        let claim_names =
+            get_persistent_volume_claim_names(self.kube_client.clone(), &self.namespace).await?;
+        Ok(Some(replicas_within_storage_quota(
+            current_replicas,
+            replicas,
+            remaining_storage,
+            |ordinal| {
+                claim_templates
+                    .iter()
+                    .filter(|(template_name, _)| {
+                        !claim_names.contains(&format!(
+                            "{}-{}-{}",
+                            template_name, self.metadata.name, ordinal
+                        ))
+                    })
+                    .map(|(_, storage)| storage)
+                    .sum()
+            },
+        )))
+    }
+
+    async fn scale(
+        &self,
+        replicas: u32,
+        reason: &str,
+        field_manager: &FieldManager,
+    ) -> Result<ScaleOutcome, Error> {
+        // Skip no-op patches, so we don't rewrite last_modified and reset the cooldown.
+        if self.spec.replicas == Some(replicas as i32) {
+            return Ok(ScaleOutcome::default());
+        }
+        let kube_client = self.kube_client.clone();
+        let mut outcome = scale_object(
+            kube_client.clone(),
+            "apps",
+            "v1",
+            "statefulsets",
+            "StatefulSet",
+            &self.namespace,
+            ListedObject {
+                metadata: &self.metadata,
+                replicas: self.spec.replicas,
+            },
+            replicas,
+            reason,
+            &self.annotation_prefix,
+            field_manager,
+        )
+        .await?;
+        // Events are best effort, the object has already been scaled.
+        outcome.event_error = emit_scale_event(
+            kube_client,
+            "apps/v1",
+            "StatefulSet",
+            &self.namespace,
+            &self.metadata,
+            outcome.previous_replicas,
+            replicas,
+            reason,
+            field_manager.dry_run,
+        )
+        .await
+        .err()
+        .map(|err| err.to_string());
+        Ok(outcome)
+    }
+}
+
+/// Is the StatefulSet part way through a rolling update? Pods at or above a partition are the only
+/// ones updated, so the revisions of a partitioned StatefulSet differ until the partition is
+/// lowered. With the OnDelete strategy pods are only updated when they are deleted, so an update
+/// is never in progress as far as scaling is concerned.
+fn rolling_out(spec: &StatefulSetSpec, status: &Option<StatefulSetStatus>) -> bool {
+    let update_strategy = spec.update_strategy.as_ref();
+    if update_strategy.and_then(|strategy| strategy.type_.as_deref()) == Some("OnDelete") {
+        return false;
+    }
+    let status = match status {
+        Some(status) => status,
+        None => return false,
+    };
+    let partition = update_partition(spec).unwrap_or(0) as i32;
+    let replicas = spec.replicas.unwrap_or(status.replicas).max(0);
+    let revisions_differ =
+        status.update_revision.is_some() && status.current_revision != status.update_revision;
+    (partition == 0 && revisions_differ)
+        || status.updated_replicas.unwrap_or(0) < (replicas - partition).max(0)
+}
+
+/// The partition of a StatefulSet's rolling updates, None unless it is greater than zero.
+fn update_partition(spec: &StatefulSetSpec) -> Option<u32> {
+    let update_strategy = spec.update_strategy.as_ref()?;
+    if update_strategy.type_.as_deref() == Some("OnDelete") {
+        return None;
+    }
+    update_strategy
+        .rolling_update
+        .as_ref()
+        .and_then(|rolling_update| rolling_update.partition)
+        .filter(|partition| *partition > 0)
+        .map(|partition| partition as u32)
+}
+
+/// The partition of a StatefulSet's rolling update that is still in flight, ie. the pods at or
+/// above the partition run a different revision to those below it.
+fn in_flight_update_partition(
+    spec: &StatefulSetSpec,
+    status: &Option<StatefulSetStatus>,
+) -> Option<u32> {
+    let status = status.as_ref()?;
+    if status.update_revision.is_none() || status.current_revision == status.update_revision {
+        return None;
+    }
+    update_partition(spec)
+}
+
+/// The DNS names, `<pod>.<service>.<namespace>.svc`, of the StatefulSet's pods that exist, in
+/// ordinal order. Pods matching its labels that aren't named `<name>-<ordinal>` belong to
+/// something else.
+fn stable_pod_dns_names(
+    name: &str,
+    service_name: &str,
+    namespace: &str,
+    pod_names: &[String],
+) -> Vec<String> {
+    let prefix = format!("{}-", name);
+    let mut ordinals: Vec<u32> = pod_names
+        .iter()
+        .filter(|pod_name| pod_name.starts_with(&prefix))
+        .map(|pod_name| &pod_name[prefix.len()..])
+        .filter(|ordinal| ordinal.chars().all(|c| c.is_ascii_digit()))
+        .filter_map(|ordinal| ordinal.parse().ok())
+        .collect();
+    ordinals.sort_unstable();
+    ordinals
+        .into_iter()
+        .map(|ordinal| format!("{}{}.{}.{}.svc", prefix, ordinal, service_name, namespace))
+        .collect()
+}
+
+/// StatefulSets always remove their highest ordinal pods, named `<name>-<ordinal>`, first.
+fn highest_ordinal_pods(name: &str, current_replicas: u32, target: u32) -> Vec<String> {
+    (target..current_replicas)
+        .rev()
+        .map(|ordinal| format!("{}-{}", name, ordinal))
+        .collect()
+}
+
+/// The largest number of replicas, between the current and desired numbers, whose new claims fit
+/// in the remaining storage. `claim_storage` is the storage the claims of the pod with an ordinal
+/// will request (bytes), zero if they already exist.
+fn replicas_within_storage_quota(
+    current_replicas: u32,
+    desired_replicas: u32,
+    remaining_storage: f64,
+    claim_storage: impl Fn(u32) -> f64,
+) -> u32 {
+    let mut requested_storage = 0.0;
+    for ordinal in current_replicas..desired_replicas {
+        requested_storage += claim_storage(ordinal);
+        if requested_storage > remaining_storage {
+            return ordinal;
+        }
+    }
+    desired_replicas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::stub::{status_response, StubApiServer};
+    use crate::kubernetes::ScalePatchType;
+    use crate::resource::ANNOTATION_BASE;
+    use k8s_openapi::api::apps::v1::{RollingUpdateStatefulSetStrategy, StatefulSetUpdateStrategy};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_scale_server_dry_run() {
+        let server = spawn_statefulset_server(3, 0);
+        let objects = KubernetesStatefulSetResource::new(
+            server.kube_client.clone(),
+            ANNOTATION_BASE,
+            Some("default"),
+            &[LabelSelector::default()],
+        )
+        .list()
+        .await
+        .unwrap();
+        for patch_type in &[ScalePatchType::Apply, ScalePatchType::Merge] {
+            let field_manager = FieldManager {
+                name: "pangolin".into(),
+                force: false,
+                patch_type: *patch_type,
+                dry_run: true,
+            };
+            objects[0]
+                .scale(5, "connections", &field_manager)
+                .await
+                .unwrap();
+        }
+
+        // Every patch, and the event, is sent as a server-side dry run.
+        let requests = server.requests();
+        let writes: Vec<_> = requests
+            .iter()
+            .filter(|request| request.method != "GET")
+            .collect();
+        // Two patches when applied, one merge patch, and an event for each.
+        assert_eq!(writes.len(), 5);
+        for request in writes {
+            assert!(
+                request.query.contains("dryRun=All"),
+                "{} {}?{}",
+                request.method,
+                request.path,
+                request.query
+            );
+        }
+    }
+
+    /// A stub api server holding a single StatefulSet, which patches are applied to. The first
+    /// patches of only its annotations conflict with a concurrent modification.
+    fn spawn_statefulset_server(replicas: u32, annotation_conflicts: u32) -> StubApiServer {
+        let statefulset = Arc::new(Mutex::new(json!({
+            "apiVersion": "apps/v1",
+            "kind": "StatefulSet",
             "metadata": {
-                "annotations": {
-                    format!("{}/last_modified", ANNOTATION_BASE): utc_now.to_rfc3339()
-                }
+                "name": "web",
+                "namespace": "default",
+                "resourceVersion": "1",
+                "annotations": {}
             },
             "spec": {
-                "replicas": replicas
+                "replicas": replicas,
+                "serviceName": "web",
+                "selector": { "matchLabels": { "app": "web" } },
+                "template": { "metadata": { "labels": { "app": "web" } } }
             }
-        });
-        // Patch (update) the StatefulSet object.
-        let patch_params = PatchParams::default();
-        let kube_client = APIClient::new(self.kube_config.clone());
-        Api::v1StatefulSet(kube_client)
-            .within(&self.namespace)
-            .patch(
-                &self.metadata.name,
-                &patch_params,
-                serde_json::to_vec(&patch).context(JsonSerialization {})?,
-            )
+        })));
+        let annotation_conflicts = Arc::new(Mutex::new(annotation_conflicts));
+        StubApiServer::spawn(move |request| {
+            let mut statefulset = statefulset.lock().unwrap();
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/apis/apps/v1/namespaces/default/statefulsets") => (
+                    200,
+                    json!({ "metadata": {}, "items": [statefulset.clone()] }),
+                ),
+                ("GET", "/apis/apps/v1/namespaces/default/statefulsets/web") => {
+                    (200, statefulset.clone())
+                }
+                // Dry runs aren't persisted.
+                ("PATCH", "/apis/apps/v1/namespaces/default/statefulsets/web")
+                    if request.query.contains("dryRun=All") =>
+                {
+                    (200, statefulset.clone())
+                }
+                ("PATCH", "/apis/apps/v1/namespaces/default/statefulsets/web")
+                    if request.body.get("spec").is_none()
+                        && *annotation_conflicts.lock().unwrap() > 0 =>
+                {
+                    *annotation_conflicts.lock().unwrap() -= 1;
+                    status_response(409, "Conflict", "the object has been modified")
+                }
+                ("PATCH", "/apis/apps/v1/namespaces/default/statefulsets/web") => {
+                    if let Some(replicas) = request.body.pointer("/spec/replicas") {
+                        statefulset["spec"]["replicas"] = replicas.clone();
+                    }
+                    if let Some(Value::Object(annotations)) =
+                        request.body.pointer("/metadata/annotations")
+                    {
+                        for (key, value) in annotations {
+                            statefulset["metadata"]["annotations"][key] = value.clone();
+                        }
+                    }
+                    (200, statefulset.clone())
+                }
+                ("POST", "/apis/events.k8s.io/v1beta1/namespaces/default/events") => {
+                    (201, request.body.clone())
+                }
+                _ => status_response(404, "NotFound", "not found"),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_list_field_selector() {
+        let server = spawn_statefulset_server(3, 0);
+        let objects = KubernetesStatefulSetResource::new(
+            server.kube_client.clone(),
+            ANNOTATION_BASE,
+            Some("default"),
+            &[LabelSelector::default()],
+        )
+        .with_field_selector(Some("metadata.name=web"))
+        .list()
+        .await
+        .unwrap();
+        assert_eq!(objects.len(), 1);
+
+        // The field selector narrows the list on the api server.
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(
+            requests[0]
+                .query
+                .contains("fieldSelector=metadata.name%3Dweb"),
+            "{}",
+            requests[0].query
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scale_skips_no_op_patches() {
+        let server = spawn_statefulset_server(3, 0);
+        let resource = KubernetesStatefulSetResource::new(
+            server.kube_client.clone(),
+            ANNOTATION_BASE,
+            Some("default"),
+            &[LabelSelector {
+                match_labels: Some(
+                    vec![(String::from("app"), String::from("web"))]
+                        .into_iter()
+                        .collect(),
+                ),
+                ..Default::default()
+            }],
+        );
+        let field_manager = FieldManager {
+            name: "pangolin".into(),
+            force: false,
+            patch_type: ScalePatchType::Merge,
+            dry_run: false,
+        };
+
+        // Scaling twice to the same replicas, as two reconciliations would, only patches once.
+        for _ in 0..2 {
+            let objects = resource.list().await.unwrap();
+            objects[0]
+                .scale(5, "connections", &field_manager)
+                .await
+                .unwrap();
+        }
+        let patches = server.patches();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].body["spec"]["replicas"], json!(5));
+        // The event records the replicas the object was scaled from.
+        let events: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "POST")
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].body["note"],
+            json!("Scaled up from 3 to 5 replicas: connections")
+        );
+        let objects = resource.list().await.unwrap();
+        assert_eq!(objects[0].desired_replicas().await.unwrap(), 5);
+        assert!(objects[0].last_modified().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_scale_retry_records_history() {
+        // The replicas are applied, but the annotations conflict with a concurrent modification.
+        let server = spawn_statefulset_server(3, 1);
+        let resource = KubernetesStatefulSetResource::new(
+            server.kube_client.clone(),
+            ANNOTATION_BASE,
+            Some("default"),
+            &[LabelSelector::default()],
+        );
+        let field_manager = FieldManager {
+            name: "pangolin".into(),
+            force: false,
+            patch_type: ScalePatchType::Apply,
+            dry_run: false,
+        };
+        let objects = resource.list().await.unwrap();
+        objects[0]
+            .scale(5, "connections", &field_manager)
             .await
-            .context(Kube {})?;
-        Ok(())
+            .unwrap();
+
+        // The first attempt starts from the listed object, only the retry fetches it.
+        let gets = server
+            .requests()
+            .into_iter()
+            .filter(|request| {
+                request.method == "GET"
+                    && request.path == "/apis/apps/v1/namespaces/default/statefulsets/web"
+            })
+            .count();
+        assert_eq!(gets, 1);
+        // The retry doesn't patch the replicas again, but still records the scaling operation.
+        let patches = server.patches();
+        assert_eq!(patches.len(), 3);
+        assert_eq!(
+            patches
+                .iter()
+                .filter(|patch| patch.body.get("spec").is_some())
+                .count(),
+            1
+        );
+        let history: Vec<Value> = serde_json::from_str(
+            patches[2].body["metadata"]["annotations"]
+                [&format!("{}/scale-history", ANNOTATION_BASE)]
+                .as_str()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0]["from"], json!(3));
+        assert_eq!(history[0]["to"], json!(5));
+        let events: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "POST")
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].body["note"],
+            json!("Scaled up from 3 to 5 replicas: connections")
+        );
+    }
+
+    #[test]
+    fn test_replicas_within_storage_quota() {
+        // Room for two more 10GB claims.
+        assert_eq!(replicas_within_storage_quota(3, 8, 25e9, |_| 10e9), 5);
+        assert_eq!(replicas_within_storage_quota(3, 4, 25e9, |_| 10e9), 4);
+        assert_eq!(replicas_within_storage_quota(3, 8, 5e9, |_| 10e9), 3);
+        // Existing claims don't need any more storage.
+        assert_eq!(
+            replicas_within_storage_quota(3, 8, 25e9, |ordinal| if ordinal < 5 {
+                0.0
+            } else {
+                10e9
+            }),
+            7
+        );
+    }
+
+    #[test]
+    fn test_rolling_out() {
+        let spec = |partition: Option<i32>| StatefulSetSpec {
+            replicas: Some(3),
+            update_strategy: Some(StatefulSetUpdateStrategy {
+                type_: Some("RollingUpdate".into()),
+                rolling_update: Some(RollingUpdateStatefulSetStrategy { partition }),
+            }),
+            ..Default::default()
+        };
+        let status = |updated_replicas: i32, update_revision: &str| {
+            Some(StatefulSetStatus {
+                replicas: 3,
+                updated_replicas: Some(updated_replicas),
+                current_revision: Some("web-1".into()),
+                update_revision: Some(update_revision.into()),
+                ..Default::default()
+            })
+        };
+        assert!(!rolling_out(&spec(None), &status(3, "web-1")));
+        assert!(rolling_out(&spec(None), &status(1, "web-2")));
+        // The update has reached every pod, but the current revision hasn't caught up yet.
+        assert!(rolling_out(&spec(None), &status(3, "web-2")));
+        // Only the pods at or above the partition are updated.
+        assert!(!rolling_out(&spec(Some(2)), &status(1, "web-2")));
+        assert!(rolling_out(&spec(Some(1)), &status(1, "web-2")));
+        assert!(!rolling_out(&spec(None), &None));
+        assert_eq!(update_partition(&spec(Some(2))), Some(2));
+        assert_eq!(update_partition(&spec(Some(0))), None);
+        // Once every pod runs the update's revision, the partition no longer guards a canary.
+        assert_eq!(
+            in_flight_update_partition(&spec(Some(2)), &status(1, "web-2")),
+            Some(2)
+        );
+        assert_eq!(
+            in_flight_update_partition(&spec(Some(2)), &status(3, "web-1")),
+            None
+        );
+        assert_eq!(in_flight_update_partition(&spec(Some(2)), &None), None);
+
+        let on_delete = StatefulSetSpec {
+            update_strategy: Some(StatefulSetUpdateStrategy {
+                type_: Some("OnDelete".into()),
+                rolling_update: None,
+            }),
+            ..spec(None)
+        };
+        assert!(!rolling_out(&on_delete, &status(1, "web-2")));
+        assert_eq!(update_partition(&on_delete), None);
+    }
+
+    #[test]
+    fn test_stable_pod_dns_names() {
+        let pod_names: Vec<String> = vec!["web-1", "web-0", "web-canary", "webapp-0"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            stable_pod_dns_names("web", "nginx", "default", &pod_names),
+            vec!["web-0.nginx.default.svc", "web-1.nginx.default.svc"]
+        );
+        // Only pods that currently exist are included.
+        assert!(stable_pod_dns_names("web", "nginx", "default", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_highest_ordinal_pods() {
+        assert_eq!(highest_ordinal_pods("web", 5, 3), vec!["web-4", "web-3"]);
+        assert_eq!(highest_ordinal_pods("web", 1, 0), vec!["web-0"]);
+        assert!(highest_ordinal_pods("web", 3, 3).is_empty());
+        assert!(highest_ordinal_pods("web", 3, 4).is_empty());
     }
 }
@@ -0,0 +1,153 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use futures::channel::oneshot;
+use futures::future;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use kube::client::APIClient;
+use kube::config::Configuration;
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/// A request received by the stub api server.
+#[derive(Clone, Debug)]
+pub struct StubRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub body: Value,
+}
+
+/// An in-process kubernetes api server for tests, answering every request from a handler and
+/// recording them. It shuts down when dropped.
+pub struct StubApiServer {
+    pub kube_client: APIClient,
+    /// The port the server listens on, eg. to serve pod metrics as well.
+    pub port: u16,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+    shutdown_sender: Option<oneshot::Sender<()>>,
+}
+
+impl StubApiServer {
+    /// Serve requests on an ephemeral port, the handler returns the status code and json body of
+    /// each response. String bodies are sent as plain text, eg. Prometheus metrics.
+    pub fn spawn<F>(handler: F) -> Self
+    where
+        F: Fn(&StubRequest) -> (u16, Value) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let base_path = format!("http://127.0.0.1:{}", port);
+        let requests: Arc<Mutex<Vec<StubRequest>>> = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            Server::from_tcp(listener)
+                .unwrap()
+                .serve(make_service_fn(move |_| {
+                    let requests = server_requests.clone();
+                    let handler = handler.clone();
+                    let service = service_fn(move |request| {
+                        serve(request, requests.clone(), handler.clone())
+                    });
+                    future::ok::<_, hyper::Error>(service)
+                }))
+                .with_graceful_shutdown(async {
+                    shutdown_receiver.await.ok();
+                })
+                .await
+                .unwrap();
+        });
+
+        Self {
+            kube_client: APIClient::new(Configuration::new(base_path, reqwest::Client::new())),
+            port,
+            requests,
+            shutdown_sender: Some(shutdown_sender),
+        }
+    }
+
+    /// Every request received so far, in the order they arrived.
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The patch requests received so far.
+    pub fn patches(&self) -> Vec<StubRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.method == "PATCH")
+            .collect()
+    }
+}
+
+impl Drop for StubApiServer {
+    fn drop(&mut self) {
+        if let Some(shutdown_sender) = self.shutdown_sender.take() {
+            shutdown_sender.send(()).ok();
+        }
+    }
+}
+
+async fn serve<F>(
+    request: Request<Body>,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+    handler: Arc<F>,
+) -> Result<Response<Body>, hyper::Error>
+where
+    F: Fn(&StubRequest) -> (u16, Value),
+{
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+    let request = StubRequest {
+        method,
+        path,
+        query,
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    };
+    let (status, body) = handler(&request);
+    requests.lock().unwrap().push(request);
+    let (content_type, body) = match body {
+        Value::String(text) => ("text/plain", text),
+        body => ("application/json", body.to_string()),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .body(Body::from(body))
+        .unwrap())
+}
+
+/// A kubernetes api error response body.
+pub fn status_response(code: u16, reason: &str, message: &str) -> (u16, Value) {
+    (
+        code,
+        json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "message": message,
+            "reason": reason,
+            "code": code
+        }),
+    )
+}
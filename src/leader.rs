@@ -0,0 +1,261 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::client::RefreshingConfig;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use k8s_openapi::api::coordination::v1::LeaseSpec;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use kube::api::{Api, Object, PostParams};
+use serde_json::{json, Value};
+use slog::{info, warn, Logger};
+use snafu::ResultExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+
+type Lease = Object<LeaseSpec, Value>;
+
+/// Lease based leader election, so that only a single replica performs scaling operations.
+pub struct LeaderElector {
+    kube_config: Arc<RefreshingConfig>,
+    namespace: String,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+    /// Set once the lease has been released, after which it is never renewed.
+    released: AtomicBool,
+    /// Serializes updates to the lease, so a renewal can't race a release.
+    lease_lock: Mutex<()>,
+}
+
+impl LeaderElector {
+    pub fn new(
+        kube_config: Arc<RefreshingConfig>,
+        namespace: &str,
+        name: &str,
+        identity: &str,
+        lease_duration: Duration,
+    ) -> Self {
+        Self {
+            kube_config,
+            namespace: namespace.into(),
+            name: name.into(),
+            identity: identity.into(),
+            lease_duration,
+            released: AtomicBool::new(false),
+            lease_lock: Mutex::new(()),
+        }
+    }
+
+    /// Continuously attempt to acquire, or renew, the lease. The supplied flag is kept up to date with
+    /// whether we are currently the leader. The lease is renewed three times per lease duration so a
+    /// standby will take over within a lease duration of the leader going away.
+    /// Stops once the lease has been released.
+    pub async fn run(&self, logger: Logger, is_leader: Arc<AtomicBool>) {
+        loop {
+            let lease_guard = self.lease_lock.lock().await;
+            if self.released.load(Ordering::SeqCst) {
+                is_leader.store(false, Ordering::SeqCst);
+                break;
+            }
+            let was_leader = is_leader.load(Ordering::SeqCst);
+            let leader = match self.try_acquire_or_renew().await {
+                Ok(leader) => leader,
+                Err(err) => {
+                    // Step down, if we can't renew the lease another replica may take over at any moment.
+                    warn!(logger, "Failed to acquire or renew leader lease";
+                        "error" => format!("{}", err));
+                    false
+                }
+            };
+            is_leader.store(leader, Ordering::SeqCst);
+            drop(lease_guard);
+
+            if leader && !was_leader {
+                info!(logger, "Acquired leader lease";
+                    "lease_namespace" => &self.namespace,
+                    "lease_name" => &self.name,
+                    "identity" => &self.identity);
+            } else if !leader && was_leader {
+                warn!(logger, "Lost leader lease";
+                    "lease_namespace" => &self.namespace,
+                    "lease_name" => &self.name,
+                    "identity" => &self.identity);
+            }
+
+            delay_for(self.lease_duration / 3).await;
+        }
+    }
+
+    /// Give up the lease, if we hold it, so that a standby can take over straight away rather than
+    /// waiting for the lease to expire.
+    pub async fn release(&self) -> Result<(), Error> {
+        let _lease_guard = self.lease_lock.lock().await;
+        self.released.store(true, Ordering::SeqCst);
+
+        let kube_client = self.kube_config.client().await;
+        let api: Api<Lease> = Api::customResource(kube_client, "leases")
+            .group("coordination.k8s.io")
+            .version("v1")
+            .within(&self.namespace);
+        let mut lease = api.get(&self.name).await.context(Kube {})?;
+        if lease.spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+
+        // An unheld lease can be taken over immediately.
+        lease.spec.holder_identity = None;
+        lease.spec.lease_duration_seconds = Some(1);
+        api.replace(
+            &self.name,
+            &PostParams::default(),
+            serde_json::to_vec(&lease).context(JsonSerialization {})?,
+        )
+        .await
+        .context(Kube {})?;
+        Ok(())
+    }
+
+    /// Returns true if we hold the lease after the attempt.
+    async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let kube_client = self.kube_config.client().await;
+        let api: Api<Lease> = Api::customResource(kube_client, "leases")
+            .group("coordination.k8s.io")
+            .version("v1")
+            .within(&self.namespace);
+        let now = Utc::now();
+
+        let mut lease = match api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(ref response)) if response.code == 404 => {
+                // Nobody has ever held the lease, create it.
+                let lease = json!({
+                    "apiVersion": "coordination.k8s.io/v1",
+                    "kind": "Lease",
+                    "metadata": {
+                        "name": self.name,
+                        "namespace": self.namespace,
+                    },
+                    "spec": self.lease_spec(now, now, 0),
+                });
+                return match api
+                    .create(
+                        &PostParams::default(),
+                        serde_json::to_vec(&lease).context(JsonSerialization {})?,
+                    )
+                    .await
+                {
+                    Ok(_) => Ok(true),
+                    // Another replica beat us to it.
+                    Err(kube::Error::Api(ref response)) if response.code == 409 => Ok(false),
+                    Err(err) => Err(err).context(Kube {}),
+                };
+            }
+            Err(err) => return Err(err).context(Kube {}),
+        };
+
+        let holder = lease.spec.holder_identity.clone();
+        if holder.as_deref() == Some(self.identity.as_str()) {
+            // We already hold the lease, renew it.
+            let acquired_at = lease
+                .spec
+                .acquire_time
+                .as_ref()
+                .map(|acquire_time| acquire_time.0)
+                .unwrap_or(now);
+            let transitions = lease.spec.lease_transitions.unwrap_or(0);
+            lease.spec = serde_json::from_value(self.lease_spec(acquired_at, now, transitions))
+                .context(JsonSerialization {})?;
+        } else if holder.is_none() || !lease_is_held(&lease.spec, now) {
+            // The lease has expired, take it over.
+            let transitions = lease.spec.lease_transitions.unwrap_or(0) + 1;
+            lease.spec = serde_json::from_value(self.lease_spec(now, now, transitions))
+                .context(JsonSerialization {})?;
+        } else {
+            return Ok(false);
+        }
+
+        // The resource version is carried over, so a concurrent takeover results in a conflict.
+        match api
+            .replace(
+                &self.name,
+                &PostParams::default(),
+                serde_json::to_vec(&lease).context(JsonSerialization {})?,
+            )
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(ref response)) if response.code == 409 => Ok(false),
+            Err(err) => Err(err).context(Kube {}),
+        }
+    }
+
+    /// The lease spec for when we are the holder.
+    fn lease_spec(
+        &self,
+        acquired_at: DateTime<Utc>,
+        renewed_at: DateTime<Utc>,
+        transitions: i32,
+    ) -> Value {
+        json!({
+            "holderIdentity": self.identity,
+            "leaseDurationSeconds": self.lease_duration.as_secs(),
+            "acquireTime": MicroTime(acquired_at),
+            "renewTime": MicroTime(renewed_at),
+            "leaseTransitions": transitions,
+        })
+    }
+}
+
+/// Has the lease been renewed within its duration.
+fn lease_is_held(lease: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    match (&lease.renew_time, lease.lease_duration_seconds) {
+        (Some(renew_time), Some(lease_duration_seconds)) => {
+            renew_time.0 + ChronoDuration::seconds(lease_duration_seconds as i64) > now
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_lease_is_held() {
+        let renewed_at = Utc.ymd(2020, 3, 29).and_hms(1, 30, 0);
+        let lease = LeaseSpec {
+            holder_identity: Some(String::from("pangolin-0")),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(renewed_at)),
+            ..Default::default()
+        };
+
+        assert!(lease_is_held(
+            &lease,
+            renewed_at + ChronoDuration::seconds(10)
+        ));
+        assert!(!lease_is_held(
+            &lease,
+            renewed_at + ChronoDuration::seconds(15)
+        ));
+        assert!(!lease_is_held(&LeaseSpec::default(), renewed_at));
+    }
+}
@@ -14,53 +14,126 @@
  * limitations under the License.
  */
 
+use crate::config::{watch_config, PangolinConfig};
+use crate::decision::{Decision, DecisionLog};
 use crate::error::*;
+use crate::kubernetes::cache::{CachedKubernetesResource, KubernetesListCache};
+use crate::kubernetes::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::kubernetes::client::{RefreshingConfig, DEFAULT_SERVICE_ACCOUNT_DIR};
+use crate::kubernetes::custom::KubernetesCustomResource;
+use crate::kubernetes::daemonset::KubernetesDaemonSetResource;
 use crate::kubernetes::deployment::KubernetesDeploymentResource;
+use crate::kubernetes::hpa::horizontal_pod_autoscaler_targets;
+use crate::kubernetes::rate_limit::{
+    parse_namespace_limit, set_kube_api_rate_limit, throttle, NamespaceLimiter,
+};
 use crate::kubernetes::replicaset::KubernetesReplicaSetResource;
+use crate::kubernetes::replicationcontroller::KubernetesReplicationControllerResource;
+use crate::kubernetes::rollout::KubernetesRolloutResource;
 use crate::kubernetes::statefulset::KubernetesStatefulSetResource;
 use crate::kubernetes::KubernetesResource;
 use crate::kubernetes::KubernetesResourceTrait;
-use crate::kubernetes::{KubernetesObject, KubernetesObjectTrait};
-use crate::metrics::retrieve_aggregate_metric;
-use crate::resource::{AutoScaler, AutoScalerKubernetesResourceKind, AutoScalerStrategyKind};
+use crate::kubernetes::{
+    keep_updated_pods, parse_label, with_timeout, FieldManager, KubernetesObject,
+    KubernetesObjectTrait, ObjectRef, ScalePatchType,
+};
+use crate::leader::LeaderElector;
+use crate::notifier::webhook::WebhookNotifier;
+use crate::notifier::{send_notification, Notifier, ScaleNotification};
+use crate::resource::{
+    AutoScaler, AutoScalerKubernetesResourceKind, AutoScalerMetric, AutoScalerMetricReducer,
+    AutoScalerRolloutPolicy, AutoScalerSpec, AutoScalerStrategyKind, LimitedReplicas, ScaleTarget,
+    ANNOTATION_BASE,
+};
+use crate::schedule::scheduled_min_replicas;
+use crate::server::{Health, ReconcileCycle};
+use crate::smoothing::MetricSmoother;
+use crate::source::connections::{ConnectionsMetricSource, InFlightDrains};
+use crate::source::{is_stale, queue_new_samples, MetricSample, MetricSource, MetricSourceTrait};
 use crate::strategy::bang_bang::BangBangAutoScalerStrategy;
+use crate::strategy::proportional::ProportionalAutoScalerStrategy;
+use crate::strategy::target_utilization::TargetUtilizationAutoScalerStrategy;
 use crate::strategy::AutoScalerStrategy;
-use crate::strategy::AutoScalerStrategyTrait;
-use chrono::{DateTime, Utc};
+use crate::strategy::{replica_delta, AutoScalerStrategyTrait};
+use crate::telemetry::{
+    CONVERGED, COOLDOWN_SUPPRESSED, DESIRED_REPLICAS, OBSERVED_REPLICAS, READY_REPLICAS,
+    RECONCILE_FAILURES,
+};
+use crate::timer::{jittered_interval, EmptyListBackoff};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use clap::{
     arg_enum, crate_authors, crate_description, crate_name, crate_version, value_t, App, Arg,
+    SubCommand,
 };
 use futures::channel::mpsc::unbounded;
 use futures::channel::mpsc::UnboundedSender;
-use futures::{SinkExt, StreamExt};
+use futures::stream::BoxStream;
+use futures::{stream, SinkExt, StreamExt};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
 use kube::api::{Api, Informer, ListParams, WatchEvent};
 use kube::client::APIClient;
-use kube::config;
 use slog::{crit, debug, error, info, o, warn, Drain, Level, LevelFilter, Logger};
 use snafu::ResultExt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::panic;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex as StdMutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use stream_cancel::TakeUntil;
 use stream_cancel::{StreamExt as StreamCancelExt, Tripwire};
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
+use tokio::sync::Semaphore;
+use tokio::time::delay_for;
 use tokio::time::interval;
+use tokio::time::timeout;
 use tokio::time::Interval;
 
+/// Configuration file support.
+mod config;
+/// Structured records of scaling decisions.
+mod decision;
 /// Pangolin error types.
 mod error;
 /// Kubernetes api abstraction.
 #[allow(clippy::type_complexity)]
 mod kubernetes;
+/// Lease based leader election.
+mod leader;
 /// Prometheus metrics related functions.
 mod metrics;
+/// Notifications about scaling operations.
+mod notifier;
 /// AutoScaler specification types.
 mod resource;
+/// Time based scaling schedules.
+mod schedule;
+/// HTTP server for pangolin's own metrics and health endpoints.
+mod server;
+/// Exponential smoothing of metric values.
+mod smoothing;
+/// Metric sources for autoscaling decisions.
+mod source;
 /// AutoScaler control strategies.
 mod strategy;
+/// Prometheus metrics describing pangolin's own operation.
+mod telemetry;
+/// Jittered interval timers.
+mod timer;
+
+/// Maximum number of attempts at scaling an object that fails with transient errors.
+const SCALE_TRANSIENT_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a scaling operation, doubled on each subsequent retry.
+const SCALE_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Number of reconciliation intervals without a value after which a metric's moving average is
+/// seeded afresh.
+const SMOOTHING_GAP_INTERVALS: u32 = 3;
 
 arg_enum! {
     /// Log level command line argument.
@@ -75,6 +148,48 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    /// Metric reducer command line argument.
+    #[derive(PartialEq, Debug)]
+    pub enum MetricReducerArgument {
+        Max,
+        Sum,
+        Mean,
+    }
+}
+
+arg_enum! {
+    /// Scale patch type command line argument.
+    #[derive(PartialEq, Debug)]
+    pub enum PatchTypeArgument {
+        Apply,
+        Merge,
+        Json,
+        Strategic,
+    }
+}
+
+impl From<PatchTypeArgument> for ScalePatchType {
+    fn from(patch_type_arg: PatchTypeArgument) -> ScalePatchType {
+        match patch_type_arg {
+            PatchTypeArgument::Apply => ScalePatchType::Apply,
+            PatchTypeArgument::Merge => ScalePatchType::Merge,
+            PatchTypeArgument::Json => ScalePatchType::Json,
+            PatchTypeArgument::Strategic => ScalePatchType::Strategic,
+        }
+    }
+}
+
+impl From<MetricReducerArgument> for AutoScalerMetricReducer {
+    fn from(reducer_arg: MetricReducerArgument) -> AutoScalerMetricReducer {
+        match reducer_arg {
+            MetricReducerArgument::Max => AutoScalerMetricReducer::Max,
+            MetricReducerArgument::Sum => AutoScalerMetricReducer::Sum,
+            MetricReducerArgument::Mean => AutoScalerMetricReducer::Mean,
+        }
+    }
+}
+
 impl From<LogLevelArgument> for Level {
     fn from(level_arg: LogLevelArgument) -> Level {
         match level_arg {
@@ -88,6 +203,59 @@ impl From<LogLevelArgument> for Level {
     }
 }
 
+/// State shared between all autoscaler tasks.
+#[derive(Clone)]
+struct SharedContext {
+    /// Limits the number of scaling operations in flight at once.
+    scale_semaphore: Arc<Semaphore>,
+    /// Limits the scaling operations in flight, and their rate, in each namespace.
+    namespace_limiter: Arc<NamespaceLimiter>,
+    /// Cache of recently listed kubernetes objects.
+    list_cache: Arc<KubernetesListCache>,
+    /// Maximum number of objects retrieved per list request.
+    list_page_limit: u32,
+    /// Maximum time a kubernetes api call can take.
+    kube_api_timeout: Duration,
+    /// Maximum time scaling an object can take, including any conflict retries.
+    scale_timeout: Duration,
+    /// Whether this replica is the leader, only the leader performs reconciliation.
+    is_leader: Arc<AtomicBool>,
+    /// Log scaling decisions without modifying any objects.
+    dry_run: bool,
+    /// Readiness and liveness, for kubernetes probes.
+    health: Arc<Health>,
+    /// Prefix of pangolin's annotations, so multiple instances can coexist in a cluster.
+    annotation_prefix: String,
+    /// Field manager that replica changes are server-side applied as.
+    field_manager: FieldManager,
+    /// Label key and value that objects must carry to be autoscaled, if opt-in is required.
+    opt_in_label: Option<(String, String)>,
+    /// How the desired replicas of several metrics are combined, unless an AutoScaler overrides it.
+    metric_reducer: AutoScalerMetricReducer,
+    /// Pauses reconciliation while the kubernetes api server is unhealthy.
+    kube_api_breaker: Arc<CircuitBreaker>,
+    /// Set on SIGTERM, no new reconciliations or scaling operations are started.
+    shutting_down: Arc<AtomicBool>,
+    /// Where to send notifications about scaling operations, if anywhere.
+    notifier: Option<Arc<Notifier>>,
+    /// Notify about every object, rather than only those that have opted in with an annotation.
+    notify_all_objects: bool,
+    /// Maximum number of objects each AutoScaler reconciles at once.
+    reconcile_workers: usize,
+    /// Percentage each reconciliation interval is randomly lengthened or shortened by.
+    reconcile_jitter: f64,
+    /// Largest multiple of its interval an AutoScaler matching no objects backs off to.
+    empty_list_backoff_limit: u32,
+    /// Kinds of controlling owner whose objects are left alone, eg. a Deployment's ReplicaSets.
+    skip_owner_kinds: Vec<String>,
+    /// Moving averages of metrics that are smoothed before each scaling decision.
+    metric_smoother: Arc<MetricSmoother>,
+    /// The latest scaling decision for each object.
+    decisions: Arc<DecisionLog>,
+    /// Pods taken out of service by drains, to return them should pangolin shut down mid-scale.
+    in_flight_drains: Arc<InFlightDrains>,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let matches = App::new(crate_name!())
@@ -103,14 +271,291 @@ async fn main() -> Result<(), Error> {
                 .case_insensitive(true)
                 .default_value("Info"),
         )
+        .arg(
+            Arg::with_name("MAX_CONCURRENT_SCALES")
+                .long("max-concurrent-scales")
+                .help("set the maximum number of scaling operations in flight at once")
+                .takes_value(true)
+                .default_value("5")
+                // Without any slots no object would ever be scaled.
+                .validator(|max| match max.parse::<usize>() {
+                    Ok(max) if max > 0 => Ok(()),
+                    _ => Err("expected a number greater than zero".into()),
+                }),
+        )
+        .arg(
+            Arg::with_name("NAMESPACE_LIMIT")
+                .long("namespace-limit")
+                .help("limit the scaling operations in a namespace, as NAMESPACE=CONCURRENCY[:QPS[:BURST]], * for every other namespace")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|limit| parse_namespace_limit(&limit).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("RECONCILE_WORKERS")
+                .long("reconcile-workers")
+                .help("set the maximum number of objects each autoscaler reconciles at once")
+                .takes_value(true)
+                .default_value("16"),
+        )
+        .arg(
+            Arg::with_name("SKIP_OWNER_KINDS")
+                .long("skip-owner-kinds")
+                .help("set the comma separated kinds of controlling owner whose objects aren't autoscaled")
+                .takes_value(true)
+                .default_value("Deployment,Rollout"),
+        )
+        .arg(
+            Arg::with_name("RECONCILE_JITTER")
+                .long("reconcile-jitter")
+                .help("set the percentage each reconciliation interval is randomly varied by")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("EMPTY_LIST_BACKOFF_LIMIT")
+                .long("empty-list-backoff-limit")
+                .help("set the largest multiple of its interval an autoscaler matching no objects backs off to, 1 disables backoff")
+                .takes_value(true)
+                .default_value("8"),
+        )
+        .arg(
+            Arg::with_name("LIST_CACHE_TTL")
+                .long("list-cache-ttl")
+                .help("set how long listed kubernetes objects are cached for (seconds)")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("LIST_PAGE_LIMIT")
+                .long("list-page-limit")
+                .help("set the maximum number of kubernetes objects retrieved per list request")
+                .takes_value(true)
+                .default_value("500"),
+        )
+        .arg(
+            Arg::with_name("KUBE_API_TIMEOUT")
+                .long("kube-api-timeout")
+                .help("set how long kubernetes api calls can take before they are abandoned (seconds)")
+                .takes_value(true)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::with_name("KUBE_API_QPS")
+                .long("kube-api-qps")
+                .help("limit the sustained rate of kubernetes api requests (requests per second), 0 for unlimited")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("KUBE_API_BURST")
+                .long("kube-api-burst")
+                .help("set the number of kubernetes api requests allowed in a burst above the rate limit")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("CIRCUIT_BREAKER_FAILURES")
+                .long("circuit-breaker-failures")
+                .help("pause kubernetes api calls after this many consecutive failures, 0 to disable")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("CIRCUIT_BREAKER_COOLDOWN")
+                .long("circuit-breaker-cooldown")
+                .help("set how long kubernetes api calls are paused for before probing again (seconds)")
+                .takes_value(true)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::with_name("NOTIFY_WEBHOOK")
+                .long("notify-webhook")
+                .help("post a json notification to this url whenever an object is scaled")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("NOTIFY_ALL_OBJECTS")
+                .long("notify-all-objects")
+                .help("notify about every scaled object, not only those with the notify annotation"),
+        )
+        .arg(
+            Arg::with_name("STATSD_LISTEN_ADDRESS")
+                .long("statsd-listen-address")
+                .help("listen for statsd gauges on this udp address, eg. 0.0.0.0:8125")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .long("config")
+                .help("load additional autoscalers from a yaml configuration file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CONFIG_RELOAD_INTERVAL")
+                .long("config-reload-interval")
+                .help("set how often the configuration file is checked for changes (seconds)")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("SERVICE_ACCOUNT_DIR")
+                .long("service-account-dir")
+                .help("set the directory the service account token, CA certificate and namespace are read from when running in a cluster")
+                .takes_value(true)
+                .default_value(DEFAULT_SERVICE_ACCOUNT_DIR),
+        )
+        .arg(
+            Arg::with_name("SHUTDOWN_TIMEOUT")
+                .long("shutdown-timeout")
+                .help("set how long to wait for in-flight scaling operations on shutdown (seconds)")
+                .takes_value(true)
+                .default_value("20"),
+        )
+        .arg(
+            Arg::with_name("SCALE_TIMEOUT")
+                .long("scale-timeout")
+                .help("set how long scaling an object can take before it is abandoned (seconds), defaults to the kubernetes api timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("LISTEN_ADDRESS")
+                .long("listen-address")
+                .help("set the address to serve pangolin's own metrics and health endpoints on")
+                .takes_value(true)
+                .default_value("0.0.0.0:8080"),
+        )
+        .arg(
+            Arg::with_name("LIVENESS_DEADLINE")
+                .long("liveness-deadline")
+                .help("set how long the reconciliation loop can stall before pangolin is no longer live (seconds)")
+                .takes_value(true)
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("ANNOTATION_PREFIX")
+                .long("annotation-prefix")
+                .help("set the prefix of annotations read and written by pangolin")
+                .takes_value(true)
+                .default_value(ANNOTATION_BASE),
+        )
+        .arg(
+            Arg::with_name("FIELD_MANAGER")
+                .long("field-manager")
+                .help("set the field manager name pangolin applies replica changes as")
+                .takes_value(true)
+                .default_value("pangolin"),
+        )
+        .arg(
+            Arg::with_name("FORCE_APPLY")
+                .long("force-apply")
+                .help("set whether to take ownership of the replicas field from other field managers")
+                .takes_value(true)
+                .possible_values(&["true", "false"])
+                .default_value("true"),
+        )
+        .arg(
+            Arg::with_name("PATCH_TYPE")
+                .long("patch-type")
+                .help("set the type of patch pangolin changes replicas with")
+                .takes_value(true)
+                .possible_values(&PatchTypeArgument::variants())
+                .case_insensitive(true)
+                .default_value("Apply"),
+        )
+        .arg(
+            Arg::with_name("SERVER_DRY_RUN")
+                .long("server-dry-run")
+                .help("send scaling operations as a server-side dry run, validating them without persisting them"),
+        )
+        .arg(
+            Arg::with_name("OPT_IN_LABEL")
+                .long("opt-in-label")
+                .help("only autoscale objects carrying this label, eg. pangolin.io/autoscale=true")
+                .takes_value(true)
+                .validator(|label| {
+                    parse_label(&label)
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("METRIC_REDUCER")
+                .long("metric-reducer")
+                .help("set how the desired replicas of autoscalers with several metrics are combined")
+                .takes_value(true)
+                .possible_values(&MetricReducerArgument::variants())
+                .case_insensitive(true)
+                .default_value("Max"),
+        )
+        .arg(
+            Arg::with_name("DRY_RUN")
+                .long("dry-run")
+                .help("log scaling decisions without modifying any objects"),
+        )
+        .arg(
+            Arg::with_name("SERVE_DECISIONS")
+                .long("serve-decisions")
+                .help("serve the latest scaling decision for each object on /decisions"),
+        )
+        .arg(
+            Arg::with_name("LEADER_ELECTION")
+                .long("leader-election")
+                .help(
+                    "enable leader election, so that only a single replica performs reconciliation",
+                ),
+        )
+        .arg(
+            Arg::with_name("LEADER_ELECTION_NAMESPACE")
+                .long("leader-election-namespace")
+                .help("set the namespace of the leader election lease")
+                .takes_value(true)
+                .default_value("autoscaler"),
+        )
+        .arg(
+            Arg::with_name("LEADER_ELECTION_NAME")
+                .long("leader-election-name")
+                .help("set the name of the leader election lease")
+                .takes_value(true)
+                .default_value("pangolin"),
+        )
+        .arg(
+            Arg::with_name("LEADER_ELECTION_IDENTITY")
+                .long("leader-election-identity")
+                .help("set the identity of this replica, defaults to the hostname")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("LEADER_ELECTION_LEASE_DURATION")
+                .long("leader-election-lease-duration")
+                .help("set how long a leader election lease is held for without renewal (seconds)")
+                .takes_value(true)
+                .default_value("15"),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("print the number of replicas each managed object would be scaled to, then exit, without scaling anything")
+                .arg(
+                    Arg::with_name("FAIL_ON_CHANGE")
+                        .long("fail-on-change")
+                        .help("exit with status 2 if any object would be scaled"),
+                ),
+        )
         .get_matches();
+    let plan_matches = matches.subcommand_matches("plan");
 
     let log_level: Level = value_t!(matches, "LOG_LEVEL", LogLevelArgument)
         .unwrap_or_else(|e| e.exit())
         .into();
+    // A plan is printed to stdout, so keep its logs out of the way.
+    let log_output: Box<dyn std::io::Write + Send> = match plan_matches {
+        Some(_) => Box::new(std::io::stderr()),
+        None => Box::new(std::io::stdout()),
+    };
     let logger = Logger::root(
         StdMutex::new(LevelFilter::new(
-            slog_json::Json::default(std::io::stdout()),
+            slog_json::Json::default(log_output),
             log_level,
         ))
         .map(slog::Fuse),
@@ -119,6 +564,93 @@ async fn main() -> Result<(), Error> {
 
     info!(logger, "Configured structured logger"; "log_level" => format!("{:?}", log_level));
 
+    // Limit the number of concurrent scaling operations to avoid api server throttling.
+    let max_concurrent_scales =
+        value_t!(matches, "MAX_CONCURRENT_SCALES", usize).unwrap_or_else(|e| e.exit());
+    let list_cache_ttl = value_t!(matches, "LIST_CACHE_TTL", u64).unwrap_or_else(|e| e.exit());
+    let list_page_limit = value_t!(matches, "LIST_PAGE_LIMIT", u32).unwrap_or_else(|e| e.exit());
+    let liveness_deadline =
+        value_t!(matches, "LIVENESS_DEADLINE", u64).unwrap_or_else(|e| e.exit());
+    let kube_api_timeout = value_t!(matches, "KUBE_API_TIMEOUT", u64).unwrap_or_else(|e| e.exit());
+    let kube_api_qps = value_t!(matches, "KUBE_API_QPS", f64).unwrap_or_else(|e| e.exit());
+    let kube_api_burst = value_t!(matches, "KUBE_API_BURST", u32).unwrap_or_else(|e| e.exit());
+    if kube_api_qps > 0.0 {
+        set_kube_api_rate_limit(kube_api_qps, kube_api_burst);
+        info!(logger, "Configured kubernetes api rate limit"; "qps" => kube_api_qps, "burst" => kube_api_burst);
+    }
+    let circuit_breaker_failures =
+        value_t!(matches, "CIRCUIT_BREAKER_FAILURES", u32).unwrap_or_else(|e| e.exit());
+    let circuit_breaker_cooldown =
+        value_t!(matches, "CIRCUIT_BREAKER_COOLDOWN", u64).unwrap_or_else(|e| e.exit());
+    let scale_timeout = if matches.is_present("SCALE_TIMEOUT") {
+        value_t!(matches, "SCALE_TIMEOUT", u64).unwrap_or_else(|e| e.exit())
+    } else {
+        kube_api_timeout
+    };
+    let shared = SharedContext {
+        scale_semaphore: Arc::new(Semaphore::new(max_concurrent_scales)),
+        namespace_limiter: Arc::new(NamespaceLimiter::new(
+            matches
+                .values_of("NAMESPACE_LIMIT")
+                .into_iter()
+                .flatten()
+                .filter_map(|limit| parse_namespace_limit(limit).ok())
+                .collect(),
+        )),
+        list_cache: Arc::new(KubernetesListCache::new(Duration::from_secs(
+            list_cache_ttl,
+        ))),
+        list_page_limit,
+        kube_api_timeout: Duration::from_secs(kube_api_timeout),
+        scale_timeout: Duration::from_secs(scale_timeout),
+        // Without leader election every replica is the leader.
+        is_leader: Arc::new(AtomicBool::new(!matches.is_present("LEADER_ELECTION"))),
+        dry_run: matches.is_present("DRY_RUN"),
+        health: Arc::new(Health::new(Duration::from_secs(liveness_deadline))),
+        annotation_prefix: matches.value_of("ANNOTATION_PREFIX").unwrap().into(),
+        field_manager: FieldManager {
+            name: matches.value_of("FIELD_MANAGER").unwrap().into(),
+            force: value_t!(matches, "FORCE_APPLY", bool).unwrap_or_else(|e| e.exit()),
+            patch_type: value_t!(matches, "PATCH_TYPE", PatchTypeArgument)
+                .unwrap_or_else(|e| e.exit())
+                .into(),
+            dry_run: matches.is_present("SERVER_DRY_RUN"),
+        },
+        opt_in_label: matches
+            .value_of("OPT_IN_LABEL")
+            .map(|label| parse_label(label).unwrap()),
+        metric_reducer: value_t!(matches, "METRIC_REDUCER", MetricReducerArgument)
+            .unwrap_or_else(|e| e.exit())
+            .into(),
+        kube_api_breaker: Arc::new(CircuitBreaker::new(
+            circuit_breaker_failures,
+            Duration::from_secs(circuit_breaker_cooldown),
+        )),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        notifier: matches
+            .value_of("NOTIFY_WEBHOOK")
+            .map(|endpoint| Arc::new(Notifier::Webhook(WebhookNotifier::new(endpoint)))),
+        notify_all_objects: matches.is_present("NOTIFY_ALL_OBJECTS"),
+        // A pool without any workers would never reconcile anything.
+        reconcile_workers: value_t!(matches, "RECONCILE_WORKERS", usize)
+            .unwrap_or_else(|e| e.exit())
+            .max(1),
+        reconcile_jitter: value_t!(matches, "RECONCILE_JITTER", f64).unwrap_or_else(|e| e.exit()),
+        empty_list_backoff_limit: value_t!(matches, "EMPTY_LIST_BACKOFF_LIMIT", u32)
+            .unwrap_or_else(|e| e.exit()),
+        skip_owner_kinds: matches
+            .value_of("SKIP_OWNER_KINDS")
+            .unwrap()
+            .split(',')
+            .map(str::trim)
+            .filter(|kind| !kind.is_empty())
+            .map(String::from)
+            .collect(),
+        metric_smoother: Arc::new(MetricSmoother::default()),
+        decisions: Arc::new(DecisionLog::default()),
+        in_flight_drains: Arc::new(InFlightDrains::default()),
+    };
+
     // Replace the panic handler with one that will exit the process on panics (in any thread).
     // This lets Kubernetes restart the process if we hit anything unexpected.
     let panic_logger = logger.clone();
@@ -128,31 +660,171 @@ async fn main() -> Result<(), Error> {
         exit(1);
     }));
 
-    let kube_config = if let Ok(kube_config) = kube::config::incluster_config() {
-        kube_config
+    // Serve pangolin's own metrics and health endpoints.
+    let listen_address =
+        value_t!(matches, "LISTEN_ADDRESS", SocketAddr).unwrap_or_else(|e| e.exit());
+    if plan_matches.is_none() {
+        tokio::spawn(server::serve(
+            logger.clone(),
+            listen_address,
+            shared.health.clone(),
+            Some(shared.decisions.clone()).filter(|_| matches.is_present("SERVE_DECISIONS")),
+        ));
+    }
+
+    // Receive gauges pushed by services that report their metrics to statsd.
+    if matches.is_present("STATSD_LISTEN_ADDRESS") {
+        let statsd_listen_address =
+            value_t!(matches, "STATSD_LISTEN_ADDRESS", SocketAddr).unwrap_or_else(|e| e.exit());
+        tokio::spawn(source::statsd::listen(
+            logger.clone(),
+            statsd_listen_address,
+        ));
+    }
+
+    // Refuse to start with an invalid configuration file, rather than silently ignoring it.
+    let (config_update_sender, mut config_updates) = unbounded::<Vec<AutoScaler>>();
+    let configured_autoscalers = match matches.value_of("CONFIG") {
+        Some(config_path) => {
+            let loaded = PangolinConfig::read(config_path).and_then(|contents| {
+                let configured_autoscalers =
+                    PangolinConfig::parse(config_path, &contents)?.autoscalers()?;
+                Ok((contents, configured_autoscalers))
+            });
+            let (contents, configured_autoscalers) = match loaded {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    crit!(logger, "Invalid configuration file";
+                        "config_path" => config_path,
+                        "error" => format!("{}", err));
+                    exit(1);
+                }
+            };
+            info!(logger, "Loaded configuration file";
+                "config_path" => config_path,
+                "autoscalers" => configured_autoscalers.len());
+
+            // Pick up changes to the file, eg. an updated ConfigMap, without a restart.
+            let config_reload_interval =
+                value_t!(matches, "CONFIG_RELOAD_INTERVAL", u64).unwrap_or_else(|e| e.exit());
+            tokio::spawn(watch_config(
+                logger.new(o!("config_path" => String::from(config_path))),
+                config_path.into(),
+                contents,
+                Duration::from_secs(config_reload_interval),
+                config_update_sender,
+            ));
+            configured_autoscalers
+        }
+        None => Vec::new(),
+    };
+    let mut configured_keys: HashSet<String> = configured_autoscalers
+        .iter()
+        .map(configured_autoscaler_key)
+        .collect();
+
+    let kube_config = Arc::new(
+        RefreshingConfig::load(
+            logger.clone(),
+            matches.value_of("SERVICE_ACCOUNT_DIR").unwrap(),
+        )
+        .await?,
+    );
+    info!(logger, "Loaded kubernetes configuration";
+        "config_source" => format!("{:?}", kube_config.source()));
+
+    let autoscaler_api = |kube_client| -> Api<AutoScaler> {
+        Api::customResource(kube_client, "autoscalers")
+            .version("v1alpha1")
+            .group("pangolinscaler.com")
+    };
+
+    // Print what every managed object would be scaled to, and stop there.
+    if let Some(plan_matches) = plan_matches {
+        throttle().await;
+        let autoscalers = autoscaler_api(kube_config.client().await)
+            .list(&ListParams::default())
+            .await
+            .context(Kube {})?;
+        let autoscalers: Vec<AutoScaler> = autoscalers
+            .into_iter()
+            .chain(configured_autoscalers)
+            .collect();
+        let (proposed_changes, failures) =
+            plan(logger.clone(), kube_config, autoscalers, shared).await?;
+        if failures > 0 {
+            exit(1);
+        }
+        if proposed_changes > 0 && plan_matches.is_present("FAIL_ON_CHANGE") {
+            exit(2);
+        }
+        return Ok(());
+    }
+
+    let leader_elector = if matches.is_present("LEADER_ELECTION") {
+        let identity = matches
+            .value_of("LEADER_ELECTION_IDENTITY")
+            .map(String::from)
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| String::from(crate_name!()));
+        let lease_duration =
+            value_t!(matches, "LEADER_ELECTION_LEASE_DURATION", u64).unwrap_or_else(|e| e.exit());
+        let leader_elector = Arc::new(LeaderElector::new(
+            kube_config.clone(),
+            matches.value_of("LEADER_ELECTION_NAMESPACE").unwrap(),
+            matches.value_of("LEADER_ELECTION_NAME").unwrap(),
+            &identity,
+            Duration::from_secs(lease_duration),
+        ));
+        let (elector, elector_logger, is_leader) = (
+            leader_elector.clone(),
+            logger.clone(),
+            shared.is_leader.clone(),
+        );
+        tokio::spawn(async move { elector.run(elector_logger, is_leader).await });
+        Some(leader_elector)
     } else {
-        config::load_kube_config().await.context(Kube {})?
+        None
     };
 
-    let kube_client = APIClient::new(kube_config.clone());
-    let autoscaler_api: Api<AutoScaler> = Api::customResource(kube_client.clone(), "autoscalers")
-        .version("v1alpha1")
-        .group("pangolinscaler.com");
+    // Finish in-flight scaling operations, and hand over the lease, when asked to stop.
+    let shutdown_timeout = value_t!(matches, "SHUTDOWN_TIMEOUT", u64).unwrap_or_else(|e| e.exit());
+    tokio::spawn(shutdown_on_signal(
+        logger.clone(),
+        shared.clone(),
+        max_concurrent_scales,
+        Duration::from_secs(shutdown_timeout),
+        leader_elector,
+    ));
 
     // Handle for managing the lifecycle of subtasks and sending update information
     let mut task_handle: HashMap<String, UnboundedSender<AutoScaler>> = HashMap::new();
 
     // Retrieve the current list of autoscalers.
-    let autoscalers = autoscaler_api
+    throttle().await;
+    let autoscalers = autoscaler_api(kube_config.client().await)
         .list(&ListParams::default())
         .await
         .context(Kube {})?;
-    for autoscaler in autoscalers {
-        let task_key = format!(
-            "{}/{}",
-            autoscaler.metadata.namespace.as_ref().unwrap(),
-            autoscaler.metadata.name
+    shared.health.heartbeat();
+    let autoscalers = autoscalers
+        .into_iter()
+        .filter(|autoscaler| match autoscaler.spec.validate() {
+            Ok(()) => true,
+            Err(err) => {
+                error!(logger, "Ignoring invalid autoscaler";
+                    "autoscaler" => autoscaler_key(autoscaler),
+                    "error" => format!("{}", err));
+                false
+            }
+        })
+        .map(|autoscaler| (autoscaler_key(&autoscaler), autoscaler))
+        .chain(
+            configured_autoscalers
+                .into_iter()
+                .map(|autoscaler| (configured_autoscaler_key(&autoscaler), autoscaler)),
         );
+    for (task_key, autoscaler) in autoscalers {
         task_handle.insert(
             task_key,
             autoscaler_loop(
@@ -161,20 +833,77 @@ async fn main() -> Result<(), Error> {
                     "autoscaler_name" => autoscaler.metadata.name.clone())),
                 kube_config.clone(),
                 autoscaler.clone(),
+                shared.clone(),
             )?,
         );
     }
+    // Readiness otherwise waits for the first successful reconciliation cycle, but with nothing to
+    // reconcile being able to list the AutoScalers is enough.
+    if task_handle.is_empty() {
+        shared.health.mark_ready();
+    }
 
     // Set up a watcher for autoscaler events.
-    let informer = Informer::new(autoscaler_api)
+    throttle().await;
+    let mut informer = Informer::new(autoscaler_api(kube_config.client().await))
         .timeout(15)
         .init()
         .await
         .context(Kube {})?;
+    let mut informer_generation = kube_config.generation().await;
 
     // Loop enables us to drop and refresh the kubernetes watcher periodically
     // reduces the reliance on long lived connections and provides us a bit more resiliency.
     loop {
+        // Every poll of the watcher times out, so this loop always makes progress unless stuck.
+        shared.health.heartbeat();
+
+        // Apply any changes to the configuration file, configured autoscalers that have been
+        // removed are shut down, new ones started and the rest sent their updated spec.
+        while let Ok(Some(updated_autoscalers)) = config_updates.try_next() {
+            let updated_keys: HashSet<String> = updated_autoscalers
+                .iter()
+                .map(configured_autoscaler_key)
+                .collect();
+            for removed_key in configured_keys.difference(&updated_keys) {
+                info!(logger, "Removed configured autoscaler"; "autoscaler" => removed_key);
+                drop(task_handle.remove(removed_key));
+            }
+            for autoscaler in updated_autoscalers {
+                let task_key = configured_autoscaler_key(&autoscaler);
+                if let Some(task_handle) = task_handle.get_mut(&task_key) {
+                    task_handle.send(autoscaler).await.unwrap();
+                } else {
+                    info!(logger, "Added configured autoscaler"; "autoscaler" => &task_key);
+                    task_handle.insert(
+                        task_key,
+                        autoscaler_loop(
+                            logger.new(o!(
+                                "autoscaler_namespace" => autoscaler.metadata.namespace.as_ref().unwrap().clone(),
+                                "autoscaler_name" => autoscaler.metadata.name.clone())),
+                            kube_config.clone(),
+                            autoscaler.clone(),
+                            shared.clone(),
+                        )?,
+                    );
+                }
+            }
+            configured_keys = updated_keys;
+        }
+
+        // Pick up a rotated service account token, carrying on watching from where we left off.
+        // Cached objects hold on to the client they were listed with, so are listed again.
+        let kube_client = kube_config.client().await;
+        let generation = kube_config.generation().await;
+        if generation != informer_generation {
+            informer = Informer::new(autoscaler_api(kube_client))
+                .timeout(15)
+                .init_from(informer.version());
+            informer_generation = generation;
+            shared.list_cache.clear().await;
+        }
+
+        throttle().await;
         let mut events = match informer
             .poll()
             .await
@@ -198,7 +927,15 @@ async fn main() -> Result<(), Error> {
                     info!(logger, "Added autoscaler";
                         "autoscaler_namespace" => autoscaler_namespace,
                         "autoscaler_name" => &autoscaler.metadata.name);
-                    let task_key = format!("{}/{}", autoscaler_namespace, autoscaler.metadata.name);
+                    // The CRD schema can't express every constraint on the specification.
+                    if let Err(err) = autoscaler.spec.validate() {
+                        error!(logger, "Ignoring invalid autoscaler";
+                            "autoscaler_namespace" => autoscaler_namespace,
+                            "autoscaler_name" => &autoscaler.metadata.name,
+                            "error" => format!("{}", err));
+                        continue;
+                    }
+                    let task_key = autoscaler_key(&autoscaler);
                     task_handle.insert(
                         task_key,
                         autoscaler_loop(
@@ -207,6 +944,7 @@ async fn main() -> Result<(), Error> {
                                 "autoscaler_name" => autoscaler.metadata.name.clone())),
                             kube_config.clone(),
                             autoscaler.clone(),
+                            shared.clone(),
                         )?,
                     );
                 }
@@ -216,7 +954,14 @@ async fn main() -> Result<(), Error> {
                     info!(logger, "Modified autoscaler";
                         "autoscaler_namespace" => autoscaler_namespace,
                         "autoscaler_name" => &autoscaler.metadata.name);
-                    let task_key = format!("{}/{}", autoscaler_namespace, autoscaler.metadata.name);
+                    if let Err(err) = autoscaler.spec.validate() {
+                        error!(logger, "Invalid autoscaler, keeping the previous specification";
+                            "autoscaler_namespace" => autoscaler_namespace,
+                            "autoscaler_name" => &autoscaler.metadata.name,
+                            "error" => format!("{}", err));
+                        continue;
+                    }
+                    let task_key = autoscaler_key(&autoscaler);
                     if let Some(task_handle) = task_handle.get_mut(&task_key) {
                         task_handle.send(autoscaler.clone()).await.unwrap();
                     } else {
@@ -233,6 +978,7 @@ async fn main() -> Result<(), Error> {
                                     "autoscaler_name" => autoscaler.metadata.name.clone())),
                                 kube_config.clone(),
                                 autoscaler.clone(),
+                                shared.clone(),
                             )?,
                         );
                     }
@@ -243,7 +989,7 @@ async fn main() -> Result<(), Error> {
                     info!(logger, "Deleted autoscaler";
                         "autoscaler_namespace" => autoscaler_namespace,
                         "autoscaler_name" => &autoscaler.metadata.name);
-                    let task_key = format!("{}/{}", autoscaler_namespace, autoscaler.metadata.name);
+                    let task_key = autoscaler_key(&autoscaler);
                     // Dropping the handle will terminate the task.
                     drop(task_handle.remove(&task_key));
                 }
@@ -256,10 +1002,26 @@ async fn main() -> Result<(), Error> {
     }
 }
 
+/// The key an AutoScaler's task is tracked by, its namespace and name.
+fn autoscaler_key(autoscaler: &AutoScaler) -> String {
+    format!(
+        "{}/{}",
+        autoscaler.metadata.namespace.as_ref().unwrap(),
+        autoscaler.metadata.name
+    )
+}
+
+/// The key a configured AutoScaler's task is tracked by, kept apart from those of AutoScaler
+/// resources so that a file entry with the same namespace and name can't replace their task.
+fn configured_autoscaler_key(autoscaler: &AutoScaler) -> String {
+    format!("config:{}", autoscaler_key(autoscaler))
+}
+
 fn autoscaler_loop(
     logger: Logger,
-    kube_config: kube::config::Configuration,
+    kube_config: Arc<RefreshingConfig>,
     autoscaler: AutoScaler,
+    shared: SharedContext,
 ) -> Result<UnboundedSender<AutoScaler>, Error> {
     // Create a channel for receiving updated AutoScaler specifications.
     let (update_sender, mut update_receiver) = unbounded::<AutoScaler>();
@@ -269,28 +1031,30 @@ fn autoscaler_loop(
     let (timer_cancel, timer_tripwire) = Tripwire::new();
     let mut timer_cancel = Some(timer_cancel);
 
-    // Create an interval timer for the metrics retrieval subtask.
-    let metric_period = autoscaler.spec.metric.interval;
+    // Create interval timers for the metrics retrieval subtasks.
     let (metric_timer_cancel, metric_timer_tripwire) = Tripwire::new();
     let mut metric_timer_cancel = Some(metric_timer_cancel);
 
     let autoscaler_namespace = String::from(autoscaler.metadata.namespace.as_ref().unwrap());
+    let autoscaler_spec = autoscaler.spec.clone();
     let autoscaler = Arc::new(RwLock::new(Some(autoscaler)));
 
     // A repository for storing a window worth of retrieved metrics.
     // This will leak a small amount of memory when matching objects get deleted.
-    let metric_repository: Arc<Mutex<HashMap<String, Vec<f64>>>> =
+    let metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
-    // AutoScaler metrics retrieval subtask.
-    tokio::spawn(metric_retriever_loop(
+    // AutoScaler metrics retrieval subtasks.
+    spawn_metric_retriever_loops(
         logger.clone(),
         kube_config.clone(),
         autoscaler_namespace.clone(),
         autoscaler.clone(),
-        interval(Duration::from_secs(metric_period as u64)).take_until(metric_timer_tripwire),
+        &autoscaler_spec,
+        metric_timer_tripwire,
         metric_repository.clone(),
-    ));
+        shared.clone(),
+    );
 
     // AutoScaler reconciliation subtask.
     tokio::spawn(reconciliation_loop(
@@ -298,8 +1062,10 @@ fn autoscaler_loop(
         kube_config.clone(),
         autoscaler_namespace.clone(),
         autoscaler.clone(),
-        interval(Duration::from_secs(period as u64)).take_until(timer_tripwire),
+        jittered_interval(Duration::from_secs(period as u64), shared.reconcile_jitter)
+            .take_until(timer_tripwire),
         metric_repository.clone(),
+        shared.clone(),
     ));
 
     // AutoScaler update receiver subtask.
@@ -330,18 +1096,17 @@ fn autoscaler_loop(
 
             debug!(logger, "Spawning updated timer driven tasks");
 
-            // Updated AutoScaler metrics retrieval subtask.
-            tokio::spawn(metric_retriever_loop(
+            // Updated AutoScaler metrics retrieval subtasks.
+            spawn_metric_retriever_loops(
                 logger.clone(),
                 kube_config.clone(),
                 autoscaler_namespace.clone(),
                 autoscaler.clone(),
-                interval(Duration::from_secs(
-                    updated_autoscaler.spec.metric.interval as u64,
-                ))
-                .take_until(updated_metric_timer_tripwire),
+                &updated_autoscaler.spec,
+                updated_metric_timer_tripwire,
                 metric_repository.clone(),
-            ));
+                shared.clone(),
+            );
 
             // Updated AutoScaler reconciliation subtask.
             tokio::spawn(reconciliation_loop(
@@ -349,9 +1114,13 @@ fn autoscaler_loop(
                 kube_config.clone(),
                 autoscaler_namespace.clone(),
                 autoscaler.clone(),
-                interval(Duration::from_secs(updated_autoscaler.spec.interval as u64))
-                    .take_until(updated_timer_tripwire),
+                jittered_interval(
+                    Duration::from_secs(updated_autoscaler.spec.interval as u64),
+                    shared.reconcile_jitter,
+                )
+                .take_until(updated_timer_tripwire),
                 metric_repository.clone(),
+                shared.clone(),
             ));
         }
 
@@ -365,41 +1134,165 @@ fn autoscaler_loop(
     Ok(update_sender)
 }
 
+/// Spawn a metrics retrieval subtask for each of an AutoScaler's metrics, on the metric's interval.
+#[allow(clippy::too_many_arguments)]
+fn spawn_metric_retriever_loops(
+    logger: Logger,
+    kube_config: Arc<RefreshingConfig>,
+    autoscaler_namespace: String,
+    autoscaler: Arc<RwLock<Option<AutoScaler>>>,
+    autoscaler_spec: &AutoScalerSpec,
+    metric_timer_tripwire: Tripwire,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    shared: SharedContext,
+) {
+    for (metric_index, metric) in autoscaler_spec.metrics().into_iter().enumerate() {
+        tokio::spawn(metric_retriever_loop(
+            logger.new(o!("metric_name" => metric.name.clone())),
+            kube_config.clone(),
+            autoscaler_namespace.clone(),
+            autoscaler.clone(),
+            metric_index,
+            interval(Duration::from_secs(metric.interval as u64))
+                .take_until(metric_timer_tripwire.clone()),
+            metric_repository.clone(),
+            shared.clone(),
+        ));
+    }
+}
+
+/// The autoscaling strategy for one of an AutoScaler's metrics, the metric's own strategy
+/// configuration takes precedence over the AutoScaler's.
+fn metric_strategy(spec: &AutoScalerSpec, metric: &AutoScalerMetric) -> Option<AutoScalerStrategy> {
+    match spec.strategy {
+        AutoScalerStrategyKind::BangBang => metric
+            .bang_bang
+            .as_ref()
+            .or_else(|| spec.bang_bang.as_ref())
+            .map(|bang_bang| {
+                AutoScalerStrategy::BangBang(BangBangAutoScalerStrategy::new(bang_bang.clone()))
+            }),
+        AutoScalerStrategyKind::TargetUtilization => metric
+            .target_utilization
+            .as_ref()
+            .or_else(|| spec.target_utilization.as_ref())
+            .map(|target_utilization| {
+                AutoScalerStrategy::TargetUtilization(TargetUtilizationAutoScalerStrategy::new(
+                    target_utilization.clone(),
+                    spec.limits.clone(),
+                ))
+            }),
+        AutoScalerStrategyKind::Proportional => metric
+            .proportional
+            .as_ref()
+            .or_else(|| spec.proportional.as_ref())
+            .map(|proportional| {
+                AutoScalerStrategy::Proportional(ProportionalAutoScalerStrategy::new(
+                    proportional.clone(),
+                    spec.limits.clone(),
+                ))
+            }),
+    }
+}
+
+/// The autoscaling strategy of each of an AutoScaler's metrics, None if any metric is missing its
+/// strategy configuration.
+fn autoscaler_strategies(
+    spec: &AutoScalerSpec,
+) -> Option<Vec<(AutoScalerMetric, AutoScalerStrategy)>> {
+    spec.metrics()
+        .into_iter()
+        .map(|metric| metric_strategy(spec, metric).map(|strategy| (metric.clone(), strategy)))
+        .collect()
+}
+
 async fn reconciliation_loop(
     logger: Logger,
-    kube_config: kube::config::Configuration,
+    kube_config: Arc<RefreshingConfig>,
     autoscaler_namespace: String,
     autoscaler: Arc<RwLock<Option<AutoScaler>>>,
-    mut timer: TakeUntil<Interval, Tripwire>,
-    metric_repository: Arc<Mutex<HashMap<String, Vec<f64>>>>,
+    mut timer: TakeUntil<BoxStream<'static, ()>, Tripwire>,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    shared: SharedContext,
 ) {
     debug!(logger, "Starting autoscaler task");
 
+    let mut empty_list_backoff = EmptyListBackoff::new(shared.empty_list_backoff_limit);
     while let Some(_) = timer.next().await {
-        // Create the strategy fresh each time, to simplify handling autoscaler spec changes.
-        let strategy = match &autoscaler.read().await.as_ref().unwrap().spec.strategy {
-            AutoScalerStrategyKind::BangBang => {
-                if let Some(bang_bang) = &autoscaler.read().await.as_ref().unwrap().spec.bang_bang {
-                    Some(AutoScalerStrategy::BangBang(
-                        BangBangAutoScalerStrategy::new(bang_bang.clone()),
-                    ))
-                } else {
-                    None
-                }
-            }
+        // A cycle that stalls, rather than completing, fails the liveness check.
+        let cycle = ReconcileCycle::start(shared.health.clone());
+
+        if shared.shutting_down.load(Ordering::SeqCst) {
+            break;
+        }
+
+        // Standby replicas wait for the leader to go away, ready to take over.
+        if !shared.is_leader.load(Ordering::SeqCst) {
+            debug!(
+                logger,
+                "Skipping reconciliation as this replica is not the leader"
+            );
+            cycle.succeeded();
+            continue;
+        }
+
+        // Give an unhealthy api server a break, rather than piling on more requests.
+        if !shared.kube_api_breaker.allow(Instant::now()) {
+            debug!(logger, "Skipping reconciliation as kubernetes api calls are paused";
+                "decision" => "circuit_open");
+            continue;
+        }
+
+        // Don't keep listing at full frequency for a selector that matches nothing.
+        if empty_list_backoff.skip() {
+            debug!(logger, "Skipping reconciliation as the autoscaler has matched no objects recently";
+                "decision" => "empty_list_backoff",
+                "interval_multiplier" => empty_list_backoff.multiplier());
+            continue;
+        }
+
+        // Create the strategies fresh each time, to simplify handling autoscaler spec changes.
+        let (strategies, metric_reducer) = {
+            let autoscaler = autoscaler.read().await;
+            let spec = &autoscaler.as_ref().unwrap().spec;
+            (
+                autoscaler_strategies(spec),
+                spec.metric_reducer.unwrap_or(shared.metric_reducer),
+            )
         };
 
-        if let Some(strategy) = strategy {
-            // Spawn subtasks to handle reconciliation of each matching object.
-            spawn_reconciliation_tasks(
-                logger.clone(),
-                kube_config.clone(),
+        // Use the latest client, in case the service account token has been rotated.
+        let kube_client = kube_config.client().await;
+
+        if let Some(strategies) = strategies {
+            // Spawn subtasks to handle reconciliation of each matching object, the cycle's log
+            // lines (listing, reconciling and scaling objects) all carry its id.
+            let matched = spawn_reconciliation_tasks(
+                logger.new(o!("cycle" => cycle.id())),
+                kube_client,
                 autoscaler.clone(),
                 autoscaler_namespace.clone(),
-                strategy,
+                strategies,
+                metric_reducer,
                 metric_repository.clone(),
+                shared.clone(),
             )
             .await;
+            // Failing to list objects isn't the same as there being none.
+            if let Some(objects) = matched {
+                cycle.succeeded();
+                let was_backing_off = empty_list_backoff.multiplier() > 1;
+                empty_list_backoff.record(objects);
+                if objects == 0 && empty_list_backoff.empty_lists() == 1 {
+                    warn!(
+                        logger,
+                        "Autoscaler matched no objects, is its selector misconfigured?"
+                    );
+                } else if objects > 0 && was_backing_off {
+                    info!(logger, "Autoscaler matched objects again, resuming its full reconciliation interval";
+                        "objects" => objects);
+                }
+            }
         } else {
             error!(
                 logger,
@@ -411,35 +1304,62 @@ async fn reconciliation_loop(
     debug!(logger, "Stopped autoscaler task");
 }
 
-/// Task to pull metrics from all objects associated with an AutoScaler.
+/// Task to pull one of an AutoScaler's metrics from all of its associated objects.
+#[allow(clippy::too_many_arguments)]
 async fn metric_retriever_loop(
     logger: Logger,
-    kube_config: kube::config::Configuration,
+    kube_config: Arc<RefreshingConfig>,
     autoscaler_namespace: String,
     autoscaler: Arc<RwLock<Option<AutoScaler>>>,
+    metric_index: usize,
     mut metric_timer: TakeUntil<Interval, Tripwire>,
-    metric_repository: Arc<Mutex<HashMap<String, Vec<f64>>>>,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    shared: SharedContext,
 ) {
     debug!(logger, "Starting autoscaler metric task");
 
+    // The newest sample queued for each object, so overlapping samples consumed by reconciliation
+    // aren't queued again.
+    let mut newest_queued = HashMap::new();
     while let Some(_) = metric_timer.next().await {
-        let metric_name = autoscaler
+        if shared.shutting_down.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let metric = match autoscaler
             .read()
             .await
             .as_ref()
             .unwrap()
             .spec
-            .metric
-            .name
-            .clone();
+            .metrics()
+            .get(metric_index)
+        {
+            Some(metric) => (*metric).clone(),
+            // The metric has been removed, a replacement task will be spawned for the update.
+            None => break,
+        };
 
-        metrics_retriever_task(
-            logger.clone(),
-            kube_config.clone(),
+        if !shared.kube_api_breaker.allow(Instant::now()) {
+            debug!(
+                logger,
+                "Skipping metric retrieval as kubernetes api calls are paused"
+            );
+            continue;
+        }
+
+        // Use the latest client, in case the service account token has been rotated.
+        let kube_client = kube_config.client().await;
+
+        metrics_retriever_task(
+            logger.clone(),
+            kube_client,
             autoscaler_namespace.clone(),
             autoscaler.clone(),
             metric_repository.clone(),
-            metric_name,
+            &mut newest_queued,
+            metric,
+            shared.clone(),
         )
         .await;
     }
@@ -447,313 +1367,1644 @@ async fn metric_retriever_loop(
     debug!(logger, "Stopped autoscaler metric task");
 }
 
-/// For each matching object, spawn a new reconciliation subtask.
+/// For each matching object, spawn a new reconciliation subtask. Returns the number of matching
+/// objects, or None if they couldn't be listed.
+#[allow(clippy::too_many_arguments)]
 async fn spawn_reconciliation_tasks(
     logger: Logger,
-    kube_config: kube::config::Configuration,
+    kube_client: APIClient,
     autoscaler: Arc<RwLock<Option<AutoScaler>>>,
     autoscaler_namespace: String,
-    strategy: AutoScalerStrategy,
-    metric_repository: Arc<Mutex<HashMap<String, Vec<f64>>>>,
-) {
+    strategies: Vec<(AutoScalerMetric, AutoScalerStrategy)>,
+    metric_reducer: AutoScalerMetricReducer,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    shared: SharedContext,
+) -> Option<usize> {
     // For each matching object run the reconciliation task.
     if let Ok(kubernetes_objects) = matching_objects(
         logger.clone(),
-        kube_config,
+        kube_client,
         autoscaler_namespace,
         autoscaler.clone(),
+        shared.clone(),
     )
     .await
     {
-        for kubernetes_object in kubernetes_objects {
-            // Resolve the name and namespace of the object.
-            let (object_namespace, object_name) = kubernetes_object.namespace_and_name();
-            debug!(logger.clone(), "Autoscaler reconciliation task found matching object";
-                "object_namespace" => &object_namespace,
-                "object_name" => &object_name);
+        let started = Instant::now();
+        // Reconcile objects in parallel, with at most a pool's worth in flight at once. Each object
+        // is reconciled in its own task, so one failing doesn't affect the others. Panics aren't
+        // isolated, the panic hook exits the whole process.
+        let results: Vec<(ObjectRef, Result<(), Error>)> = stream::iter(kubernetes_objects)
+            .map(|kubernetes_object| {
+                // Resolve the name and namespace of the object.
+                let object_ref = ObjectRef::new(&kubernetes_object);
+                debug!(logger.clone(), "Autoscaler reconciliation task found matching object";
+                    "object_namespace" => &object_ref.namespace,
+                    "object_name" => &object_ref.name);
 
-            // Run all the reconciliation tasks in parallel.
-            tokio::spawn(reconciliation_task(
-                logger.new(o!(
-                    "object_namespace" => object_namespace.clone(),
-                    "object_name" => object_name.clone())),
-                autoscaler.clone(),
-                kubernetes_object,
-                object_namespace,
-                object_name,
-                strategy.clone(),
-                metric_repository.clone(),
-            ));
+                let task = tokio::spawn(reconciliation_task(
+                    logger.new(o!(
+                        "object_kind" => object_ref.kind,
+                        "object_namespace" => object_ref.namespace.clone(),
+                        "object_name" => object_ref.name.clone())),
+                    autoscaler.clone(),
+                    kubernetes_object,
+                    object_ref.namespace.clone(),
+                    object_ref.name.clone(),
+                    strategies.clone(),
+                    metric_reducer,
+                    metric_repository.clone(),
+                    shared.clone(),
+                ));
+                async move {
+                    let result = match task.await {
+                        Ok(result) => result,
+                        Err(err) => ReconcileTask {
+                            message: err.to_string(),
+                        }
+                        .fail(),
+                    };
+                    (object_ref, result)
+                }
+            })
+            .buffer_unordered(shared.reconcile_workers)
+            .collect()
+            .await;
+
+        // Summarize the cycle, so a partial failure is obvious from a single log line.
+        let failures: Vec<String> = results
+            .iter()
+            .filter_map(|(object_ref, result)| {
+                result
+                    .as_ref()
+                    .err()
+                    .map(|err| format!("{}: {}", object_ref, err))
+            })
+            .collect();
+        if let Some(autoscaler) = autoscaler.read().await.as_ref() {
+            RECONCILE_FAILURES
+                .with_label_values(&[
+                    autoscaler.metadata.namespace.as_deref().unwrap_or_default(),
+                    &autoscaler.metadata.name,
+                ])
+                .set(failures.len() as i64);
+        }
+        if failures.is_empty() {
+            debug!(logger, "Autoscaler reconciled matching objects";
+                "succeeded" => results.len(),
+                "failed" => 0,
+                "duration_ms" => started.elapsed().as_millis() as u64);
+        } else {
+            warn!(logger, "Autoscaler failed to reconcile some matching objects";
+                "succeeded" => results.len() - failures.len(),
+                "failed" => failures.len(),
+                "failures" => format!("{:?}", failures),
+                "duration_ms" => started.elapsed().as_millis() as u64);
         }
+        Some(results.len())
+    } else {
+        None
     }
 }
 
-/// Perform any reconciliation tasks required in this iteration.
-#[allow(clippy::cognitive_complexity)]
+/// Perform any reconciliation tasks required in this iteration. Errors have already been logged,
+/// they are returned so the caller can account for them.
+#[allow(clippy::too_many_arguments)]
 async fn reconciliation_task(
     logger: Logger,
     autoscaler: Arc<RwLock<Option<AutoScaler>>>,
     kubernetes_object: KubernetesObject,
     object_namespace: String,
     object_name: String,
-    strategy: AutoScalerStrategy,
-    metric_repository: Arc<Mutex<HashMap<String, Vec<f64>>>>,
-) {
-    // Ensure the object hasn't been recently modified by another pangolin autoscaler.
-    match kubernetes_object.last_modified().await {
-        Ok(Some(last_modified)) => {
-            // Has it been long enough since our last scaling operation?
-            // We subtract 5 seconds to account for any lag in this processes reconciliation loop.
-            let utc_now: DateTime<Utc> = Utc::now();
-            if utc_now.signed_duration_since(last_modified).num_seconds()
-                < autoscaler.read().await.as_ref().unwrap().spec.interval as i64 - 5
-            {
-                warn!(logger, "Autoscaler skipping object due to having been recently modified by another process");
-                return;
-            }
-        }
-        Ok(None) => (),
+    strategies: Vec<(AutoScalerMetric, AutoScalerStrategy)>,
+    metric_reducer: AutoScalerMetricReducer,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    shared: SharedContext,
+) -> Result<(), Error> {
+    let mut decision = Decision::new(kubernetes_object.kind(), &object_namespace, &object_name);
+    let result = reconcile_object(
+        logger.clone(),
+        autoscaler,
+        kubernetes_object,
+        object_namespace,
+        object_name,
+        strategies,
+        metric_reducer,
+        metric_repository,
+        shared.clone(),
+        &mut decision,
+    )
+    .await;
+    if let Err(err) = &result {
+        decision.error = Some(format!("{}", err));
+    }
+
+    // One record of everything that went into the decision, rather than piecing it together from
+    // the log lines above.
+    info!(logger, "Recorded scaling decision";
+        "record" => serde_json::to_string(&decision).unwrap_or_default());
+    shared.decisions.record(decision);
+    result
+}
+
+/// Decide whether to scale an object, and scale it, recording each step of the decision.
+#[allow(clippy::cognitive_complexity, clippy::too_many_arguments)]
+async fn reconcile_object(
+    logger: Logger,
+    autoscaler: Arc<RwLock<Option<AutoScaler>>>,
+    kubernetes_object: KubernetesObject,
+    object_namespace: String,
+    object_name: String,
+    strategies: Vec<(AutoScalerMetric, AutoScalerStrategy)>,
+    metric_reducer: AutoScalerMetricReducer,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    shared: SharedContext,
+    decision: &mut Decision,
+) -> Result<(), Error> {
+    // Leave paused objects, and their annotations, untouched.
+    if kubernetes_object.is_paused() {
+        decision.conclude("paused");
+        info!(logger, "Autoscaler skipping object as autoscaling is paused";
+            "decision" => "paused");
+        return Ok(());
+    }
+
+    // When was the object last modified by a pangolin autoscaler?
+    let seconds_since_last_modified = match kubernetes_object.seconds_since_last_modified().await {
+        Ok(seconds_since_last_modified) => seconds_since_last_modified,
         Err(err) => {
+            decision.conclude("error");
             error!(logger, "Autoscaler skipping object due to error retrieving annotations";
+                "decision" => "error",
                 "error" => format!("{}", err));
-            return;
+            return Err(err);
         }
-    }
+    };
 
-    // Get the current number of replicas.
-    let current_replicas = match kubernetes_object.replicas().await {
-        Ok(current_replicas) => current_replicas,
+    // Get the current number of replicas, any scaling decisions are relative to the desired count.
+    let status = match kubernetes_object.scaling_status().await {
+        Ok(status) => status,
         Err(err) => {
+            decision.conclude("error");
             error!(logger, "Autoscaler skipping object due to error retrieving replica count";
+                "decision" => "error",
                 "error" => format!("{}", err));
-            return;
+            return Err(err);
         }
     };
+    let current_replicas = status.desired;
+    decision.current_replicas = Some(current_replicas);
+    let labels = [
+        kubernetes_object.kind(),
+        object_namespace.as_str(),
+        object_name.as_str(),
+    ];
+    OBSERVED_REPLICAS
+        .with_label_values(&labels)
+        .set(status.current as i64);
+    DESIRED_REPLICAS
+        .with_label_values(&labels)
+        .set(status.desired as i64);
+    READY_REPLICAS
+        .with_label_values(&labels)
+        .set(status.ready as i64);
+    CONVERGED
+        .with_label_values(&labels)
+        .set(status.converged as i64);
+
+    // Wait for the object to converge on the desired number of replicas before scaling it again.
+    if status.current != status.desired {
+        decision.conclude("converging");
+        info!(logger, "Autoscaler skipping object as it has not yet converged";
+            "decision" => "converging",
+            "observed_replicas" => status.current,
+            "current_replicas" => current_replicas);
+        return Ok(());
+    }
 
-    // Retrieve the latest window of metrics from the repository
-    let metric_name = autoscaler
+    // Scaling while an update is rolling out disturbs the object twice over, so wait for the
+    // update to settle unless the AutoScaler says otherwise.
+    let rollout_policy = autoscaler
         .read()
         .await
         .as_ref()
         .unwrap()
         .spec
-        .metric
-        .name
-        .clone();
-    let metric_key = format!("{}/{}/{}", object_namespace, object_name, metric_name);
-    let current_metric_value = {
-        if let Some(metric_values) = metric_repository.lock().await.remove(&metric_key) {
+        .rollout_policy
+        .unwrap_or(AutoScalerRolloutPolicy::Defer);
+    if kubernetes_object.is_rolling_out() && rollout_policy == AutoScalerRolloutPolicy::Defer {
+        decision.conclude("rolling_out");
+        info!(logger, "Autoscaler deferring scaling object until its update has rolled out";
+            "decision" => "rolling_out",
+            "current_replicas" => current_replicas);
+        return Ok(());
+    }
+
+    // Evaluate the autoscaling strategy of each metric we have the latest window of values for,
+    // then combine the desired numbers of replicas.
+    let mut metric_values: Vec<(String, f64)> = Vec::new();
+    let mut metric_reasons: Vec<String> = Vec::new();
+    let mut metric_desired_replicas: Vec<u32> = Vec::new();
+    for (metric, strategy) in &strategies {
+        let metric_key = format!("{}/{}/{}", object_namespace, object_name, metric.name);
+        if let Some(samples) = metric_repository.lock().await.remove(&metric_key) {
             debug!(logger, "Found collected metrics for object";
-                "count" => metric_values.len());
-            Some(metric_values.iter().sum::<f64>() / metric_values.len() as f64)
+                "metric_name" => &metric.name,
+                "count" => samples.len());
+
+            // Acting on stuck or cached values is worse than not acting at all.
+            if let Some(max_age) = metric.max_age {
+                if is_stale(
+                    &samples,
+                    ChronoDuration::seconds(max_age as i64),
+                    Utc::now(),
+                ) {
+                    decision.conclude("stale_metrics");
+                    warn!(logger, "Autoscaler skipping object due to stale metrics";
+                        "decision" => "stale_metrics",
+                        "metric_name" => &metric.name,
+                        "max_age" => max_age);
+                    return Ok(());
+                }
+            }
+
+            let value =
+                samples.iter().map(|sample| sample.value).sum::<f64>() / samples.len() as f64;
+            // Damp transient spikes with a moving average across reconciliations.
+            let value = match metric.smoothing {
+                Some(smoothing) => {
+                    let interval = autoscaler.read().await.as_ref().unwrap().spec.interval;
+                    let max_gap = Duration::from_secs(interval as u64) * SMOOTHING_GAP_INTERVALS;
+                    shared
+                        .metric_smoother
+                        .smooth(&metric_key, value, smoothing, max_gap)
+                        .await
+                }
+                None => value,
+            };
+            let delta = strategy.evaluate(current_replicas, value).unwrap_or(0);
+            let desired_replicas = (current_replicas as i64 + delta as i64)
+                .max(0)
+                .min(std::u32::MAX as i64) as u32;
+            decision.metric(&metric.name, value, desired_replicas);
+            metric_values.push((metric.name.clone(), value));
+            metric_reasons.push(format!(
+                "metric={} value={} {}",
+                metric.name,
+                value,
+                strategy.describe()
+            ));
+            metric_desired_replicas.push(desired_replicas);
         } else {
-            None
+            debug!(logger, "No collected metrics for object";
+                "metric_name" => &metric.name);
+        }
+    }
+    let metric_replicas = metric_reducer.reduce(current_replicas, &metric_desired_replicas);
+
+    // Active schedules keep the object at no fewer than their minimum number of replicas.
+    let schedules = autoscaler
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .spec
+        .schedules
+        .clone()
+        .unwrap_or_default();
+    let scheduled_min_replicas = match scheduled_min_replicas(&schedules, Utc::now()) {
+        Ok(scheduled_min_replicas) => scheduled_min_replicas,
+        Err(err) => {
+            decision.conclude("error");
+            error!(logger, "Autoscaler skipping object due to invalid schedule";
+                "decision" => "error",
+                "error" => format!("{}", err));
+            return Err(err);
         }
     };
 
-    // Do we have any metrics for this object?
-    if let Some(current_metric_value) = current_metric_value {
-        // Evaluate the autoscaling strategy.
-        if let Some(delta) = strategy.evaluate(current_replicas, current_metric_value) {
-            info!(logger, "Scaling object based on autoscaler strategy";
-                "aggregate_metric_value" => current_metric_value,
+    let scale_target = autoscaler.read().await.as_ref().unwrap().spec.scale_target(
+        current_replicas,
+        metric_replicas,
+        scheduled_min_replicas,
+    );
+    let metric_names: Vec<&String> = strategies.iter().map(|(metric, _)| &metric.name).collect();
+    let (target_replicas, scale_target) = match (scale_target, metric_values.is_empty()) {
+        (Some((target_replicas, scale_target)), _) if target_replicas != current_replicas => {
+            match scale_target {
+                ScaleTarget::Metrics => (),
+                ScaleTarget::ScheduleMinReplicas => {
+                    info!(logger, "Raising target to the minimum replicas of an active schedule";
+                        "scheduled_min_replicas" => scheduled_min_replicas,
+                        "target_replicas" => target_replicas,
+                        "current_replicas" => current_replicas);
+                    metric_reasons.push(format!(
+                        "schedule-min-replicas={}",
+                        scheduled_min_replicas.unwrap_or_default()
+                    ));
+                }
+                // Move to a known safe number of replicas rather than holding on to whatever the
+                // object was last scaled to.
+                ScaleTarget::FallbackReplicas => {
+                    warn!(logger, "Applying fallback replicas due to no available metrics";
+                        "fallback_replicas" => target_replicas,
+                        "current_replicas" => current_replicas,
+                        "metric_names" => format!("{:?}", metric_names));
+                    metric_reasons.push(format!("fallback-replicas={}", target_replicas));
+                }
+            }
+            (target_replicas, scale_target)
+        }
+        (_, false) => {
+            decision.conclude("no_change");
+            info!(logger, "Object does not require scaling";
+                "decision" => "no_change",
+                "metric_values" => format!("{:?}", metric_values),
+                "current_replicas" => current_replicas);
+            return Ok(());
+        }
+        // Without metrics the object is already where the fallback would scale it to, so it is
+        // being held there as intended.
+        (Some((fallback_replicas, ScaleTarget::FallbackReplicas)), true) => {
+            decision.conclude("fallback");
+            info!(logger, "Object is already at its fallback replicas due to no available metrics";
+                "decision" => "fallback",
+                "current_replicas" => current_replicas,
+                "fallback_replicas" => fallback_replicas,
+                "metric_names" => format!("{:?}", metric_names));
+            return Ok(());
+        }
+        (_, true) => {
+            // No metrics are available, there are some innocent causes for this, but most of the time
+            // it is concerning.
+            decision.conclude("no_metrics");
+            error!(logger, "Skipping scaling object due to no available metrics";
+                "decision" => "no_metrics",
                 "current_replicas" => current_replicas,
+                "metric_names" => format!("{:?}", metric_names));
+            return Ok(());
+        }
+    };
+    // Reaching an active schedule's minimum isn't held back by the usual reasons not to scale, and
+    // neither a schedule's minimum nor the fallback replicas by the tolerance or replica limits.
+    let hard_target = scale_target != ScaleTarget::Metrics;
+    let raising_to_schedule = scale_target == ScaleTarget::ScheduleMinReplicas;
+    let delta = replica_delta(current_replicas, target_replicas).unwrap_or(0);
+
+    info!(logger, "Scaling object based on autoscaler strategy";
+        "metric_values" => format!("{:?}", metric_values),
+        "current_replicas" => current_replicas,
+        "delta" => delta);
+
+    // Ignore small changes within the deadband, so noisy metrics don't flip-flop replicas.
+    decision.target_replicas = Some(target_replicas);
+    if !hard_target
+        && autoscaler
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .spec
+            .within_tolerance(current_replicas, target_replicas)
+    {
+        decision.conclude("tolerance");
+        info!(logger, "Object does not require scaling as target is within tolerance";
+            "decision" => "tolerance",
+            "current_replicas" => current_replicas,
+            "target_replicas" => target_replicas);
+        return Ok(());
+    }
+
+    // Ensure the object hasn't been recently modified by another pangolin autoscaler.
+    // The cooldown depends on the direction of scaling, an object that has never been
+    // scaled has always satisfied the cooldown.
+    if let Some(seconds_since_last_modified) = seconds_since_last_modified {
+        let cooldown = autoscaler
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .spec
+            .cooldown_for(delta > 0);
+        // Has it been long enough since our last scaling operation?
+        // We subtract 5 seconds to account for any lag in this processes reconciliation loop.
+        if seconds_since_last_modified < cooldown as i64 - 5 && !raising_to_schedule {
+            COOLDOWN_SUPPRESSED
+                .with_label_values(&[
+                    kubernetes_object.kind(),
+                    &object_namespace,
+                    &object_name,
+                    if delta > 0 { "up" } else { "down" },
+                ])
+                .inc();
+            decision.conclude("cooldown");
+            warn!(logger, "Autoscaler skipping object due to having been recently modified";
+                "decision" => "cooldown",
+                "seconds_since_last_modified" => seconds_since_last_modified,
+                "cooldown" => cooldown,
                 "delta" => delta);
+            return Ok(());
+        }
+    }
 
-            // Verify the action wouldn't exceed a maximum replicas limit.
-            if let Some(Some(max_replicas)) = autoscaler
+    // Freshly added pods report low utilization while they warm up, so don't scale back down
+    // straight after a scale up. Pods that start one at a time take longer to all warm up.
+    if let (true, Some(seconds_since_last_modified)) = (delta < 0, seconds_since_last_modified) {
+        let pod_management_policy = kubernetes_object.pod_management_policy();
+        let warmup = pod_management_policy.warmup_for(
+            autoscaler
                 .read()
                 .await
                 .as_ref()
                 .unwrap()
                 .spec
-                .limits
-                .as_ref()
-                .map(|limit| limit.replicas.as_ref().map(|replicas| replicas.max))
-            {
-                if current_replicas as i32 + delta >= max_replicas as i32 {
-                    warn!(logger, "Autoscaler refusing to scale up due to maximum replicas";
-                        "max_replicas" => max_replicas,
-                        "current_replicas" => current_replicas,
-                        "delta" => delta);
-                    return;
-                }
+                .warmup_for(kubernetes_object.readiness_seconds()),
+            kubernetes_object.last_scale_up_pods(),
+        );
+        if kubernetes_object.last_scaled_up() && seconds_since_last_modified < warmup as i64 - 5 {
+            decision.conclude("warmup");
+            warn!(logger, "Autoscaler refusing to scale down while new pods warm up";
+                "decision" => "warmup",
+                "seconds_since_last_modified" => seconds_since_last_modified,
+                "warmup" => warmup,
+                "pod_management_policy" => format!("{:?}", pod_management_policy),
+                "delta" => delta);
+            return Ok(());
+        }
+    }
+
+    // Low metrics from mostly unready pods, eg. while a dependency is down, may reflect a problem
+    // rather than low demand, and scaling down would make recovery harder.
+    if delta < 0
+        && autoscaler
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .spec
+            .blocks_scale_down(status.ready, status.current)
+    {
+        decision.conclude("ready_floor");
+        warn!(logger, "Autoscaler refusing to scale down while too few replicas are ready";
+            "decision" => "ready_floor",
+            "ready_replicas" => status.ready,
+            "observed_replicas" => status.current,
+            "current_replicas" => current_replicas,
+            "delta" => delta);
+        return Ok(());
+    }
+
+    // Don't scale up any further while pods from a previous scale up are still coming online.
+    if delta > 0 && !status.converged && !raising_to_schedule {
+        decision.conclude("not_ready");
+        warn!(logger, "Autoscaler refusing to scale up while replicas are not ready";
+            "decision" => "not_ready",
+            "ready_replicas" => status.ready,
+            "current_replicas" => current_replicas,
+            "delta" => delta);
+        return Ok(());
+    }
+
+    // Stop at the replica limits, refusing to scale any further past a limit already reached.
+    let mut desired_replicas = target_replicas;
+    match autoscaler
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .spec
+        .limit_replicas(current_replicas, desired_replicas)
+    {
+        LimitedReplicas::Within(_) => (),
+        LimitedReplicas::AtLimit(limit, limited_replicas) if !hard_target => {
+            decision.conclude(limit);
+            warn!(logger, "Autoscaler refusing to scale past replica limits";
+                "decision" => limit,
+                "limited_replicas" => limited_replicas,
+                "current_replicas" => current_replicas,
+                "delta" => delta);
+            return Ok(());
+        }
+        LimitedReplicas::Clamped(limit, limited_replicas)
+        | LimitedReplicas::AtLimit(limit, limited_replicas) => {
+            warn!(logger, "Autoscaler clamping replicas to replica limits";
+                "limit" => limit,
+                "limited_replicas" => limited_replicas,
+                "desired_replicas" => desired_replicas);
+            decision.clamp(limit, desired_replicas, limited_replicas);
+            desired_replicas = limited_replicas;
+        }
+    }
+
+    // An active schedule's minimum is a floor, that neither the metrics nor the limits take the
+    // object below.
+    if let Some(scheduled_min_replicas) = scheduled_min_replicas {
+        if desired_replicas < scheduled_min_replicas {
+            info!(logger, "Autoscaler raising replicas to the minimum replicas of an active schedule";
+                "scheduled_min_replicas" => scheduled_min_replicas,
+                "desired_replicas" => desired_replicas);
+            decision.clamp(
+                "schedule_min_replicas",
+                desired_replicas,
+                scheduled_min_replicas,
+            );
+            desired_replicas = scheduled_min_replicas;
+        }
+    }
+
+    // Clamp the desired number of replicas into the object's own bounds.
+    match kubernetes_object.replica_bounds() {
+        Ok((min_replicas, max_replicas)) => {
+            let clamped_replicas = desired_replicas.max(min_replicas).min(max_replicas);
+            if clamped_replicas != desired_replicas {
+                warn!(logger, "Autoscaler clamping replicas to object bounds";
+                    "min_replicas" => min_replicas,
+                    "max_replicas" => max_replicas,
+                    "desired_replicas" => desired_replicas);
+                decision.clamp("object_bounds", desired_replicas, clamped_replicas);
+                desired_replicas = clamped_replicas;
             }
+        }
+        Err(err) => {
+            decision.conclude("error");
+            error!(logger, "Autoscaler skipping object due to invalid replica bounds";
+                "decision" => "error",
+                "error" => format!("{}", err));
+            return Err(err);
+        }
+    }
 
-            // Verify the action wouldn't fall below the minimum replicas limit.
-            if let Some(Some(min_replicas)) = autoscaler
-                .read()
-                .await
-                .as_ref()
-                .unwrap()
-                .spec
-                .limits
-                .as_ref()
-                .map(|limit| limit.replicas.as_ref().map(|replicas| replicas.min))
-            {
-                if current_replicas as i32 + delta <= min_replicas as i32 {
-                    warn!(logger, "Autoscaler refusing to scale down due to minimum replicas";
-                        "min_replicas" => min_replicas,
-                        "current_replicas" => current_replicas,
-                        "delta" => delta);
-                    return;
+    // Only scale down to zero replicas if the object has explicitly opted in.
+    if desired_replicas == 0 && !kubernetes_object.allows_scale_to_zero() {
+        warn!(logger, "Autoscaler clamping to one replica as object does not allow scale to zero";
+            "current_replicas" => current_replicas,
+            "delta" => delta);
+        decision.clamp("scale_to_zero", desired_replicas, 1);
+        desired_replicas = 1;
+    }
+
+    // Move at most the maximum scale step toward the desired number of replicas, later
+    // reconciliations will continue to converge on it.
+    let max_scale_step = autoscaler
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .spec
+        .max_scale_step(current_replicas);
+    if let Some(max_scale_step) = max_scale_step {
+        let stepped_replicas = desired_replicas
+            .min(current_replicas.saturating_add(max_scale_step))
+            .max(current_replicas.saturating_sub(max_scale_step));
+        if stepped_replicas != desired_replicas {
+            info!(logger, "Autoscaler limiting scaling operation to maximum scale step";
+                "max_scale_step" => max_scale_step,
+                "desired_replicas" => desired_replicas,
+                "stepped_replicas" => stepped_replicas);
+            decision.clamp("max_scale_step", desired_replicas, stepped_replicas);
+            desired_replicas = stepped_replicas;
+        }
+    }
+
+    // Don't scale down by more pods than the object's pod disruption budgets allow.
+    if desired_replicas < current_replicas {
+        match kubernetes_object.disruptions_allowed().await {
+            Ok(Some(disruptions_allowed)) => {
+                let safe_replicas = current_replicas.saturating_sub(disruptions_allowed);
+                if desired_replicas < safe_replicas {
+                    warn!(logger, "Autoscaler clamping replicas to respect pod disruption budget";
+                        "disruptions_allowed" => disruptions_allowed,
+                        "desired_replicas" => desired_replicas,
+                        "safe_replicas" => safe_replicas);
+                    decision.clamp("disruption_budget", desired_replicas, safe_replicas);
+                    desired_replicas = safe_replicas;
                 }
             }
+            Ok(None) => (),
+            Err(err) => {
+                decision.conclude("error");
+                error!(logger, "Autoscaler skipping object due to error retrieving pod disruption budgets";
+                    "decision" => "error",
+                    "error" => format!("{}", err));
+                return Err(err);
+            }
+        }
+    }
+    // StatefulSets remove their highest ordinal pods first, which are the updated pods of a
+    // partitioned update, so don't remove the last of them and undo an in-flight canary.
+    if let Some(partition) = kubernetes_object.update_partition() {
+        let safe_replicas = keep_updated_pods(partition, current_replicas, desired_replicas);
+        if safe_replicas != desired_replicas {
+            warn!(logger, "Autoscaler clamping replicas to keep the updated pods of a partitioned update";
+                "partition" => partition,
+                "desired_replicas" => desired_replicas,
+                "safe_replicas" => safe_replicas);
+            decision.clamp("update_partition", desired_replicas, safe_replicas);
+            desired_replicas = safe_replicas;
+        } else if desired_replicas < current_replicas && current_replicas > partition {
+            warn!(logger, "Autoscaler scaling down will remove updated pods of a partitioned update";
+                "partition" => partition,
+                "desired_replicas" => desired_replicas);
+        }
+    }
+    // Don't scale up by more pods than the namespace's storage quota has room for the volumes of,
+    // they would never be scheduled.
+    if desired_replicas > current_replicas {
+        match kubernetes_object
+            .max_replicas_within_storage_quota(desired_replicas)
+            .await
+        {
+            Ok(Some(quota_replicas)) if quota_replicas <= current_replicas => {
+                decision.conclude("storage_quota");
+                warn!(logger, "Autoscaler refusing to scale up as the storage quota is exhausted";
+                    "decision" => "storage_quota",
+                    "current_replicas" => current_replicas,
+                    "desired_replicas" => desired_replicas);
+                return Ok(());
+            }
+            Ok(Some(quota_replicas)) if quota_replicas < desired_replicas => {
+                warn!(logger, "Autoscaler clamping replicas to fit the storage quota";
+                    "desired_replicas" => desired_replicas,
+                    "quota_replicas" => quota_replicas);
+                decision.clamp("storage_quota", desired_replicas, quota_replicas);
+                desired_replicas = quota_replicas;
+            }
+            Ok(_) => (),
+            Err(err) => {
+                decision.conclude("error");
+                error!(logger, "Autoscaler skipping object due to error retrieving storage quota";
+                    "decision" => "error",
+                    "error" => format!("{}", err));
+                return Err(err);
+            }
+        }
+    }
+    decision.desired_replicas = Some(desired_replicas);
+    if desired_replicas == current_replicas {
+        decision.conclude("bounded");
+        info!(logger, "Object does not require scaling after applying bounds";
+            "decision" => "bounded",
+            "current_replicas" => current_replicas);
+        return Ok(());
+    }
+
+    // In dry run mode stop here, before the object (or its annotations) are modified.
+    if shared.dry_run {
+        decision.conclude("dry_run");
+        info!(logger, "Dry run, skipping scaling object";
+            "decision" => "dry_run",
+            "current_replicas" => current_replicas,
+            "desired_replicas" => desired_replicas);
+        return Ok(());
+    }
 
-            // Scale the object.
-            if let Err(err) = kubernetes_object
-                .scale((current_replicas as i32 + delta) as u32)
+    // Give the connections on any pods being removed a chance to drain before scaling down. A
+    // server-side dry run doesn't remove any pods, so there is nothing to drain.
+    let connections = autoscaler
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .spec
+        .metric
+        .connections
+        .clone();
+    let scaling_down = desired_replicas < current_replicas && !shared.field_manager.dry_run;
+    let drain_source = match (scaling_down, connections) {
+        (true, Some(connections)) => {
+            Some(ConnectionsMetricSource::new(logger.clone(), connections))
+        }
+        _ => None,
+    };
+    let mut drained_pods = Vec::new();
+    let mut drain_id = None;
+
+    // Record what drove the scaling operation, so it can be explained after the fact.
+    let reason = metric_reasons.join(", ");
+    let scale_started = Instant::now();
+    let mut attempt = 1;
+    let result = loop {
+        // Scale the object, waiting for a free slot if too many scaling operations are in flight.
+        // The namespace's slot comes first, so a busy namespace doesn't hold global slots while it
+        // waits.
+        let namespace_permit = shared.namespace_limiter.acquire(&object_namespace).await;
+        let scale_permit = shared.scale_semaphore.acquire().await;
+        if shared.shutting_down.load(Ordering::SeqCst) {
+            decision.conclude("shutdown");
+            info!(logger, "Skipping scaling object as pangolin is shutting down";
+                "decision" => "shutdown",
+                "current_replicas" => current_replicas,
+                "desired_replicas" => desired_replicas);
+            if let Some(drain_source) = &drain_source {
+                drain_source
+                    .undrain(&kubernetes_object, &drained_pods)
+                    .await;
+            }
+            if let Some(drain_id) = drain_id {
+                shared.in_flight_drains.finish(drain_id);
+            }
+            return Ok(());
+        }
+        // Drain while holding the slots, so pods are only taken out of service once the object is
+        // about to be scaled down, and shutting down waits on the drain as on the scaling itself.
+        // Registering the pods first lets shutting down return them should it time out mid-drain.
+        if let (1, Some(drain_source)) = (attempt, &drain_source) {
+            if let Ok(Some(removed_pods)) = kubernetes_object.removed_pods(desired_replicas) {
+                drain_id = Some(shared.in_flight_drains.start(
+                    drain_source,
+                    &kubernetes_object,
+                    removed_pods,
+                ));
+            }
+            match drain_source
+                .drain(&kubernetes_object, desired_replicas)
                 .await
             {
-                error!(logger, "Autoscaler encountered error scaling object";
-                    "current_replicas" => current_replicas,
-                    "delta" => delta,
+                Ok(pods) => drained_pods = pods,
+                Err(err) => {
+                    warn!(logger, "Autoscaler failed to drain connections, scaling down anyway";
+                        "error" => format!("{}", err));
+                }
+            }
+        }
+        let result = with_timeout(
+            "scaling object",
+            shared.scale_timeout,
+            kubernetes_object.scale(desired_replicas, &reason, &shared.field_manager),
+        )
+        .await;
+        // Once scaled down the drained pods are being removed, there is nothing to return.
+        if let (Ok(_), Some(drain_id)) = (&result, drain_id) {
+            shared.in_flight_drains.finish(drain_id);
+        }
+        // Other objects may scale while this one backs off.
+        drop(scale_permit);
+        drop(namespace_permit);
+        match result {
+            // Back off and retry errors that are likely to go away, eg. rate limiting.
+            Err(ref err) if err.is_transient() && attempt < SCALE_TRANSIENT_ATTEMPTS => {
+                warn!(logger, "Autoscaler retrying scaling object after transient error";
+                    "attempt" => attempt,
                     "error" => format!("{}", err));
-                return;
+                delay_for(SCALE_RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
             }
-        } else {
-            info!(logger, "Object does not require scaling";
-                "aggregate_metric_value" => current_metric_value,
-                "current_replicas" => current_replicas);
+            result => break result,
         }
-    } else {
-        // No metrics are available, there are some innocent causes for this, but most of the time
-        // it is concerning.
-        error!(logger, "Skipping scaling object due to no available metrics";
-            "aggregate_metric_value" => current_metric_value,
+    };
+    record_kube_api_outcome(&logger, &shared, &result);
+    if let Ok(outcome) = &result {
+        if let Some(event_error) = &outcome.event_error {
+            debug!(logger, "Failed to record scaling event";
+                "error" => event_error);
+        }
+        if outcome.trimmed() {
+            warn!(logger, "Trimmed pangolin's annotations to keep the object's annotations within the size limit";
+                "trimmed_history_entries" => outcome.trimmed_history_entries,
+                "dropped_reason" => outcome.dropped_reason,
+                "dropped_annotations" => outcome.dropped_annotations);
+        }
+    }
+    if let Err(err) = result {
+        // Objects deleted since they were listed are expected, not an error.
+        if let Error::ObjectGone { .. } = err {
+            if let Some(drain_id) = drain_id {
+                shared.in_flight_drains.finish(drain_id);
+            }
+            decision.conclude("gone");
+            debug!(logger, "Object was deleted before it could be scaled";
+                "decision" => "gone");
+            return Ok(());
+        }
+        // The pods weren't removed after all, so they should go back to serving connections.
+        if let Some(drain_source) = &drain_source {
+            drain_source
+                .undrain(&kubernetes_object, &drained_pods)
+                .await;
+        }
+        if let Some(drain_id) = drain_id {
+            shared.in_flight_drains.finish(drain_id);
+        }
+        // The object's bounds changed since it was listed, or are misconfigured.
+        if let Error::ReplicasOutOfBounds { .. } | Error::InvalidReplicaBounds { .. } = err {
+            decision.conclude("out_of_bounds");
+            error!(logger, "Autoscaler refusing to scale object outside of its replica bounds";
+                "decision" => "out_of_bounds",
+                "current_replicas" => current_replicas,
+                "desired_replicas" => desired_replicas,
+                "error" => format!("{}", err));
+            return Err(err);
+        }
+        decision.conclude("error");
+        error!(logger, "Autoscaler encountered error scaling object";
+            "decision" => "error",
+            "current_replicas" => current_replicas,
+            "desired_replicas" => desired_replicas,
+            "transient" => err.is_transient(),
+            "error" => format!("{}", err));
+        return Err(err);
+    }
+
+    // The api server and its admission webhooks accepted the change, but nothing was persisted.
+    if shared.field_manager.dry_run {
+        decision.conclude("server_dry_run");
+        info!(logger, "Server-side dry run of scaling object succeeded";
+            "decision" => "server_dry_run",
             "current_replicas" => current_replicas,
-            "metric_name" => metric_name);
+            "desired_replicas" => desired_replicas,
+            "reason" => &reason,
+            "duration_ms" => scale_started.elapsed().as_millis() as u64);
+        return Ok(());
     }
+
+    decision.conclude("scaled");
+    info!(logger, "Scaled object";
+        "decision" => "scaled",
+        "current_replicas" => current_replicas,
+        "desired_replicas" => desired_replicas,
+        "reason" => &reason,
+        "duration_ms" => scale_started.elapsed().as_millis() as u64);
+
+    // Let anyone who is interested know, without holding up reconciliation.
+    if let Some(notifier) = &shared.notifier {
+        if shared.notify_all_objects || kubernetes_object.wants_scale_notifications() {
+            tokio::spawn(send_notification(
+                logger.clone(),
+                notifier.clone(),
+                ScaleNotification::new(
+                    kubernetes_object.kind(),
+                    &object_namespace,
+                    &object_name,
+                    current_replicas,
+                    desired_replicas,
+                ),
+            ));
+        }
+    }
+
+    // Make sure the next reconciliation sees the result of this scaling operation.
+    shared.list_cache.invalidate(&object_namespace).await;
+    Ok(())
 }
 
 /// Every metrics retrieval interval run task.
+#[allow(clippy::too_many_arguments)]
 async fn metrics_retriever_task(
     logger: Logger,
-    kube_config: kube::config::Configuration,
+    kube_client: APIClient,
     autoscaler_namespace: String,
     autoscaler: Arc<RwLock<Option<AutoScaler>>>,
-    metric_repository: Arc<Mutex<HashMap<String, Vec<f64>>>>,
-    metric_name: String,
+    metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>>,
+    newest_queued: &mut HashMap<String, DateTime<Utc>>,
+    metric: AutoScalerMetric,
+    shared: SharedContext,
 ) {
+    // Construct the configured metric source.
+    let metric_source =
+        MetricSource::from_configuration(logger.clone(), &metric, shared.kube_api_timeout);
+
     if let Ok(kubernetes_objects) = matching_objects(
         logger.clone(),
-        kube_config.clone(),
+        kube_client.clone(),
         autoscaler_namespace.clone(),
         autoscaler.clone(),
+        shared.clone(),
     )
     .await
     {
+        // Only objects that still match are tracked.
+        let mut previously_queued = std::mem::replace(newest_queued, HashMap::new());
         for kubernetes_object in kubernetes_objects {
             // Resolve the name and namespace of the object.
             let (object_namespace, object_name) = kubernetes_object.namespace_and_name();
+            let metric_key = format!("{}/{}/{}", object_namespace, object_name, metric.name);
+            let previous = previously_queued.remove(&metric_key);
+            if let Some(previous) = previous {
+                newest_queued.insert(metric_key.clone(), previous);
+            }
             debug!(logger.clone(), "Autoscaler metric task found matching object";
                     "object_namespace" => &object_namespace,
                     "object_name" => &object_name);
+            let logger = logger.new(o!(
+                "object_kind" => kubernetes_object.kind(),
+                "object_namespace" => object_namespace.clone(),
+                "object_name" => object_name.clone()));
 
-            // Get the list of pod ips associated with this deployment.
-            let pod_ips_and_ports = match kubernetes_object.pod_ips().await {
-                Ok(pod_ips) => pod_ips
-                    .iter()
-                    .map(|pod_ip| format!("{}:9090", pod_ip))
-                    .collect::<Vec<_>>(),
-                Err(err) => {
-                    warn!(logger, "Autoscaler metric task skipping object due to error retrieving pod ips";
-                            "error" => format!("{}", err));
-                    return;
-                }
-            };
-
-            // Pull metrics from all of the pods.
-            let current_metric_value = match retrieve_aggregate_metric(
-                logger.clone(),
-                pod_ips_and_ports.clone(),
-                &metric_name,
-            )
-            .await
-            {
-                Ok(Some(current_metric_value)) => current_metric_value,
-                Ok(None) => {
-                    warn!(
-                        logger,
-                        "Autoscaler metric task skipping object due to no available metrics"
-                    );
-                    return;
-                }
+            // Retrieve the metric from the configured metric source, range queries also return its
+            // recent history.
+            let metric_samples = match metric_source.series_for(&kubernetes_object).await {
+                Ok(metric_samples) => match metric_samples.last() {
+                    Some(current_metric_sample) => {
+                        info!(logger, "Successfully retrieved autoscaler metric from metric source";
+                            "aggregate_metric_value" => current_metric_sample.value,
+                            "metric_timestamp" => current_metric_sample.timestamp.to_rfc3339(),
+                            "count" => metric_samples.len());
+                        metric_samples
+                    }
+                    None => {
+                        warn!(logger, "Autoscaler metric task skipping object as the metric source returned no values");
+                        continue;
+                    }
+                },
                 Err(err) => {
-                    error!(logger, "Autoscaler metric task skipping object due to error pulling metrics";
-                            "error" => format!("{}", err));
-                    return;
+                    warn!(logger, "Autoscaler metric task skipping object due to error retrieving metric";
+                        "transient" => err.is_transient(),
+                        "error" => format!("{}", err));
+                    continue;
                 }
             };
 
-            info!(logger, "Successfully pulled autoscaler metric from pods";
-                    "pod_ips_and_ports" => format!("{:?}", pod_ips_and_ports),
-                    "aggregate_metric_value" => current_metric_value);
-
             // Add the received metrics into our shared queue for later pickup by the
             // reconciliation subtask.
-            let metric_key = format!("{}/{}/{}", object_namespace, object_name, metric_name);
-            {
-                let mut metric_repository_writer = metric_repository.lock().await;
-                metric_repository_writer
-                    .entry(metric_key)
-                    .or_insert_with(Vec::new)
-                    .push(current_metric_value);
+            let mut metric_repository_writer = metric_repository.lock().await;
+            let queued_samples = metric_repository_writer
+                .entry(metric_key.clone())
+                .or_insert_with(Vec::new);
+            if let Some(newest) = queue_new_samples(queued_samples, previous, metric_samples) {
+                newest_queued.insert(metric_key, newest);
             }
         }
     }
 }
 
+/// Work out the number of replicas each object managed by the AutoScalers would be scaled to,
+/// printing a line per object, without scaling anything. Each metric is retrieved once, rather
+/// than over a window. Returns the number of objects that would be scaled, and the number that
+/// couldn't be planned.
+async fn plan(
+    logger: Logger,
+    kube_config: Arc<RefreshingConfig>,
+    autoscalers: Vec<AutoScaler>,
+    shared: SharedContext,
+) -> Result<(usize, usize), Error> {
+    // Stopping at the dry run check guarantees nothing is ever scaled.
+    let shared = SharedContext {
+        dry_run: true,
+        ..shared
+    };
+    let kube_client = kube_config.client().await;
+    let (mut proposed_changes, mut failures) = (0, 0);
+    for autoscaler in autoscalers {
+        let autoscaler_namespace = String::from(autoscaler.metadata.namespace.as_ref().unwrap());
+        let logger = logger.new(o!(
+            "autoscaler_namespace" => autoscaler_namespace.clone(),
+            "autoscaler_name" => autoscaler.metadata.name.clone()));
+        let spec = autoscaler.spec.clone();
+        let strategies = match autoscaler_strategies(&spec) {
+            Some(strategies) => strategies,
+            None => {
+                error!(
+                    logger,
+                    "Autoscaler is missing required autoscaling strategy configuration"
+                );
+                failures += 1;
+                continue;
+            }
+        };
+        let metric_reducer = spec.metric_reducer.unwrap_or(shared.metric_reducer);
+        let autoscaler = Arc::new(RwLock::new(Some(autoscaler)));
+        let metric_repository: Arc<Mutex<HashMap<String, Vec<MetricSample>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        for metric in spec.metrics() {
+            metrics_retriever_task(
+                logger.new(o!("metric_name" => metric.name.clone())),
+                kube_client.clone(),
+                autoscaler_namespace.clone(),
+                autoscaler.clone(),
+                metric_repository.clone(),
+                &mut HashMap::new(),
+                metric.clone(),
+                shared.clone(),
+            )
+            .await;
+        }
+
+        let kubernetes_objects = match matching_objects(
+            logger.clone(),
+            kube_client.clone(),
+            autoscaler_namespace,
+            autoscaler.clone(),
+            shared.clone(),
+        )
+        .await
+        {
+            Ok(kubernetes_objects) => kubernetes_objects,
+            Err(_) => {
+                failures += 1;
+                continue;
+            }
+        };
+        for kubernetes_object in kubernetes_objects {
+            let object_ref = ObjectRef::new(&kubernetes_object);
+            let mut decision =
+                Decision::new(object_ref.kind, &object_ref.namespace, &object_ref.name);
+            let result = reconcile_object(
+                logger.new(o!(
+                    "object_kind" => object_ref.kind,
+                    "object_namespace" => object_ref.namespace.clone(),
+                    "object_name" => object_ref.name.clone())),
+                autoscaler.clone(),
+                kubernetes_object,
+                object_ref.namespace.clone(),
+                object_ref.name.clone(),
+                strategies.clone(),
+                metric_reducer,
+                metric_repository.clone(),
+                shared.clone(),
+                &mut decision,
+            )
+            .await;
+            if result.is_err() {
+                failures += 1;
+            }
+
+            // Only objects that reached the dry run check would have been scaled.
+            let current_replicas = decision
+                .current_replicas
+                .map_or_else(|| String::from("?"), |replicas| replicas.to_string());
+            let proposed_replicas = match (decision.action, decision.desired_replicas) {
+                ("dry_run", Some(desired_replicas)) => {
+                    proposed_changes += 1;
+                    desired_replicas.to_string()
+                }
+                _ => current_replicas.clone(),
+            };
+            println!(
+                "{}/{}: {} -> {} ({})",
+                object_ref.namespace,
+                object_ref.name,
+                current_replicas,
+                proposed_replicas,
+                decision.action
+            );
+        }
+    }
+    Ok((proposed_changes, failures))
+}
+
 /// Find a list of matching objects for an AutoScaler.
 async fn matching_objects(
     logger: Logger,
-    kube_config: kube::config::Configuration,
+    kube_client: APIClient,
     autoscaler_namespace: String,
     autoscaler: Arc<RwLock<Option<AutoScaler>>>,
+    shared: SharedContext,
 ) -> Result<Vec<KubernetesObject>, Error> {
     let resource_kind = autoscaler.read().await.as_ref().unwrap().spec.kind.clone();
-    let match_labels = autoscaler
+    let custom_resource = autoscaler
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .spec
+        .resource
+        .clone();
+    let all_namespaces = autoscaler
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .spec
+        .all_namespaces
+        .unwrap_or(false);
+    let selectors = autoscaler.read().await.as_ref().unwrap().spec.selectors();
+    // Fold the opt-in label into each selector, so unmarked objects are never listed.
+    let selectors: Vec<LabelSelector> = match &shared.opt_in_label {
+        Some((key, value)) => selectors
+            .into_iter()
+            .map(|mut selector| {
+                selector
+                    .match_expressions
+                    .get_or_insert_with(Vec::new)
+                    .push(LabelSelectorRequirement {
+                        key: key.clone(),
+                        operator: "In".into(),
+                        values: Some(vec![value.clone()]),
+                    });
+                selector
+            })
+            .collect(),
+        None => selectors,
+    };
+    let field_selector = autoscaler
         .read()
         .await
         .as_ref()
         .unwrap()
         .spec
-        .selector
-        .match_labels
+        .field_selector
         .clone();
 
+    // Cluster wide autoscalers target matching objects in every namespace.
+    let namespace = if all_namespaces {
+        None
+    } else {
+        Some(autoscaler_namespace.as_str())
+    };
+
     // Construct a client for the expected kubernetes resource kind.
-    let kubernetes_resource = match resource_kind {
-        AutoScalerKubernetesResourceKind::Deployment => {
-            KubernetesResource::Deployment(KubernetesDeploymentResource::new(
-                kube_config.clone(),
-                &autoscaler_namespace,
-                &match_labels,
-            ))
-        }
-        AutoScalerKubernetesResourceKind::ReplicaSet => {
-            KubernetesResource::ReplicaSet(KubernetesReplicaSetResource::new(
-                kube_config.clone(),
-                &autoscaler_namespace,
-                &match_labels,
-            ))
+    let kubernetes_resource = match &resource_kind {
+        AutoScalerKubernetesResourceKind::DaemonSet => KubernetesResource::DaemonSet(
+            KubernetesDaemonSetResource::new(
+                kube_client.clone(),
+                &shared.annotation_prefix,
+                namespace,
+                &selectors,
+            )
+            .with_field_selector(field_selector.as_deref())
+            .with_page_limit(shared.list_page_limit),
+        ),
+        AutoScalerKubernetesResourceKind::Deployment => KubernetesResource::Deployment(
+            KubernetesDeploymentResource::new(
+                kube_client.clone(),
+                &shared.annotation_prefix,
+                namespace,
+                &selectors,
+            )
+            .with_field_selector(field_selector.as_deref())
+            .with_page_limit(shared.list_page_limit),
+        ),
+        AutoScalerKubernetesResourceKind::ReplicaSet => KubernetesResource::ReplicaSet(
+            KubernetesReplicaSetResource::new(
+                kube_client.clone(),
+                &shared.annotation_prefix,
+                namespace,
+                &selectors,
+            )
+            .with_field_selector(field_selector.as_deref())
+            .with_page_limit(shared.list_page_limit),
+        ),
+        AutoScalerKubernetesResourceKind::ReplicationController => {
+            KubernetesResource::ReplicationController(
+                KubernetesReplicationControllerResource::new(
+                    kube_client.clone(),
+                    &shared.annotation_prefix,
+                    namespace,
+                    &selectors,
+                )
+                .with_field_selector(field_selector.as_deref())
+                .with_page_limit(shared.list_page_limit),
+            )
         }
-        AutoScalerKubernetesResourceKind::StatefulSet => {
-            KubernetesResource::StatefulSet(KubernetesStatefulSetResource::new(
-                kube_config.clone(),
-                &autoscaler_namespace,
-                &match_labels,
-            ))
+        AutoScalerKubernetesResourceKind::Rollout => KubernetesResource::Rollout(
+            KubernetesRolloutResource::new(
+                kube_client.clone(),
+                &shared.annotation_prefix,
+                namespace,
+                &selectors,
+            )
+            .with_field_selector(field_selector.as_deref())
+            .with_page_limit(shared.list_page_limit),
+        ),
+        AutoScalerKubernetesResourceKind::StatefulSet => KubernetesResource::StatefulSet(
+            KubernetesStatefulSetResource::new(
+                kube_client.clone(),
+                &shared.annotation_prefix,
+                namespace,
+                &selectors,
+            )
+            .with_field_selector(field_selector.as_deref())
+            .with_page_limit(shared.list_page_limit),
+        ),
+        AutoScalerKubernetesResourceKind::Custom => match &custom_resource {
+            Some(custom_resource) => KubernetesResource::Custom(
+                KubernetesCustomResource::new(
+                    kube_client.clone(),
+                    &shared.annotation_prefix,
+                    namespace,
+                    &selectors,
+                    custom_resource,
+                )
+                .with_field_selector(field_selector.as_deref())
+                .with_page_limit(shared.list_page_limit),
+            ),
+            None => {
+                return InvalidSpec {
+                    field: "resource",
+                    reason: "is required by the Custom kind",
+                }
+                .fail()
+            }
+        },
+    };
+    // Custom resources share a kind, so are cached by their resource.
+    let cache_kind = match (&resource_kind, &custom_resource) {
+        (AutoScalerKubernetesResourceKind::Custom, Some(custom_resource)) => {
+            format!(
+                "{}/{}/{}",
+                custom_resource.group, custom_resource.version, custom_resource.resource
+            )
         }
+        _ => format!("{:?}", resource_kind),
     };
 
-    // Get the list of matching kubernetes resources.
-    match kubernetes_resource.list().await {
-        Ok(kubernetes_objects) => Ok(kubernetes_objects),
+    // Get the list of matching kubernetes resources, recently listed objects are served from cache.
+    let kubernetes_resource = CachedKubernetesResource::new(
+        kubernetes_resource,
+        shared.list_cache.clone(),
+        &cache_kind,
+        namespace,
+        &selectors,
+    )
+    .with_field_selector(field_selector.as_deref());
+    let list_started = Instant::now();
+    let kubernetes_objects = with_timeout(
+        "listing objects",
+        shared.kube_api_timeout,
+        kubernetes_resource.list(),
+    )
+    .await;
+    record_kube_api_outcome(&logger, &shared, &kubernetes_objects);
+    let kubernetes_objects = match kubernetes_objects {
+        Ok(kubernetes_objects) => {
+            debug!(logger, "Autoscaler listed matching objects";
+                "resource_kind" => format!("{:?}", resource_kind),
+                "count" => kubernetes_objects.len(),
+                "duration_ms" => list_started.elapsed().as_millis() as u64);
+            kubernetes_objects
+        }
         Err(err) => {
             warn!(logger, "Autoscaler failed to list objects";
                 "error" => format!("{}", err));
-            Err(err)
+            return Err(err);
         }
+    };
+
+    // Skip objects whose replicas are managed by a higher level controller, so we don't fight it.
+    let kubernetes_objects: Vec<KubernetesObject> = kubernetes_objects
+        .into_iter()
+        .filter(|kubernetes_object| {
+            match kubernetes_object.controlling_owner(&shared.skip_owner_kinds) {
+                Some(owner) => {
+                    let (object_namespace, object_name) = kubernetes_object.namespace_and_name();
+                    debug!(logger, "Autoscaler skipping object controlled by a higher level controller";
+                        "object_namespace" => object_namespace,
+                        "object_name" => object_name,
+                        "owner_kind" => &owner.kind,
+                        "owner_name" => &owner.name);
+                    false
+                }
+                None => true,
+            }
+        })
+        .collect();
+
+    // Skip any objects that are already being scaled by a HorizontalPodAutoscaler.
+    let hpa_targets = horizontal_pod_autoscaler_targets(kube_client, namespace).await;
+    record_kube_api_outcome(&logger, &shared, &hpa_targets);
+    let hpa_targets = match hpa_targets {
+        Ok(hpa_targets) => hpa_targets,
+        Err(err) => {
+            warn!(logger, "Autoscaler failed to list horizontal pod autoscalers";
+                "error" => format!("{}", err));
+            return Err(err);
+        }
+    };
+    Ok(kubernetes_objects
+        .into_iter()
+        .filter(|kubernetes_object| {
+            let (object_namespace, object_name) = kubernetes_object.namespace_and_name();
+            let hpa_target = (
+                object_namespace,
+                String::from(kubernetes_object.kind()),
+                object_name,
+            );
+            if hpa_targets.contains(&hpa_target) {
+                warn!(logger, "Autoscaler skipping object targeted by a horizontal pod autoscaler";
+                    "object_namespace" => &hpa_target.0,
+                    "object_name" => &hpa_target.2);
+                false
+            } else {
+                true
+            }
+        })
+        .collect())
+}
+
+/// Wait for SIGTERM (or SIGINT), then stop reconciling, wait for any in-flight scaling operations
+/// to finish and release the leader lease, before exiting.
+async fn shutdown_on_signal(
+    logger: Logger,
+    shared: SharedContext,
+    max_concurrent_scales: usize,
+    shutdown_timeout: Duration,
+    leader_elector: Option<Arc<LeaderElector>>,
+) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            error!(logger, "Failed to install SIGTERM handler"; "error" => format!("{}", err));
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = ctrl_c() => (),
+    }
+
+    info!(logger, "Shutting down, waiting for in-flight scaling operations";
+        "shutdown_timeout" => shutdown_timeout.as_secs());
+    shared.shutting_down.store(true, Ordering::SeqCst);
+
+    // Holding every scaling permit means no scaling operations are in flight, or can start.
+    let scale_semaphore = shared.scale_semaphore.clone();
+    let _scale_permits = match timeout(shutdown_timeout, async {
+        let mut permits = Vec::with_capacity(max_concurrent_scales);
+        for _ in 0..max_concurrent_scales {
+            permits.push(scale_semaphore.acquire().await);
+        }
+        permits
+    })
+    .await
+    {
+        Ok(permits) => Some(permits),
+        Err(_) => {
+            warn!(logger, "Timed out waiting for in-flight scaling operations");
+            None
+        }
+    };
+
+    // Drains still in flight, or whose scaling down failed and is backing off, won't finish.
+    if !shared.in_flight_drains.is_empty() {
+        info!(logger, "Returning pods of unfinished drains to service");
+        shared.in_flight_drains.undrain_all().await;
+    }
+
+    if let Some(leader_elector) = leader_elector {
+        match leader_elector.release().await {
+            Ok(()) => info!(logger, "Released leader lease"),
+            Err(err) => {
+                warn!(logger, "Failed to release leader lease"; "error" => format!("{}", err))
+            }
+        }
+    }
+
+    info!(logger, "Shutdown complete");
+    exit(0);
+}
+
+/// Feed the outcome of a kubernetes api call to the circuit breaker, logging when it opens or closes.
+fn record_kube_api_outcome<T>(logger: &Logger, shared: &SharedContext, result: &Result<T, Error>) {
+    match shared.kube_api_breaker.record(result, Instant::now()) {
+        Some(CircuitState::Open { .. }) => {
+            error!(logger, "Pausing kubernetes api calls as the api server appears unhealthy";
+                "error" => result.as_ref().err().map(|err| format!("{}", err)));
+        }
+        Some(_) => info!(
+            logger,
+            "Resuming kubernetes api calls as the api server has recovered"
+        ),
+        None => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::stub::StubApiServer;
+    use crate::resource::ANNOTATION_BASE;
+    use serde_json::json;
+
+    #[test]
+    fn test_configured_autoscaler_key() {
+        let config = PangolinConfig::parse(
+            "config.yaml",
+            r#"
+autoscalers:
+  - name: web
+    namespace: default
+    spec:
+      strategy: BangBang
+      kind: Deployment
+      selector:
+        matchLabels:
+          app: web
+      metric:
+        name: connections
+        interval: 10
+      interval: 60
+      bangBang:
+        lower: 10.0
+        upper: 50.0
+"#,
+        )
+        .unwrap();
+        let autoscalers = config.autoscalers().unwrap();
+
+        // A file entry doesn't take over the task of the AutoScaler resource it shares a name with.
+        assert_eq!(autoscaler_key(&autoscalers[0]), "default/web");
+        assert_eq!(
+            configured_autoscaler_key(&autoscalers[0]),
+            "config:default/web"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_object_paused() {
+        let config = PangolinConfig::parse(
+            "config.yaml",
+            r#"
+autoscalers:
+  - name: web
+    namespace: default
+    spec:
+      strategy: BangBang
+      kind: StatefulSet
+      selector:
+        matchLabels:
+          app: web
+      metric:
+        name: connections
+        interval: 10
+      interval: 60
+      bangBang:
+        lower: 10.0
+        upper: 50.0
+"#,
+        )
+        .unwrap();
+        for &paused in &[true, false] {
+            let server = StubApiServer::spawn(move |request| match request.method.as_str() {
+                "GET" => (
+                    200,
+                    json!({ "metadata": {}, "items": [{
+                        "apiVersion": "apps/v1",
+                        "kind": "StatefulSet",
+                        "metadata": {
+                            "name": "web",
+                            "namespace": "default",
+                            "annotations": {
+                                format!("{}/paused", ANNOTATION_BASE): paused.to_string()
+                            }
+                        },
+                        "spec": {
+                            "replicas": 3,
+                            "serviceName": "web",
+                            "selector": { "matchLabels": { "app": "web" } },
+                            "template": { "metadata": { "labels": { "app": "web" } } }
+                        },
+                        "status": { "replicas": 3, "readyReplicas": 3, "updatedReplicas": 3 }
+                    }] }),
+                ),
+                _ => (200, request.body.clone()),
+            });
+            let autoscaler = config.autoscalers().unwrap().remove(0);
+            let strategies = autoscaler_strategies(&autoscaler.spec).unwrap();
+            let objects = KubernetesStatefulSetResource::new(
+                server.kube_client.clone(),
+                ANNOTATION_BASE,
+                Some("default"),
+                &[LabelSelector::default()],
+            )
+            .list()
+            .await
+            .unwrap();
+
+            // The connections are well above the upper bound.
+            let metric_repository = Arc::new(Mutex::new(HashMap::new()));
+            metric_repository.lock().await.insert(
+                String::from("default/web/connections"),
+                vec![MetricSample::new(100.0, Utc::now())],
+            );
+            let mut decision = Decision::new("StatefulSet", "default", "web");
+            reconcile_object(
+                get_logger(),
+                Arc::new(RwLock::new(Some(autoscaler))),
+                objects.into_iter().next().unwrap(),
+                "default".into(),
+                "web".into(),
+                strategies,
+                AutoScalerMetricReducer::Max,
+                metric_repository,
+                test_shared_context(),
+                &mut decision,
+            )
+            .await
+            .unwrap();
+
+            // Only the object that isn't paused is scaled.
+            if paused {
+                assert_eq!(decision.action, "paused");
+                assert!(server.patches().is_empty());
+            } else {
+                assert_eq!(decision.action, "scaled");
+                assert_eq!(server.patches()[0].body["spec"]["replicas"], json!(4));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_object_at_fallback() {
+        let config = PangolinConfig::parse(
+            "config.yaml",
+            r#"
+autoscalers:
+  - name: web
+    namespace: default
+    spec:
+      strategy: BangBang
+      kind: StatefulSet
+      selector:
+        matchLabels:
+          app: web
+      metric:
+        name: connections
+        interval: 10
+      interval: 60
+      fallbackReplicas: 3
+      bangBang:
+        lower: 10.0
+        upper: 50.0
+"#,
+        )
+        .unwrap();
+        let server = StubApiServer::spawn(|request| match request.method.as_str() {
+            "GET" => (
+                200,
+                json!({ "metadata": {}, "items": [{
+                    "apiVersion": "apps/v1",
+                    "kind": "StatefulSet",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "replicas": 3,
+                        "serviceName": "web",
+                        "selector": { "matchLabels": { "app": "web" } },
+                        "template": { "metadata": { "labels": { "app": "web" } } }
+                    },
+                    "status": { "replicas": 3, "readyReplicas": 3, "updatedReplicas": 3 }
+                }] }),
+            ),
+            _ => (200, request.body.clone()),
+        });
+        let autoscaler = config.autoscalers().unwrap().remove(0);
+        let strategies = autoscaler_strategies(&autoscaler.spec).unwrap();
+        let objects = KubernetesStatefulSetResource::new(
+            server.kube_client.clone(),
+            ANNOTATION_BASE,
+            Some("default"),
+            &[LabelSelector::default()],
+        )
+        .list()
+        .await
+        .unwrap();
+
+        // Without any metrics the object is held at its fallback replicas, which it is already at.
+        let mut decision = Decision::new("StatefulSet", "default", "web");
+        reconcile_object(
+            get_logger(),
+            Arc::new(RwLock::new(Some(autoscaler))),
+            objects.into_iter().next().unwrap(),
+            "default".into(),
+            "web".into(),
+            strategies,
+            AutoScalerMetricReducer::Max,
+            Arc::new(Mutex::new(HashMap::new())),
+            test_shared_context(),
+            &mut decision,
+        )
+        .await
+        .unwrap();
+        assert_eq!(decision.action, "fallback");
+        assert!(server.patches().is_empty());
+    }
+
+    /// Shared context for tests, without list caching, leader election or notifications.
+    fn test_shared_context() -> SharedContext {
+        SharedContext {
+            scale_semaphore: Arc::new(Semaphore::new(5)),
+            namespace_limiter: Arc::new(NamespaceLimiter::new(Vec::new())),
+            list_cache: Arc::new(KubernetesListCache::new(Duration::from_secs(0))),
+            list_page_limit: 500,
+            kube_api_timeout: Duration::from_secs(30),
+            scale_timeout: Duration::from_secs(30),
+            is_leader: Arc::new(AtomicBool::new(true)),
+            dry_run: false,
+            health: Arc::new(Health::new(Duration::from_secs(300))),
+            annotation_prefix: ANNOTATION_BASE.into(),
+            field_manager: FieldManager {
+                name: "pangolin".into(),
+                force: false,
+                patch_type: ScalePatchType::Merge,
+                dry_run: false,
+            },
+            opt_in_label: None,
+            metric_reducer: AutoScalerMetricReducer::Max,
+            kube_api_breaker: Arc::new(CircuitBreaker::new(5, Duration::from_secs(30))),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            notifier: None,
+            notify_all_objects: false,
+            reconcile_workers: 1,
+            reconcile_jitter: 0.0,
+            empty_list_backoff_limit: 1,
+            skip_owner_kinds: Vec::new(),
+            metric_smoother: Arc::new(MetricSmoother::default()),
+            decisions: Arc::new(DecisionLog::default()),
+            in_flight_drains: Arc::new(InFlightDrains::default()),
+        }
+    }
+
+    fn get_logger() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
     }
 }
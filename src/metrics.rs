@@ -16,9 +16,10 @@
 
 use crate::error::*;
 use futures::channel::mpsc::unbounded;
+use futures::future;
 use futures::sink::SinkExt;
 use futures::StreamExt;
-use slog::{debug, Logger};
+use slog::{debug, warn, Logger};
 use snafu::ResultExt;
 use std::time::Duration;
 
@@ -68,6 +69,42 @@ pub async fn retrieve_aggregate_metric(
     })
 }
 
+/// Retrieve the total number of active TCP connections across a list of pod addresses. The count is scraped
+/// from a metrics endpoint on each pod (eg. a sidecar exporter). Unreachable pods are counted as zero.
+pub async fn retrieve_total_connections(
+    logger: Logger,
+    pod_ips_and_ports: Vec<String>,
+    metric_name: &str,
+) -> f64 {
+    // All pods are interrogated concurrently.
+    let connection_counts = future::join_all(pod_ips_and_ports.into_iter().map(
+        |pod_ip_and_port| async move {
+            let connection_count = pull_metric_from_pod(&pod_ip_and_port, metric_name).await;
+            (pod_ip_and_port, connection_count)
+        },
+    ))
+    .await;
+
+    let mut total_connections = 0.0;
+    for (pod_ip_and_port, connection_count) in connection_counts {
+        match connection_count {
+            Ok(Some(connection_count)) => total_connections += connection_count,
+            Ok(None) => {
+                warn!(logger, "Pod did not report a connection count, counting as zero";
+                    "pod_ip_and_port" => pod_ip_and_port,
+                    "metric_name" => metric_name);
+            }
+            Err(err) => {
+                warn!(logger, "Pod is unreachable, counting as zero connections";
+                    "pod_ip_and_port" => pod_ip_and_port,
+                    "error" => format!("{}", err));
+            }
+        }
+    }
+
+    total_connections
+}
+
 /// Retrieve a Prometheus metric value from a pod.
 async fn pull_metric_from_pod(
     pod_ip_and_port: &str,
@@ -155,6 +192,34 @@ mod tests {
         assert_eq!(response_latency_ms.unwrap() as i32, 165);
     }
 
+    #[tokio::test]
+    async fn test_retrieve_total_connections() {
+        let port = get_available_port(8_500).unwrap();
+        let shutdown_sender = spawn_server(port, 12.0);
+        // Nothing is listening on the second port, so it is counted as zero.
+        let unreachable_port = get_available_port(port + 1).unwrap();
+
+        delay_for(Duration::from_millis(20)).await;
+
+        let total_connections = retrieve_total_connections(
+            get_logger(),
+            vec![format!("localhost:{}", port), format!("localhost:{}", port)],
+            "response_latency_ms",
+        )
+        .await;
+        let unreachable_connections = retrieve_total_connections(
+            get_logger(),
+            vec![format!("localhost:{}", unreachable_port)],
+            "response_latency_ms",
+        )
+        .await;
+
+        shutdown_sender.send(()).unwrap();
+
+        assert_eq!(total_connections as i32, 24);
+        assert_eq!(unreachable_connections as i32, 0);
+    }
+
     fn spawn_server(port: u16, response_latency_ms: f64) -> oneshot::Sender<()> {
         let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
         tokio::spawn(async move {
@@ -0,0 +1,130 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::notifier::webhook::WebhookNotifier;
+use async_trait::async_trait;
+use enum_dispatch::enum_dispatch;
+use serde::Serialize;
+use slog::{debug, warn, Logger};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Http webhook notifier implementation.
+pub mod webhook;
+
+/// Maximum number of attempts at delivering a notification.
+const NOTIFICATION_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a notification, doubled on each subsequent retry.
+const NOTIFICATION_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A scaling operation that has been performed on an object.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScaleNotification {
+    pub kind: String,
+    pub namespace: String,
+    pub name: String,
+    /// The number of replicas before scaling.
+    pub from: u32,
+    /// The number of replicas after scaling.
+    pub to: u32,
+    /// `ScaledUp` or `ScaledDown`, as in the object's events.
+    pub reason: String,
+    /// A human readable summary, eg. for Slack incoming webhooks.
+    pub text: String,
+}
+
+impl ScaleNotification {
+    pub fn new(kind: &str, namespace: &str, name: &str, from: u32, to: u32) -> Self {
+        let reason = if to > from { "ScaledUp" } else { "ScaledDown" };
+        Self {
+            kind: kind.into(),
+            namespace: namespace.into(),
+            name: name.into(),
+            from,
+            to,
+            reason: reason.into(),
+            text: format!(
+                "Pangolin scaled {} {}/{} from {} to {} replicas",
+                kind, namespace, name, from, to
+            ),
+        }
+    }
+}
+
+/// Destinations for notifications about scaling operations.
+#[enum_dispatch]
+#[derive(Clone, Debug)]
+pub enum Notifier {
+    Webhook(WebhookNotifier),
+}
+
+/// Notifier trait.
+#[async_trait]
+#[enum_dispatch(Notifier)]
+pub trait NotifierTrait {
+    /// Deliver a single notification.
+    async fn notify(&self, notification: &ScaleNotification) -> Result<(), Error>;
+}
+
+/// Deliver a notification, retrying with backoff. Failures are only logged, so this is intended
+/// to be spawned rather than awaited by reconciliation.
+pub async fn send_notification(
+    logger: Logger,
+    notifier: Arc<Notifier>,
+    notification: ScaleNotification,
+) {
+    for attempt in 1..=NOTIFICATION_ATTEMPTS {
+        match notifier.notify(&notification).await {
+            Ok(()) => {
+                debug!(logger, "Sent scaling notification"; "attempt" => attempt);
+                return;
+            }
+            Err(err) if attempt < NOTIFICATION_ATTEMPTS => {
+                debug!(logger, "Retrying scaling notification";
+                    "attempt" => attempt,
+                    "error" => format!("{}", err));
+                delay_for(NOTIFICATION_RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+            }
+            Err(err) => {
+                warn!(logger, "Failed to send scaling notification";
+                    "attempts" => attempt,
+                    "error" => format!("{}", err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale_notification() {
+        let notification = ScaleNotification::new("StatefulSet", "default", "web", 2, 4);
+        assert_eq!(notification.reason, "ScaledUp");
+        assert_eq!(
+            notification.text,
+            "Pangolin scaled StatefulSet default/web from 2 to 4 replicas"
+        );
+        assert_eq!(
+            ScaleNotification::new("StatefulSet", "default", "web", 4, 2).reason,
+            "ScaledDown"
+        );
+    }
+}
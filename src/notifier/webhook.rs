@@ -0,0 +1,65 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::notifier::{NotifierTrait, ScaleNotification};
+use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
+use snafu::{ensure, ResultExt};
+use std::time::Duration;
+
+/// Timeout for webhook requests.
+const WEBHOOK_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// POSTs each notification as JSON to an http endpoint, eg. a Slack incoming webhook.
+#[derive(Clone, Debug)]
+pub struct WebhookNotifier {
+    endpoint: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl NotifierTrait for WebhookNotifier {
+    async fn notify(&self, notification: &ScaleNotification) -> Result<(), Error> {
+        let webhook_client = reqwest::Client::builder()
+            .timeout(WEBHOOK_REQUEST_TIMEOUT)
+            .build()
+            .context(HttpClient {})?;
+        let response = webhook_client
+            .post(&self.endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(notification).context(JsonSerialization {})?)
+            .send()
+            .await
+            .context(HttpClient {})?;
+        let status = response.status();
+        ensure!(
+            status.is_success(),
+            WebhookNotification {
+                endpoint: self.endpoint.clone(),
+                message: format!("unexpected status: {}", status),
+            }
+        );
+        Ok(())
+    }
+}
@@ -14,21 +14,62 @@
  * limitations under the License.
  */
 
+use crate::error::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
 use kube::api::{Object, Void};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::HashSet;
 
 pub type AutoScaler = Object<AutoScalerSpec, Void>;
 
-/// Prefix to use for all annotations.
+/// Default prefix to use for all annotations.
 pub const ANNOTATION_BASE: &str = "pangolinscaler.com";
 
+/// Default window of Prometheus range queries (seconds).
+pub const DEFAULT_PROMETHEUS_RANGE_WINDOW: u32 = 900;
+
+/// Default step of Prometheus range queries (seconds).
+pub const DEFAULT_PROMETHEUS_RANGE_STEP: u32 = 60;
+
+/// Maximum number of points Prometheus returns per series of a range query.
+const MAX_PROMETHEUS_RANGE_POINTS: u32 = 11_000;
+
+/// Format a timestamp for storage in an annotation, always UTC with a `Z` suffix.
+pub fn format_annotation_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Parse a timestamp stored in an annotation, normalizing any offset to UTC.
+pub fn parse_annotation_timestamp(timestamp: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(timestamp).map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
 /// Kubernetes resource type to scale.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum AutoScalerKubernetesResourceKind {
+    /// DaemonSets are tracked for monitoring purposes only, they can't be scaled.
+    DaemonSet,
     Deployment,
     ReplicaSet,
+    ReplicationController,
+    /// Argo Rollouts, requires the Argo Rollouts CRD to be installed.
+    Rollout,
     StatefulSet,
+    /// Any custom resource with a scale subresource, discovered from the `resource` field.
+    Custom,
+}
+
+/// A custom resource to autoscale, by api group, version and plural resource name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerCustomResource {
+    /// The api group of the resource, eg. `example.com`.
+    pub group: String,
+    /// The api version of the resource, eg. `v1`.
+    pub version: String,
+    /// The plural resource name, eg. `widgets`.
+    pub resource: String,
 }
 
 /// Strategy to use for autoscaling.
@@ -36,14 +77,19 @@ pub enum AutoScalerKubernetesResourceKind {
 pub enum AutoScalerStrategyKind {
     /// A bang-bang control strategy.
     BangBang,
+    /// Size objects so that each replica handles a target share of the metric.
+    TargetUtilization,
+    /// A proportional controller, adjusting replicas in proportion to the metric's error.
+    Proportional,
 }
 
-/// Deployment selector.
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct AutoScalerSelector {
-    /// Autoscaling deployments matching the supplied labels.
-    #[serde(rename = "matchLabels")]
-    pub match_labels: BTreeMap<String, String>,
+/// IP address family, for pods with addresses from both families in dual-stack clusters.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum IpFamily {
+    /// IPv4 addresses.
+    IPv4,
+    /// IPv6 addresses.
+    IPv6,
 }
 
 /// Prometheus metrics configuration.
@@ -53,6 +99,182 @@ pub struct AutoScalerMetric {
     pub name: String,
     /// How often to pull Prometheus metrics (seconds).
     pub interval: u32,
+    /// Maximum age of the freshest value before the metric is considered stale and the object
+    /// isn't scaled (seconds), by default values never go stale.
+    #[serde(rename = "maxAge")]
+    pub max_age: Option<u32>,
+    /// Weight of each new value in an exponential moving average of the metric, from 0 (exclusive)
+    /// to 1, smaller factors smooth more. By default values aren't smoothed.
+    pub smoothing: Option<f64>,
+    /// Query a Prometheus server for the metric, rather than pulling it from each pod.
+    pub prometheus: Option<AutoScalerPrometheusMetric>,
+    /// Use the total number of active TCP connections to each object's pods as the metric.
+    pub connections: Option<AutoScalerConnectionsMetric>,
+    /// Request the metric from an external http endpoint.
+    pub external: Option<AutoScalerExternalMetric>,
+    /// Read the metric from gauges pushed to pangolin's StatsD listener.
+    pub statsd: Option<AutoScalerStatsdMetric>,
+    /// Use the average CPU or memory usage of each object's pods, from the metrics.k8s.io api.
+    pub utilization: Option<AutoScalerUtilizationMetric>,
+    /// Bang-bang controller configuration for this metric, overriding the AutoScaler's.
+    #[serde(rename = "bangBang")]
+    pub bang_bang: Option<AutoScalerBangBangStrategyConfiguration>,
+    /// Target utilization configuration for this metric, overriding the AutoScaler's.
+    #[serde(rename = "targetUtilization")]
+    pub target_utilization: Option<AutoScalerTargetUtilizationStrategyConfiguration>,
+    /// Proportional controller configuration for this metric, overriding the AutoScaler's.
+    pub proportional: Option<AutoScalerProportionalStrategyConfiguration>,
+}
+
+/// Whether objects are scaled while they are rolling out an update.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum AutoScalerRolloutPolicy {
+    /// Wait for the update to finish, the default.
+    Defer,
+    /// Scale regardless of the update.
+    Proceed,
+}
+
+/// How the desired numbers of replicas of an AutoScaler's metrics are combined.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum AutoScalerMetricReducer {
+    /// The largest desired number of replicas, as the Horizontal Pod Autoscaler does.
+    Max,
+    /// The current number of replicas plus the total of the changes each metric asks for.
+    Sum,
+    /// The mean desired number of replicas, rounded up.
+    Mean,
+}
+
+impl AutoScalerMetricReducer {
+    /// Combine the desired numbers of replicas of each metric, if there are any.
+    pub fn reduce(self, current_replicas: u32, desired_replicas: &[u32]) -> Option<u32> {
+        if desired_replicas.is_empty() {
+            return None;
+        }
+        Some(match self {
+            AutoScalerMetricReducer::Max => *desired_replicas.iter().max().unwrap(),
+            AutoScalerMetricReducer::Sum => {
+                let delta: i64 = desired_replicas
+                    .iter()
+                    .map(|replicas| *replicas as i64 - current_replicas as i64)
+                    .sum();
+                (current_replicas as i64 + delta)
+                    .max(0)
+                    .min(std::u32::MAX as i64) as u32
+            }
+            AutoScalerMetricReducer::Mean => {
+                let total: u64 = desired_replicas
+                    .iter()
+                    .map(|replicas| *replicas as u64)
+                    .sum();
+                let count = desired_replicas.len() as u64;
+                ((total + count - 1) / count) as u32
+            }
+        })
+    }
+}
+
+/// Prometheus server query configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerPrometheusMetric {
+    /// Base url of the Prometheus server, eg. http://prometheus:9090.
+    pub endpoint: String,
+    /// PromQL query, `{namespace}` and `{name}` are replaced with those of each object.
+    pub query: String,
+    /// Query timeout (milliseconds).
+    pub timeout: Option<u32>,
+    /// Run the query over a recent window of time, retrieving a series of values rather than
+    /// only the current one.
+    pub range: Option<AutoScalerPrometheusRange>,
+}
+
+/// Prometheus range query configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerPrometheusRange {
+    /// How far back to query (seconds), defaults to 15 minutes.
+    pub window: Option<u32>,
+    /// Resolution of the retrieved series (seconds), defaults to one minute.
+    pub step: Option<u32>,
+}
+
+/// External http endpoint configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerExternalMetric {
+    /// Url returning the current metric value as a plain number, `{namespace}` and `{name}` are
+    /// replaced with those of each object, eg. http://adapter/metrics/{namespace}/{name}.
+    pub endpoint: String,
+    /// Request timeout (milliseconds).
+    pub timeout: Option<u32>,
+}
+
+/// StatsD gauge configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerStatsdMetric {
+    /// Gauge holding each object's metric, `{namespace}` and `{name}` are replaced with those of
+    /// the object, defaults to `<metric name>.{namespace}.{name}`.
+    pub key: Option<String>,
+}
+
+/// Pod resource usage configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerUtilizationMetric {
+    /// Resource whose usage is averaged across the object's pods.
+    pub resource: AutoScalerUtilizationResource,
+}
+
+/// A resource reported by the metrics.k8s.io api.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum AutoScalerUtilizationResource {
+    /// CPU usage (cores).
+    #[serde(rename = "cpu")]
+    Cpu,
+    /// Memory usage (bytes).
+    #[serde(rename = "memory")]
+    Memory,
+}
+
+impl std::fmt::Display for AutoScalerUtilizationResource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AutoScalerUtilizationResource::Cpu => write!(f, "cpu"),
+            AutoScalerUtilizationResource::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// TCP connection count configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerConnectionsMetric {
+    /// Port of the metrics endpoint reporting each pod's connection count, eg. a sidecar exporter.
+    /// Either a port number or the name of a container port.
+    pub port: IntOrString,
+    /// Metric holding the connection count, defaults to `node_netstat_Tcp_CurrEstab`.
+    pub name: Option<String>,
+    /// Service fronting the object, when set its EndpointSlices are used to discover ready pods
+    /// rather than listing them.
+    pub service: Option<String>,
+    /// Preferred address family of the metrics endpoint, defaults to the pod's primary address.
+    #[serde(rename = "ipFamily")]
+    pub ip_family: Option<IpFamily>,
+    /// Connection draining before scaling down, for objects that remove pods in a predictable order.
+    pub drain: Option<AutoScalerConnectionsDrain>,
+}
+
+/// Connection draining configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerConnectionsDrain {
+    /// Combined number of connections on the pods being removed below which they are considered
+    /// drained, defaults to zero.
+    pub threshold: Option<f64>,
+    /// Maximum time to wait for the connections to drain before scaling down anyway (seconds).
+    /// Must be less than the AutoScaler's interval.
+    pub timeout: u32,
+    /// Pod template label selected on by the services fronting the object, but not by the object
+    /// itself. It is removed from the pods being removed, so they stop receiving new connections
+    /// while draining, and restored should scaling down fail.
+    #[serde(rename = "serviceLabel")]
+    pub service_label: Option<String>,
 }
 
 /// Maximum and minimum number of replicas configuration.
@@ -64,11 +286,50 @@ pub struct AutoScalerReplicaLimit {
     pub max: u32,
 }
 
+/// Maximum number of replicas to add or remove in a single scaling operation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerScaleStep {
+    /// Maximum number of replicas.
+    pub replicas: Option<u32>,
+    /// Maximum percentage of the current number of replicas, rounded up.
+    pub percent: Option<u32>,
+}
+
+/// Deadband around the current number of replicas, within which no scaling takes place.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerTolerance {
+    /// Number of replicas that the target may differ by.
+    pub replicas: Option<u32>,
+    /// Percentage of the current number of replicas that the target may differ by.
+    pub percent: Option<u32>,
+}
+
+/// A recurring window of time during which an object is kept at a minimum number of replicas.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerSchedule {
+    /// Cron expression for the start of the window, eg. `0 8 * * Mon-Fri`.
+    pub start: String,
+    /// Cron expression for the end of the window, eg. `0 18 * * Mon-Fri`.
+    pub end: String,
+    /// IANA timezone the cron expressions are evaluated in, defaults to UTC.
+    pub timezone: Option<String>,
+    /// Minimum number of replicas while the schedule is active.
+    #[serde(rename = "minReplicas")]
+    pub min_replicas: u32,
+}
+
 /// Resource limit configuration.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AutoScalerLimits {
     /// Maximum and minimum number of replicas.
     pub replicas: Option<AutoScalerReplicaLimit>,
+    /// Maximum scaling step, larger changes converge over several reconciliations.
+    #[serde(rename = "maxScaleStep")]
+    pub max_scale_step: Option<AutoScalerScaleStep>,
+    /// Minimum percentage of replicas that must be ready before scaling down, as low metrics
+    /// from mostly unready pods are more likely to reflect a problem than low demand.
+    #[serde(rename = "minReadyPercent")]
+    pub min_ready_percent: Option<u32>,
 }
 
 /// Bang-bang controller specific configuration.
@@ -80,6 +341,23 @@ pub struct AutoScalerBangBangStrategyConfiguration {
     pub upper: f64,
 }
 
+/// Target utilization specific configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerTargetUtilizationStrategyConfiguration {
+    /// The share of the metric each replica should handle, eg. 100 connections per replica.
+    #[serde(rename = "targetPerReplica")]
+    pub target_per_replica: f64,
+}
+
+/// Proportional controller specific configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoScalerProportionalStrategyConfiguration {
+    /// The metric value the controller aims for.
+    pub setpoint: f64,
+    /// Replicas added per unit of the metric above the setpoint (or removed below it).
+    pub gain: f64,
+}
+
 /// Pangolin AutoScaler resource specification.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AutoScalerSpec {
@@ -87,15 +365,754 @@ pub struct AutoScalerSpec {
     pub strategy: AutoScalerStrategyKind,
     /// Kubernetes resource kind of the autoscaling target.
     pub kind: AutoScalerKubernetesResourceKind,
-    /// Selector for the autoscaling target.
-    pub selector: AutoScalerSelector,
+    /// The custom resource to autoscale, required by the Custom kind.
+    pub resource: Option<AutoScalerCustomResource>,
+    /// Selector for the autoscaling target, supports both matchLabels and matchExpressions.
+    pub selector: LabelSelector,
+    /// Further selectors for autoscaling targets, objects matching any of the selectors are targeted.
+    #[serde(rename = "additionalSelectors")]
+    pub additional_selectors: Option<Vec<LabelSelector>>,
+    /// Optional field selector further narrowing the autoscaling targets, eg. `metadata.name=web`.
+    #[serde(rename = "fieldSelector")]
+    pub field_selector: Option<String>,
+    /// Target matching objects in every namespace, rather than only the AutoScaler's namespace.
+    #[serde(rename = "allNamespaces")]
+    pub all_namespaces: Option<bool>,
     /// Prometheus metrics configuration.
     pub metric: AutoScalerMetric,
+    /// Further metrics to scale on, each is evaluated separately and the results combined.
+    #[serde(rename = "additionalMetrics")]
+    pub additional_metrics: Option<Vec<AutoScalerMetric>>,
+    /// How the desired replicas of each metric are combined, defaults to the global reducer.
+    #[serde(rename = "metricReducer")]
+    pub metric_reducer: Option<AutoScalerMetricReducer>,
     /// How often to evaluate the autoscaling strategy (seconds).
     pub interval: u32,
+    /// Minimum time between scaling operations on an object (seconds), defaults to the interval.
+    pub cooldown: Option<u32>,
+    /// Minimum time before scaling an object up (seconds), defaults to the cooldown.
+    #[serde(rename = "scaleUpCooldown")]
+    pub scale_up_cooldown: Option<u32>,
+    /// Minimum time before scaling an object down (seconds), defaults to the cooldown.
+    #[serde(rename = "scaleDownCooldown")]
+    pub scale_down_cooldown: Option<u32>,
+    /// Time after a scale up during which the object won't be scaled down, while its new pods warm
+    /// up (seconds), defaults to the time its pods are expected to take to become ready.
+    pub warmup: Option<u32>,
     /// Any autoscaling limits, eg the number of replicas.
     pub limits: Option<AutoScalerLimits>,
+    /// Time based minimum numbers of replicas, applied on top of the metric driven target.
+    pub schedules: Option<Vec<AutoScalerSchedule>>,
+    /// Deadband around the current number of replicas, to avoid flip-flopping on noisy metrics.
+    pub tolerance: Option<AutoScalerTolerance>,
+    /// Number of replicas to scale objects to when none of their metrics are available, by
+    /// default they are held at their current number of replicas.
+    #[serde(rename = "fallbackReplicas")]
+    pub fallback_replicas: Option<u32>,
+    /// Whether objects part way through rolling out an update are scaled, defaults to deferring
+    /// scaling until the update has finished.
+    #[serde(rename = "rolloutPolicy")]
+    pub rollout_policy: Option<AutoScalerRolloutPolicy>,
     /// Bang-bang controller configuration.
     #[serde(rename = "bangBang")]
     pub bang_bang: Option<AutoScalerBangBangStrategyConfiguration>,
+    /// Target utilization configuration.
+    #[serde(rename = "targetUtilization")]
+    pub target_utilization: Option<AutoScalerTargetUtilizationStrategyConfiguration>,
+    /// Proportional controller configuration.
+    pub proportional: Option<AutoScalerProportionalStrategyConfiguration>,
+}
+
+impl AutoScalerSpec {
+    /// Every metric to scale on, the primary metric first.
+    pub fn metrics(&self) -> Vec<&AutoScalerMetric> {
+        let mut metrics = vec![&self.metric];
+        if let Some(additional_metrics) = &self.additional_metrics {
+            metrics.extend(additional_metrics);
+        }
+        metrics
+    }
+
+    /// Every selector for autoscaling targets, the primary selector first.
+    pub fn selectors(&self) -> Vec<LabelSelector> {
+        let mut selectors = vec![self.selector.clone()];
+        if let Some(additional_selectors) = &self.additional_selectors {
+            selectors.extend(additional_selectors.iter().cloned());
+        }
+        selectors
+    }
+
+    /// The minimum time since an object was last scaled before scaling it up, or down, again (seconds).
+    pub fn cooldown_for(&self, scale_up: bool) -> u32 {
+        let directional_cooldown = if scale_up {
+            self.scale_up_cooldown
+        } else {
+            self.scale_down_cooldown
+        };
+        directional_cooldown
+            .or(self.cooldown)
+            .unwrap_or(self.interval)
+    }
+
+    /// The time after a scale up during which scaling down is suppressed (seconds), by default the
+    /// time the object's pods are expected to take to become ready, or failing that the scale up
+    /// cooldown.
+    pub fn warmup_for(&self, readiness_seconds: Option<u32>) -> u32 {
+        self.warmup
+            .or(readiness_seconds)
+            .unwrap_or_else(|| self.cooldown_for(true))
+    }
+
+    /// Is the target number of replicas within the tolerance of the current number of replicas?
+    /// A target is acted upon once it differs by more than any one of the configured tolerances, eg.
+    /// more than one replica or more than 10% of the current replicas.
+    pub fn within_tolerance(&self, current_replicas: u32, target_replicas: u32) -> bool {
+        let tolerance = match &self.tolerance {
+            Some(tolerance) => tolerance,
+            None => return false,
+        };
+        let difference = (target_replicas as i64 - current_replicas as i64).abs();
+        let within_replicas = tolerance
+            .replicas
+            .map(|replicas| difference <= replicas as i64)
+            .unwrap_or(true);
+        let within_percent = tolerance
+            .percent
+            .map(|percent| difference * 100 <= current_replicas as i64 * percent as i64)
+            .unwrap_or(true);
+        (tolerance.replicas.is_some() || tolerance.percent.is_some())
+            && within_replicas
+            && within_percent
+    }
+
+    /// The maximum number of replicas a single scaling operation may add or remove, given the
+    /// current number of replicas.
+    pub fn max_scale_step(&self, current_replicas: u32) -> Option<u32> {
+        self.limits.as_ref()?.max_scale_step(current_replicas)
+    }
+
+    /// The number of replicas to scale toward, from the combined target of the metrics, if any of
+    /// them have values, otherwise the fallback replicas, and the minimum of any active schedules.
+    /// Objects below an active schedule's minimum are raised to it regardless of their metrics,
+    /// unless the metrics (or fallback replicas) call for more.
+    pub fn scale_target(
+        &self,
+        current_replicas: u32,
+        metric_replicas: Option<u32>,
+        scheduled_min_replicas: Option<u32>,
+    ) -> Option<(u32, ScaleTarget)> {
+        let target = match (metric_replicas, self.fallback_replicas) {
+            (Some(metric_replicas), _) => Some((metric_replicas, ScaleTarget::Metrics)),
+            (None, Some(fallback_replicas)) => {
+                Some((fallback_replicas, ScaleTarget::FallbackReplicas))
+            }
+            (None, None) => None,
+        };
+        match (target, scheduled_min_replicas) {
+            // A higher target is reached on its own terms, the schedule's minimum only binds when
+            // it is at least the target.
+            (Some((target_replicas, _)), Some(scheduled_min_replicas))
+                if target_replicas > scheduled_min_replicas =>
+            {
+                target
+            }
+            (_, Some(scheduled_min_replicas)) if current_replicas < scheduled_min_replicas => {
+                Some((scheduled_min_replicas, ScaleTarget::ScheduleMinReplicas))
+            }
+            (target, _) => target,
+        }
+    }
+
+    /// Where a scaling operation from the current to the target number of replicas lands against
+    /// the replica limits.
+    pub fn limit_replicas(&self, current_replicas: u32, target_replicas: u32) -> LimitedReplicas {
+        match self
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.replicas.as_ref())
+        {
+            Some(replicas) => replicas.limit(current_replicas, target_replicas),
+            None => LimitedReplicas::Within(target_replicas),
+        }
+    }
+
+    /// Would scaling down be unsafe, as too few of the observed replicas are ready?
+    pub fn blocks_scale_down(&self, ready_replicas: u32, observed_replicas: u32) -> bool {
+        match self
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.min_ready_percent)
+        {
+            // Without any replicas there is nothing to be ready.
+            Some(min_ready_percent) if observed_replicas > 0 => {
+                (ready_replicas as u64) * 100
+                    < (observed_replicas as u64) * min_ready_percent as u64
+            }
+            _ => false,
+        }
+    }
+
+    /// Check for settings that can't be acted upon, naming the offending field (eg.
+    /// `limits.replicas`). Fields covered by the CRD schema are checked as well, as specs may
+    /// also come from a configuration file.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |field: &str, reason: String| InvalidSpec { field, reason }.fail();
+        if self.interval == 0 {
+            return invalid("interval", "must be greater than zero".into());
+        }
+        match (&self.kind, &self.resource) {
+            (AutoScalerKubernetesResourceKind::Custom, None) => {
+                return invalid("resource", "is required by the Custom kind".into());
+            }
+            (AutoScalerKubernetesResourceKind::Custom, Some(resource))
+                if resource.group.is_empty()
+                    || resource.version.is_empty()
+                    || resource.resource.is_empty() =>
+            {
+                // Core resources have kinds of their own.
+                return invalid(
+                    "resource",
+                    "group, version and resource must all be set".into(),
+                );
+            }
+            _ => (),
+        }
+        if let Some(AutoScalerReplicaLimit { min, max }) = self
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.replicas.as_ref())
+        {
+            if min > max {
+                return invalid(
+                    "limits.replicas",
+                    format!("min ({}) is greater than max ({})", min, max),
+                );
+            }
+        }
+        if let Some(min_ready_percent) = self
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.min_ready_percent)
+        {
+            if min_ready_percent > 100 {
+                return invalid(
+                    "limits.minReadyPercent",
+                    format!("{} is greater than 100", min_ready_percent),
+                );
+            }
+        }
+        let mut metric_names = HashSet::new();
+        for (index, metric) in self.metrics().into_iter().enumerate() {
+            let metric_field = match index {
+                0 => String::from("metric"),
+                index => format!("additionalMetrics[{}]", index - 1),
+            };
+            // Collected values are keyed by metric name, so names must tell metrics apart.
+            if !metric_names.insert(&metric.name) {
+                return invalid(
+                    &format!("{}.name", metric_field),
+                    format!("{} is the name of another metric", metric.name),
+                );
+            }
+            if metric.interval == 0 {
+                return invalid(
+                    &format!("{}.interval", metric_field),
+                    "must be greater than zero".into(),
+                );
+            }
+            // Draining holds up the reconcile cycle, which must finish before the next one starts.
+            if let Some(drain) = metric
+                .connections
+                .as_ref()
+                .and_then(|connections| connections.drain.as_ref())
+            {
+                if drain.timeout >= self.interval {
+                    return invalid(
+                        &format!("{}.connections.drain.timeout", metric_field),
+                        format!(
+                            "{} must be less than the interval ({})",
+                            drain.timeout, self.interval
+                        ),
+                    );
+                }
+            }
+            if let Some(smoothing) = metric.smoothing {
+                if smoothing.is_nan() || smoothing <= 0.0 || smoothing > 1.0 {
+                    return invalid(
+                        &format!("{}.smoothing", metric_field),
+                        "must be greater than zero and at most one".into(),
+                    );
+                }
+            }
+            if let Some(range) = metric
+                .prometheus
+                .as_ref()
+                .and_then(|prometheus| prometheus.range.as_ref())
+            {
+                let window = range.window.unwrap_or(DEFAULT_PROMETHEUS_RANGE_WINDOW);
+                let step = range.step.unwrap_or(DEFAULT_PROMETHEUS_RANGE_STEP);
+                let range_field = format!("{}.prometheus.range", metric_field);
+                if step == 0 || step > window {
+                    return invalid(
+                        &range_field,
+                        format!("step ({}) must be between 1 and window ({})", step, window),
+                    );
+                }
+                // Prometheus refuses range queries that would return too many points per series.
+                if window / step > MAX_PROMETHEUS_RANGE_POINTS {
+                    return invalid(
+                        &range_field,
+                        format!(
+                            "window ({}) / step ({}) is more than {} points",
+                            window, step, MAX_PROMETHEUS_RANGE_POINTS
+                        ),
+                    );
+                }
+            }
+            // The metric's own strategy configuration takes precedence over the AutoScaler's.
+            let field = |name: &str, overridden: bool| {
+                if overridden {
+                    format!("{}.{}", metric_field, name)
+                } else {
+                    String::from(name)
+                }
+            };
+            match self.strategy {
+                AutoScalerStrategyKind::BangBang => {
+                    match metric
+                        .bang_bang
+                        .as_ref()
+                        .or_else(|| self.bang_bang.as_ref())
+                    {
+                        Some(bang_bang) if bang_bang.lower > bang_bang.upper => {
+                            return invalid(
+                                &field("bangBang", metric.bang_bang.is_some()),
+                                format!(
+                                    "lower ({}) is greater than upper ({})",
+                                    bang_bang.lower, bang_bang.upper
+                                ),
+                            );
+                        }
+                        Some(_) => (),
+                        None => {
+                            return invalid(
+                                "bangBang",
+                                "is required by the BangBang strategy".into(),
+                            );
+                        }
+                    }
+                }
+                AutoScalerStrategyKind::TargetUtilization => {
+                    match metric
+                        .target_utilization
+                        .as_ref()
+                        .or_else(|| self.target_utilization.as_ref())
+                    {
+                        Some(target_utilization)
+                            if target_utilization.target_per_replica.is_nan()
+                                || target_utilization.target_per_replica <= 0.0 =>
+                        {
+                            return invalid(
+                                &field(
+                                    "targetUtilization.targetPerReplica",
+                                    metric.target_utilization.is_some(),
+                                ),
+                                "must be greater than zero".into(),
+                            );
+                        }
+                        Some(_) => (),
+                        None => {
+                            return invalid(
+                                "targetUtilization",
+                                "is required by the TargetUtilization strategy".into(),
+                            );
+                        }
+                    }
+                }
+                AutoScalerStrategyKind::Proportional => {
+                    if metric
+                        .proportional
+                        .as_ref()
+                        .or_else(|| self.proportional.as_ref())
+                        .is_none()
+                    {
+                        return invalid(
+                            "proportional",
+                            "is required by the Proportional strategy".into(),
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What an AutoScaler is scaling an object toward.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScaleTarget {
+    /// The combined target of the metrics' strategies.
+    Metrics,
+    /// An active schedule's minimum number of replicas, which the object is below. The usual
+    /// reasons not to scale don't hold an object below it.
+    ScheduleMinReplicas,
+    /// The fallback replicas, as none of the metrics have values. Unlike the metrics' target it
+    /// isn't held back by the tolerance or the replica limits.
+    FallbackReplicas,
+}
+
+/// Where a scaling operation lands against an AutoScaler's replica limits, the limits are named
+/// as in decisions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitedReplicas {
+    /// The target passes neither limit.
+    Within(u32),
+    /// The target passes the limit, and is clamped to it.
+    Clamped(&'static str, u32),
+    /// The object is already at the limit, in the direction of scaling.
+    AtLimit(&'static str, u32),
+}
+
+impl AutoScalerReplicaLimit {
+    /// Where a scaling operation from the current to the target number of replicas lands against
+    /// these limits. Scaling past a limit stops at it, and only once it has been reached is scaling
+    /// any further refused.
+    pub fn limit(&self, current_replicas: u32, target_replicas: u32) -> LimitedReplicas {
+        if target_replicas > current_replicas && current_replicas >= self.max {
+            LimitedReplicas::AtLimit("max_replicas", self.max)
+        } else if target_replicas < current_replicas && current_replicas <= self.min {
+            LimitedReplicas::AtLimit("min_replicas", self.min)
+        } else if target_replicas > self.max {
+            LimitedReplicas::Clamped("max_replicas", self.max)
+        } else if target_replicas < self.min {
+            LimitedReplicas::Clamped("min_replicas", self.min)
+        } else {
+            LimitedReplicas::Within(target_replicas)
+        }
+    }
+}
+
+impl AutoScalerLimits {
+    /// The maximum number of replicas a single scaling operation may add or remove, given the
+    /// current number of replicas. When both a count and a percentage are set the smaller wins.
+    pub fn max_scale_step(&self, current_replicas: u32) -> Option<u32> {
+        let max_scale_step = self.max_scale_step.as_ref()?;
+        let percent_step = max_scale_step
+            .percent
+            // Always allow at least one replica, so objects can still scale up from zero.
+            .map(|percent| {
+                ((current_replicas as u64 * percent as u64 + 99) / 100)
+                    .max(1)
+                    .min(std::u32::MAX as u64) as u32
+            });
+        match (max_scale_step.replicas, percent_step) {
+            (Some(replicas), Some(percent_step)) => Some(replicas.min(percent_step)),
+            (replicas, percent_step) => replicas.or(percent_step),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, TimeZone};
+
+    /// A minimal AutoScaler spec, with the given fields added or replaced.
+    fn spec(extra: serde_json::Value) -> AutoScalerSpec {
+        let mut spec = serde_json::json!({
+            "strategy": "BangBang",
+            "kind": "StatefulSet",
+            "selector": { "matchLabels": { "app": "test" } },
+            "metric": { "name": "connections", "interval": 10 },
+            "interval": 60,
+        });
+        if let serde_json::Value::Object(extra) = extra {
+            spec.as_object_mut().unwrap().extend(extra);
+        }
+        serde_json::from_value(spec).unwrap()
+    }
+
+    #[test]
+    fn test_annotation_timestamp_round_trip() {
+        let expected = Utc.ymd(2020, 3, 29).and_hms(1, 30, 0);
+
+        // The same instant expressed with a variety of offsets.
+        for offset_hours in &[-10, -5, 0, 1, 2, 11] {
+            let timestamp = expected.with_timezone(&FixedOffset::east(offset_hours * 3600));
+            let parsed = parse_annotation_timestamp(&timestamp.to_rfc3339()).unwrap();
+            assert_eq!(parsed, expected);
+            assert_eq!(format_annotation_timestamp(parsed), "2020-03-29T01:30:00Z");
+        }
+
+        let formatted = format_annotation_timestamp(expected);
+        assert_eq!(parse_annotation_timestamp(&formatted).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_cooldown_for() {
+        let mut spec = spec(serde_json::json!({}));
+        assert_eq!(spec.cooldown_for(true), 60);
+
+        spec.cooldown = Some(120);
+        assert_eq!(spec.cooldown_for(true), 120);
+        assert_eq!(spec.cooldown_for(false), 120);
+
+        spec.scale_up_cooldown = Some(30);
+        spec.scale_down_cooldown = Some(600);
+        assert_eq!(spec.cooldown_for(true), 30);
+        assert_eq!(spec.cooldown_for(false), 600);
+
+        assert_eq!(spec.warmup_for(None), 30);
+        assert_eq!(spec.warmup_for(Some(45)), 45);
+        spec.warmup = Some(90);
+        assert_eq!(spec.warmup_for(Some(45)), 90);
+    }
+
+    #[test]
+    fn test_max_scale_step() {
+        let mut spec = spec(serde_json::json!({
+            "limits": { "maxScaleStep": { "replicas": 5 } },
+        }));
+        assert_eq!(spec.max_scale_step(20), Some(5));
+
+        let max_scale_step = spec
+            .limits
+            .as_mut()
+            .unwrap()
+            .max_scale_step
+            .as_mut()
+            .unwrap();
+        max_scale_step.percent = Some(10);
+        assert_eq!(spec.max_scale_step(20), Some(2));
+        assert_eq!(spec.max_scale_step(0), Some(1));
+
+        // Large numbers of replicas or percentages saturate rather than overflowing.
+        spec.limits.as_mut().unwrap().max_scale_step = Some(AutoScalerScaleStep {
+            replicas: None,
+            percent: Some(std::u32::MAX),
+        });
+        assert_eq!(spec.max_scale_step(std::u32::MAX), Some(std::u32::MAX));
+
+        spec.limits = None;
+        assert_eq!(spec.max_scale_step(20), None);
+    }
+
+    #[test]
+    fn test_scale_target() {
+        let spec = spec(serde_json::json!({}));
+        assert_eq!(
+            spec.scale_target(4, Some(6), None),
+            Some((6, ScaleTarget::Metrics))
+        );
+        assert_eq!(spec.scale_target(4, None, None), None);
+
+        // Schedules raise objects below their minimum, even without metrics.
+        assert_eq!(
+            spec.scale_target(4, Some(3), Some(8)),
+            Some((8, ScaleTarget::ScheduleMinReplicas))
+        );
+        // A higher metric target isn't the schedule's doing, so it is treated as any other.
+        assert_eq!(
+            spec.scale_target(4, Some(10), Some(8)),
+            Some((10, ScaleTarget::Metrics))
+        );
+        assert_eq!(
+            spec.scale_target(4, None, Some(8)),
+            Some((8, ScaleTarget::ScheduleMinReplicas))
+        );
+        // Objects at or above the minimum follow their metrics.
+        assert_eq!(
+            spec.scale_target(8, Some(6), Some(8)),
+            Some((6, ScaleTarget::Metrics))
+        );
+        assert_eq!(spec.scale_target(8, None, Some(8)), None);
+    }
+
+    #[test]
+    fn test_scale_target_fallback() {
+        let mut spec = spec(serde_json::json!({
+            "fallbackReplicas": 5,
+            "tolerance": { "replicas": 2 },
+            "limits": { "replicas": { "min": 1, "max": 5 } },
+        }));
+        // The fallback only applies without any metric values.
+        assert_eq!(
+            spec.scale_target(4, Some(3), None),
+            Some((3, ScaleTarget::Metrics))
+        );
+        assert_eq!(
+            spec.scale_target(4, None, None),
+            Some((5, ScaleTarget::FallbackReplicas))
+        );
+        assert_eq!(
+            spec.scale_target(5, None, None),
+            Some((5, ScaleTarget::FallbackReplicas))
+        );
+        // A fallback at the maximum replicas lands on the limit rather than being refused by it.
+        assert_eq!(spec.limit_replicas(4, 5), LimitedReplicas::Within(5));
+        // Active schedules still raise the fallback to their minimum.
+        assert_eq!(
+            spec.scale_target(4, None, Some(8)),
+            Some((8, ScaleTarget::ScheduleMinReplicas))
+        );
+
+        spec.fallback_replicas = None;
+        assert_eq!(spec.scale_target(4, None, None), None);
+    }
+
+    #[test]
+    fn test_limit_replicas() {
+        let mut spec = spec(serde_json::json!({
+            "limits": { "replicas": { "min": 2, "max": 10 } },
+        }));
+        assert_eq!(spec.limit_replicas(5, 8), LimitedReplicas::Within(8));
+        assert_eq!(spec.limit_replicas(5, 10), LimitedReplicas::Within(10));
+        assert_eq!(spec.limit_replicas(5, 2), LimitedReplicas::Within(2));
+        assert_eq!(
+            spec.limit_replicas(8, 12),
+            LimitedReplicas::Clamped("max_replicas", 10)
+        );
+        assert_eq!(
+            spec.limit_replicas(5, 0),
+            LimitedReplicas::Clamped("min_replicas", 2)
+        );
+        assert_eq!(
+            spec.limit_replicas(10, 12),
+            LimitedReplicas::AtLimit("max_replicas", 10)
+        );
+        assert_eq!(
+            spec.limit_replicas(2, 1),
+            LimitedReplicas::AtLimit("min_replicas", 2)
+        );
+        // Objects beyond a limit may still scale back toward it.
+        assert_eq!(
+            spec.limit_replicas(14, 12),
+            LimitedReplicas::Clamped("max_replicas", 10)
+        );
+        assert_eq!(spec.limit_replicas(1, 3), LimitedReplicas::Within(3));
+
+        spec.limits = None;
+        assert_eq!(spec.limit_replicas(10, 12), LimitedReplicas::Within(12));
+    }
+
+    #[test]
+    fn test_within_tolerance() {
+        let mut spec = spec(serde_json::json!({}));
+        assert!(!spec.within_tolerance(10, 11));
+
+        spec.tolerance = Some(AutoScalerTolerance {
+            replicas: Some(1),
+            percent: Some(10),
+        });
+        assert!(spec.within_tolerance(10, 11));
+        assert!(spec.within_tolerance(10, 9));
+        // Two replicas is more than the replica tolerance.
+        assert!(!spec.within_tolerance(20, 22));
+        // One replica is more than 10% of five replicas.
+        assert!(!spec.within_tolerance(5, 6));
+    }
+
+    #[test]
+    fn test_within_tolerance_mixed() {
+        let mut spec = spec(serde_json::json!({
+            "tolerance": { "replicas": 2, "percent": 10 },
+        }));
+        // Within both tolerances.
+        assert!(spec.within_tolerance(100, 102));
+        // Within the replica tolerance but more than 10% of ten replicas.
+        assert!(!spec.within_tolerance(10, 12));
+        // Within 10% of a hundred replicas but more than the replica tolerance.
+        assert!(!spec.within_tolerance(100, 97));
+
+        // Only a percentage, any number of replicas within it is tolerated.
+        spec.tolerance = Some(AutoScalerTolerance {
+            replicas: None,
+            percent: Some(10),
+        });
+        assert!(spec.within_tolerance(100, 110));
+        assert!(!spec.within_tolerance(100, 111));
+
+        // Only a count of replicas, regardless of the current replicas.
+        spec.tolerance = Some(AutoScalerTolerance {
+            replicas: Some(1),
+            percent: None,
+        });
+        assert!(spec.within_tolerance(1, 2));
+        assert!(!spec.within_tolerance(100, 102));
+    }
+
+    #[test]
+    fn test_blocks_scale_down() {
+        let mut spec = spec(serde_json::json!({
+            "limits": { "minReadyPercent": 50 },
+        }));
+        assert!(!spec.blocks_scale_down(5, 10));
+        assert!(spec.blocks_scale_down(4, 10));
+        assert!(spec.blocks_scale_down(0, 10));
+        assert!(!spec.blocks_scale_down(0, 0));
+
+        spec.limits = None;
+        assert!(!spec.blocks_scale_down(0, 10));
+    }
+
+    #[test]
+    fn test_metric_reducer() {
+        let desired_replicas = [3, 8, 4];
+        assert_eq!(
+            AutoScalerMetricReducer::Max.reduce(2, &desired_replicas),
+            Some(8)
+        );
+        assert_eq!(
+            AutoScalerMetricReducer::Sum.reduce(2, &desired_replicas),
+            Some(11)
+        );
+        assert_eq!(
+            AutoScalerMetricReducer::Sum.reduce(5, &desired_replicas),
+            Some(5)
+        );
+        assert_eq!(AutoScalerMetricReducer::Sum.reduce(5, &[0, 0]), Some(0));
+        assert_eq!(
+            AutoScalerMetricReducer::Mean.reduce(2, &desired_replicas),
+            Some(5)
+        );
+        assert_eq!(AutoScalerMetricReducer::Max.reduce(2, &[]), None);
+    }
+
+    #[test]
+    fn test_validate_metric_names() {
+        let mut spec = spec(serde_json::json!({
+            "additionalMetrics": [{ "name": "cpu", "interval": 10 }],
+            "bangBang": { "lower": 1.0, "upper": 2.0 },
+        }));
+        assert!(spec.validate().is_ok());
+
+        spec.additional_metrics.as_mut().unwrap()[0].name = "connections".into();
+        match spec.validate() {
+            Err(Error::InvalidSpec { field, .. }) => {
+                assert_eq!(field, "additionalMetrics[0].name")
+            }
+            _ => panic!("expected a duplicate metric name to be invalid"),
+        }
+    }
+
+    #[test]
+    fn test_validate_drain_timeout() {
+        let mut spec = spec(serde_json::json!({
+            "metric": {
+                "name": "connections",
+                "interval": 10,
+                "connections": { "port": 9100, "drain": { "timeout": 30 } },
+            },
+            "bangBang": { "lower": 1.0, "upper": 2.0 },
+        }));
+        assert!(spec.validate().is_ok());
+
+        spec.interval = 30;
+        match spec.validate() {
+            Err(Error::InvalidSpec { field, .. }) => {
+                assert_eq!(field, "metric.connections.drain.timeout")
+            }
+            _ => panic!("expected a drain timeout as long as the interval to be invalid"),
+        }
+    }
 }
@@ -0,0 +1,103 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::resource::AutoScalerSchedule;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::str::FromStr;
+
+/// The largest minimum number of replicas of any schedule that is active at the given time.
+pub fn scheduled_min_replicas(
+    schedules: &[AutoScalerSchedule],
+    now: DateTime<Utc>,
+) -> Result<Option<u32>, Error> {
+    let mut min_replicas: Option<u32> = None;
+    for schedule in schedules {
+        if is_active(schedule, now)? {
+            min_replicas = min_replicas.max(Some(schedule.min_replicas));
+        }
+    }
+    Ok(min_replicas)
+}
+
+/// A schedule is active when its window will end before it next starts.
+fn is_active(schedule: &AutoScalerSchedule, now: DateTime<Utc>) -> Result<bool, Error> {
+    let timezone = match &schedule.timezone {
+        Some(timezone) => Tz::from_str(timezone).map_err(|_| Error::InvalidTimezone {
+            timezone: timezone.clone(),
+        })?,
+        None => Tz::UTC,
+    };
+    let now = now.with_timezone(&timezone);
+
+    let next_start = parse_cron(&schedule.start)?.after(&now).next();
+    let next_end = parse_cron(&schedule.end)?.after(&now).next();
+    Ok(match (next_start, next_end) {
+        (Some(next_start), Some(next_end)) => next_end < next_start,
+        (None, Some(_)) => true,
+        (_, None) => false,
+    })
+}
+
+/// Parse a standard five field cron expression (minute, hour, day of month, month, day of week).
+fn parse_cron(expression: &str) -> Result<Schedule, Error> {
+    // The cron crate expects a leading seconds field.
+    Schedule::from_str(&format!("0 {}", expression)).map_err(|err| Error::InvalidSchedule {
+        expression: expression.to_string(),
+        reason: err.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn business_hours(timezone: Option<&str>) -> AutoScalerSchedule {
+        AutoScalerSchedule {
+            start: "0 8 * * Mon-Fri".into(),
+            end: "0 18 * * Mon-Fri".into(),
+            timezone: timezone.map(String::from),
+            min_replicas: 10,
+        }
+    }
+
+    #[test]
+    fn test_scheduled_min_replicas() {
+        let schedules = vec![business_hours(None)];
+        // Wednesday morning.
+        let wednesday = Utc.ymd(2020, 3, 4).and_hms(10, 0, 0);
+        assert_eq!(
+            scheduled_min_replicas(&schedules, wednesday).unwrap(),
+            Some(10)
+        );
+        // Wednesday evening.
+        let evening = Utc.ymd(2020, 3, 4).and_hms(19, 0, 0);
+        assert_eq!(scheduled_min_replicas(&schedules, evening).unwrap(), None);
+        // Saturday morning.
+        let saturday = Utc.ymd(2020, 3, 7).and_hms(10, 0, 0);
+        assert_eq!(scheduled_min_replicas(&schedules, saturday).unwrap(), None);
+
+        // 07:00 UTC is 08:00 in Paris during the winter.
+        let schedules = vec![business_hours(Some("Europe/Paris"))];
+        let early = Utc.ymd(2020, 3, 4).and_hms(7, 30, 0);
+        assert_eq!(scheduled_min_replicas(&schedules, early).unwrap(), Some(10));
+
+        assert!(scheduled_min_replicas(&[business_hours(Some("Mars/Olympus"))], early).is_err());
+    }
+}
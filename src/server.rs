@@ -0,0 +1,272 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::decision::DecisionLog;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use prometheus::{Encoder, TextEncoder};
+use slog::{error, info, Logger};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Readiness and liveness of the process, for kubernetes probes.
+pub struct Health {
+    started: Instant,
+    deadline: Duration,
+    ready: AtomicBool,
+    /// Milliseconds after start of the latest heartbeat.
+    last_heartbeat: AtomicU64,
+    /// Milliseconds after start each reconciliation cycle in progress began, by cycle.
+    cycles: Mutex<HashMap<u64, u64>>,
+    next_cycle: AtomicU64,
+}
+
+impl Health {
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            deadline,
+            ready: AtomicBool::new(false),
+            last_heartbeat: AtomicU64::new(0),
+            cycles: Mutex::new(HashMap::new()),
+            next_cycle: AtomicU64::new(0),
+        }
+    }
+
+    /// Mark the process as ready, eg. once a reconciliation cycle has completed successfully.
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// Record that pangolin is making progress, eg. watching for AutoScalers.
+    pub fn heartbeat(&self) {
+        self.last_heartbeat
+            .store(self.elapsed_millis(), Ordering::SeqCst);
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Live if there's been a heartbeat within the deadline, and no reconciliation cycle has been
+    /// in progress for longer than it.
+    fn is_live(&self) -> bool {
+        let elapsed = self.started.elapsed();
+        let since_start = |millis: u64| elapsed.checked_sub(Duration::from_millis(millis));
+        let last_heartbeat = self.last_heartbeat.load(Ordering::SeqCst);
+        let stalled = self
+            .cycles
+            .lock()
+            .unwrap()
+            .values()
+            .any(|started| since_start(*started).unwrap_or_default() >= self.deadline);
+        !stalled && since_start(last_heartbeat).unwrap_or_default() < self.deadline
+    }
+}
+
+/// A reconciliation cycle in progress, which counts against liveness until it is dropped.
+/// Completing a cycle is a heartbeat.
+pub struct ReconcileCycle {
+    health: Arc<Health>,
+    id: u64,
+}
+
+impl ReconcileCycle {
+    pub fn start(health: Arc<Health>) -> Self {
+        let id = health.next_cycle.fetch_add(1, Ordering::SeqCst);
+        let started = health.elapsed_millis();
+        health.cycles.lock().unwrap().insert(id, started);
+        Self { health, id }
+    }
+
+    /// Identifies the cycle, eg. in the log lines of everything it reconciles.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The cycle reconciled its AutoScaler, so pangolin is ready.
+    pub fn succeeded(&self) {
+        self.health.mark_ready();
+    }
+}
+
+impl Drop for ReconcileCycle {
+    fn drop(&mut self) {
+        self.health.cycles.lock().unwrap().remove(&self.id);
+        self.health.heartbeat();
+    }
+}
+
+/// Serve pangolin's own Prometheus metrics, and health endpoints, on the supplied address. The
+/// latest scaling decisions are only served if a decision log is supplied.
+pub async fn serve(
+    logger: Logger,
+    listen_address: SocketAddr,
+    health: Arc<Health>,
+    decisions: Option<Arc<DecisionLog>>,
+) {
+    let server = Server::bind(&listen_address).serve(make_service_fn(move |_| {
+        let health = health.clone();
+        let decisions = decisions.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let health = health.clone();
+                let decisions = decisions.clone();
+                async move { Ok::<_, Infallible>(route(request, &health, decisions.as_deref())) }
+            }))
+        }
+    }));
+
+    info!(logger, "Serving metrics and health endpoints";
+        "listen_address" => format!("{}", listen_address));
+    if let Err(err) = server.await {
+        error!(logger, "Metrics server failed"; "error" => format!("{}", err));
+    }
+}
+
+fn route(
+    request: Request<Body>,
+    health: &Health,
+    decisions: Option<&DecisionLog>,
+) -> Response<Body> {
+    match (request.uri().path(), decisions) {
+        ("/metrics", _) => metrics(),
+        ("/healthz", _) => probe(health.is_live()),
+        ("/readyz", _) => probe(health.is_ready()),
+        ("/decisions", Some(decisions)) => latest_decisions(decisions),
+        _ => Response::builder().status(404).body(Body::empty()).unwrap(),
+    }
+}
+
+fn probe(healthy: bool) -> Response<Body> {
+    if healthy {
+        Response::builder()
+            .status(200)
+            .body(Body::from("ok"))
+            .unwrap()
+    } else {
+        Response::builder().status(503).body(Body::empty()).unwrap()
+    }
+}
+
+/// Encode all registered metrics in the Prometheus text format.
+fn metrics() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(_) => Response::builder()
+            .status(200)
+            .header("Content-Type", encoder.format_type())
+            .body(Body::from(buffer))
+            .unwrap(),
+        Err(_) => Response::builder().status(500).body(Body::empty()).unwrap(),
+    }
+}
+
+/// Encode the latest decision for each object as JSON.
+fn latest_decisions(decisions: &DecisionLog) -> Response<Body> {
+    match serde_json::to_vec(&decisions.latest()) {
+        Ok(body) => Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(_) => Response::builder().status(500).body(Body::empty()).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decision::Decision;
+    use crate::telemetry::SCALE_OPERATIONS;
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let health = Health::new(Duration::from_secs(60));
+        SCALE_OPERATIONS
+            .with_label_values(&["statefulsets", "success"])
+            .inc();
+
+        let response = route(get("/metrics"), &health, None);
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("pangolin_scale_operations_total"));
+
+        let response = route(get("/missing"), &health, None);
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_decisions() {
+        let health = Health::new(Duration::from_secs(60));
+        let decisions = DecisionLog::default();
+        decisions.record(Decision::new("Deployment", "default", "web"));
+
+        // Decisions are only served when enabled.
+        assert_eq!(route(get("/decisions"), &health, None).status(), 404);
+        let response = route(get("/decisions"), &health, Some(&decisions));
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["name"], "web");
+    }
+
+    #[test]
+    fn test_health() {
+        let health = Health::new(Duration::from_secs(60));
+        assert_eq!(route(get("/readyz"), &health, None).status(), 503);
+        assert_eq!(route(get("/healthz"), &health, None).status(), 200);
+
+        health.mark_ready();
+        assert_eq!(route(get("/readyz"), &health, None).status(), 200);
+
+        // Without a heartbeat within the deadline the process is no longer live.
+        let health = Health::new(Duration::from_secs(0));
+        assert_eq!(route(get("/healthz"), &health, None).status(), 503);
+    }
+
+    #[test]
+    fn test_reconcile_cycle() {
+        let health = Arc::new(Health::new(Duration::from_millis(50)));
+        let cycle = ReconcileCycle::start(health.clone());
+        assert_eq!(route(get("/readyz"), &health, None).status(), 503);
+
+        // A cycle stalled past the deadline fails liveness, even with other heartbeats.
+        std::thread::sleep(Duration::from_millis(60));
+        health.heartbeat();
+        assert_eq!(route(get("/healthz"), &health, None).status(), 503);
+
+        // Completing it is a heartbeat, and readiness waits for a successful cycle.
+        drop(cycle);
+        assert_eq!(route(get("/healthz"), &health, None).status(), 200);
+        assert_eq!(route(get("/readyz"), &health, None).status(), 503);
+        ReconcileCycle::start(health.clone()).succeeded();
+        assert_eq!(route(get("/readyz"), &health, None).status(), 200);
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+}
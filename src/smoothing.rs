@@ -0,0 +1,153 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// The latest exponential moving average of a metric, when it was updated, and how long it may go
+/// without an update before it is outdated.
+struct SmoothedValue {
+    value: f64,
+    updated_at: Instant,
+    max_gap: Duration,
+}
+
+impl SmoothedValue {
+    /// Has the average gone without an update for longer than its maximum gap? Averages updated
+    /// after the given time, by a concurrent reconciliation, are current.
+    fn is_outdated(&self, now: Instant) -> bool {
+        now.checked_duration_since(self.updated_at)
+            .map(|gap| gap > self.max_gap)
+            .unwrap_or(false)
+    }
+}
+
+/// Exponential moving averages of each object's metrics, kept in memory across reconciliations.
+#[derive(Default)]
+pub struct MetricSmoother {
+    averages: Mutex<HashMap<String, SmoothedValue>>,
+}
+
+impl MetricSmoother {
+    /// Fold a new value into the moving average of a metric, weighted by the smoothing factor,
+    /// and return the average. The first value, and the first after a gap of longer than the
+    /// maximum, seed the average rather than being averaged with zero or an outdated value.
+    pub async fn smooth(&self, key: &str, value: f64, factor: f64, max_gap: Duration) -> f64 {
+        let mut averages = self.averages.lock().await;
+        fold(&mut averages, key, value, factor, max_gap, Instant::now())
+    }
+
+    #[cfg(test)]
+    async fn smooth_at(
+        &self,
+        key: &str,
+        value: f64,
+        factor: f64,
+        max_gap: Duration,
+        now: Instant,
+    ) -> f64 {
+        let mut averages = self.averages.lock().await;
+        fold(&mut averages, key, value, factor, max_gap, now)
+    }
+}
+
+/// Fold a new value into the moving average of a metric at the given time.
+fn fold(
+    averages: &mut HashMap<String, SmoothedValue>,
+    key: &str,
+    value: f64,
+    factor: f64,
+    max_gap: Duration,
+    now: Instant,
+) -> f64 {
+    // Forget the averages of objects that have stopped reporting, eg. deleted objects. Each
+    // average keeps its own maximum gap, as AutoScalers reconcile on different intervals.
+    averages.retain(|_, average| !average.is_outdated(now));
+    let value = match averages.get(key) {
+        Some(average) => factor * value + (1.0 - factor) * average.value,
+        None => value,
+    };
+    averages.insert(
+        key.into(),
+        SmoothedValue {
+            value,
+            updated_at: now,
+            max_gap,
+        },
+    );
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::float_cmp)]
+    #[tokio::test]
+    async fn test_smooth() {
+        let smoother = MetricSmoother::default();
+        let started = Instant::now();
+        let max_gap = Duration::from_secs(30);
+        let at = |seconds| started + Duration::from_secs(seconds);
+
+        // The first value seeds the average.
+        assert_eq!(
+            smoother.smooth_at("web", 100.0, 0.5, max_gap, at(0)).await,
+            100.0
+        );
+        assert_eq!(
+            smoother.smooth_at("web", 200.0, 0.5, max_gap, at(10)).await,
+            150.0
+        );
+        assert_eq!(
+            smoother.smooth_at("web", 150.0, 0.5, max_gap, at(20)).await,
+            150.0
+        );
+
+        // Each metric is averaged separately.
+        assert_eq!(
+            smoother.smooth_at("api", 10.0, 0.5, max_gap, at(20)).await,
+            10.0
+        );
+
+        // Averages with a longer maximum gap outlast those with a shorter one.
+        assert_eq!(
+            smoother
+                .smooth_at("worker", 50.0, 0.5, Duration::from_secs(300), at(20))
+                .await,
+            50.0
+        );
+
+        // After a gap the average is seeded again.
+        assert_eq!(
+            smoother.smooth_at("web", 400.0, 0.5, max_gap, at(60)).await,
+            400.0
+        );
+        assert_eq!(
+            smoother
+                .smooth_at("worker", 150.0, 0.5, Duration::from_secs(300), at(60))
+                .await,
+            100.0
+        );
+
+        // Values read before a concurrent update, and folded in after it, don't panic.
+        assert_eq!(
+            smoother.smooth_at("web", 200.0, 0.5, max_gap, at(50)).await,
+            300.0
+        );
+    }
+}
@@ -0,0 +1,462 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::{
+    EndpointDiscovery, KubernetesObject, KubernetesObjectTrait, PodEndpoint, PodPort,
+};
+use crate::metrics::retrieve_total_connections;
+use crate::resource::AutoScalerConnectionsMetric;
+use crate::source::{MetricSample, MetricSourceTrait};
+use async_trait::async_trait;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use slog::{debug, error, info, warn, Logger};
+use snafu::OptionExt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+/// Default metric reporting the number of established TCP connections.
+const DEFAULT_CONNECTIONS_METRIC: &str = "node_netstat_Tcp_CurrEstab";
+
+/// How often to check on the connections of draining pods.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pods that drains may have taken out of service, whose objects haven't finished scaling down.
+/// Nothing returns them to service once pangolin exits, so shutting down returns them first.
+#[derive(Default)]
+pub struct InFlightDrains {
+    next_id: AtomicU64,
+    drains: Mutex<HashMap<u64, InFlightDrain>>,
+}
+
+/// The pods a drain may have taken out of service, and how to return them.
+struct InFlightDrain {
+    source: ConnectionsMetricSource,
+    object: KubernetesObject,
+    pod_names: Vec<String>,
+}
+
+impl InFlightDrains {
+    /// Record the pods a drain is about to take out of service, returning an id to finish it with.
+    pub fn start(
+        &self,
+        source: &ConnectionsMetricSource,
+        object: &KubernetesObject,
+        pod_names: Vec<String>,
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let drain = InFlightDrain {
+            source: source.clone(),
+            object: object.clone(),
+            pod_names,
+        };
+        self.drains.lock().unwrap().insert(id, drain);
+        id
+    }
+
+    /// Forget a drain, once its pods have been removed by scaling down or returned to service.
+    pub fn finish(&self, id: u64) {
+        self.drains.lock().unwrap().remove(&id);
+    }
+
+    /// Whether every drain has been finished.
+    pub fn is_empty(&self) -> bool {
+        self.drains.lock().unwrap().is_empty()
+    }
+
+    /// Return the pods of every unfinished drain to service.
+    pub async fn undrain_all(&self) {
+        let drains: Vec<_> = self.drains.lock().unwrap().drain().collect();
+        for (_, drain) in drains {
+            drain.source.undrain(&drain.object, &drain.pod_names).await;
+        }
+    }
+}
+
+/// Retrieves the total number of active TCP connections across all of an object's pods.
+#[derive(Clone, Debug)]
+pub struct ConnectionsMetricSource {
+    logger: Logger,
+    configuration: AutoScalerConnectionsMetric,
+}
+
+impl ConnectionsMetricSource {
+    pub fn new(logger: Logger, configuration: AutoScalerConnectionsMetric) -> Self {
+        Self {
+            logger,
+            configuration,
+        }
+    }
+
+    /// Drain the connections from the pods that scaling down to the given number of replicas will
+    /// remove. With a service label configured the pods are first taken out of their services, so
+    /// they stop receiving new connections, then their connections are waited on until the drain
+    /// timeout expires. Returns the pods taken out of service, to return them should scaling down
+    /// fail. Objects that don't remove pods in a predictable order, or sources without draining
+    /// configured, return immediately.
+    pub async fn drain(
+        &self,
+        object: &KubernetesObject,
+        replicas: u32,
+    ) -> Result<Vec<String>, Error> {
+        let drain = match &self.configuration.drain {
+            Some(drain) => drain,
+            None => return Ok(Vec::new()),
+        };
+        let removed_pods = match object.removed_pods(replicas)? {
+            Some(removed_pods) => removed_pods,
+            None => return Ok(Vec::new()),
+        };
+        let threshold = drain.threshold.unwrap_or(0.0);
+        let deadline = Instant::now() + Duration::from_secs(drain.timeout as u64);
+
+        // Find the pods before they are taken out of service, after which they are no longer
+        // selected by their labels or listed in their service's EndpointSlices.
+        let draining_pod_endpoints: Vec<String> = self
+            .ready_pod_endpoints(object)
+            .await?
+            .into_iter()
+            .filter(|pod_endpoint| match &pod_endpoint.pod_name {
+                Some(pod_name) => removed_pods.contains(pod_name),
+                None => false,
+            })
+            .map(|pod_endpoint| pod_endpoint.to_string())
+            .collect();
+
+        let out_of_service = match &drain.service_label {
+            Some(service_label) => {
+                if let Err(err) = object
+                    .set_pods_serving(&removed_pods, service_label, false)
+                    .await
+                {
+                    // Return any pods that were taken out of service before the failure.
+                    self.undrain(object, &removed_pods).await;
+                    return Err(err);
+                }
+                info!(self.logger, "Took pods being removed out of service";
+                    "removed_pods" => removed_pods.join(","),
+                    "service_label" => service_label);
+                removed_pods.clone()
+            }
+            None => Vec::new(),
+        };
+
+        loop {
+            let connections = if !draining_pod_endpoints.is_empty() {
+                retrieve_total_connections(
+                    self.logger.clone(),
+                    draining_pod_endpoints.clone(),
+                    self.metric_name(),
+                )
+                .await
+            } else {
+                0.0
+            };
+            if connections <= threshold {
+                info!(self.logger, "Drained connections from pods being removed";
+                    "removed_pods" => removed_pods.join(","),
+                    "connections" => connections);
+                return Ok(out_of_service);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(self.logger, "Drain timeout expired, scaling down with active connections";
+                    "removed_pods" => removed_pods.join(","),
+                    "connections" => connections,
+                    "drain_timeout" => drain.timeout);
+                return Ok(out_of_service);
+            }
+
+            debug!(self.logger, "Waiting for connections to drain from pods being removed";
+                "connections" => connections,
+                "threshold" => threshold);
+            delay_for(DRAIN_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    /// Return pods taken out of service by a drain, eg. after scaling down failed. Failures are
+    /// logged rather than returned, as there is nothing more to be done about them.
+    pub async fn undrain(&self, object: &KubernetesObject, pod_names: &[String]) {
+        let service_label = match self
+            .configuration
+            .drain
+            .as_ref()
+            .and_then(|drain| drain.service_label.as_ref())
+        {
+            Some(service_label) if !pod_names.is_empty() => service_label,
+            _ => return,
+        };
+        match object
+            .set_pods_serving(pod_names, service_label, true)
+            .await
+        {
+            Ok(()) => info!(self.logger, "Returned drained pods to service";
+                "pods" => pod_names.join(","),
+                "service_label" => service_label),
+            Err(err) => error!(self.logger, "Failed to return drained pods to service";
+                "pods" => pod_names.join(","),
+                "service_label" => service_label,
+                "error" => format!("{}", err)),
+        }
+    }
+
+    /// The endpoints of the object's ready pods, warning about any that don't expose the port.
+    async fn ready_pod_endpoints(
+        &self,
+        object: &KubernetesObject,
+    ) -> Result<Vec<PodEndpoint>, Error> {
+        let pod_endpoints = object
+            .ready_pod_endpoints(
+                &self.endpoint_discovery(),
+                &self.pod_port()?,
+                self.configuration.ip_family,
+            )
+            .await?;
+        if !pod_endpoints.skipped.is_empty() {
+            warn!(self.logger, "Skipping pods without the connections metric port";
+                "skipped" => pod_endpoints.skipped.join(","),
+                "port" => format!("{:?}", self.configuration.port));
+        }
+        Ok(pod_endpoints.endpoints)
+    }
+
+    /// The metric holding each pod's connection count.
+    fn metric_name(&self) -> &str {
+        self.configuration
+            .name
+            .as_deref()
+            .unwrap_or(DEFAULT_CONNECTIONS_METRIC)
+    }
+
+    /// Discover pods through the service's EndpointSlices, if one has been configured.
+    fn endpoint_discovery(&self) -> EndpointDiscovery {
+        match &self.configuration.service {
+            Some(service_name) => EndpointDiscovery::EndpointSlices(service_name.clone()),
+            None => EndpointDiscovery::Pods,
+        }
+    }
+
+    /// The metrics port to interrogate on each pod.
+    fn pod_port(&self) -> Result<PodPort, Error> {
+        match &self.configuration.port {
+            IntOrString::Int(port) => Ok(PodPort::Number(
+                u16::try_from(*port)
+                    .ok()
+                    .filter(|port| *port > 0)
+                    .context(InvalidPort { port: *port })?,
+            )),
+            IntOrString::String(name) => Ok(PodPort::Name(name.clone())),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for ConnectionsMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        // Pods that are failing their readiness checks aren't serving connections.
+        let pod_endpoints = self.ready_pod_endpoints(object).await?;
+        let total_connections = retrieve_total_connections(
+            self.logger.clone(),
+            pod_endpoints
+                .iter()
+                .map(|pod_endpoint| pod_endpoint.to_string())
+                .collect(),
+            self.metric_name(),
+        )
+        .await;
+        // The connection counts were observed when they were scraped.
+        Ok(MetricSample::now(total_connections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::statefulset::KubernetesStatefulSetObject;
+    use crate::kubernetes::stub::{status_response, StubApiServer};
+    use crate::resource::ANNOTATION_BASE;
+    use kube::api::ObjectMeta;
+    use serde_json::{json, Value};
+    use slog::{o, Drain};
+
+    /// A stub api server listing the pods of a StatefulSet with three replicas, each reporting
+    /// their connections on the metrics server's port.
+    fn spawn_pods_server(metrics_port: u16) -> StubApiServer {
+        let pods: Vec<Value> = (0..3)
+            .map(|ordinal| {
+                json!({
+                    "metadata": {
+                        "name": format!("web-{}", ordinal),
+                        "namespace": "default",
+                        "labels": { "app": "web", "serving": "true" }
+                    },
+                    "spec": {
+                        "containers": [{
+                            "name": "web",
+                            "ports": [{ "name": "metrics", "containerPort": metrics_port }]
+                        }]
+                    },
+                    "status": {
+                        "phase": "Running",
+                        "podIP": "127.0.0.1",
+                        "conditions": [{ "type": "Ready", "status": "True" }]
+                    }
+                })
+            })
+            .collect();
+        StubApiServer::spawn(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/api/v1/namespaces/default/pods") => {
+                    (200, json!({ "metadata": {}, "items": pods.clone() }))
+                }
+                ("PATCH", "/api/v1/namespaces/default/pods/web-2") => (200, pods[2].clone()),
+                _ => status_response(404, "NotFound", "not found"),
+            }
+        })
+    }
+
+    fn statefulset_object(server: &StubApiServer) -> KubernetesObject {
+        let spec = serde_json::from_value(json!({
+            "replicas": 3,
+            "serviceName": "web",
+            "selector": { "matchLabels": { "app": "web" } },
+            "template": { "metadata": { "labels": { "app": "web", "serving": "true" } } }
+        }))
+        .unwrap();
+        KubernetesObject::StatefulSet(KubernetesStatefulSetObject::new(
+            server.kube_client.clone(),
+            ANNOTATION_BASE,
+            "default",
+            &ObjectMeta {
+                name: "web".into(),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            &spec,
+            &None,
+        ))
+    }
+
+    fn connections_source(drain: Value) -> ConnectionsMetricSource {
+        ConnectionsMetricSource::new(
+            get_logger(),
+            serde_json::from_value(json!({ "port": "metrics", "drain": drain })).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let metrics_server =
+            StubApiServer::spawn(|_| (200, Value::String("node_netstat_Tcp_CurrEstab 0\n".into())));
+        let server = spawn_pods_server(metrics_server.port);
+        let object = statefulset_object(&server);
+        let source = connections_source(json!({ "timeout": 30, "serviceLabel": "serving" }));
+
+        // The pod being removed is taken out of service before its connections are checked.
+        let drained_pods = source.drain(&object, 2).await.unwrap();
+        assert_eq!(drained_pods, vec![String::from("web-2")]);
+        let patches = server.patches();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(
+            patches[0].body,
+            json!({ "metadata": { "labels": { "serving": null } } })
+        );
+        assert_eq!(metrics_server.requests().len(), 1);
+
+        // Should scaling down fail, it is returned to service with the template's value.
+        source.undrain(&object, &drained_pods).await;
+        let patches = server.patches();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[1].body,
+            json!({ "metadata": { "labels": { "serving": "true" } } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let metrics_server = StubApiServer::spawn(|_| {
+            (200, Value::String("node_netstat_Tcp_CurrEstab 10\n".into()))
+        });
+        let server = spawn_pods_server(metrics_server.port);
+        let object = statefulset_object(&server);
+
+        // Without a service label the connections are only waited on, until the timeout expires.
+        let source = connections_source(json!({ "timeout": 0, "threshold": 5 }));
+        assert!(source.drain(&object, 2).await.unwrap().is_empty());
+        assert!(server.patches().is_empty());
+        assert_eq!(metrics_server.requests().len(), 1);
+
+        // Scaling up doesn't remove any pods.
+        let source = connections_source(json!({ "timeout": 0, "serviceLabel": "serving" }));
+        assert!(source.drain(&object, 3).await.unwrap().is_empty());
+        assert!(server.patches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drain_rejects_selector_label() {
+        let metrics_server =
+            StubApiServer::spawn(|_| (200, Value::String("node_netstat_Tcp_CurrEstab 0\n".into())));
+        let server = spawn_pods_server(metrics_server.port);
+        let object = statefulset_object(&server);
+
+        // Removing a label the StatefulSet selects on would orphan the pod.
+        let source = connections_source(json!({ "timeout": 30, "serviceLabel": "app" }));
+        match source.drain(&object, 2).await {
+            Err(Error::InvalidSpec { field, .. }) => {
+                assert_eq!(field, "connections.drain.serviceLabel")
+            }
+            result => panic!("expected a selector label to be rejected, got {:?}", result),
+        }
+        assert!(server.patches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_drains() {
+        let metrics_server =
+            StubApiServer::spawn(|_| (200, Value::String("node_netstat_Tcp_CurrEstab 0\n".into())));
+        let server = spawn_pods_server(metrics_server.port);
+        let object = statefulset_object(&server);
+        let source = connections_source(json!({ "timeout": 30, "serviceLabel": "serving" }));
+        let in_flight_drains = InFlightDrains::default();
+
+        // A drain that was finished, eg. because its object scaled down, is left alone.
+        let finished = in_flight_drains.start(&source, &object, vec!["web-2".into()]);
+        in_flight_drains.finish(finished);
+        assert!(in_flight_drains.is_empty());
+
+        // Shutting down returns the pods of a drain that didn't finish to service.
+        in_flight_drains.start(&source, &object, source.drain(&object, 2).await.unwrap());
+        assert!(!in_flight_drains.is_empty());
+        in_flight_drains.undrain_all().await;
+        assert!(in_flight_drains.is_empty());
+        let patches = server.patches();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[1].body,
+            json!({ "metadata": { "labels": { "serving": "true" } } })
+        );
+    }
+
+    fn get_logger() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+}
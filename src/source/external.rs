@@ -0,0 +1,124 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::{KubernetesObject, KubernetesObjectTrait};
+use crate::resource::AutoScalerExternalMetric;
+use crate::source::{MetricSample, MetricSourceTrait};
+use async_trait::async_trait;
+use snafu::{ensure, ResultExt};
+use std::time::Duration;
+
+/// Default timeout for external metric requests (milliseconds).
+const DEFAULT_REQUEST_TIMEOUT_MS: u32 = 1_000;
+
+/// Retrieves metrics from an external http endpoint, eg. an adapter in front of StatsD or a
+/// queueing system. The endpoint responds with the current value as a plain number.
+#[derive(Clone, Debug)]
+pub struct ExternalMetricSource {
+    configuration: AutoScalerExternalMetric,
+}
+
+impl ExternalMetricSource {
+    pub fn new(configuration: AutoScalerExternalMetric) -> Self {
+        Self { configuration }
+    }
+
+    /// Request the metric for an object, `{namespace}` and `{name}` in the endpoint are replaced
+    /// with those of the object.
+    async fn fetch(&self, namespace: &str, name: &str) -> Result<f64, Error> {
+        let endpoint = self
+            .configuration
+            .endpoint
+            .replace("{namespace}", namespace)
+            .replace("{name}", name);
+
+        let timeout = self
+            .configuration
+            .timeout
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS);
+        let external_client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(timeout as u64))
+            .build()
+            .context(HttpClient {})?;
+
+        let response = external_client
+            .get(&endpoint)
+            .send()
+            .await
+            .map_err(|err| request_error(&endpoint, timeout, err))?;
+        let status = response.status();
+        ensure!(
+            status.is_success(),
+            ExternalMetricStatus {
+                endpoint: endpoint.clone(),
+                status: status.as_u16(),
+            }
+        );
+        let body = response
+            .text()
+            .await
+            .map_err(|err| request_error(&endpoint, timeout, err))?;
+        parse_metric_value(&endpoint, &body)
+    }
+}
+
+/// Tell timeouts apart from other request errors, so they can be reported as such.
+fn request_error(endpoint: &str, timeout_ms: u32, err: reqwest::Error) -> Error {
+    if err.is_timeout() {
+        Error::ExternalMetricTimeout {
+            endpoint: endpoint.into(),
+            timeout_ms,
+        }
+    } else {
+        Error::HttpClient { source: err }
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for ExternalMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        let (namespace, name) = object.namespace_and_name();
+        // The endpoint only reports the current value, so it was observed when it was fetched.
+        self.fetch(&namespace, &name).await.map(MetricSample::now)
+    }
+}
+
+/// Parse the plain number returned by an external endpoint, ignoring surrounding whitespace.
+fn parse_metric_value(endpoint: &str, body: &str) -> Result<f64, Error> {
+    match body.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => ExternalMetric {
+            endpoint,
+            message: format!("invalid metric value: {}", body.trim()),
+        }
+        .fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::float_cmp)]
+    #[test]
+    fn test_parse_metric_value() {
+        assert_eq!(parse_metric_value("http://adapter", "42\n").unwrap(), 42.0);
+        assert_eq!(parse_metric_value("http://adapter", " 0.5 ").unwrap(), 0.5);
+        assert!(parse_metric_value("http://adapter", "NaN").is_err());
+        assert!(parse_metric_value("http://adapter", "").is_err());
+    }
+}
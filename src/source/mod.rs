@@ -0,0 +1,194 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::KubernetesObject;
+use crate::resource::AutoScalerMetric;
+use crate::source::connections::ConnectionsMetricSource;
+use crate::source::external::ExternalMetricSource;
+use crate::source::pods::PodsMetricSource;
+use crate::source::prometheus::{PrometheusMetricSource, PrometheusRangeMetricSource};
+use crate::source::statsd::StatsdMetricSource;
+use crate::source::utilization::UtilizationMetricSource;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use enum_dispatch::enum_dispatch;
+use slog::Logger;
+use std::time::Duration;
+
+/// TCP connection count based metric source implementation.
+pub mod connections;
+/// External http endpoint based metric source implementation.
+pub mod external;
+/// Pod scraping based metric source implementation.
+pub mod pods;
+/// Prometheus query based metric source implementation.
+pub mod prometheus;
+/// StatsD gauge based metric source implementation.
+pub mod statsd;
+/// Pod resource usage based metric source implementation.
+pub mod utilization;
+
+/// A metric value, and when it was observed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MetricSample {
+    pub value: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl MetricSample {
+    pub fn new(value: f64, timestamp: DateTime<Utc>) -> Self {
+        Self { value, timestamp }
+    }
+
+    /// A value observed just now, eg. by scraping it.
+    pub fn now(value: f64) -> Self {
+        Self::new(value, Utc::now())
+    }
+}
+
+/// Are all of the samples older than the maximum age? An empty window has no data rather than
+/// stale data, so it isn't considered stale.
+pub fn is_stale(samples: &[MetricSample], max_age: ChronoDuration, now: DateTime<Utc>) -> bool {
+    match samples.iter().map(|sample| sample.timestamp).max() {
+        Some(freshest) => now - freshest > max_age,
+        None => false,
+    }
+}
+
+/// Queue the samples newer than every sample queued before them, including those since consumed,
+/// as successive range queries overlap. Returns the timestamp of the newest sample queued so far.
+pub fn queue_new_samples(
+    queue: &mut Vec<MetricSample>,
+    newest_queued: Option<DateTime<Utc>>,
+    samples: Vec<MetricSample>,
+) -> Option<DateTime<Utc>> {
+    let newest_queued = newest_queued.max(queue.last().map(|sample| sample.timestamp));
+    queue.extend(
+        samples
+            .into_iter()
+            .filter(|sample| newest_queued.map_or(true, |newest| sample.timestamp > newest)),
+    );
+    newest_queued.max(queue.last().map(|sample| sample.timestamp))
+}
+
+/// Sources of metrics for autoscaling decisions.
+#[enum_dispatch]
+#[derive(Clone, Debug)]
+pub enum MetricSource {
+    Connections(ConnectionsMetricSource),
+    External(ExternalMetricSource),
+    Pods(PodsMetricSource),
+    Prometheus(PrometheusMetricSource),
+    PrometheusRange(PrometheusRangeMetricSource),
+    Statsd(StatsdMetricSource),
+    Utilization(UtilizationMetricSource),
+}
+
+impl MetricSource {
+    /// Construct the metric source selected by an AutoScaler's metric configuration. Without
+    /// any other source configured, the metric is pulled directly from each pod.
+    pub fn from_configuration(
+        logger: Logger,
+        configuration: &AutoScalerMetric,
+        kube_api_timeout: Duration,
+    ) -> Self {
+        if let Some(prometheus) = &configuration.prometheus {
+            match &prometheus.range {
+                Some(range) => MetricSource::PrometheusRange(PrometheusRangeMetricSource::new(
+                    prometheus.clone(),
+                    range.clone(),
+                )),
+                None => MetricSource::Prometheus(PrometheusMetricSource::new(prometheus.clone())),
+            }
+        } else if let Some(connections) = &configuration.connections {
+            MetricSource::Connections(ConnectionsMetricSource::new(logger, connections.clone()))
+        } else if let Some(external) = &configuration.external {
+            MetricSource::External(ExternalMetricSource::new(external.clone()))
+        } else if let Some(statsd) = &configuration.statsd {
+            MetricSource::Statsd(StatsdMetricSource::new(&configuration.name, statsd.clone()))
+        } else if let Some(utilization) = &configuration.utilization {
+            MetricSource::Utilization(UtilizationMetricSource::new(
+                utilization.clone(),
+                kube_api_timeout,
+            ))
+        } else {
+            MetricSource::Pods(PodsMetricSource::new(
+                logger,
+                &configuration.name,
+                kube_api_timeout,
+            ))
+        }
+    }
+}
+
+/// Metric source trait.
+#[async_trait]
+#[enum_dispatch(MetricSource)]
+pub trait MetricSourceTrait {
+    /// Retrieve the current value of the metric for an object, and when it was observed.
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error>;
+    /// Retrieve the recent values of the metric for an object, oldest first. Most sources only
+    /// know the current value.
+    async fn series_for(&self, object: &KubernetesObject) -> Result<Vec<MetricSample>, Error> {
+        Ok(vec![self.value_for(object).await?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_is_stale() {
+        let now = Utc.ymd(2020, 3, 29).and_hms(12, 0, 0);
+        let max_age = ChronoDuration::seconds(60);
+        let sample =
+            |seconds_ago| MetricSample::new(1.0, now - ChronoDuration::seconds(seconds_ago));
+
+        assert!(!is_stale(&[sample(120), sample(30)], max_age, now));
+        assert!(!is_stale(&[sample(60)], max_age, now));
+        assert!(is_stale(&[sample(120), sample(61)], max_age, now));
+        assert!(!is_stale(&[], max_age, now));
+    }
+
+    #[test]
+    fn test_queue_new_samples() {
+        let started = Utc.ymd(2020, 3, 29).and_hms(12, 0, 0);
+        let window = |from: i64, to: i64| {
+            (from..=to)
+                .map(|minute| {
+                    MetricSample::new(minute as f64, started + ChronoDuration::minutes(minute))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut queue = Vec::new();
+        let newest_queued = queue_new_samples(&mut queue, None, window(0, 14));
+        assert_eq!(queue.len(), 15);
+
+        // Overlapping windows only queue their new samples.
+        let newest_queued = queue_new_samples(&mut queue, newest_queued, window(1, 15));
+        assert_eq!(queue.len(), 16);
+
+        // Nor are consumed samples queued again.
+        queue.clear();
+        let newest_queued = queue_new_samples(&mut queue, newest_queued, window(2, 16));
+        assert_eq!(queue, window(16, 16));
+        assert_eq!(newest_queued, Some(started + ChronoDuration::minutes(16)));
+    }
+}
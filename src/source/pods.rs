@@ -0,0 +1,68 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::{with_timeout, KubernetesObject, KubernetesObjectTrait};
+use crate::metrics::retrieve_aggregate_metric;
+use crate::source::{MetricSample, MetricSourceTrait};
+use async_trait::async_trait;
+use slog::{debug, Logger};
+use snafu::OptionExt;
+use std::time::Duration;
+
+/// Port each pod serves its Prometheus metrics on.
+const POD_METRICS_PORT: u16 = 9090;
+
+/// Pulls a Prometheus metric directly from each of an object's pods, averaging the results.
+#[derive(Clone, Debug)]
+pub struct PodsMetricSource {
+    logger: Logger,
+    metric_name: String,
+    kube_api_timeout: Duration,
+}
+
+impl PodsMetricSource {
+    pub fn new(logger: Logger, metric_name: &str, kube_api_timeout: Duration) -> Self {
+        Self {
+            logger,
+            metric_name: metric_name.into(),
+            kube_api_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for PodsMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        let pod_ips_and_ports: Vec<String> = with_timeout(
+            "retrieving pod ips",
+            self.kube_api_timeout,
+            object.pod_ips(),
+        )
+        .await?
+        .iter()
+        .map(|pod_ip| format!("{}:{}", pod_ip, POD_METRICS_PORT))
+        .collect();
+        debug!(self.logger, "Pulling autoscaler metric from pods";
+            "pod_ips_and_ports" => format!("{:?}", pod_ips_and_ports));
+        retrieve_aggregate_metric(self.logger.clone(), pod_ips_and_ports, &self.metric_name)
+            .await?
+            .map(MetricSample::now)
+            .context(NoPodMetrics {
+                metric: self.metric_name.clone(),
+            })
+    }
+}
@@ -0,0 +1,418 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::{KubernetesObject, KubernetesObjectTrait};
+use crate::resource::{
+    AutoScalerPrometheusMetric, AutoScalerPrometheusRange, DEFAULT_PROMETHEUS_RANGE_STEP,
+    DEFAULT_PROMETHEUS_RANGE_WINDOW,
+};
+use crate::source::{MetricSample, MetricSourceTrait};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Default timeout for Prometheus queries (milliseconds).
+const DEFAULT_QUERY_TIMEOUT_MS: u32 = 1_000;
+
+/// Retrieves metrics by running a PromQL query against a Prometheus server.
+#[derive(Clone, Debug)]
+pub struct PrometheusMetricSource {
+    configuration: AutoScalerPrometheusMetric,
+}
+
+impl PrometheusMetricSource {
+    pub fn new(configuration: AutoScalerPrometheusMetric) -> Self {
+        Self { configuration }
+    }
+
+    /// Run the configured query for an object, eg. `connections{statefulset="{name}"}`.
+    /// If the query returns multiple series their values are summed.
+    async fn query(&self, namespace: &str, name: &str) -> Result<MetricSample, Error> {
+        let query = render_query(&self.configuration, namespace, name);
+        let (total, evaluated_at) = match request(&self.configuration, "query", &query, &[]).await?
+        {
+            Some(PrometheusData::Vector(samples)) if !samples.is_empty() => {
+                let mut total = 0.0;
+                for sample in &samples {
+                    total += parse_sample_value(&query, &sample.value.1)?;
+                }
+                (total, samples[0].value.0)
+            }
+            Some(PrometheusData::Scalar((timestamp, value))) => {
+                (parse_sample_value(&query, &value)?, timestamp)
+            }
+            _ => return NoMetricSeries { query }.fail(),
+        };
+        // Instant queries stamp every result with the evaluation time, even when the latest sample
+        // of a series is minutes old, so ask for the samples' own timestamps. The total is as old
+        // as its oldest series. Results that aren't samples, eg. of functions, only have the
+        // evaluation time.
+        let timestamp_query = format!("timestamp({})", query);
+        let mut oldest: Option<f64> = None;
+        if let Some(PrometheusData::Vector(timestamps)) =
+            request(&self.configuration, "query", &timestamp_query, &[]).await?
+        {
+            for timestamp in timestamps {
+                let timestamp = parse_sample_value(&timestamp_query, &timestamp.value.1)?;
+                oldest = Some(oldest.map_or(timestamp, |oldest| oldest.min(timestamp)));
+            }
+        }
+        Ok(MetricSample::new(
+            total,
+            sample_timestamp(&query, oldest.unwrap_or(evaluated_at))?,
+        ))
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for PrometheusMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        let (namespace, name) = object.namespace_and_name();
+        self.query(&namespace, &name).await
+    }
+}
+
+/// Retrieves the recent history of a metric by running a PromQL range query against a
+/// Prometheus server, eg. for smoothing or detecting trends.
+#[derive(Clone, Debug)]
+pub struct PrometheusRangeMetricSource {
+    configuration: AutoScalerPrometheusMetric,
+    range: AutoScalerPrometheusRange,
+}
+
+impl PrometheusRangeMetricSource {
+    pub fn new(
+        configuration: AutoScalerPrometheusMetric,
+        range: AutoScalerPrometheusRange,
+    ) -> Self {
+        Self {
+            configuration,
+            range,
+        }
+    }
+
+    /// Run the configured query for an object over the window ending now, one value per step.
+    /// If the query returns multiple series their values are summed at each step, an empty
+    /// window returns no values.
+    async fn query_range(
+        &self,
+        namespace: &str,
+        name: &str,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<MetricSample>, Error> {
+        let query = render_query(&self.configuration, namespace, name);
+        let window = self.range.window.unwrap_or(DEFAULT_PROMETHEUS_RANGE_WINDOW);
+        let step = self
+            .range
+            .step
+            .unwrap_or(DEFAULT_PROMETHEUS_RANGE_STEP)
+            .max(1);
+        let parameters = [
+            ("start", (end.timestamp() - window as i64).to_string()),
+            ("end", end.timestamp().to_string()),
+            ("step", step.to_string()),
+        ];
+        match request(&self.configuration, "query_range", &query, &parameters).await? {
+            Some(PrometheusData::Matrix(series)) => sum_series(&query, series),
+            Some(_) => PrometheusQuery {
+                query,
+                message: "range query did not return a matrix",
+            }
+            .fail(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for PrometheusRangeMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        let (namespace, name) = object.namespace_and_name();
+        let samples = self.query_range(&namespace, &name, Utc::now()).await?;
+        samples.last().copied().context(NoMetricSeries {
+            query: render_query(&self.configuration, &namespace, &name),
+        })
+    }
+
+    async fn series_for(&self, object: &KubernetesObject) -> Result<Vec<MetricSample>, Error> {
+        let (namespace, name) = object.namespace_and_name();
+        self.query_range(&namespace, &name, Utc::now()).await
+    }
+}
+
+/// Substitute an object's namespace and name into the configured query.
+fn render_query(configuration: &AutoScalerPrometheusMetric, namespace: &str, name: &str) -> String {
+    configuration
+        .query
+        .replace("{namespace}", namespace)
+        .replace("{name}", name)
+}
+
+/// Run a query against one of the Prometheus http api's query endpoints, eg. `query_range`.
+async fn request(
+    configuration: &AutoScalerPrometheusMetric,
+    endpoint: &str,
+    query: &str,
+    parameters: &[(&str, String)],
+) -> Result<Option<PrometheusData>, Error> {
+    let timeout = configuration.timeout.unwrap_or(DEFAULT_QUERY_TIMEOUT_MS);
+    let prometheus_client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(timeout as u64))
+        .build()
+        .context(HttpClient {})?;
+
+    let response = prometheus_client
+        .get(&format!(
+            "{}/api/v1/{}",
+            configuration.endpoint.trim_end_matches('/'),
+            endpoint
+        ))
+        .query(&[("query", query)])
+        .query(parameters)
+        .send()
+        .await
+        .context(HttpClient {})?
+        .text()
+        .await
+        .context(HttpClient {})?;
+    let response: PrometheusResponse =
+        serde_json::from_str(&response).context(JsonSerialization {})?;
+
+    ensure!(
+        response.status == "success",
+        PrometheusQuery {
+            query,
+            message: response.error.unwrap_or_default(),
+        }
+    );
+    Ok(response.data)
+}
+
+/// Sum the series of a range query at each step. Series may only cover part of the window, eg.
+/// for pods that have since been added or removed, so each step totals the series with a value
+/// at it. NaN values, eg. rates over series with no samples, are left out.
+fn sum_series(query: &str, series: Vec<PrometheusSeries>) -> Result<Vec<MetricSample>, Error> {
+    // Keyed by millisecond, so that totals are ordered by time.
+    let mut totals: BTreeMap<i64, (f64, f64)> = BTreeMap::new();
+    for series in series {
+        for (timestamp, value) in series.values {
+            let value = parse_sample_value(query, &value)?;
+            if value.is_nan() {
+                continue;
+            }
+            totals
+                .entry((timestamp * 1_000.0).round() as i64)
+                .or_insert((timestamp, 0.0))
+                .1 += value;
+        }
+    }
+    totals
+        .into_iter()
+        .map(|(_, (timestamp, total))| {
+            Ok(MetricSample::new(
+                total,
+                sample_timestamp(query, timestamp)?,
+            ))
+        })
+        .collect()
+}
+
+/// Prometheus http api query response.
+#[derive(Deserialize)]
+struct PrometheusResponse {
+    status: String,
+    data: Option<PrometheusData>,
+    error: Option<String>,
+}
+
+/// Prometheus query results, by result type.
+#[derive(Deserialize)]
+#[serde(tag = "resultType", content = "result", rename_all = "lowercase")]
+enum PrometheusData {
+    Vector(Vec<PrometheusSample>),
+    Scalar((f64, String)),
+    Matrix(Vec<PrometheusSeries>),
+    #[serde(rename = "string")]
+    Text(Value),
+}
+
+/// A single instant vector sample.
+#[derive(Deserialize)]
+struct PrometheusSample {
+    value: (f64, String),
+}
+
+/// A single range vector series, its values at each step.
+#[derive(Deserialize)]
+struct PrometheusSeries {
+    values: Vec<(f64, String)>,
+}
+
+/// Prometheus reports sample timestamps as fractional seconds since the unix epoch.
+fn sample_timestamp(query: &str, timestamp: f64) -> Result<DateTime<Utc>, Error> {
+    let invalid = || Error::PrometheusQuery {
+        query: query.into(),
+        message: format!("invalid sample timestamp: {}", timestamp),
+    };
+    // Far outside of the range of dates, the conversions below wouldn't be meaningful.
+    if !timestamp.is_finite() || timestamp.abs() > 1e15 {
+        return Err(invalid());
+    }
+    let seconds = timestamp.floor();
+    let nanoseconds = ((timestamp - seconds) * 1_000_000_000.0).min(999_999_999.0);
+    Utc.timestamp_opt(seconds as i64, nanoseconds as u32)
+        .single()
+        .ok_or_else(invalid)
+}
+
+/// Prometheus encodes sample values as strings, eg. "NaN" or "42".
+fn parse_sample_value(query: &str, value: &str) -> Result<f64, Error> {
+    value.parse().map_err(|_| Error::PrometheusQuery {
+        query: query.into(),
+        message: format!("invalid sample value: {}", value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::{future, FutureExt};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::net::{SocketAddr, TcpListener};
+    use tokio::time::delay_for;
+
+    #[tokio::test]
+    async fn test_prometheus_query() {
+        let port = get_available_port(9_100).unwrap();
+        let shutdown_sender = spawn_server(port);
+
+        delay_for(Duration::from_millis(20)).await;
+
+        let source = PrometheusMetricSource::new(AutoScalerPrometheusMetric {
+            endpoint: format!("http://localhost:{}", port),
+            query: String::from("connections{statefulset=\"{name}\"}"),
+            timeout: None,
+            range: None,
+        });
+        let connections = source.query("default", "web").await.unwrap();
+        let missing = source.query("default", "missing").await;
+
+        shutdown_sender.send(()).unwrap();
+
+        assert_eq!(connections.value as i32, 30);
+        // The oldest of the series' own sample timestamps, rather than the evaluation time.
+        assert_eq!(connections.timestamp.timestamp(), 1_581_983_970);
+        match missing {
+            Err(Error::NoMetricSeries { .. }) => (),
+            _ => panic!("expected no metric series"),
+        }
+    }
+
+    #[test]
+    fn test_sum_series() {
+        let series: Vec<PrometheusSeries> = serde_json::from_str(
+            r#"[
+                {"metric":{"pod":"web-0"},"values":[[1581984000,"10"],[1581984060,"12"],[1581984120,"NaN"]]},
+                {"metric":{"pod":"web-1"},"values":[[1581984060,"20"],[1581984120,"22"]]}
+            ]"#,
+        )
+        .unwrap();
+        let samples = sum_series("connections", series).unwrap();
+
+        // Each step totals the series with a value at it.
+        let values: Vec<(i64, f64)> = samples
+            .iter()
+            .map(|sample| (sample.timestamp.timestamp(), sample.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (1_581_984_000, 10.0),
+                (1_581_984_060, 32.0),
+                (1_581_984_120, 22.0)
+            ]
+        );
+
+        assert!(sum_series("connections", Vec::new()).unwrap().is_empty());
+        let invalid: Vec<PrometheusSeries> =
+            serde_json::from_str(r#"[{"metric":{},"values":[[1581984000,"lots"]]}]"#).unwrap();
+        assert!(sum_series("connections", invalid).is_err());
+    }
+
+    #[test]
+    fn test_sample_timestamp() {
+        let timestamp = sample_timestamp("connections", 1_581_984_000.25).unwrap();
+        assert_eq!(timestamp.timestamp(), 1_581_984_000);
+        assert_eq!(timestamp.timestamp_subsec_millis(), 250);
+        assert!(sample_timestamp("connections", std::f64::NAN).is_err());
+        assert!(sample_timestamp("connections", std::f64::INFINITY).is_err());
+        assert!(sample_timestamp("connections", 1e300).is_err());
+        assert!(sample_timestamp("connections", 1e14).is_err());
+    }
+
+    fn spawn_server(port: u16) -> oneshot::Sender<()> {
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            Server::bind(&addr)
+                .serve(make_service_fn(|_| {
+                    let service =
+                        service_fn(move |request| serve(request).map(Ok::<_, hyper::Error>));
+                    future::ok::<_, hyper::Error>(service)
+                }))
+                .with_graceful_shutdown(async {
+                    shutdown_receiver.await.ok();
+                })
+                .await
+                .unwrap();
+        });
+        shutdown_sender
+    }
+
+    async fn serve(request: Request<Body>) -> Response<Body> {
+        let query = request.uri().query().unwrap_or_default();
+        let result = if query.starts_with("query=timestamp") && query.contains("web") {
+            r#"[{"metric":{"pod":"web-0"},"value":[1581984000.0,"1581983990"]},{"metric":{"pod":"web-1"},"value":[1581984000.0,"1581983970.5"]}]"#
+        } else if query.contains("web") {
+            r#"[{"metric":{"pod":"web-0"},"value":[1581984000.0,"10"]},{"metric":{"pod":"web-1"},"value":[1581984000.0,"20"]}]"#
+        } else {
+            "[]"
+        };
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Body::from(format!(
+                r#"{{"status":"success","data":{{"resultType":"vector","result":{}}}}}"#,
+                result
+            )))
+            .unwrap()
+    }
+
+    fn get_available_port(base_port: u16) -> Option<u16> {
+        (base_port..(base_port + 1000u16)).find(|port| port_is_available(*port))
+    }
+
+    fn port_is_available(port: u16) -> bool {
+        TcpListener::bind(("127.0.0.1", port)).is_ok()
+    }
+}
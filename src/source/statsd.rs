@@ -0,0 +1,196 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::{KubernetesObject, KubernetesObjectTrait};
+use crate::resource::AutoScalerStatsdMetric;
+use crate::source::{MetricSample, MetricSourceTrait};
+use async_trait::async_trait;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use slog::{debug, error, info, Logger};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use tokio::net::UdpSocket;
+
+lazy_static! {
+    /// The latest value of every gauge pushed to the StatsD listener, and when it was received.
+    static ref STATSD_GAUGES: RwLock<HashMap<String, MetricSample>> = RwLock::new(HashMap::new());
+}
+
+/// Reads gauges pushed to pangolin's StatsD listener, keyed by the object they describe.
+#[derive(Clone, Debug)]
+pub struct StatsdMetricSource {
+    metric_name: String,
+    configuration: AutoScalerStatsdMetric,
+}
+
+impl StatsdMetricSource {
+    pub fn new(metric_name: &str, configuration: AutoScalerStatsdMetric) -> Self {
+        Self {
+            metric_name: metric_name.into(),
+            configuration,
+        }
+    }
+
+    /// The gauge holding the metric for an object, `{namespace}` and `{name}` in the key are
+    /// replaced with those of the object. Defaults to `<metric name>.{namespace}.{name}`.
+    fn gauge_key(&self, namespace: &str, name: &str) -> String {
+        match &self.configuration.key {
+            Some(key) => key
+                .replace("{namespace}", namespace)
+                .replace("{name}", name),
+            None => format!("{}.{}.{}", self.metric_name, namespace, name),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for StatsdMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        let (namespace, name) = object.namespace_and_name();
+        let key = self.gauge_key(&namespace, &name);
+        // A gauge that has never been pushed is missing, which is not the same as a zero value.
+        match STATSD_GAUGES.read().unwrap().get(&key) {
+            Some(sample) => Ok(*sample),
+            None => NoStatsdGauge { key }.fail(),
+        }
+    }
+}
+
+/// Receive StatsD packets on the address, recording the latest value of each gauge. Other metric
+/// types (counters, timers etc.) are ignored.
+pub async fn listen(logger: Logger, address: SocketAddr) {
+    let mut socket = match UdpSocket::bind(address).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!(logger, "Failed to bind statsd listener";
+                "address" => format!("{}", address),
+                "error" => format!("{}", err));
+            return;
+        }
+    };
+    info!(logger, "Listening for statsd gauges"; "address" => format!("{}", address));
+
+    let mut buffer = vec![0u8; 65_536];
+    loop {
+        let (length, peer) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                error!(logger, "Failed to receive statsd packet"; "error" => format!("{}", err));
+                continue;
+            }
+        };
+        let packet = String::from_utf8_lossy(&buffer[..length]);
+        let updates = parse_gauges(&packet);
+        debug!(logger, "Received statsd packet";
+            "peer" => format!("{}", peer),
+            "gauges" => updates.len());
+
+        let now = Utc::now();
+        let mut gauges = STATSD_GAUGES.write().unwrap();
+        for (key, update) in updates {
+            let value = match update {
+                GaugeUpdate::Set(value) => value,
+                // Relative updates to a gauge we haven't seen start from zero, as in StatsD.
+                GaugeUpdate::Adjust(delta) => {
+                    gauges.get(&key).map(|sample| sample.value).unwrap_or(0.0) + delta
+                }
+            };
+            gauges.insert(key, MetricSample::new(value, now));
+        }
+    }
+}
+
+/// A change to the value of a gauge.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GaugeUpdate {
+    /// `key:42|g` sets the gauge.
+    Set(f64),
+    /// `key:+5|g` or `key:-5|g` adjusts the current value.
+    Adjust(f64),
+}
+
+/// Parse the gauges out of a StatsD packet, one metric per line. Malformed lines, and lines that
+/// aren't gauges, are skipped.
+fn parse_gauges(packet: &str) -> Vec<(String, GaugeUpdate)> {
+    packet
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split('|');
+            let (key, value) = {
+                let mut key_value = fields.next()?.splitn(2, ':');
+                (key_value.next()?, key_value.next()?)
+            };
+            if key.is_empty() || fields.next()? != "g" {
+                return None;
+            }
+            let parsed = value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())?;
+            let update = if value.starts_with('+') || value.starts_with('-') {
+                GaugeUpdate::Adjust(parsed)
+            } else {
+                GaugeUpdate::Set(parsed)
+            };
+            Some((String::from(key), update))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gauges() {
+        let packet = "connections.default.web:42|g\nconnections.default.api:+5|g\n\
+                      requests:1|c\nbroken\nlatency:-1.5|g|@0.5\nempty:|g";
+        assert_eq!(
+            parse_gauges(packet),
+            vec![
+                (
+                    String::from("connections.default.web"),
+                    GaugeUpdate::Set(42.0)
+                ),
+                (
+                    String::from("connections.default.api"),
+                    GaugeUpdate::Adjust(5.0)
+                ),
+                (String::from("latency"), GaugeUpdate::Adjust(-1.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gauge_key() {
+        let source = StatsdMetricSource::new("connections", AutoScalerStatsdMetric { key: None });
+        assert_eq!(
+            source.gauge_key("default", "web"),
+            "connections.default.web"
+        );
+
+        let source = StatsdMetricSource::new(
+            "connections",
+            AutoScalerStatsdMetric {
+                key: Some(String::from("pods.{name}.active")),
+            },
+        );
+        assert_eq!(source.gauge_key("default", "web"), "pods.web.active");
+    }
+}
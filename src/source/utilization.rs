@@ -0,0 +1,113 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::*;
+use crate::kubernetes::{with_timeout, KubernetesObject, KubernetesObjectTrait, PodMetrics};
+use crate::resource::{AutoScalerUtilizationMetric, AutoScalerUtilizationResource};
+use crate::source::{MetricSample, MetricSourceTrait};
+use async_trait::async_trait;
+use chrono::Utc;
+use snafu::OptionExt;
+use std::time::Duration;
+
+/// Averages the CPU or memory usage of an object's pods, as reported by the metrics.k8s.io api.
+#[derive(Clone, Debug)]
+pub struct UtilizationMetricSource {
+    configuration: AutoScalerUtilizationMetric,
+    kube_api_timeout: Duration,
+}
+
+impl UtilizationMetricSource {
+    pub fn new(configuration: AutoScalerUtilizationMetric, kube_api_timeout: Duration) -> Self {
+        Self {
+            configuration,
+            kube_api_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl MetricSourceTrait for UtilizationMetricSource {
+    async fn value_for(&self, object: &KubernetesObject) -> Result<MetricSample, Error> {
+        let pod_metrics = with_timeout(
+            "retrieving pod utilization",
+            self.kube_api_timeout,
+            object.pod_utilization(),
+        )
+        .await?;
+        average_usage(&pod_metrics, self.configuration.resource).context(NoPodMetrics {
+            metric: self.configuration.resource.to_string(),
+        })
+    }
+}
+
+/// The mean usage of a resource across the pods, stamped with the oldest of their samples so that
+/// a pod the metrics server has stopped sampling makes the value stale. None without any pods.
+fn average_usage(
+    pod_metrics: &[PodMetrics],
+    resource: AutoScalerUtilizationResource,
+) -> Option<MetricSample> {
+    if pod_metrics.is_empty() {
+        return None;
+    }
+    let total: f64 = pod_metrics
+        .iter()
+        .map(|pod| match resource {
+            AutoScalerUtilizationResource::Cpu => pod.cpu,
+            AutoScalerUtilizationResource::Memory => pod.memory,
+        })
+        .sum();
+    let timestamp = pod_metrics
+        .iter()
+        .filter_map(|pod| pod.timestamp)
+        .min()
+        .unwrap_or_else(Utc::now);
+    Some(MetricSample::new(
+        total / pod_metrics.len() as f64,
+        timestamp,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_average_usage() {
+        let sampled_at = Utc.ymd(2020, 3, 29).and_hms(12, 0, 0);
+        let pod = |name: &str, cpu, memory, seconds_ago| PodMetrics {
+            pod_name: name.into(),
+            cpu,
+            memory,
+            timestamp: Some(sampled_at - chrono::Duration::seconds(seconds_ago)),
+        };
+        let pod_metrics = vec![pod("web-0", 0.5, 256.0, 0), pod("web-1", 1.5, 768.0, 15)];
+
+        assert_eq!(
+            average_usage(&pod_metrics, AutoScalerUtilizationResource::Cpu),
+            Some(MetricSample::new(
+                1.0,
+                sampled_at - chrono::Duration::seconds(15)
+            ))
+        );
+        assert_eq!(
+            average_usage(&pod_metrics, AutoScalerUtilizationResource::Memory).map(|s| s.value),
+            Some(512.0)
+        );
+        assert_eq!(average_usage(&[], AutoScalerUtilizationResource::Cpu), None);
+    }
+}
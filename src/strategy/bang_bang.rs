@@ -41,6 +41,13 @@ impl AutoScalerStrategyTrait for BangBangAutoScalerStrategy {
             None
         }
     }
+
+    fn describe(&self) -> String {
+        format!(
+            "lower={} upper={}",
+            self.configuration.lower, self.configuration.upper
+        )
+    }
 }
 
 #[cfg(test)]
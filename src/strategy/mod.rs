@@ -15,16 +15,24 @@
  */
 
 use crate::strategy::bang_bang::BangBangAutoScalerStrategy;
+use crate::strategy::proportional::ProportionalAutoScalerStrategy;
+use crate::strategy::target_utilization::TargetUtilizationAutoScalerStrategy;
 use enum_dispatch::enum_dispatch;
 
 /// Bang-bang autoscaling strategy implementation.
 pub mod bang_bang;
+/// Proportional autoscaling strategy implementation.
+pub mod proportional;
+/// Target utilization autoscaling strategy implementation.
+pub mod target_utilization;
 
 /// Autoscaling strategies / control algorithms.
 #[enum_dispatch]
 #[derive(Clone, Debug)]
 pub enum AutoScalerStrategy {
     BangBang(BangBangAutoScalerStrategy),
+    TargetUtilization(TargetUtilizationAutoScalerStrategy),
+    Proportional(ProportionalAutoScalerStrategy),
 }
 
 /// Autoscaling strtategy trait.
@@ -32,4 +40,19 @@ pub enum AutoScalerStrategy {
 pub trait AutoScalerStrategyTrait {
     /// What is the next desired state? Return the delta in terms of the number of replicas.
     fn evaluate(&self, replicas: u32, value: f64) -> Option<i32>;
+    /// The strategy's parameters, eg. `target-per-replica=100`, for recording why an object was
+    /// scaled.
+    fn describe(&self) -> String;
+}
+
+/// The delta that takes the current number of replicas to the desired number, clamped into the
+/// range of a delta, or none if they are the same.
+pub fn replica_delta(replicas: u32, desired_replicas: u32) -> Option<i32> {
+    let delta = (desired_replicas as i64 - replicas as i64)
+        .max(std::i32::MIN as i64)
+        .min(std::i32::MAX as i64) as i32;
+    match delta {
+        0 => None,
+        delta => Some(delta),
+    }
 }
@@ -0,0 +1,203 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::resource::{AutoScalerLimits, AutoScalerProportionalStrategyConfiguration};
+use crate::strategy::{replica_delta, AutoScalerStrategyTrait};
+
+/// Implementation of a proportional (P) controller, the replica delta is proportional to the
+/// error between the metric and its setpoint.
+#[derive(Clone, Debug)]
+pub struct ProportionalAutoScalerStrategy {
+    configuration: AutoScalerProportionalStrategyConfiguration,
+    limits: Option<AutoScalerLimits>,
+}
+
+impl ProportionalAutoScalerStrategy {
+    pub fn new(
+        configuration: AutoScalerProportionalStrategyConfiguration,
+        limits: Option<AutoScalerLimits>,
+    ) -> Self {
+        Self {
+            configuration,
+            limits,
+        }
+    }
+}
+
+impl AutoScalerStrategyTrait for ProportionalAutoScalerStrategy {
+    fn evaluate(&self, replicas: u32, value: f64) -> Option<i32> {
+        let replica_limits = self
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.replicas.as_ref());
+        let desired_replicas = proportional_replicas(
+            value,
+            self.configuration.setpoint,
+            self.configuration.gain,
+            replicas,
+            replica_limits.map(|replica_limits| replica_limits.min),
+            replica_limits.map(|replica_limits| replica_limits.max),
+            self.limits
+                .as_ref()
+                .and_then(|limits| limits.max_scale_step(replicas)),
+        );
+        replica_delta(replicas, desired_replicas)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "setpoint={} gain={}",
+            self.configuration.setpoint, self.configuration.gain
+        )
+    }
+}
+
+/// The current number of replicas adjusted by `round(gain * (value - setpoint))`, moving at most
+/// the maximum scale step and clamped into the replica bounds.
+pub fn proportional_replicas(
+    value: f64,
+    setpoint: f64,
+    gain: f64,
+    current_replicas: u32,
+    min_replicas: Option<u32>,
+    max_replicas: Option<u32>,
+    max_scale_step: Option<u32>,
+) -> u32 {
+    // Without a usable metric or gain there's no error to correct, so stay put.
+    if !value.is_finite() || !setpoint.is_finite() || !gain.is_finite() {
+        return current_replicas;
+    }
+    let mut delta = (gain * (value - setpoint))
+        .round()
+        .max(std::i32::MIN as f64)
+        .min(std::i32::MAX as f64) as i64;
+    if let Some(max_scale_step) = max_scale_step {
+        delta = delta
+            .max(-(max_scale_step as i64))
+            .min(max_scale_step as i64);
+    }
+    let mut desired_replicas = (current_replicas as i64 + delta)
+        .max(0)
+        .min(std::u32::MAX as i64) as u32;
+    if let Some(max_replicas) = max_replicas {
+        desired_replicas = desired_replicas.min(max_replicas);
+    }
+    if let Some(min_replicas) = min_replicas {
+        desired_replicas = desired_replicas.max(min_replicas);
+    }
+    desired_replicas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::{AutoScalerReplicaLimit, LimitedReplicas};
+
+    #[test]
+    fn test_proportional_replicas() {
+        // The delta is the rounded, scaled error.
+        assert_eq!(
+            proportional_replicas(150.0, 100.0, 0.1, 4, None, None, None),
+            9
+        );
+        assert_eq!(
+            proportional_replicas(80.0, 100.0, 0.1, 4, None, None, None),
+            2
+        );
+        assert_eq!(
+            proportional_replicas(102.0, 100.0, 0.1, 4, None, None, None),
+            4
+        );
+        // Clamped by the maximum scale step and into the bounds.
+        assert_eq!(
+            proportional_replicas(150.0, 100.0, 0.1, 4, None, None, Some(2)),
+            6
+        );
+        assert_eq!(
+            proportional_replicas(0.0, 100.0, 0.1, 4, Some(1), None, None),
+            1
+        );
+        assert_eq!(
+            proportional_replicas(150.0, 100.0, 0.1, 4, None, Some(8), None),
+            8
+        );
+    }
+
+    #[test]
+    fn test_proportional_strategy() {
+        let strategy = ProportionalAutoScalerStrategy::new(
+            AutoScalerProportionalStrategyConfiguration {
+                setpoint: 100.0,
+                gain: 0.1,
+            },
+            None,
+        );
+        assert_eq!(strategy.evaluate(4, 150.0).unwrap(), 5);
+        assert!(strategy.evaluate(4, 102.0).is_none());
+
+        // Replica counts past the range of a delta don't overflow it.
+        let strategy = ProportionalAutoScalerStrategy::new(
+            AutoScalerProportionalStrategyConfiguration {
+                setpoint: 100.0,
+                gain: 1e10,
+            },
+            None,
+        );
+        assert_eq!(
+            strategy.evaluate(3_000_000_000, 0.0).unwrap(),
+            std::i32::MIN
+        );
+    }
+
+    #[test]
+    fn test_proportional_step_response() {
+        // The load steps up fivefold, spread evenly over the replicas, with a setpoint of 100
+        // per replica the controller should converge on 10 replicas without overshooting.
+        let load = 1000.0;
+        let mut replicas = 2;
+        let mut history = vec![replicas];
+        for _ in 0..20 {
+            let value = load / replicas as f64;
+            replicas = proportional_replicas(value, 100.0, 0.05, replicas, None, None, Some(4));
+            history.push(replicas);
+        }
+
+        assert!(history.windows(2).all(|window| window[0] <= window[1]));
+        assert_eq!(*history.last().unwrap(), 10);
+        assert_eq!(&history[history.len() - 5..], &[10; 5]);
+    }
+
+    #[test]
+    fn test_proportional_reaches_max_replicas() {
+        // Targets are clamped into the bounds, so once the load outgrows the maximum the target
+        // lands on it exactly, which the replica limits must let the object scale to.
+        let limit = AutoScalerReplicaLimit { min: 1, max: 8 };
+        let load = 1000.0;
+        let mut replicas = 2;
+        for _ in 0..20 {
+            let value = load / replicas as f64;
+            let desired_replicas =
+                proportional_replicas(value, 100.0, 0.05, replicas, Some(1), Some(8), Some(4));
+            replicas = match limit.limit(replicas, desired_replicas) {
+                LimitedReplicas::Within(replicas) | LimitedReplicas::Clamped(_, replicas) => {
+                    replicas
+                }
+                LimitedReplicas::AtLimit(_, _) => break,
+            };
+        }
+        assert_eq!(replicas, 8);
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::resource::{AutoScalerLimits, AutoScalerTargetUtilizationStrategyConfiguration};
+use crate::strategy::{replica_delta, AutoScalerStrategyTrait};
+
+/// Implementation of a target utilization controller, as used by the Horizontal Pod Autoscaler.
+#[derive(Clone, Debug)]
+pub struct TargetUtilizationAutoScalerStrategy {
+    configuration: AutoScalerTargetUtilizationStrategyConfiguration,
+    limits: Option<AutoScalerLimits>,
+}
+
+impl TargetUtilizationAutoScalerStrategy {
+    pub fn new(
+        configuration: AutoScalerTargetUtilizationStrategyConfiguration,
+        limits: Option<AutoScalerLimits>,
+    ) -> Self {
+        Self {
+            configuration,
+            limits,
+        }
+    }
+}
+
+impl AutoScalerStrategyTrait for TargetUtilizationAutoScalerStrategy {
+    fn evaluate(&self, replicas: u32, value: f64) -> Option<i32> {
+        let replica_limits = self
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.replicas.as_ref());
+        let desired_replicas = target_utilization_replicas(
+            value,
+            self.configuration.target_per_replica,
+            replicas,
+            replica_limits.map(|replica_limits| replica_limits.min),
+            replica_limits.map(|replica_limits| replica_limits.max),
+            self.limits
+                .as_ref()
+                .and_then(|limits| limits.max_scale_step(replicas)),
+        );
+        replica_delta(replicas, desired_replicas)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "target-per-replica={}",
+            self.configuration.target_per_replica
+        )
+    }
+}
+
+/// The number of replicas needed for each to handle the target share of the metric, ie.
+/// `ceil(value / target_per_replica)`, clamped into the replica bounds and moving at most the
+/// maximum scale step away from the current number of replicas.
+pub fn target_utilization_replicas(
+    value: f64,
+    target_per_replica: f64,
+    current_replicas: u32,
+    min_replicas: Option<u32>,
+    max_replicas: Option<u32>,
+    max_scale_step: Option<u32>,
+) -> u32 {
+    // Without a usable target or metric there's nothing to size against, so stay put.
+    if target_per_replica.is_nan() || target_per_replica <= 0.0 || !value.is_finite() {
+        return current_replicas;
+    }
+    let mut desired_replicas = (value.max(0.0) / target_per_replica)
+        .ceil()
+        .min(std::u32::MAX as f64) as u32;
+    if let Some(max_scale_step) = max_scale_step {
+        desired_replicas = desired_replicas
+            .min(current_replicas.saturating_add(max_scale_step))
+            .max(current_replicas.saturating_sub(max_scale_step));
+    }
+    if let Some(max_replicas) = max_replicas {
+        desired_replicas = desired_replicas.min(max_replicas);
+    }
+    if let Some(min_replicas) = min_replicas {
+        desired_replicas = desired_replicas.max(min_replicas);
+    }
+    desired_replicas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_utilization_replicas() {
+        // Exact multiples need exactly that many replicas, anything over needs one more.
+        assert_eq!(
+            target_utilization_replicas(300.0, 100.0, 1, None, None, None),
+            3
+        );
+        assert_eq!(
+            target_utilization_replicas(301.0, 100.0, 1, None, None, None),
+            4
+        );
+        // A zero metric scales down as far as the minimum allows.
+        assert_eq!(
+            target_utilization_replicas(0.0, 100.0, 4, None, None, None),
+            0
+        );
+        assert_eq!(
+            target_utilization_replicas(0.0, 100.0, 4, Some(2), None, None),
+            2
+        );
+        // Clamped into the bounds and by the maximum scale step.
+        assert_eq!(
+            target_utilization_replicas(5000.0, 100.0, 4, None, Some(10), None),
+            10
+        );
+        assert_eq!(
+            target_utilization_replicas(5000.0, 100.0, 4, None, None, Some(2)),
+            6
+        );
+        assert_eq!(
+            target_utilization_replicas(0.0, 100.0, 4, None, None, Some(2)),
+            2
+        );
+        // An unusable target leaves the replicas unchanged.
+        assert_eq!(
+            target_utilization_replicas(300.0, 0.0, 4, None, None, None),
+            4
+        );
+    }
+
+    #[test]
+    fn test_target_utilization_strategy() {
+        let strategy = TargetUtilizationAutoScalerStrategy::new(
+            AutoScalerTargetUtilizationStrategyConfiguration {
+                target_per_replica: 10.0,
+            },
+            None,
+        );
+
+        assert_eq!(strategy.evaluate(2, 45.0).unwrap(), 3);
+        assert!(strategy.evaluate(2, 20.0).is_none());
+        assert_eq!(strategy.evaluate(2, 5.0).unwrap(), -1);
+
+        // Scaling up further than a delta can express is clamped, rather than overflowing.
+        let strategy = TargetUtilizationAutoScalerStrategy::new(
+            AutoScalerTargetUtilizationStrategyConfiguration {
+                target_per_replica: 1.0,
+            },
+            None,
+        );
+        assert_eq!(strategy.evaluate(0, 4e9).unwrap(), std::i32::MAX);
+    }
+}
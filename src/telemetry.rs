@@ -0,0 +1,100 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+};
+use std::time::Instant;
+
+lazy_static! {
+    /// Scaling operations, by resource and result.
+    pub static ref SCALE_OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "pangolin_scale_operations_total",
+        "Number of scaling operations performed.",
+        &["resource", "result"]
+    )
+    .unwrap();
+    /// Scaling operations suppressed by a cooldown, by object and direction.
+    pub static ref COOLDOWN_SUPPRESSED: IntCounterVec = register_int_counter_vec!(
+        "pangolin_cooldown_suppressed_total",
+        "Number of scaling operations suppressed by a cooldown.",
+        &["kind", "namespace", "name", "direction"]
+    )
+    .unwrap();
+    /// The observed number of replicas of each object, from its status.
+    pub static ref OBSERVED_REPLICAS: IntGaugeVec = register_int_gauge_vec!(
+        "pangolin_observed_replicas",
+        "Observed number of replicas of an autoscaled object.",
+        &["kind", "namespace", "name"]
+    )
+    .unwrap();
+    /// The desired number of replicas of each object, from its spec.
+    pub static ref DESIRED_REPLICAS: IntGaugeVec = register_int_gauge_vec!(
+        "pangolin_desired_replicas",
+        "Desired number of replicas of an autoscaled object.",
+        &["kind", "namespace", "name"]
+    )
+    .unwrap();
+    /// The number of ready replicas of each object.
+    pub static ref READY_REPLICAS: IntGaugeVec = register_int_gauge_vec!(
+        "pangolin_ready_replicas",
+        "Ready number of replicas of an autoscaled object.",
+        &["kind", "namespace", "name"]
+    )
+    .unwrap();
+    /// The number of objects that failed to reconcile in each AutoScaler's latest cycle.
+    pub static ref RECONCILE_FAILURES: IntGaugeVec = register_int_gauge_vec!(
+        "pangolin_reconcile_failures",
+        "Number of objects that failed to reconcile in an autoscaler's latest cycle.",
+        &["namespace", "name"]
+    )
+    .unwrap();
+    /// Whether each object has converged on its desired number of replicas.
+    pub static ref CONVERGED: IntGaugeVec = register_int_gauge_vec!(
+        "pangolin_converged",
+        "Whether an autoscaled object's desired replicas are all observed and ready.",
+        &["kind", "namespace", "name"]
+    )
+    .unwrap();
+    /// Latency of requests to the kubernetes api, by operation.
+    pub static ref KUBE_API_LATENCY: HistogramVec = register_histogram_vec!(
+        "pangolin_kube_api_request_duration_seconds",
+        "Latency of requests to the kubernetes api.",
+        &["operation"]
+    )
+    .unwrap();
+    /// Time kubernetes api requests spent waiting on the client side rate limiter.
+    pub static ref KUBE_API_RATE_LIMIT_DELAY: Histogram = register_histogram!(
+        "pangolin_kube_api_rate_limit_delay_seconds",
+        "Time kubernetes api requests spent waiting on the client side rate limiter."
+    )
+    .unwrap();
+    /// Whether the kubernetes api circuit breaker is open (1) or closed (0).
+    pub static ref KUBE_API_CIRCUIT_OPEN: IntGauge = register_int_gauge!(
+        "pangolin_kube_api_circuit_open",
+        "Whether calls to the kubernetes api are paused by the circuit breaker."
+    )
+    .unwrap();
+}
+
+/// Record the latency of a kubernetes api operation that began at the supplied instant.
+pub fn observe_kube_api_latency(operation: &str, started: Instant) {
+    KUBE_API_LATENCY
+        .with_label_values(&[operation])
+        .observe(started.elapsed().as_secs_f64());
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright 2020 Damian Peckett <damian@pecke.tt>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use futures::stream::{self, BoxStream, StreamExt};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Number of consecutive reconciliations matching no objects before reconciliation backs off.
+const EMPTY_LIST_BACKOFF_THRESHOLD: u32 = 3;
+
+/// A timer that ticks immediately, then once every period, with each period independently
+/// randomized by up to the jitter percentage either way. Jittering every cycle, rather than only
+/// the first, keeps many timers with the same period from falling back into step.
+pub fn jittered_interval(period: Duration, jitter_percent: f64) -> BoxStream<'static, ()> {
+    stream::once(async {})
+        .chain(stream::unfold((), move |_| async move {
+            let sample = rand::thread_rng().gen_range(-1.0, 1.0);
+            delay_for(jittered_period(period, jitter_percent, sample)).await;
+            Some(((), ()))
+        }))
+        .boxed()
+}
+
+/// A period randomized by a sample in the range -1 to 1, scaled by the jitter percentage.
+fn jittered_period(period: Duration, jitter_percent: f64, sample: f64) -> Duration {
+    let jitter_percent = jitter_percent.max(0.0).min(100.0);
+    period.mul_f64(1.0 + sample * jitter_percent / 100.0)
+}
+
+/// Backs off a reconciliation loop whose objects keep coming back empty, eg. because of a
+/// misconfigured label selector, by skipping an exponentially growing number of its timer's ticks.
+/// Any objects appearing resets it to the full frequency.
+#[derive(Clone, Debug)]
+pub struct EmptyListBackoff {
+    max_multiplier: u32,
+    empty_lists: u32,
+    skip_ticks: u32,
+}
+
+impl EmptyListBackoff {
+    /// Back off to at most the given multiple of the timer's period.
+    pub fn new(max_multiplier: u32) -> Self {
+        Self {
+            max_multiplier: max_multiplier.max(1),
+            empty_lists: 0,
+            skip_ticks: 0,
+        }
+    }
+
+    /// Should reconciliation be skipped on this tick of the timer?
+    pub fn skip(&mut self) -> bool {
+        if self.skip_ticks > 0 {
+            self.skip_ticks -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record the number of objects a reconciliation matched.
+    pub fn record(&mut self, objects: usize) {
+        if objects > 0 {
+            self.empty_lists = 0;
+        } else {
+            self.empty_lists = self.empty_lists.saturating_add(1);
+        }
+        self.skip_ticks = self.multiplier() - 1;
+    }
+
+    /// The number of consecutive reconciliations that matched no objects.
+    pub fn empty_lists(&self) -> u32 {
+        self.empty_lists
+    }
+
+    /// The multiple of the timer's period between reconciliations, doubling with each empty list
+    /// past the threshold.
+    pub fn multiplier(&self) -> u32 {
+        if self.empty_lists < EMPTY_LIST_BACKOFF_THRESHOLD {
+            return 1;
+        }
+        1u32.checked_shl(self.empty_lists - EMPTY_LIST_BACKOFF_THRESHOLD + 1)
+            .unwrap_or(std::u32::MAX)
+            .min(self.max_multiplier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jittered_period() {
+        let period = Duration::from_secs(10);
+        assert_eq!(jittered_period(period, 0.0, 1.0), period);
+        assert_eq!(jittered_period(period, 10.0, 1.0), Duration::from_secs(11));
+        assert_eq!(jittered_period(period, 10.0, -1.0), Duration::from_secs(9));
+        // The period can't go negative, however much jitter is asked for.
+        assert_eq!(jittered_period(period, 500.0, -1.0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_empty_list_backoff() {
+        let mut backoff = EmptyListBackoff::new(8);
+        let mut reconciled = Vec::new();
+        for objects in &[0, 0, 0, 0, 0, 0, 0, 0, 2, 2] {
+            while backoff.skip() {
+                reconciled.push(false);
+            }
+            reconciled.push(true);
+            backoff.record(*objects);
+        }
+        // Past the threshold the gaps double, up to the maximum, and reset once objects appear.
+        assert_eq!(backoff.multiplier(), 1);
+        assert_eq!(
+            reconciled.iter().filter(|reconciled| !**reconciled).count(),
+            1 + 3 + 7 + 7 + 7 + 7
+        );
+
+        let mut backoff = EmptyListBackoff::new(1);
+        for _ in 0..10 {
+            backoff.record(0);
+        }
+        assert_eq!(backoff.multiplier(), 1);
+        assert!(!backoff.skip());
+    }
+}